@@ -3,6 +3,8 @@ use common::io::FileExt;
 use common::io::ReadExt;
 use common::io::seek_overflow;
 use common::num::Cast;
+use std::error;
+use std::fmt;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::Seek;
@@ -24,6 +26,24 @@ pub enum Error {
     Io(io::Error),
 }
 
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Df(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Df(ref e) => write!(f, "datafile: {}", e),
+            Error::Io(ref e) => write!(f, "io: {}", e),
+        }
+    }
+}
+
 impl From<format::Error> for Error {
     fn from(err: format::Error) -> Error {
         Error::Df(err)
@@ -54,6 +74,9 @@ struct CallbackData {
 pub struct Reader {
     callback_data: CallbackData,
     raw: raw::Reader,
+    // Holds the compressed data in `read_data` and `data_compressed`, kept
+    // across calls to avoid reallocating it.
+    raw_data: Vec<u8>,
 }
 
 trait ResultExt {
@@ -72,7 +95,7 @@ impl<T> ResultExt for Result<T, raw::Error> {
 }
 
 impl Reader {
-    fn new_impl(file: File, check_initial_offset: bool) -> Result<Reader, Error> {
+    fn new_impl(file: File, check_initial_offset: bool, limits: raw::Limits) -> Result<Reader, Error> {
         let mut file = file;
         let datafile_start = if check_initial_offset {
             file.seek(SeekFrom::Current(0))?
@@ -86,7 +109,7 @@ impl Reader {
             seek_base: None,
             error: None,
         };
-        let raw = raw::Reader::new(&mut callback_data_new)
+        let raw = raw::Reader::new_with_limits(&mut callback_data_new, limits)
             .retrieve(&mut callback_data_new.error)?;
         let callback_data = CallbackData {
             file: callback_data_new.file.into_inner(),
@@ -97,16 +120,23 @@ impl Reader {
         Ok(Reader {
             callback_data: callback_data,
             raw: raw,
+            raw_data: Vec::new(),
         })
     }
     pub fn new(file: File) -> Result<Reader, Error> {
-        Reader::new_impl(file, true)
+        Reader::new_with_limits(file, raw::Limits::default())
+    }
+    pub fn new_with_limits(file: File, limits: raw::Limits) -> Result<Reader, Error> {
+        Reader::new_impl(file, true, limits)
     }
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Reader, Error> {
-        fn inner(path: &Path) -> Result<Reader, Error> {
-            Reader::new_impl(File::open(path)?, false)
+        Reader::open_with_limits(path, raw::Limits::default())
+    }
+    pub fn open_with_limits<P: AsRef<Path>>(path: P, limits: raw::Limits) -> Result<Reader, Error> {
+        fn inner(path: &Path, limits: raw::Limits) -> Result<Reader, Error> {
+            Reader::new_impl(File::open(path)?, false, limits)
         }
-        inner(path.as_ref())
+        inner(path.as_ref(), limits)
     }
     pub fn debug_dump(&mut self) -> Result<(), Error> {
         Ok(self.raw.debug_dump(&mut self.callback_data)
@@ -116,10 +146,39 @@ impl Reader {
         self.raw.version()
     }
     pub fn read_data(&mut self, index: usize) -> Result<Vec<u8>, Error> {
-        self.raw.read_data(&mut self.callback_data, index)
+        self.raw.read_data_with_buffer(&mut self.callback_data, index, &mut self.raw_data)
+            .retrieve(&mut self.callback_data.error)?;
+        Ok(self.callback_data.buffer.take().unwrap())
+    }
+    /// Reads the data as stored in the file, see `raw::Reader::read_raw_data`.
+    pub fn read_raw_data(&mut self, index: usize) -> Result<Vec<u8>, Error> {
+        self.raw.read_raw_data(&mut self.callback_data, index)
             .retrieve(&mut self.callback_data.error)?;
         Ok(self.callback_data.buffer.take().unwrap())
     }
+    /// Returns the data as stored in the file, without decompressing it.
+    ///
+    /// The data is zlib-compressed unless the datafile is of version 3, see
+    /// `version`. Unlike `read_raw_data`, this doesn't allocate memory for
+    /// each call, which makes it cheap to compare or hash data without
+    /// decompressing it. The returned slice is overwritten by the next read.
+    pub fn data_compressed(&mut self, index: usize) -> Result<&[u8], Error> {
+        self.raw.read_raw_data_with_buffer(&mut self.callback_data, index, &mut self.raw_data)
+            .retrieve(&mut self.callback_data.error)?;
+        Ok(&self.raw_data)
+    }
+    /// Returns a stream reading the data incrementally, see
+    /// `raw::Reader::data_stream`.
+    pub fn data_stream<'a>(&'a mut self, index: usize) -> Result<DataStream<'a>, Error> {
+        let stream = self.raw.data_stream(index).retrieve(&mut None)?;
+        Ok(DataStream {
+            callback_data: &mut self.callback_data,
+            stream: stream,
+        })
+    }
+    pub fn data_size(&self, index: usize) -> usize {
+        self.raw.data_size(index)
+    }
     pub fn item(&self, index: usize) -> ItemView {
         self.raw.item(index)
     }
@@ -161,6 +220,27 @@ impl Reader {
     }
 }
 
+/// Incremental reader for one data item, see `Reader::data_stream`.
+pub struct DataStream<'a> {
+    callback_data: &'a mut CallbackData,
+    stream: raw::DataStream,
+}
+
+impl<'a> DataStream<'a> {
+    /// Returns the number of bytes not read yet.
+    pub fn remaining(&self) -> usize {
+        self.stream.remaining()
+    }
+    /// Reads the next bytes of the data into `buffer`.
+    ///
+    /// Returns the number of bytes read, zero only at the end of the data
+    /// or if `buffer` is empty.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.stream.read(self.callback_data, buffer)
+            .retrieve(&mut self.callback_data.error)
+    }
+}
+
 pub type DataIter<'a> = MapIterator<Result<Vec<u8>, Error>, &'a mut Reader, ops::Range<usize>>;
 
 // "SeekOverflow"
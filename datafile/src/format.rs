@@ -7,6 +7,8 @@ use common::num::Cast;
 use common::slice::mut_ref_slice;
 use raw::CallbackNew;
 use raw;
+use std::error;
+use std::fmt;
 use zlib;
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -17,11 +19,39 @@ pub enum Error {
     Malformed,
     CompressionWrongSize,
     CompressionError(zlib::Error),
+    DecompressionLimit,
     TooShort,
     TooShortHeaderVersion,
     TooShortHeader,
 }
 
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::CompressionError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match *self {
+            WrongMagic(ref m) => write!(f, "wrong magic {:?}", String::from_utf8_lossy(m)),
+            UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            MalformedHeader => f.write_str("malformed header"),
+            Malformed => f.write_str("malformed datafile"),
+            CompressionWrongSize => f.write_str("decompressed data has the wrong size"),
+            CompressionError(ref e) => write!(f, "decompression failed: {}", e),
+            DecompressionLimit => f.write_str("decompressed data exceeds the size limit"),
+            TooShort => f.write_str("file too short"),
+            TooShortHeaderVersion => f.write_str("file too short for the header version"),
+            TooShortHeader => f.write_str("file too short for the header"),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct Header {
@@ -7,6 +7,7 @@ extern crate itertools;
 extern crate zlib_minimal as zlib;
 
 pub use file::DataIter;
+pub use file::DataStream;
 pub use file::Error;
 pub use file::Reader;
 pub use format::ItemView;
@@ -14,7 +15,9 @@ pub use format::OnlyI32;
 pub use raw::ItemTypeItems;
 pub use raw::ItemTypes;
 pub use raw::Items;
+pub use raw::Limits;
 pub use raw::Version;
+pub use writer::Writer;
 
 mod bitmagic;
 pub mod buffer;
@@ -4,6 +4,9 @@ use hexdump::hexdump_iter;
 use hexdump::sanitize_byte;
 use itertools::Itertools;
 use log;
+use std::cmp;
+use std::error;
+use std::fmt;
 use std::mem;
 use std::ops;
 use zlib;
@@ -33,8 +36,17 @@ impl Version {
     }
 }
 
+#[derive(Debug)]
 pub struct CallbackError;
 
+impl error::Error for CallbackError {}
+
+impl fmt::Display for CallbackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("callback error")
+    }
+}
+
 pub trait CallbackNew {
     fn read(&mut self, buffer: &mut [u8]) -> Result<usize, CallbackError>;
     fn set_seek_base(&mut self) -> Result<(), CallbackError>;
@@ -53,12 +65,41 @@ pub enum CallbackReadError {
     EndOfFile,
 }
 
+impl error::Error for CallbackReadError {}
+
+impl fmt::Display for CallbackReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            CallbackReadError::Callback => "callback error",
+            CallbackReadError::EndOfFile => "unexpected end of file",
+        })
+    }
+}
+
 #[derive(Clone, Copy, Eq, Hash, PartialEq, Debug)]
 pub enum Error {
     Df(format::Error),
     Callback,
 }
 
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Df(ref e) => Some(e),
+            Error::Callback => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Df(ref e) => write!(f, "datafile: {}", e),
+            Error::Callback => f.write_str("callback error"),
+        }
+    }
+}
+
 impl From<format::Error> for Error {
     fn from(err: format::Error) -> Error {
         Error::Df(err)
@@ -85,6 +126,132 @@ impl CallbackReadError {
     }
 }
 
+/// Default for `Limits::max_data_size`.
+pub const DEFAULT_MAX_DATA_SIZE: usize = 256 * 1024 * 1024;
+/// Default for `Limits::max_total_data_size`.
+pub const DEFAULT_MAX_TOTAL_DATA_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Bounds on the memory needed to read the data of a datafile.
+///
+/// The uncompressed sizes declared in the datafile are checked against them
+/// when it is opened, and decompression never produces more than the declared
+/// size. Datafiles exceeding them are rejected with
+/// `format::Error::DecompressionLimit`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// Maximum uncompressed size of a single data item.
+    ///
+    /// Default: `DEFAULT_MAX_DATA_SIZE`.
+    pub max_data_size: usize,
+    /// Maximum sum of the uncompressed sizes of all data items.
+    ///
+    /// Default: `DEFAULT_MAX_TOTAL_DATA_SIZE`.
+    pub max_total_data_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_data_size: DEFAULT_MAX_DATA_SIZE,
+            max_total_data_size: DEFAULT_MAX_TOTAL_DATA_SIZE,
+        }
+    }
+}
+
+/// Size of the chunks in which a `DataStream` reads compressed data.
+pub const DATA_STREAM_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Incremental reader for one data item, see `Reader::data_stream`.
+pub struct DataStream {
+    index: usize,
+    // Position and remaining size of the data in the file.
+    file_offset: u32,
+    file_remaining: usize,
+    // Remaining size after decompression.
+    remaining: usize,
+    // Only present in datafiles with compressed data.
+    inflate: Option<Inflate>,
+}
+
+struct Inflate {
+    inflate: zlib::Inflate,
+    // Compressed data read from the file, consumed up to `input_pos`.
+    input: Vec<u8>,
+    input_pos: usize,
+}
+
+impl DataStream {
+    /// Returns the number of bytes not read yet.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+    /// Reads the next bytes of the data into `buffer`.
+    ///
+    /// Returns the number of bytes read, zero only at the end of the data
+    /// or if `buffer` is empty.
+    pub fn read(&mut self, mut cb: &mut dyn CallbackReadData, buffer: &mut [u8]) -> Result<usize, Error> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let inflate = match self.inflate {
+            Some(ref mut i) => i,
+            None => {
+                let len = cmp::min(buffer.len(), self.remaining);
+                cb.seek_read_exact(self.file_offset, &mut buffer[..len]).map_err(|e| e.on_eof(format::Error::TooShort))?;
+                self.file_offset += len.assert_u32();
+                self.file_remaining -= len;
+                self.remaining -= len;
+                return Ok(len);
+            }
+        };
+        loop {
+            if inflate.input_pos == inflate.input.len() && self.file_remaining != 0 {
+                let len = cmp::min(self.file_remaining, DATA_STREAM_CHUNK_SIZE);
+                inflate.input.resize(len, 0);
+                cb.seek_read_exact(self.file_offset, &mut inflate.input).map_err(|e| e.on_eof(format::Error::TooShort))?;
+                inflate.input_pos = 0;
+                self.file_offset += len.assert_u32();
+                self.file_remaining -= len;
+            }
+            // Check that the data doesn't continue after the declared size.
+            let mut excess = [0];
+            let output = if self.remaining != 0 {
+                let len = cmp::min(buffer.len(), self.remaining);
+                &mut buffer[..len]
+            } else if !inflate.inflate.is_finished() {
+                &mut excess[..]
+            } else {
+                return Ok(0);
+            };
+            let (consumed, written) = inflate.inflate.inflate(output, &inflate.input[inflate.input_pos..])
+                .map_err(|e| {
+                    error!("decompression error: {:?}", e);
+                    format::Error::CompressionError(e)
+                })?;
+            inflate.input_pos += consumed;
+            if self.remaining == 0 && written != 0 {
+                error!("decompression error: data larger than declared, data={}", self.index);
+                return Err(format::Error::DecompressionLimit.into());
+            }
+            self.remaining -= written;
+            if written != 0 {
+                return Ok(written);
+            }
+            if inflate.inflate.is_finished() {
+                if self.remaining != 0 {
+                    error!("decompression error: data smaller than declared, data={} missing={}", self.index, self.remaining);
+                    return Err(format::Error::CompressionWrongSize.into());
+                }
+                return Ok(0);
+            }
+            if consumed == 0 && (inflate.input_pos != inflate.input.len() || self.file_remaining == 0) {
+                error!("decompression error: truncated data, data={}", self.index);
+                return Err(format::Error::CompressionWrongSize.into());
+            }
+        }
+    }
+}
+
 pub struct Reader {
     header: format::Header,
     item_types: Vec<format::ItemType>,
@@ -93,10 +260,20 @@ pub struct Reader {
     uncomp_data_sizes: Option<Vec<i32>>,
     items_raw: Vec<i32>,
     version: Version,
+    limits: Limits,
 }
 
 impl Reader {
+    /// Reads the header and the items of a datafile.
+    ///
+    /// The sizes claimed by the header are checked against the file size
+    /// before anything is allocated, so a lying header can't cause huge
+    /// allocations. The format can't describe files larger than 2 GiB, their
+    /// headers are rejected as malformed.
     pub fn new(cb: &mut dyn CallbackNew) -> Result<Reader, Error> {
+        Reader::new_with_limits(cb, Limits::default())
+    }
+    pub fn new_with_limits(cb: &mut dyn CallbackNew, limits: Limits) -> Result<Reader, Error> {
         fn read_i32s<T: OnlyI32>(mut cb: &mut dyn CallbackNew, len: usize) -> Result<Vec<T>,Error> {
             cb.read_exact_le_i32s_owned::<T>(len).map_err(|e| e.on_eof(format::Error::TooShort))
         }
@@ -108,6 +285,12 @@ impl Reader {
             4 => if !header_check.crude_version { Version::V4 } else { Version::V4Crude },
             _ => unreachable!(), // Should have been caught earlier, in Header::read().
         };
+
+        cb.ensure_filesize(header_check.expected_size)?.map_err(|()| {
+            error!("file is not long enough, wanted {}", header_check.expected_size);
+            format::Error::TooShort
+        })?;
+
         let item_types_raw = read_i32s(cb, header.hr.num_item_types as usize)?;
         let item_offsets = read_i32s(cb, header.hr.num_items as usize)?;
         let data_offsets = read_i32s(cb, header.hr.num_data as usize)?;
@@ -122,11 +305,6 @@ impl Reader {
 
         cb.set_seek_base()?;
 
-        cb.ensure_filesize(header_check.expected_size)?.map_err(|()| {
-            error!("file is not long enough, wanted {}", header_check.expected_size);
-            format::Error::TooShort
-        })?;
-
         let result = Reader {
             header: header,
             item_types: item_types_raw,
@@ -135,6 +313,7 @@ impl Reader {
             uncomp_data_sizes: uncomp_data_sizes,
             items_raw: items_raw,
             version: version,
+            limits: limits,
         };
         result.check()?;
         Ok(result)
@@ -230,6 +409,21 @@ impl Reader {
                 previous = offset;
             }
         }
+        {
+            let mut total = 0;
+            for i in 0..self.header.hr.num_data as usize {
+                let size = self.data_size(i);
+                if size > self.limits.max_data_size {
+                    error!("data too large, data={} size={} max={}", i, size, self.limits.max_data_size);
+                    return Err(format::Error::DecompressionLimit);
+                }
+                total += size.u64();
+                if total > self.limits.max_total_data_size {
+                    error!("data too large in total, data={} total={} max={}", i, total, self.limits.max_total_data_size);
+                    return Err(format::Error::DecompressionLimit);
+                }
+            }
+        }
         {
             for (i, t) in self.item_types.iter().enumerate() {
                 for k in t.start as usize..(t.start + t.num) as usize {
@@ -263,19 +457,37 @@ impl Reader {
         assert!(start <= end);
         end - start
     }
+    /// Returns the size of the data after decompression.
+    pub fn data_size(&self, index: usize) -> usize {
+        match self.uncomp_data_sizes {
+            Some(ref uds) => uds[index].assert_usize(),
+            None => self.data_size_file(index),
+        }
+    }
     pub fn version(&self) -> Version {
         self.version
     }
-    pub fn read_data<'a>(&self, mut cb: &'a mut dyn CallbackReadData, index: usize) -> Result<(), Error> {
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+    pub fn read_data<'a>(&self, cb: &'a mut dyn CallbackReadData, index: usize) -> Result<(), Error> {
+        self.read_data_with_buffer(cb, index, &mut Vec::new())
+    }
+    /// Like `read_data`, but uses `raw_data` to hold the data as stored in
+    /// the file.
+    ///
+    /// `raw_data` is only grown, never shrunk, so reusing it across calls
+    /// avoids allocating memory for the compressed data each time.
+    pub fn read_data_with_buffer<'a>(&self, cb: &'a mut dyn CallbackReadData, index: usize, raw_data: &mut Vec<u8>) -> Result<(), Error> {
         let raw_data_len = self.data_size_file(index);
-        let raw_data = cb.seek_read_exact_owned(self.data_offsets[index] as u32, raw_data_len).map_err(|e| e.on_eof(format::Error::TooShort))?;
+        self.read_raw_data_with_buffer(cb, index, raw_data)?;
 
         if let Some(ref uds) = self.uncomp_data_sizes {
             let data_len = uds[index] as usize;
             cb.alloc_data_buffer(data_len)?;
             let data = cb.data_buffer();
 
-            match zlib::uncompress(data, &raw_data) {
+            match zlib::uncompress(data, raw_data) {
                 Ok(len) if len == data_len => {
                     Ok(())
                 }
@@ -283,6 +495,10 @@ impl Reader {
                     error!("decompression error: wrong size, data={} size={} wanted={}", index, data_len, len);
                     Err(format::Error::CompressionWrongSize.into())
                 }
+                Err(ref e) if e.kind() == Ok(zlib::ErrorKind::OutputBufferTooSmall) => {
+                    error!("decompression error: data larger than declared, data={} wanted={}", index, data_len);
+                    Err(format::Error::DecompressionLimit.into())
+                }
                 Err(e) => {
                     error!("decompression error: {:?}", e);
                     Err(format::Error::CompressionError(e).into())
@@ -296,6 +512,47 @@ impl Reader {
             Ok(())
         }
     }
+    /// Reads the data as stored in the file, without decompressing it.
+    ///
+    /// The data is zlib-compressed unless the datafile is of version 3.
+    pub fn read_raw_data(&self, mut cb: &mut dyn CallbackReadData, index: usize) -> Result<(), Error> {
+        let len = self.data_size_file(index);
+        let raw_data = cb.seek_read_exact_owned(self.data_offsets[index] as u32, len).map_err(|e| e.on_eof(format::Error::TooShort))?;
+        cb.alloc_data_buffer(len)?;
+        cb.data_buffer().copy_from_slice(&raw_data);
+        Ok(())
+    }
+    /// Like `read_raw_data`, but reads the data into `raw_data` instead of
+    /// the data buffer of the callback.
+    ///
+    /// `raw_data` is only grown, never shrunk, see `read_data_with_buffer`.
+    pub fn read_raw_data_with_buffer(&self, mut cb: &mut dyn CallbackReadData, index: usize, raw_data: &mut Vec<u8>) -> Result<(), Error> {
+        raw_data.clear();
+        raw_data.resize(self.data_size_file(index), 0);
+        cb.seek_read_exact(self.data_offsets[index] as u32, raw_data).map_err(|e| e.on_eof(format::Error::TooShort))?;
+        Ok(())
+    }
+    /// Returns a stream reading the data incrementally.
+    ///
+    /// Unlike `read_data`, this doesn't need memory for the whole data.
+    pub fn data_stream(&self, index: usize) -> Result<DataStream, Error> {
+        let inflate = if self.uncomp_data_sizes.is_some() {
+            Some(Inflate {
+                inflate: zlib::Inflate::new().map_err(format::Error::CompressionError)?,
+                input: Vec::new(),
+                input_pos: 0,
+            })
+        } else {
+            None
+        };
+        Ok(DataStream {
+            index: index,
+            file_offset: self.data_offsets[index] as u32,
+            file_remaining: self.data_size_file(index),
+            remaining: self.data_size(index),
+            inflate: inflate,
+        })
+    }
     pub fn item(&self, index: usize) -> ItemView {
         let item_header = self.item_header(index);
         let data = &self.items_raw
@@ -1,52 +1,138 @@
-/*
-pub fn write_datafile<T:Datafile,W:Writer>(df: &T, writer: &mut W) -> Result<IoResult<()>,()> {
-    let compressed_data: Vec<Vec<u8>> = try!(result::collect(df.data_iter().map(|maybe_x| maybe_x.map(|x| {
-        zlib::compress_vec(x).unwrap()
-        (item_type.start, item_type.num)
-    }
+use common::num::Cast;
+use std::io;
+use std::mem;
+use zlib;
+
+use format::ItemHeader;
+use format::ItemType;
+use format;
+
+/// Creates version 4 datafiles.
+///
+/// Items are written sorted by their type ID, items of the same type in the
+/// order they were added. Together with `Reader::items` and
+/// `Reader::read_raw_data`, this allows copying datafiles without having to
+/// understand their items.
+#[derive(Clone, Debug, Default)]
+pub struct Writer {
+    // (type_id, id, data)
+    items: Vec<(u16, u16, Vec<i32>)>,
+    // Compressed data and its uncompressed size.
+    data: Vec<(Vec<u8>, usize)>,
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "datafile too large")
+}
+
+fn size_i32(size: usize) -> io::Result<i32> {
+    size.try_i32().ok_or_else(too_large)
 }
 
-/*
-pub fn write_datafile<T:Datafile,W:Writer>(df: &T, writer: &mut W) -> Result<IoResult<()>,()> {
-    let compressed_data: Vec<Vec<u8>> = result::collect(df.data_iter().map(|maybe_x| maybe_x.map(|x| {
-        zlib::compress_vec(x).unwrap()
-    })))?;
-
-    let size_items = df.items().fold(0, |s, i| {
-        s + i.data.len() * mem::size_of::<i32>() + mem::size_of::<DatafileItemHeader>()
-    });
-
-    let size_data = compressed_data.iter().fold(0, |s, d| s + d.len());
-
-    DatafileHeaderVersion {
-        magic: DATAFILE_MAGIC,
-        version: 4,
-    }.write(writer)?;
-
-    DatafileHeader {
-        _size: unimplemented!(),
-        _swaplen: unimplemented!(),
-        num_item_types: df.item_types().len(),
-        num_items: df.items().len(),
-        num_data: df.data_iter().len(),
-        size_items: size_items,
-        size_data: size_data,
-    }.write(writer)?;
-
-    for &type_id in df.item_types() {
-        let (start, num) = df.item_type_indexes_start_num(type_id);
-        DatafileItemType {
-            type_id: type_id.as_i32().unwrap(),
-            start: start.as_i32().unwrap(),
-            num: num.as_i32().unwrap(),
-        }.write(writer)?;
+impl Writer {
+    pub fn new() -> Writer {
+        Default::default()
+    }
+    pub fn add_item(&mut self, type_id: u16, id: u16, data: &[i32]) {
+        self.items.push((type_id, id, data.to_vec()));
     }
+    /// Compresses the data and adds it, returning its data index.
+    pub fn add_data(&mut self, data: &[u8]) -> Result<usize, zlib::Error> {
+        let compressed = zlib::compress_vec(data)?;
+        Ok(self.add_compressed_data(compressed, data.len()))
+    }
+    /// Adds zlib-compressed data verbatim, returning its data index.
+    ///
+    /// `size` is the size of the data after decompression.
+    pub fn add_compressed_data(&mut self, compressed: Vec<u8>, size: usize) -> usize {
+        self.data.push((compressed, size));
+        self.data.len() - 1
+    }
+    pub fn num_items(&self) -> usize {
+        self.items.len()
+    }
+    pub fn num_data(&self) -> usize {
+        self.data.len()
+    }
+    pub fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut order: Vec<usize> = (0..self.items.len()).collect();
+        // Stable, keeps the order of items of the same type.
+        order.sort_by_key(|&i| self.items[i].0);
+
+        let mut item_types: Vec<ItemType> = Vec::new();
+        for (i, &index) in order.iter().enumerate() {
+            let type_id = self.items[index].0.i32();
+            match item_types.last_mut() {
+                Some(ref mut t) if t.type_id == type_id => {
+                    t.num += 1;
+                    continue;
+                }
+                _ => {},
+            }
+            item_types.push(ItemType {
+                type_id: type_id,
+                start: size_i32(i)?,
+                num: 1,
+            });
+        }
+
+        let mut item_offsets = Vec::with_capacity(order.len());
+        let mut size_items = 0usize;
+        for &index in &order {
+            item_offsets.push(size_i32(size_items)?);
+            size_items = size_items.checked_add(mem::size_of::<ItemHeader>())
+                .and_then(|s| s.checked_add(self.items[index].2.len().checked_mul(mem::size_of::<i32>())?))
+                .ok_or_else(too_large)?;
+        }
+        let mut data_offsets = Vec::with_capacity(self.data.len());
+        let mut size_data = 0usize;
+        for &(ref compressed, _) in &self.data {
+            data_offsets.push(size_i32(size_data)?);
+            size_data = size_data.checked_add(compressed.len()).ok_or_else(too_large)?;
+        }
+
+        let total_size = (mem::size_of::<format::Header>()
+            + mem::size_of::<ItemType>() * item_types.len()
+            + mem::size_of::<i32>() * (order.len() + 2 * self.data.len()))
+            .checked_add(size_items)
+            .and_then(|s| s.checked_add(size_data))
+            .ok_or_else(too_large)?;
+        // The first four i32 fields are not accounted for in the size field.
+        let size = size_i32(total_size)? - mem::size_of::<i32>().assert_i32() * 4;
+
+        let mut ints = vec![
+            format::VERSION4,
+            size,
+            size - size_i32(size_data)?,
+            size_i32(item_types.len())?,
+            size_i32(order.len())?,
+            size_i32(self.data.len())?,
+            size_i32(size_items)?,
+            size_i32(size_data)?,
+        ];
+        for t in &item_types {
+            ints.extend_from_slice(&[t.type_id, t.start, t.num]);
+        }
+        ints.extend_from_slice(&item_offsets);
+        ints.extend_from_slice(&data_offsets);
+        for &(_, size) in &self.data {
+            ints.push(size_i32(size)?);
+        }
+        for &index in &order {
+            let (type_id, id, ref data) = self.items[index];
+            let header = ItemHeader::new(type_id, id, size_i32(data.len() * mem::size_of::<i32>())?);
+            ints.extend_from_slice(&[header.type_id_and_id, header.size]);
+            ints.extend_from_slice(data);
+        }
 
-    for DatafileItem { type_id, id, data } in df.items() {
-        DatafileItemHeader::new(type_id, id, data.len()).write(writer)?;
+        let mut bytes = Vec::with_capacity(total_size);
+        bytes.extend_from_slice(&format::MAGIC);
+        for i in ints {
+            bytes.extend_from_slice(&i.to_le_bytes());
+        }
+        for &(ref compressed, _) in &self.data {
+            bytes.extend_from_slice(compressed);
+        }
+        writer.write_all(&bytes)
     }
-    unimplemented!();
-    Ok(Ok(()))
 }
-*/
-*/
@@ -0,0 +1,82 @@
+extern crate datafile;
+
+use datafile::format;
+use datafile::raw::CallbackError;
+use datafile::raw::CallbackNew;
+use datafile::raw::Error;
+use datafile::raw::Reader;
+use std::cmp;
+
+struct Memory {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl CallbackNew for Memory {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, CallbackError> {
+        let len = cmp::min(buffer.len(), self.data.len() - self.pos);
+        buffer[..len].copy_from_slice(&self.data[self.pos..][..len]);
+        self.pos += len;
+        Ok(len)
+    }
+    fn set_seek_base(&mut self) -> Result<(), CallbackError> {
+        Ok(())
+    }
+    fn ensure_filesize(&mut self, filesize: u32) -> Result<Result<(), ()>, CallbackError> {
+        Ok(if self.data.len() as u64 >= filesize as u64 { Ok(()) } else { Err(()) })
+    }
+}
+
+// Creates a version 4 header with consistent `size` and `swaplen` fields.
+fn header(num_item_types: i32, num_items: i32, num_data: i32, size_items: i32, size_data: i32) -> Vec<u8> {
+    let total = 36 + 12 * num_item_types as i64 + 4 * num_items as i64 + 8 * num_data as i64
+        + size_items as i64 + size_data as i64;
+    let size = (total - 16) as i32;
+    let swaplen = size.wrapping_sub(size_data);
+    let mut result = b"DATA".to_vec();
+    for &i in &[4, size, swaplen, num_item_types, num_items, num_data, size_items, size_data] {
+        result.extend(&[i as u8, (i >> 8) as u8, (i >> 16) as u8, (i >> 24) as u8]);
+    }
+    result
+}
+
+fn read(data: Vec<u8>) -> Result<Reader, Error> {
+    Reader::new(&mut Memory { data: data, pos: 0 })
+}
+
+#[test]
+fn empty() {
+    let reader = read(header(0, 0, 0, 0, 0)).unwrap();
+    assert_eq!(reader.num_items(), 0);
+    assert_eq!(reader.num_data(), 0);
+}
+
+#[test]
+fn unsupported_version() {
+    let mut data = header(0, 0, 0, 0, 0);
+    data[4] = 5;
+    assert_eq!(read(data).err().unwrap(), Error::Df(format::Error::UnsupportedVersion(5)));
+}
+
+#[test]
+fn huge_item_area() {
+    // Claims 1.6 GB of item offsets, but the file only consists of the
+    // header. This must be rejected before allocating the offsets.
+    let err = read(header(0, 400_000_000, 0, 0, 0)).err().unwrap();
+    assert_eq!(err, Error::Df(format::Error::TooShort));
+    let err = read(header(1, 0, 0, 0x7000_0000, 0)).err().unwrap();
+    assert_eq!(err, Error::Df(format::Error::TooShort));
+}
+
+#[test]
+fn huge_data_area() {
+    let err = read(header(0, 0, 1, 0, 0x7000_0000)).err().unwrap();
+    assert_eq!(err, Error::Df(format::Error::TooShort));
+}
+
+#[test]
+fn overflowing_sizes() {
+    // The total size doesn't fit into the 32-bit size field.
+    let err = read(header(0, 0, 0, 0x4000_0000, 0x4000_0000)).err().unwrap();
+    assert_eq!(err, Error::Df(format::Error::MalformedHeader));
+}
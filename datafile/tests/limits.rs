@@ -0,0 +1,122 @@
+extern crate datafile;
+extern crate zlib_minimal as zlib;
+
+use datafile::format;
+use datafile::raw::CallbackError;
+use datafile::raw::CallbackNew;
+use datafile::raw::CallbackReadData;
+use datafile::raw::Error;
+use datafile::raw::Limits;
+use datafile::raw::Reader;
+use std::cmp;
+
+struct Memory {
+    data: Vec<u8>,
+    pos: usize,
+    seek_base: usize,
+    buffer: Vec<u8>,
+}
+
+impl CallbackNew for Memory {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, CallbackError> {
+        let len = cmp::min(buffer.len(), self.data.len() - self.pos);
+        buffer[..len].copy_from_slice(&self.data[self.pos..][..len]);
+        self.pos += len;
+        Ok(len)
+    }
+    fn set_seek_base(&mut self) -> Result<(), CallbackError> {
+        self.seek_base = self.pos;
+        Ok(())
+    }
+    fn ensure_filesize(&mut self, filesize: u32) -> Result<Result<(), ()>, CallbackError> {
+        Ok(if self.data.len() as u64 >= filesize as u64 { Ok(()) } else { Err(()) })
+    }
+}
+
+impl CallbackReadData for Memory {
+    fn seek_read(&mut self, start: u32, buffer: &mut [u8]) -> Result<usize, CallbackError> {
+        let start = cmp::min(self.seek_base + start as usize, self.data.len());
+        let len = cmp::min(buffer.len(), self.data.len() - start);
+        buffer[..len].copy_from_slice(&self.data[start..][..len]);
+        Ok(len)
+    }
+    fn alloc_data_buffer(&mut self, length: usize) -> Result<(), CallbackError> {
+        self.buffer = vec![0; length];
+        Ok(())
+    }
+    fn data_buffer(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+fn le(result: &mut Vec<u8>, i: i32) {
+    result.extend(&[i as u8, (i >> 8) as u8, (i >> 16) as u8, (i >> 24) as u8]);
+}
+
+// Creates a version 4 datafile containing a single data item with the given
+// declared uncompressed size.
+fn datafile(data: &[u8], declared_size: i32) -> Memory {
+    let compressed = zlib::compress_vec(data).unwrap();
+    let size_data = compressed.len() as i32;
+    let size = 36 - 16 + 8 + size_data;
+    let mut result = b"DATA".to_vec();
+    for &i in &[4, size, size - size_data, 0, 0, 1, 0, size_data] {
+        le(&mut result, i);
+    }
+    le(&mut result, 0);
+    le(&mut result, declared_size);
+    result.extend(&compressed);
+    Memory {
+        data: result,
+        pos: 0,
+        seek_base: 0,
+        buffer: Vec::new(),
+    }
+}
+
+#[test]
+fn within_limits() {
+    let mut df = datafile(&[1; 1000], 1000);
+    let reader = Reader::new(&mut df).unwrap();
+    reader.read_data(&mut df, 0).unwrap();
+    assert_eq!(df.buffer, &[1; 1000][..]);
+}
+
+#[test]
+fn over_expanding_block() {
+    // The block decompresses to 1 MiB, but only claims 1000 bytes.
+    let mut df = datafile(&vec![0; 1024 * 1024], 1000);
+    let reader = Reader::new(&mut df).unwrap();
+    let err = reader.read_data(&mut df, 0).err().unwrap();
+    assert_eq!(err, Error::Df(format::Error::DecompressionLimit));
+}
+
+#[test]
+fn declared_size_limit() {
+    let limits = Limits {
+        max_data_size: 999,
+        ..Limits::default()
+    };
+    let mut df = datafile(&[1; 1000], 1000);
+    let err = Reader::new_with_limits(&mut df, limits).err().unwrap();
+    assert_eq!(err, Error::Df(format::Error::DecompressionLimit));
+}
+
+#[test]
+fn total_size_limit() {
+    let limits = Limits {
+        max_data_size: 1000,
+        max_total_data_size: 999,
+    };
+    let mut df = datafile(&[1; 1000], 1000);
+    let err = Reader::new_with_limits(&mut df, limits).err().unwrap();
+    assert_eq!(err, Error::Df(format::Error::DecompressionLimit));
+}
+
+#[test]
+fn huge_declared_size() {
+    // Would allocate 2 GiB for a tiny block with the default limits.
+    let mut df = datafile(&[1; 1000], 0x7fff_ffff);
+    let err = Reader::new(&mut df).err().unwrap();
+    assert_eq!(err, Error::Df(format::Error::DecompressionLimit));
+}
@@ -0,0 +1,231 @@
+extern crate datafile;
+extern crate zlib_minimal as zlib;
+
+use datafile::format;
+use datafile::raw::CallbackError;
+use datafile::raw::CallbackNew;
+use datafile::raw::CallbackReadData;
+use datafile::raw::Error;
+use datafile::raw::Reader;
+use datafile::raw::Version;
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::cell::Cell;
+use std::cmp;
+
+// Tracks the allocated memory per thread, so that tests running in parallel
+// don't influence each other.
+struct Counting;
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+}
+
+fn track(delta: isize) {
+    let _ = ALLOCATED.try_with(|a| {
+        a.set(a.get() + delta);
+        let _ = PEAK.try_with(|p| p.set(cmp::max(p.get(), a.get())));
+    });
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        track(layout.size() as isize);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        track(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+// Returns the peak of additionally allocated memory while running `f`.
+fn peak_memory<F: FnOnce()>(f: F) -> isize {
+    let start = ALLOCATED.with(|a| a.get());
+    PEAK.with(|p| p.set(start));
+    f();
+    PEAK.with(|p| p.get()) - start
+}
+
+struct Memory {
+    data: Vec<u8>,
+    pos: usize,
+    seek_base: usize,
+    buffer: Vec<u8>,
+}
+
+impl CallbackNew for Memory {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, CallbackError> {
+        let len = cmp::min(buffer.len(), self.data.len() - self.pos);
+        buffer[..len].copy_from_slice(&self.data[self.pos..][..len]);
+        self.pos += len;
+        Ok(len)
+    }
+    fn set_seek_base(&mut self) -> Result<(), CallbackError> {
+        self.seek_base = self.pos;
+        Ok(())
+    }
+    fn ensure_filesize(&mut self, filesize: u32) -> Result<Result<(), ()>, CallbackError> {
+        Ok(if self.data.len() as u64 >= filesize as u64 { Ok(()) } else { Err(()) })
+    }
+}
+
+impl CallbackReadData for Memory {
+    fn seek_read(&mut self, start: u32, buffer: &mut [u8]) -> Result<usize, CallbackError> {
+        let start = cmp::min(self.seek_base + start as usize, self.data.len());
+        let len = cmp::min(buffer.len(), self.data.len() - start);
+        buffer[..len].copy_from_slice(&self.data[start..][..len]);
+        Ok(len)
+    }
+    fn alloc_data_buffer(&mut self, length: usize) -> Result<(), CallbackError> {
+        self.buffer = vec![0; length];
+        Ok(())
+    }
+    fn data_buffer(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+fn le(result: &mut Vec<u8>, i: i32) {
+    result.extend(&[i as u8, (i >> 8) as u8, (i >> 16) as u8, (i >> 24) as u8]);
+}
+
+// Creates a version 4 datafile containing a single data item with the given
+// declared uncompressed size.
+fn datafile(data: &[u8], declared_size: i32) -> Memory {
+    let compressed = zlib::compress_vec(data).unwrap();
+    let size_data = compressed.len() as i32;
+    let size = 36 - 16 + 8 + size_data;
+    let mut result = b"DATA".to_vec();
+    for &i in &[4, size, size - size_data, 0, 0, 1, 0, size_data] {
+        le(&mut result, i);
+    }
+    le(&mut result, 0);
+    le(&mut result, declared_size);
+    result.extend(&compressed);
+    Memory {
+        data: result,
+        pos: 0,
+        seek_base: 0,
+        buffer: Vec::new(),
+    }
+}
+
+// Creates a version 3 datafile containing a single uncompressed data item.
+fn datafile_v3(data: &[u8]) -> Memory {
+    let size_data = data.len() as i32;
+    let size = 36 - 16 + 4 + size_data;
+    let mut result = b"DATA".to_vec();
+    for &i in &[3, size, size - size_data, 0, 0, 1, 0, size_data] {
+        le(&mut result, i);
+    }
+    le(&mut result, 0);
+    result.extend(data);
+    Memory {
+        data: result,
+        pos: 0,
+        seek_base: 0,
+        buffer: Vec::new(),
+    }
+}
+
+fn read_stream(df: &mut Memory, buffer_size: usize) -> Result<Vec<u8>, Error> {
+    df.pos = 0;
+    let reader = Reader::new(df).unwrap();
+    let mut stream = reader.data_stream(0)?;
+    let mut buffer = vec![0; buffer_size];
+    let mut result = Vec::new();
+    loop {
+        let remaining = stream.remaining();
+        let read = stream.read(df, &mut buffer)?;
+        assert_eq!(stream.remaining(), remaining - read);
+        if read == 0 {
+            return Ok(result);
+        }
+        result.extend(&buffer[..read]);
+    }
+}
+
+#[test]
+fn stream() {
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut df = datafile(&data, data.len() as i32);
+    assert_eq!(read_stream(&mut df, 1000).unwrap(), data);
+    assert_eq!(read_stream(&mut df, 1).unwrap()[..], data[..]);
+}
+
+#[test]
+fn version3_uncompressed() {
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut df = datafile_v3(&data);
+    let reader = Reader::new(&mut df).unwrap();
+    assert_eq!(reader.version(), Version::V3);
+    assert_eq!(reader.data_size(0), data.len());
+    reader.read_data(&mut df, 0).unwrap();
+    assert_eq!(df.buffer, data);
+    // The data is stored as is.
+    reader.read_raw_data(&mut df, 0).unwrap();
+    assert_eq!(df.buffer, data);
+    assert_eq!(read_stream(&mut df, 1000).unwrap(), data);
+}
+
+#[test]
+fn read_data_with_buffer() {
+    let mut raw_data = Vec::new();
+    for &(len, v3) in &[(10_000, false), (10, false), (1000, true), (100, false)] {
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        let mut df = if v3 { datafile_v3(&data) } else { datafile(&data, len as i32) };
+        let reader = Reader::new(&mut df).unwrap();
+        reader.read_data(&mut df, 0).unwrap();
+        let expected = df.buffer.clone();
+        reader.read_data_with_buffer(&mut df, 0, &mut raw_data).unwrap();
+        assert_eq!(df.buffer, data);
+        assert_eq!(df.buffer, expected);
+    }
+}
+
+#[test]
+fn over_expanding_block() {
+    let mut df = datafile(&[0; 2000], 1000);
+    let err = read_stream(&mut df, 100).err().unwrap();
+    assert_eq!(err, Error::Df(format::Error::DecompressionLimit));
+}
+
+#[test]
+fn under_expanding_block() {
+    let mut df = datafile(&[0; 1000], 2000);
+    let err = read_stream(&mut df, 100).err().unwrap();
+    assert_eq!(err, Error::Df(format::Error::CompressionWrongSize));
+}
+
+#[test]
+fn peak_memory_compared_to_read_data() {
+    const SIZE: usize = 64 * 1024 * 1024;
+    let mut df = datafile(&vec![0; SIZE], SIZE as i32);
+    let reader = Reader::new(&mut df).unwrap();
+
+    let read_data = peak_memory(|| {
+        reader.read_data(&mut df, 0).unwrap();
+        df.buffer = Vec::new();
+    });
+    let stream = peak_memory(|| {
+        let mut stream = reader.data_stream(0).unwrap();
+        let mut buffer = [0; 4096];
+        let mut total = 0;
+        loop {
+            match stream.read(&mut df, &mut buffer).unwrap() {
+                0 => break,
+                read => total += read,
+            }
+        }
+        assert_eq!(total, SIZE);
+    });
+    assert!(read_data >= SIZE as isize);
+    // Only the chunk of compressed input is buffered.
+    assert!(stream < 64 * 1024, "stream={}", stream);
+}
@@ -0,0 +1,82 @@
+extern crate datafile;
+
+use datafile::Reader;
+use datafile::Version;
+use datafile::Writer;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+// Copies all items and data of the datafile, without interpreting them.
+fn copy(reader: &mut Reader) -> Vec<u8> {
+    let mut writer = Writer::new();
+    for item in reader.items() {
+        writer.add_item(item.type_id, item.id, item.data);
+    }
+    for i in 0..reader.num_data() {
+        if reader.version() == Version::V3 {
+            writer.add_data(&reader.read_data(i).unwrap()).unwrap();
+        } else {
+            let size = reader.data_size(i);
+            writer.add_compressed_data(reader.read_raw_data(i).unwrap(), size);
+        }
+    }
+    let mut result = Vec::new();
+    writer.write(&mut result).unwrap();
+    result
+}
+
+#[test]
+fn round_trip() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../server/dm1.map");
+    let mut reader = Reader::open(path).unwrap();
+    assert_eq!(copy(&mut reader), fs::read(path).unwrap());
+}
+
+#[test]
+fn data_compressed() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../server/dm1.map");
+    let mut reader = Reader::open(path).unwrap();
+    let raw: Vec<_> = (0..reader.num_data()).map(|i| reader.read_raw_data(i).unwrap()).collect();
+    for i in 0..reader.num_data() {
+        assert_eq!(reader.data_compressed(i).unwrap(), &raw[i][..]);
+    }
+    // A smaller data item after a larger one.
+    let largest = (0..raw.len()).max_by_key(|&i| raw[i].len()).unwrap();
+    let smallest = (0..raw.len()).min_by_key(|&i| raw[i].len()).unwrap();
+    reader.data_compressed(largest).unwrap();
+    assert_eq!(reader.data_compressed(smallest).unwrap(), &raw[smallest][..]);
+}
+
+#[test]
+fn unknown_items() {
+    let mut writer = Writer::new();
+    // Added out of type order, unknown to any reader.
+    writer.add_item(0x7123, 1, &[1, 2, 3]);
+    writer.add_item(0, 0, &[1]);
+    writer.add_item(0x7123, 0, &[]);
+    writer.add_item(0xffff, 0x8000, &[0x3e1b2716, 0x178c3978, -0x64264ee6, -0x1fbef228]);
+    assert_eq!(writer.add_data(b"unknown data\0").unwrap(), 0);
+    assert_eq!(writer.add_data(&[0; 1000]).unwrap(), 1);
+    let mut written = Vec::new();
+    writer.write(&mut written).unwrap();
+
+    let path = env::temp_dir().join(format!("datafile-writer-{}.dat", process::id()));
+    fs::write(&path, &written).unwrap();
+    let result = (|| {
+        let mut reader = Reader::open(Path::new(&path)).unwrap();
+        let items: Vec<_> = reader.items().map(|i| (i.type_id, i.id, i.data.to_vec())).collect();
+        assert_eq!(items, [
+            (0, 0, vec![1]),
+            (0x7123, 1, vec![1, 2, 3]),
+            (0x7123, 0, vec![]),
+            (0xffff, 0x8000, vec![0x3e1b2716, 0x178c3978, -0x64264ee6, -0x1fbef228]),
+        ]);
+        assert_eq!(reader.read_data(0).unwrap(), b"unknown data\0");
+        assert_eq!(reader.read_data(1).unwrap(), &[0; 1000][..]);
+        copy(&mut reader)
+    })();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(result, written);
+}
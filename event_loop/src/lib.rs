@@ -69,7 +69,8 @@ impl Loop for SocketLoop {
     fn accept_connections_on_port(port: u16) -> SocketLoop {
         SocketLoop {
             socket: Socket::bound(port).unwrap(),
-            net: Net::server(),
+            // `Loop::connect` works on servers, too.
+            net: Net::both(),
             want_to_flush: PeerSet::new(),
             disconnected: Default::default(),
             server: true,
@@ -89,6 +90,7 @@ impl Loop for SocketLoop {
         let mut buf2: ArrayVec<[u8; 4096]> = ArrayVec::new();
 
         loop {
+            self.net.send_blocked(&mut self.socket).unwrap();
             self.net.tick(&mut self.socket).foreach(|e| panic!("{:?}", e));
             application.on_tick(&mut self);
 
@@ -124,11 +126,11 @@ impl Loop for SocketLoop {
                             application.on_packet(&mut self, c),
                         Connless(c) =>
                             application.on_connless_packet(&mut self, c),
-                        Connect(pid) =>
+                        Connect(pid, _) =>
                             application.on_connect(&mut self, pid),
                         Ready(pid) =>
                             application.on_ready(&mut self, pid),
-                        Disconnect(pid, r) =>
+                        Disconnect(pid, _, r) =>
                             application.on_disconnect(&mut self, pid, true, r),
                     }
                 }
@@ -76,6 +76,8 @@ pub const FORWARD_CHECK: &'static [u8; 8] = b"\xff\xff\xff\xfffw??";
 pub const FORWARD_RESPONSE: &'static [u8; 8] = b"\xff\xff\xff\xfffw!!";
 pub const FORWARD_OK: &'static [u8; 8] = b"\xff\xff\xff\xfffwok";
 pub const FORWARD_ERROR: &'static [u8; 8] = b"\xff\xff\xff\xfffwer";
+pub const REQUEST_VERSION: &'static [u8; 8] = b"\xff\xff\xff\xffverg";
+pub const VERSION: &'static [u8; 8] = b"\xff\xff\xff\xffvers";
 
 #[derive(Clone, Copy)]
 pub enum Connless<'a> {
@@ -90,6 +92,8 @@ pub enum Connless<'a> {
     ForwardResponse(ForwardResponse),
     ForwardOk(ForwardOk),
     ForwardError(ForwardError),
+    RequestVersion(RequestVersion),
+    Version(Version<'a>),
 }
 
 impl<'a> Connless<'a> {
@@ -106,6 +110,8 @@ impl<'a> Connless<'a> {
             FORWARD_RESPONSE => Connless::ForwardResponse(ForwardResponse::decode(warn, _p)?),
             FORWARD_OK => Connless::ForwardOk(ForwardOk::decode(warn, _p)?),
             FORWARD_ERROR => Connless::ForwardError(ForwardError::decode(warn, _p)?),
+            REQUEST_VERSION => Connless::RequestVersion(RequestVersion::decode(warn, _p)?),
+            VERSION => Connless::Version(Version::decode(warn, _p)?),
             _ => return Err(Error::UnknownId),
         })
     }
@@ -122,6 +128,8 @@ impl<'a> Connless<'a> {
             Connless::ForwardResponse(_) => *FORWARD_RESPONSE,
             Connless::ForwardOk(_) => *FORWARD_OK,
             Connless::ForwardError(_) => *FORWARD_ERROR,
+            Connless::RequestVersion(_) => *REQUEST_VERSION,
+            Connless::Version(_) => *VERSION,
         }
     }
     pub fn encode_connless<'d, 's>(&self, p: Packer<'d, 's>) -> Result<&'d [u8], CapacityError> {
@@ -137,6 +145,8 @@ impl<'a> Connless<'a> {
             Connless::ForwardResponse(ref i) => i.encode(p),
             Connless::ForwardOk(ref i) => i.encode(p),
             Connless::ForwardError(ref i) => i.encode(p),
+            Connless::RequestVersion(ref i) => i.encode(p),
+            Connless::Version(ref i) => i.encode(p),
         }
     }
 }
@@ -155,6 +165,8 @@ impl<'a> fmt::Debug for Connless<'a> {
             Connless::ForwardResponse(ref i) => i.fmt(f),
             Connless::ForwardOk(ref i) => i.fmt(f),
             Connless::ForwardError(ref i) => i.fmt(f),
+            Connless::RequestVersion(ref i) => i.fmt(f),
+            Connless::Version(ref i) => i.fmt(f),
         }
     }
 }
@@ -224,6 +236,18 @@ impl<'a> From<ForwardError> for Connless<'a> {
         Connless::ForwardError(i)
     }
 }
+
+impl<'a> From<RequestVersion> for Connless<'a> {
+    fn from(i: RequestVersion) -> Connless<'a> {
+        Connless::RequestVersion(i)
+    }
+}
+
+impl<'a> From<Version<'a>> for Connless<'a> {
+    fn from(i: Version<'a>) -> Connless<'a> {
+        Connless::Version(i)
+    }
+}
 #[derive(Clone, Copy)]
 pub struct RequestList;
 
@@ -277,6 +301,14 @@ pub struct ForwardOk;
 #[derive(Clone, Copy)]
 pub struct ForwardError;
 
+#[derive(Clone, Copy)]
+pub struct RequestVersion;
+
+#[derive(Clone, Copy)]
+pub struct Version<'a> {
+    pub version: &'a [u8],
+}
+
 impl RequestList {
     pub fn decode<W: Warn<Warning>>(warn: &mut W, _p: &mut Unpacker) -> Result<RequestList, Error> {
         let result = Ok(RequestList);
@@ -514,3 +546,41 @@ impl fmt::Debug for ForwardError {
     }
 }
 
+impl RequestVersion {
+    pub fn decode<W: Warn<Warning>>(warn: &mut W, _p: &mut Unpacker) -> Result<RequestVersion, Error> {
+        let result = Ok(RequestVersion);
+        _p.finish(warn);
+        result
+    }
+    pub fn encode<'d, 's>(&self, mut _p: Packer<'d, 's>) -> Result<&'d [u8], CapacityError> {
+        Ok(_p.written())
+    }
+}
+impl fmt::Debug for RequestVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RequestVersion")
+            .finish()
+    }
+}
+
+impl<'a> Version<'a> {
+    pub fn decode<W: Warn<Warning>>(warn: &mut W, _p: &mut Unpacker<'a>) -> Result<Version<'a>, Error> {
+        let result = Ok(Version {
+            version: _p.read_string()?,
+        });
+        _p.finish(warn);
+        result
+    }
+    pub fn encode<'d, 's>(&self, mut _p: Packer<'d, 's>) -> Result<&'d [u8], CapacityError> {
+        _p.write_string(self.version)?;
+        Ok(_p.written())
+    }
+}
+impl<'a> fmt::Debug for Version<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Version")
+            .field("version", &pretty::Bytes::new(&self.version))
+            .finish()
+    }
+}
+
@@ -0,0 +1,39 @@
+extern crate gamenet_teeworlds_0_6 as gamenet;
+extern crate packer;
+extern crate warn;
+
+use gamenet::enums::VERSION;
+use gamenet::msg::Connless;
+use gamenet::msg::connless;
+use packer::Unpacker;
+use packer::with_packer;
+use warn::Panic;
+
+// A version query and the answer of a 0.6 server.
+const REQUEST_VERSION: &'static [u8] = b"\xff\xff\xff\xffverg";
+const VERSION_RESPONSE: &'static [u8] = b"\xff\xff\xff\xffvers0.6 626fce9a778df4d4\0";
+
+fn encode(msg: Connless) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(64);
+    with_packer(&mut buf, |p| msg.encode(p).unwrap());
+    buf
+}
+
+#[test]
+fn request_version() {
+    match Connless::decode(&mut Panic, &mut Unpacker::new(REQUEST_VERSION)).unwrap() {
+        Connless::RequestVersion(_) => {},
+        msg => panic!("unexpected message {:?}", msg),
+    }
+    assert_eq!(&encode(connless::RequestVersion.into())[..], REQUEST_VERSION);
+}
+
+#[test]
+fn version() {
+    match Connless::decode(&mut Panic, &mut Unpacker::new(VERSION_RESPONSE)).unwrap() {
+        Connless::Version(v) => assert_eq!(v.version, VERSION.as_bytes()),
+        msg => panic!("unexpected message {:?}", msg),
+    }
+    let response = connless::Version { version: VERSION.as_bytes() };
+    assert_eq!(&encode(response.into())[..], VERSION_RESPONSE);
+}
@@ -0,0 +1,61 @@
+#[macro_use] extern crate bencher;
+extern crate datafile;
+extern crate map;
+
+use bencher::Bencher;
+use bencher::black_box;
+use map::format;
+use map::reader::LayerType;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+const NUM_LAYERS: i32 = 64;
+const SIZE: i32 = 100;
+
+// Writes a map with a single group of `NUM_LAYERS` tile layers of
+// `SIZE`x`SIZE` tiles each.
+fn many_layers_map() -> PathBuf {
+    let mut writer = datafile::Writer::new();
+    writer.add_item(format::MAP_ITEMTYPE_VERSION, 0, &[1]);
+    writer.add_item(format::MAP_ITEMTYPE_GROUP, 0, &[1, 0, 0, 100, 100, 0, NUM_LAYERS]);
+    for i in 0..NUM_LAYERS {
+        let flags = if i == 0 { format::TILELAYERFLAG_GAME as i32 } else { 0 };
+        writer.add_item(format::MAP_ITEMTYPE_LAYER, i as u16, &[
+            0, format::MAP_ITEMTYPE_LAYER_V1_TILEMAP, 0,
+            3, SIZE, SIZE, flags, 255, 255, 255, 255, -1, 0, -1, i, 0, 0, 0,
+        ]);
+        let tiles: Vec<u8> = (0..SIZE * SIZE)
+            .flat_map(|j| vec![((i + j) % 7) as u8, 0, 0, 0])
+            .collect();
+        writer.add_data(&tiles).unwrap();
+    }
+    let mut written = Vec::new();
+    writer.write(&mut written).unwrap();
+    let path = env::temp_dir().join(format!("map-bench-layers-{}.map", process::id()));
+    fs::write(&path, &written).unwrap();
+    path
+}
+
+fn read_all_layers(map: &mut map::Reader) {
+    for g in map.group_indices() {
+        for l in map.group(g).unwrap().layer_indices {
+            if let LayerType::Tilemap(t) = map.layer(l).unwrap().t {
+                let data = t.type_.tiles().unwrap();
+                black_box(map.layer_tiles(t.tiles(data)).unwrap());
+            }
+        }
+    }
+}
+
+fn layer_tiles(bench: &mut Bencher) {
+    let path = many_layers_map();
+    let mut map = map::Reader::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    bench.iter(|| read_all_layers(&mut map));
+    bench.bytes = (NUM_LAYERS * SIZE * SIZE * 4) as u64;
+}
+
+benchmark_group!(layers, layer_tiles);
+benchmark_main!(layers);
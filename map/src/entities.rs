@@ -0,0 +1,177 @@
+//! Names of the tile indices used by DDNet in the game, front, teleport,
+//! speedup, switch and tune layers.
+//!
+//! The names follow the `TILE_*` and `ENTITY_*` definitions of DDNet's
+//! `mapitems.h`, without the prefix.
+
+/// Offset of the `ENTITY_*` indices, e.g. `ENTITY_SPAWN` is
+/// `ENTITY_OFFSET + 1`.
+pub const ENTITY_OFFSET: u8 = 255 - 16 * 4;
+
+/// Index of the start line of a race.
+pub const TILE_START: u8 = 33;
+/// Index of the finish line of a race.
+pub const TILE_FINISH: u8 = 34;
+
+/// Returns the name of the given tile index, or `None` for empty tiles and
+/// unknown indices.
+///
+/// Some indices mean different things in different layers, the name of such
+/// an index is the one it has in the game layer. E.g. 22 is `SOLO_DISABLE`,
+/// even though it's a timed switch in the switch layer.
+pub fn name(index: u8) -> Option<&'static str> {
+    Some(match index {
+        1 => "SOLID",
+        2 => "DEATH",
+        3 => "NOHOOK",
+        4 => "NOLASER",
+        5 => "THROUGH_CUT",
+        6 => "THROUGH",
+        7 => "JUMP",
+        9 => "FREEZE",
+        10 => "TELEINEVIL",
+        11 => "UNFREEZE",
+        12 => "DFREEZE",
+        13 => "DUNFREEZE",
+        14 => "TELEINWEAPON",
+        15 => "TELEINHOOK",
+        16 => "WALLJUMP",
+        17 => "EHOOK_ENABLE",
+        18 => "EHOOK_DISABLE",
+        19 => "HIT_ENABLE",
+        20 => "HIT_DISABLE",
+        21 => "SOLO_ENABLE",
+        22 => "SOLO_DISABLE",
+        23 => "SWITCHTIMEDCLOSE",
+        24 => "SWITCHOPEN",
+        25 => "SWITCHCLOSE",
+        26 => "TELEIN",
+        27 => "TELEOUT",
+        28 => "BOOST",
+        29 => "TELECHECK",
+        30 => "TELECHECKOUT",
+        31 => "TELECHECKIN",
+        32 => "REFILL_JUMPS",
+        TILE_START => "START",
+        TILE_FINISH => "FINISH",
+        35..=59 => "TIME_CHECKPOINT",
+        60 => "STOP",
+        61 => "STOPS",
+        62 => "STOPA",
+        63 => "TELECHECKINEVIL",
+        64 => "CP",
+        65 => "CP_F",
+        66 => "THROUGH_ALL",
+        67 => "THROUGH_DIR",
+        68 => "TUNE",
+        71 => "OLDLASER",
+        72 => "NPC",
+        73 => "EHOOK",
+        74 => "NOHIT",
+        75 => "NPH",
+        76 => "UNLOCK_TEAM",
+        79 => "ADD_TIME",
+        88 => "NPC_DISABLE",
+        89 => "UNLIMITED_JUMPS_DISABLE",
+        90 => "JETPACK_DISABLE",
+        91 => "NPH_DISABLE",
+        95 => "SUBTRACT_TIME",
+        96 => "TELE_GUN_ENABLE",
+        97 => "TELE_GUN_DISABLE",
+        98 => "ALLOW_TELE_GUN",
+        99 => "ALLOW_BLUE_TELE_GUN",
+        104 => "NPC_ENABLE",
+        105 => "UNLIMITED_JUMPS_ENABLE",
+        106 => "JETPACK_ENABLE",
+        107 => "NPH_ENABLE",
+        112 => "TELE_GRENADE_ENABLE",
+        113 => "TELE_GRENADE_DISABLE",
+        128 => "TELE_LASER_ENABLE",
+        129 => "TELE_LASER_DISABLE",
+        140..=143 | 156..=159 => "CREDITS",
+        144 => "LFREEZE",
+        145 => "LUNFREEZE",
+        190 | 191 => "ENTITIES_OFF",
+
+        192 => "SPAWN",
+        193 => "SPAWN_RED",
+        194 => "SPAWN_BLUE",
+        195 => "FLAGSTAND_RED",
+        196 => "FLAGSTAND_BLUE",
+        197 => "ARMOR_1",
+        198 => "HEALTH_1",
+        199 => "WEAPON_SHOTGUN",
+        200 => "WEAPON_GRENADE",
+        201 => "POWERUP_NINJA",
+        202 => "WEAPON_LASER",
+        203 => "LASER_FAST_CCW",
+        204 => "LASER_NORMAL_CCW",
+        205 => "LASER_SLOW_CCW",
+        206 => "LASER_STOP",
+        207 => "LASER_SLOW_CW",
+        208 => "LASER_NORMAL_CW",
+        209 => "LASER_FAST_CW",
+        210 => "LASER_SHORT",
+        211 => "LASER_MEDIUM",
+        212 => "LASER_LONG",
+        213 => "LASER_C_SLOW",
+        214 => "LASER_C_NORMAL",
+        215 => "LASER_C_FAST",
+        216 => "LASER_O_SLOW",
+        217 => "LASER_O_NORMAL",
+        218 => "LASER_O_FAST",
+        220 => "PLASMAE",
+        221 => "PLASMAF",
+        222 => "PLASMA",
+        223 => "PLASMAU",
+        224 => "CRAZY_SHOTGUN_EX",
+        225 => "CRAZY_SHOTGUN",
+        226 => "ARMOR_SHOTGUN",
+        227 => "ARMOR_GRENADE",
+        228 => "ARMOR_NINJA",
+        229 => "ARMOR_LASER",
+        233 => "DRAGGER_WEAK",
+        234 => "DRAGGER_NORMAL",
+        235 => "DRAGGER_STRONG",
+        236 => "DRAGGER_WEAK_NW",
+        237 => "DRAGGER_NORMAL_NW",
+        238 => "DRAGGER_STRONG_NW",
+        240 => "DOOR",
+
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::ENTITY_OFFSET;
+    use super::TILE_FINISH;
+    use super::TILE_START;
+    use super::name;
+
+    #[test]
+    fn names() {
+        assert_eq!(name(0), None);
+        assert_eq!(name(9), Some("FREEZE"));
+        assert_eq!(name(11), Some("UNFREEZE"));
+        assert_eq!(name(TILE_START), Some("START"));
+        assert_eq!(name(TILE_FINISH), Some("FINISH"));
+        assert_eq!(name(35), Some("TIME_CHECKPOINT"));
+        assert_eq!(name(59), Some("TIME_CHECKPOINT"));
+        assert_eq!(name(ENTITY_OFFSET + 1), Some("SPAWN"));
+        assert_eq!(name(201), Some("POWERUP_NINJA"));
+        assert_eq!(name(240), Some("DOOR"));
+        assert_eq!(name(255), None);
+    }
+
+    #[test]
+    fn dm1() {
+        // All tiles of dm1 are known.
+        let mut map = ::Reader::open(concat!(env!("CARGO_MANIFEST_DIR"), "/../server/dm1.map")).unwrap();
+        let game = map.game_layers().unwrap().game();
+        for tile in map.layer_tiles_stream(game).unwrap() {
+            let index = tile.unwrap().index;
+            assert!(index == 0 || name(index).is_some(), "{}", index);
+        }
+    }
+}
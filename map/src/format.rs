@@ -1,5 +1,6 @@
 use common;
 use datafile::OnlyI32;
+use std::error;
 use std::fmt;
 use std::mem;
 use std::ops;
@@ -25,11 +26,10 @@ pub trait MapItemExt: MapItem {
     }
     fn from_slice_rest(slice: &[i32]) -> Result<Option<(&Self, &[i32])>, TooShort> {
         if !Self::ignore_version() {
-            if slice.len() == 0 {
-                return Err(TooShort);
-            }
-            if slice[0] < Self::version() {
-                return Ok(None);
+            match ItemView::new(slice).version() {
+                None => return Err(TooShort),
+                Some(v) if v < Self::version() => return Ok(None),
+                Some(_) => {},
             }
         }
         if slice.len() < Self::sum_len() {
@@ -44,11 +44,10 @@ pub trait MapItemExt: MapItem {
         -> Result<Option<(&mut Self, &mut [i32])>, TooShort>
     {
         if !Self::ignore_version() {
-            if slice.len() == 0 {
-                return Err(TooShort);
-            }
-            if slice[0] < Self::version() {
-                return Ok(None);
+            match ItemView::new(slice).version() {
+                None => return Err(TooShort),
+                Some(v) if v < Self::version() => return Ok(None),
+                Some(_) => {},
             }
         }
         if slice.len() < Self::sum_len() {
@@ -65,6 +64,44 @@ impl<T: MapItem> MapItemExt for T { }
 
 pub struct TooShort;
 
+/// Bounds-checked access to the fields of a raw item.
+///
+/// Items written by older or broken implementations can be shorter than
+/// expected, missing fields are reported as `None` instead of panicking.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ItemView<'a> {
+    data: &'a [i32],
+}
+
+impl<'a> ItemView<'a> {
+    pub fn new(data: &'a [i32]) -> ItemView<'a> {
+        ItemView {
+            data: data,
+        }
+    }
+    pub fn data(&self) -> &'a [i32] {
+        self.data
+    }
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+    /// Returns the `i`-th field, or `None` if the item is too short.
+    pub fn field(&self, i: usize) -> Option<i32> {
+        self.data.get(i).cloned()
+    }
+    /// Returns the `i`-th field, or `default` if the item is too short.
+    pub fn field_or(&self, i: usize, default: i32) -> i32 {
+        self.field(i).unwrap_or(default)
+    }
+    /// Returns the version field of the item, its first field.
+    pub fn version(&self) -> Option<i32> {
+        self.field(0)
+    }
+}
+
 pub fn i32s_to_bytes(result: &mut [u8], input: &[i32]) {
     assert!(result.len() == input.len() * mem::size_of::<i32>());
     for (output, input) in result.chunks_mut(mem::size_of::<i32>()).zip(input) {
@@ -260,14 +297,13 @@ impl MapItemLayerV1TilemapExtraRace {
         let offset = unwrap_or_return!(
             MapItemLayerV1TilemapExtraRace::offset(version, flags), None
         );
-        if slice.len() <= offset {
-            return None;
-        }
+        ItemView::new(slice).field(offset)?;
         Some(&(unsafe { common::slice::transmute(slice) })[offset])
     }
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 #[repr(C)]
 pub struct Tile {
     pub index: u8,
@@ -277,6 +313,7 @@ pub struct Tile {
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 #[repr(C)]
 pub struct TeleTile {
     pub number: u8,
@@ -284,17 +321,75 @@ pub struct TeleTile {
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 #[repr(C)]
 pub struct TuneTile {
     pub number: u8,
     pub index: u8,
 }
 
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[repr(C)]
+pub struct SpeedupTile {
+    pub force: u8,
+    pub max_speed: u8,
+    pub index: u8,
+    pub padding: u8,
+    // Little-endian, use `angle` to read it.
+    pub angle_raw: [u8; 2],
+}
+
+impl SpeedupTile {
+    pub fn angle(&self) -> i16 {
+        i16::from_le_bytes(self.angle_raw)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[repr(C)]
+pub struct SwitchTile {
+    pub number: u8,
+    pub index: u8,
+    pub flags: u8,
+    pub delay: u8,
+}
+
 pub const TILEFLAG_VFLIP: u8 = 1 << 0;
 pub const TILEFLAG_HFLIP: u8 = 1 << 1;
 pub const TILEFLAG_OPAQUE: u8 = 1 << 2;
 pub const TILEFLAG_ROTATE: u8 = 1 << 3;
 
+impl Tile {
+    /// Whether the tile is mirrored along the vertical axis, swapping left
+    /// and right (`TILEFLAG_VFLIP`).
+    pub fn is_flipped_v(&self) -> bool {
+        self.flags & TILEFLAG_VFLIP != 0
+    }
+    /// Whether the tile is mirrored along the horizontal axis, swapping top
+    /// and bottom (`TILEFLAG_HFLIP`).
+    pub fn is_flipped_h(&self) -> bool {
+        self.flags & TILEFLAG_HFLIP != 0
+    }
+    /// Whether the tile has no transparent pixels (`TILEFLAG_OPAQUE`).
+    pub fn is_opaque(&self) -> bool {
+        self.flags & TILEFLAG_OPAQUE != 0
+    }
+    /// Whether the tile is rotated by 90 degrees clockwise
+    /// (`TILEFLAG_ROTATE`).
+    ///
+    /// The rotation is applied before the flips, a rotation by 180 degrees is
+    /// expressed by flipping in both directions.
+    pub fn is_rotated(&self) -> bool {
+        self.flags & TILEFLAG_ROTATE != 0
+    }
+    /// Returns the clockwise rotation in degrees, either 0 or 90.
+    pub fn rotation(&self) -> u32 {
+        if self.is_rotated() { 90 } else { 0 }
+    }
+}
+
 pub const LAYERFLAG_DETAIL: u32 = 1;
 pub const LAYERFLAGS_ALL: u32 = 1;
 
@@ -317,6 +412,24 @@ pub enum GroupError {
     InvalidNumLayers(i32, i32),
 }
 
+impl error::Error for GroupError {}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::GroupError::*;
+        match *self {
+            TooShort(len) => write!(f, "item too short ({} ints)", len),
+            TooShortV2(len) => write!(f, "item too short for version 2 ({} ints)", len),
+            TooShortV3(len) => write!(f, "item too short for version 3 ({} ints)", len),
+            InvalidVersion(v) => write!(f, "invalid version {}", v),
+            InvalidStartLayerIndex(start, num) =>
+                write!(f, "invalid start layer index {} ({} layers)", start, num),
+            InvalidNumLayers(start, num) =>
+                write!(f, "invalid number of layers {} (starting at {})", num, start),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum DdraceLayerSoundsError {
     TooShort(usize),
@@ -327,6 +440,22 @@ pub enum DdraceLayerSoundsError {
     InvalidDataIndex(i32),
 }
 
+impl error::Error for DdraceLayerSoundsError {}
+
+impl fmt::Display for DdraceLayerSoundsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DdraceLayerSoundsError::*;
+        match *self {
+            TooShort(len) => write!(f, "item too short ({} ints)", len),
+            TooShortV2(len) => write!(f, "item too short for version 2 ({} ints)", len),
+            InvalidVersion(v) => write!(f, "invalid version {}", v),
+            InvalidSoundIndex(i) => write!(f, "invalid sound index {}", i),
+            InvalidNumSources(n) => write!(f, "invalid number of sound sources {}", n),
+            InvalidDataIndex(i) => write!(f, "invalid data index {}", i),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum LayerQuadsError {
     TooShort(usize),
@@ -337,6 +466,22 @@ pub enum LayerQuadsError {
     InvalidDataIndex(i32),
 }
 
+impl error::Error for LayerQuadsError {}
+
+impl fmt::Display for LayerQuadsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::LayerQuadsError::*;
+        match *self {
+            TooShort(len) => write!(f, "item too short ({} ints)", len),
+            TooShortV2(len) => write!(f, "item too short for version 2 ({} ints)", len),
+            InvalidVersion(v) => write!(f, "invalid version {}", v),
+            InvalidImageIndex(i) => write!(f, "invalid image index {}", i),
+            InvalidNumQuads(n) => write!(f, "invalid number of quads {}", n),
+            InvalidDataIndex(i) => write!(f, "invalid data index {}", i),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum ColorComponent {
     Red,
@@ -345,6 +490,17 @@ pub enum ColorComponent {
     Alpha,
 }
 
+impl fmt::Display for ColorComponent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ColorComponent::Red => "red",
+            ColorComponent::Green => "green",
+            ColorComponent::Blue => "blue",
+            ColorComponent::Alpha => "alpha",
+        })
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum LayerTilemapError {
     TooShort(usize),
@@ -370,6 +526,42 @@ pub enum LayerTilemapError {
     InvalidHeight(i32),
 }
 
+impl error::Error for LayerTilemapError {}
+
+impl fmt::Display for LayerTilemapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::LayerTilemapError::*;
+        match *self {
+            TooShort(len) => write!(f, "item too short ({} ints)", len),
+            TooShortV2(len) => write!(f, "item too short for version 2 ({} ints)", len),
+            TooShortV3(len) => write!(f, "item too short for version 3 ({} ints)", len),
+            TooShortRaceTeleport(len) =>
+                write!(f, "item too short for a teleport layer ({} ints)", len),
+            TooShortRaceSpeedup(len) =>
+                write!(f, "item too short for a speedup layer ({} ints)", len),
+            TooShortDdraceFront(len) =>
+                write!(f, "item too short for a front layer ({} ints)", len),
+            TooShortDdraceSwitch(len) =>
+                write!(f, "item too short for a switch layer ({} ints)", len),
+            TooShortDdraceTune(len) =>
+                write!(f, "item too short for a tune layer ({} ints)", len),
+            InvalidVersion(v) => write!(f, "invalid version {}", v),
+            InvalidColor(c, v) => write!(f, "invalid {} color component {}", c, v),
+            InvalidColorEnvelopeIndex(i) => write!(f, "invalid color envelope index {}", i),
+            InvalidImageIndex(i) => write!(f, "invalid image index {}", i),
+            InvalidDataIndex(i) => write!(f, "invalid data index {}", i),
+            InvalidRaceTeleportDataIndex(i) => write!(f, "invalid teleport data index {}", i),
+            InvalidRaceSpeedupDataIndex(i) => write!(f, "invalid speedup data index {}", i),
+            InvalidDdraceFrontDataIndex(i) => write!(f, "invalid front data index {}", i),
+            InvalidDdraceSwitchDataIndex(i) => write!(f, "invalid switch data index {}", i),
+            InvalidDdraceTuneDataIndex(i) => write!(f, "invalid tune data index {}", i),
+            InvalidFlags(flags) => write!(f, "invalid flags {:#x}", flags),
+            InvalidWidth(w) => write!(f, "invalid width {}", w),
+            InvalidHeight(h) => write!(f, "invalid height {}", h),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum LayerError {
     Tilemap(LayerTilemapError),
@@ -398,6 +590,31 @@ impl From<DdraceLayerSoundsError> for LayerError {
     }
 }
 
+impl error::Error for LayerError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            LayerError::Tilemap(ref e) => Some(e),
+            LayerError::Quads(ref e) => Some(e),
+            LayerError::DdraceSounds(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LayerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::LayerError::*;
+        match *self {
+            Tilemap(ref e) => write!(f, "tile layer: {}", e),
+            Quads(ref e) => write!(f, "quad layer: {}", e),
+            DdraceSounds(ref e) => write!(f, "sound layer: {}", e),
+            TooShort(len) => write!(f, "item too short ({} ints)", len),
+            InvalidFlags(flags) => write!(f, "invalid flags {:#x}", flags),
+            InvalidType(t) => write!(f, "invalid layer type {}", t),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum ImageError {
     TooShort(usize),
@@ -408,6 +625,22 @@ pub enum ImageError {
     InvalidNameIndex(i32),
 }
 
+impl error::Error for ImageError {}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ImageError::*;
+        match *self {
+            TooShort(len) => write!(f, "item too short ({} ints)", len),
+            InvalidVersion(v) => write!(f, "invalid version {}", v),
+            InvalidDataIndex(i) => write!(f, "invalid data index {}", i),
+            InvalidWidth(w) => write!(f, "invalid width {}", w),
+            InvalidHeight(h) => write!(f, "invalid height {}", h),
+            InvalidNameIndex(i) => write!(f, "invalid name index {}", i),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum InfoError {
     TooShort(usize),
@@ -419,6 +652,41 @@ pub enum InfoError {
     InvalidSettingsIndex(i32),
 }
 
+impl error::Error for InfoError {}
+
+impl fmt::Display for InfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InfoError::*;
+        match *self {
+            TooShort(len) => write!(f, "item too short ({} ints)", len),
+            InvalidVersion(v) => write!(f, "invalid version {}", v),
+            InvalidAuthorIndex(i) => write!(f, "invalid author index {}", i),
+            InvalidVersionIndex(i) => write!(f, "invalid version index {}", i),
+            InvalidCreditsIndex(i) => write!(f, "invalid credits index {}", i),
+            InvalidLicenseIndex(i) => write!(f, "invalid license index {}", i),
+            InvalidSettingsIndex(i) => write!(f, "invalid settings index {}", i),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum AutomapperConfigError {
+    TooShort(usize),
+    InvalidVersion(i32),
+}
+
+impl error::Error for AutomapperConfigError {}
+
+impl fmt::Display for AutomapperConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::AutomapperConfigError::*;
+        match *self {
+            TooShort(len) => write!(f, "item too short ({} ints)", len),
+            InvalidVersion(v) => write!(f, "invalid version {}", v),
+        }
+    }
+}
+
 impl From<InfoError> for Error {
     fn from(e: InfoError) -> Error {
         Error::Info(e)
@@ -431,11 +699,14 @@ pub enum Error {
     Layer(usize, LayerError),
     Image(usize, ImageError),
     Info(InfoError),
+    AutomapperConfig(usize, AutomapperConfigError),
 
     InconsistentGameLayerDimensions,
     InvalidTilesLength(usize),
     InvalidTeleTilesLength(usize),
     InvalidTuneTilesLength(usize),
+    InvalidSpeedupTilesLength(usize),
+    InvalidSwitchTilesLength(usize),
     InvalidVersion(i32),
     MalformedImageName(usize),
     // InvalidTilesDimensions(length, width, height)
@@ -455,6 +726,56 @@ pub enum Error {
     TooManyGameLayers,
 }
 
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Group(_, ref e) => Some(e),
+            Error::Layer(_, ref e) => Some(e),
+            Error::Image(_, ref e) => Some(e),
+            Error::Info(ref e) => Some(e),
+            Error::AutomapperConfig(_, ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match *self {
+            Group(i, ref e) => write!(f, "group {}: {}", i, e),
+            Layer(i, ref e) => write!(f, "layer {}: {}", i, e),
+            Image(i, ref e) => write!(f, "image {}: {}", i, e),
+            Info(ref e) => write!(f, "info: {}", e),
+            AutomapperConfig(i, ref e) => write!(f, "automapper config {}: {}", i, e),
+            InconsistentGameLayerDimensions =>
+                f.write_str("game layers have inconsistent dimensions"),
+            InvalidTilesLength(len) => write!(f, "invalid tile data length {}", len),
+            InvalidTeleTilesLength(len) => write!(f, "invalid teleport tile data length {}", len),
+            InvalidTuneTilesLength(len) => write!(f, "invalid tune tile data length {}", len),
+            InvalidSpeedupTilesLength(len) => write!(f, "invalid speedup tile data length {}", len),
+            InvalidSwitchTilesLength(len) => write!(f, "invalid switch tile data length {}", len),
+            InvalidVersion(v) => write!(f, "invalid map version {}", v),
+            MalformedImageName(i) => write!(f, "malformed image name (data index {})", i),
+            InvalidTilesDimensions(len, w, h) =>
+                write!(f, "tile data length {} doesn't match dimensions {}x{}", len, w, h),
+            InvalidTeleTilesDimensions(len, w, h) =>
+                write!(f, "teleport tile data length {} doesn't match dimensions {}x{}", len, w, h),
+            InvalidTuneTilesDimensions(len, w, h) =>
+                write!(f, "tune tile data length {} doesn't match dimensions {}x{}", len, w, h),
+            EmptyVersion => f.write_str("empty version item"),
+            MissingVersion => f.write_str("missing version item"),
+            MissingInfo => f.write_str("missing info item"),
+            InvalidStringMissingNullTermination => f.write_str("string not nul-terminated"),
+            InvalidStringNullTermination => f.write_str("string contains nul bytes"),
+            InvalidSettingsMissingNullTermination => f.write_str("settings not nul-terminated"),
+            NoGameLayer => f.write_str("no game layer"),
+            TooManyGameGroups => f.write_str("game layers in more than one group"),
+            TooManyGameLayers => f.write_str("more than one game layer of a kind"),
+        }
+    }
+}
+
 pub const MAP_ITEMTYPE_LAYER_V1_DDRACE_SOUNDS_LEGACY: i32 = 9;
 
 pub const MAP_ITEMTYPE_VERSION: u16 = 0;
@@ -466,6 +787,37 @@ pub const MAP_ITEMTYPE_LAYER: u16 = 5;
 pub const MAP_ITEMTYPE_ENVPOINTS: u16 = 6;
 pub const MAP_ITEMTYPE_DDRACE_SOUND: u16 = 7;
 
+// DDNet extension: Items of this type register additional item types by UUID.
+// The item ID is the registered type ID, the item data is the UUID, stored as
+// four big-endian integers.
+pub const ITEMTYPE_EX: u16 = 0xffff;
+
+pub const UUID_MAPITEMTYPE_AUTOMAPPER_CONFIG: [u8; 16] = [
+    0x3e, 0x1b, 0x27, 0x16, 0x17, 0x8c, 0x39, 0x78,
+    0x9b, 0xd9, 0xb1, 0x1a, 0xe0, 0x41, 0x0d, 0xd8,
+];
+pub const UUID_MAPITEMTYPE_GROUP_EX: [u8; 16] = [
+    0x6e, 0xc2, 0x67, 0xb7, 0x65, 0xb3, 0x3f, 0x8f,
+    0xb4, 0x67, 0x6f, 0x0c, 0x92, 0x61, 0x63, 0x2d,
+];
+
+// DDNet extension, the item type is registered as
+// `UUID_MAPITEMTYPE_AUTOMAPPER_CONFIG`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct MapItemAutomapperConfigV1 {
+    pub group: i32,
+    pub layer: i32,
+    pub config: i32,
+    pub seed: i32,
+    pub flags: i32,
+}
+
+unsafe impl OnlyI32 for MapItemAutomapperConfigV1 { }
+impl MapItem for MapItemAutomapperConfigV1 { fn version() -> i32 { 1 } fn offset() -> usize { 1 } fn ignore_version() -> bool { false } }
+
+pub const AUTOMAPPER_CONFIG_FLAG_AUTOMATIC: i32 = 1;
+
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct MapItemVersionV1;
@@ -77,11 +77,10 @@ pub trait MapItemExt: MapItem {
     }
     fn from_slice_rest(slice: &[i32]) -> Result<Option<(&Self, &[i32])>, TooShort> {
         if !Self::ignore_version() {
-            if slice.len() == 0 {
-                return Err(TooShort);
-            }
-            if slice[0] < Self::version() {
-                return Ok(None);
+            match ItemView::new(slice).version() {
+                None => return Err(TooShort),
+                Some(v) if v < Self::version() => return Ok(None),
+                Some(_) => {},
             }
         }
         if slice.len() < Self::sum_len() {
@@ -96,11 +95,10 @@ pub trait MapItemExt: MapItem {
         -> Result<Option<(&mut Self, &mut [i32])>, TooShort>
     {
         if !Self::ignore_version() {
-            if slice.len() == 0 {
-                return Err(TooShort);
-            }
-            if slice[0] < Self::version() {
-                return Ok(None);
+            match ItemView::new(slice).version() {
+                None => return Err(TooShort),
+                Some(v) if v < Self::version() => return Ok(None),
+                Some(_) => {},
             }
         }
         if slice.len() < Self::sum_len() {
@@ -117,6 +115,44 @@ impl<T: MapItem> MapItemExt for T { }
 
 pub struct TooShort;
 
+/// Bounds-checked access to the fields of a raw item.
+///
+/// Items written by older or broken implementations can be shorter than
+/// expected, missing fields are reported as `None` instead of panicking.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ItemView<'a> {
+    data: &'a [i32],
+}
+
+impl<'a> ItemView<'a> {
+    pub fn new(data: &'a [i32]) -> ItemView<'a> {
+        ItemView {
+            data: data,
+        }
+    }
+    pub fn data(&self) -> &'a [i32] {
+        self.data
+    }
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+    /// Returns the `i`-th field, or `None` if the item is too short.
+    pub fn field(&self, i: usize) -> Option<i32> {
+        self.data.get(i).cloned()
+    }
+    /// Returns the `i`-th field, or `default` if the item is too short.
+    pub fn field_or(&self, i: usize, default: i32) -> i32 {
+        self.field(i).unwrap_or(default)
+    }
+    /// Returns the version field of the item, its first field.
+    pub fn version(&self) -> Option<i32> {
+        self.field(0)
+    }
+}
+
 pub fn i32s_to_bytes(result: &mut [u8], input: &[i32]) {
     assert!(result.len() == input.len() * mem::size_of::<i32>());
     for (output, input) in result.chunks_mut(mem::size_of::<i32>()).zip(input) {
@@ -312,14 +348,13 @@ impl MapItemLayerV1TilemapExtraRace {
         let offset = unwrap_or_return!(
             MapItemLayerV1TilemapExtraRace::offset(version, flags), None
         );
-        if slice.len() <= offset {
-            return None;
-        }
+        ItemView::new(slice).field(offset)?;
         Some(&(unsafe { common::slice::transmute(slice) })[offset])
     }
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 #[repr(C)]
 pub struct Tile {
     pub index: u8,
@@ -329,6 +364,7 @@ pub struct Tile {
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 #[repr(C)]
 pub struct TeleTile {
     pub number: u8,
@@ -336,6 +372,7 @@ pub struct TeleTile {
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 #[repr(C)]
 pub struct TuneTile {
     pub number: u8,
@@ -347,6 +384,35 @@ pub const TILEFLAG_HFLIP: u8 = 1 << 1;
 pub const TILEFLAG_OPAQUE: u8 = 1 << 2;
 pub const TILEFLAG_ROTATE: u8 = 1 << 3;
 
+impl Tile {
+    /// Whether the tile is mirrored along the vertical axis, swapping left
+    /// and right (`TILEFLAG_VFLIP`).
+    pub fn is_flipped_v(&self) -> bool {
+        self.flags & TILEFLAG_VFLIP != 0
+    }
+    /// Whether the tile is mirrored along the horizontal axis, swapping top
+    /// and bottom (`TILEFLAG_HFLIP`).
+    pub fn is_flipped_h(&self) -> bool {
+        self.flags & TILEFLAG_HFLIP != 0
+    }
+    /// Whether the tile has no transparent pixels (`TILEFLAG_OPAQUE`).
+    pub fn is_opaque(&self) -> bool {
+        self.flags & TILEFLAG_OPAQUE != 0
+    }
+    /// Whether the tile is rotated by 90 degrees clockwise
+    /// (`TILEFLAG_ROTATE`).
+    ///
+    /// The rotation is applied before the flips, a rotation by 180 degrees is
+    /// expressed by flipping in both directions.
+    pub fn is_rotated(&self) -> bool {
+        self.flags & TILEFLAG_ROTATE != 0
+    }
+    /// Returns the clockwise rotation in degrees, either 0 or 90.
+    pub fn rotation(&self) -> u32 {
+        if self.is_rotated() { 90 } else { 0 }
+    }
+}
+
 pub const LAYERFLAG_DETAIL: u32 = 1;
 pub const LAYERFLAGS_ALL: u32 = 1;
 
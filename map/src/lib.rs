@@ -2,9 +2,12 @@
 extern crate common;
 extern crate datafile;
 extern crate ndarray;
+#[cfg(feature = "serde")] #[macro_use] extern crate serde;
+#[cfg(all(test, feature = "serde"))] extern crate serde_json;
 
 pub use reader::Reader;
 pub use reader::Error;
 
+pub mod entities;
 pub mod format;
 pub mod reader;
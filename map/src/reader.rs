@@ -2,11 +2,14 @@ use common::num::Cast;
 use common::vec;
 use datafile as df;
 use ndarray::Array2;
+use std::error;
+use std::fmt;
 use std::io;
 use std::mem;
 use std::ops;
 use std::path::Path;
 
+use entities;
 use format::Error as MapError;
 use format::MapItem;
 use format::MapItemExt;
@@ -36,7 +39,26 @@ impl From<io::Error> for Error {
     }
 }
 
-#[derive(Clone, Copy)]
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Map(ref e) => Some(e),
+            Error::Df(ref e) => Some(e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Map(ref e) => write!(f, "map: {}", e),
+            Error::Df(ref e) => e.fmt(f),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Color {
     pub red: u8,
     pub green: u8,
@@ -45,6 +67,7 @@ pub struct Color {
 }
 
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Clipping {
     pub x: i32,
     pub y: i32,
@@ -53,6 +76,7 @@ pub struct Clipping {
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Group {
     pub offset_x: i32,
     pub offset_y: i32,
@@ -60,6 +84,7 @@ pub struct Group {
     pub parallax_y: i32,
     pub layer_indices: ops::Range<usize>,
     pub clipping: Option<Clipping>,
+    #[cfg_attr(feature = "serde", serde(with = "serde_name"))]
     pub name: [u8; 12],
 }
 
@@ -100,7 +125,9 @@ trait MapItemExtInternal: MapItem {
         where TS: FnOnce(usize) -> E,
               IV: FnOnce(i32) -> E,
     {
-        Self::optional(slice, too_short)?.ok_or_else(|| invalid_version(slice[0]))
+        Self::optional(slice, too_short)?.ok_or_else(|| {
+            invalid_version(format::ItemView::new(slice).field_or(0, -1))
+        })
     }
     fn optional_rest<E, TS>(slice: &[i32], too_short: TS)
         -> Result<Option<(&Self, &[i32])>, E>
@@ -113,7 +140,9 @@ trait MapItemExtInternal: MapItem {
         where TS: FnOnce(usize) -> E,
               IV: FnOnce(i32) -> E,
     {
-        Self::optional_rest(slice, too_short)?.ok_or_else(|| invalid_version(slice[0]))
+        Self::optional_rest(slice, too_short)?.ok_or_else(|| {
+            invalid_version(format::ItemView::new(slice).field_or(0, -1))
+        })
     }
 }
 
@@ -199,11 +228,13 @@ impl Group {
 }
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct DdraceLayerSounds {
     pub num_sources: usize,
     pub data: usize,
     pub sound: Option<usize>,
     pub legacy: bool,
+    #[cfg_attr(feature = "serde", serde(with = "serde_name"))]
     pub name: [u8; 12],
 }
 
@@ -231,10 +262,12 @@ impl DdraceLayerSounds {
 }
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct LayerQuads {
     pub num_quads: usize,
     pub data: usize,
     pub image: Option<usize>,
+    #[cfg_attr(feature = "serde", serde(with = "serde_name"))]
     pub name: [u8; 12],
 }
 
@@ -260,6 +293,7 @@ impl LayerQuads {
 }
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub enum LayerTilemapType {
     // Normal(normal)
     Normal(LayerTilemapNormal),
@@ -277,11 +311,18 @@ pub enum LayerTilemapType {
     DdraceTune(usize, usize),
 }
 
+/// Appearance and tiles of a tile layer that isn't a game layer.
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct LayerTilemapNormal {
+    /// Tint multiplied with the image.
     pub color: Color,
+    /// Item index of the color envelope and its time offset in
+    /// milliseconds.
     pub color_env_and_offset: Option<(usize, i32)>,
+    /// Item index of the image, `None` if the layer has no image.
     pub image: Option<usize>,
+    /// Data index of the tiles.
     pub data: usize,
 }
 
@@ -303,10 +344,12 @@ impl LayerTilemapType {
 }
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct LayerTilemap {
     pub width: u32,
     pub height: u32,
     pub type_: LayerTilemapType,
+    #[cfg_attr(feature = "serde", serde(with = "serde_name"))]
     pub name: [u8; 12],
 }
 
@@ -449,12 +492,14 @@ impl LayerTilemap {
 }
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Layer {
     pub detail: bool,
     pub t: LayerType,
 }
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub enum LayerType {
     Quads(LayerQuads),
     Tilemap(LayerTilemap),
@@ -506,6 +551,7 @@ impl Layer {
     }
 }
 
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Image {
     pub width: u32,
     pub height: u32,
@@ -575,6 +621,24 @@ impl GameLayers {
     }
 }
 
+/// Start and finish tiles of a race map, see `Reader::race_info`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct RaceInfo {
+    /// Positions `(x, y)` of the start tiles.
+    pub start: Vec<(u32, u32)>,
+    /// Positions `(x, y)` of the finish tiles.
+    pub finish: Vec<(u32, u32)>,
+}
+
+impl RaceInfo {
+    /// Returns whether the map has both start and finish tiles.
+    pub fn is_complete(&self) -> bool {
+        !self.start.is_empty() && !self.finish.is_empty()
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Info {
     pub author: Option<usize>,
     pub version: Option<usize>,
@@ -605,6 +669,91 @@ impl Info {
     }
 }
 
+/// DDNet extensions used by a map.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DdnetMapVersion {
+    /// Ex item types registered by the map, as `(type_id, uuid)`.
+    pub ex_item_types: Vec<(u16, [u8; 16])>,
+    /// Whether the map contains sounds.
+    pub sounds: bool,
+}
+
+fn ex_item_uuid(item: &df::ItemView) -> Option<[u8; 16]> {
+    let fields = format::ItemView::new(item.data);
+    let mut uuid = [0; 16];
+    for i in 0..4 {
+        let word = fields.field(i)? as u32;
+        uuid[i * 4 + 0] = (word >> 24) as u8;
+        uuid[i * 4 + 1] = (word >> 16) as u8;
+        uuid[i * 4 + 2] = (word >> 8) as u8;
+        uuid[i * 4 + 3] = word as u8;
+    }
+    Some(uuid)
+}
+
+impl DdnetMapVersion {
+    fn from_items<'a, I>(ex_items: I, sounds: bool) -> Option<DdnetMapVersion>
+        where I: Iterator<Item=df::ItemView<'a>>,
+    {
+        let ex_item_types: Vec<_> = ex_items
+            .filter_map(|item| ex_item_uuid(&item).map(|uuid| (item.id, uuid)))
+            .collect();
+        if ex_item_types.is_empty() && !sounds {
+            return None;
+        }
+        Some(DdnetMapVersion {
+            ex_item_types: ex_item_types,
+            sounds: sounds,
+        })
+    }
+    /// Returns the type ID registered for the given ex item UUID.
+    pub fn ex_item_type(&self, uuid: &[u8; 16]) -> Option<u16> {
+        self.ex_item_types.iter().find(|&&(_, u)| u == *uuid).map(|&(t, _)| t)
+    }
+    pub fn has_automapper_config(&self) -> bool {
+        self.ex_item_type(&format::UUID_MAPITEMTYPE_AUTOMAPPER_CONFIG).is_some()
+    }
+    pub fn has_group_ex(&self) -> bool {
+        self.ex_item_type(&format::UUID_MAPITEMTYPE_GROUP_EX).is_some()
+    }
+}
+
+/// Automapper settings of a tile layer, a DDNet extension.
+///
+/// The rules themselves aren't stored in the map, `config` selects one of the
+/// configurations in the rules file belonging to the layer's image.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct AutomapperConfig {
+    /// Index of the group among all groups.
+    pub group: i32,
+    /// Index of the layer within the group.
+    pub layer: i32,
+    /// Index of the configuration in the rules file, -1 if none is selected.
+    pub config: i32,
+    pub seed: i32,
+    pub flags: i32,
+}
+
+impl AutomapperConfig {
+    fn from_raw(raw: &[i32]) -> Result<AutomapperConfig, format::AutomapperConfigError> {
+        use format::AutomapperConfigError::*;
+
+        let v1 = format::MapItemAutomapperConfigV1::mandatory(raw, TooShort, InvalidVersion)?;
+        Ok(AutomapperConfig {
+            group: v1.group,
+            layer: v1.layer,
+            config: v1.config,
+            seed: v1.seed,
+            flags: v1.flags,
+        })
+    }
+    /// Whether the editor reruns the automapper whenever the layer changes.
+    pub fn is_automatic(&self) -> bool {
+        self.flags & format::AUTOMAPPER_CONFIG_FLAG_AUTOMATIC != 0
+    }
+}
+
 pub struct Settings {
     pub raw: Vec<u8>,
 }
@@ -634,14 +783,79 @@ impl<'a> Iterator for SettingsIter<'a> {
     }
 }
 
+// Must be a multiple of the tile size.
+const LAYER_TILES_STREAM_BUFFER_SIZE: usize = 4096;
+
+// Iterator over the tiles of a tile layer, see `Reader::layer_tiles_stream`.
+struct LayerTilesStream<'a> {
+    stream: df::DataStream<'a>,
+    buffer: Vec<u8>,
+    // Unread tiles in `buffer`.
+    pos: usize,
+    len: usize,
+    failed: bool,
+}
+
+impl<'a> Iterator for LayerTilesStream<'a> {
+    type Item = Result<format::Tile, Error>;
+    fn next(&mut self) -> Option<Result<format::Tile, Error>> {
+        if self.pos == self.len {
+            if self.failed {
+                return None;
+            }
+            self.pos = 0;
+            self.len = 0;
+            // Fill the buffer with whole tiles.
+            loop {
+                match self.stream.read(&mut self.buffer[self.len..]) {
+                    Ok(0) => break,
+                    Ok(read) => self.len += read,
+                    Err(e) => {
+                        self.failed = true;
+                        return Some(Err(e.into()));
+                    }
+                }
+                if self.len % mem::size_of::<format::Tile>() == 0 {
+                    break;
+                }
+            }
+            if self.len == 0 {
+                return None;
+            }
+        }
+        let tile = &self.buffer[self.pos..][..mem::size_of::<format::Tile>()];
+        self.pos += mem::size_of::<format::Tile>();
+        Some(Ok(format::Tile {
+            index: tile[0],
+            flags: tile[1],
+            skip: tile[2],
+            reserved: tile[3],
+        }))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = if !self.failed {
+            (self.stream.remaining() + self.len - self.pos) / mem::size_of::<format::Tile>()
+        } else {
+            0
+        };
+        (len, Some(len))
+    }
+}
+
 pub struct Reader {
     pub reader: df::Reader,
 }
 
 impl Reader {
+    /// Opens the map and checks its version.
+    ///
+    /// Maps with an unsupported version are rejected, use `from_datafile` to
+    /// read them anyway.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Reader, Error> {
         fn inner(path: &Path) -> Result<Reader, Error> {
-            Ok(Reader::from_datafile(df::Reader::open(path)?))
+            let map = Reader::from_datafile(df::Reader::open(path)?);
+            map.check_version()?;
+            Ok(map)
         }
         inner(path.as_ref())
     }
@@ -666,6 +880,26 @@ impl Reader {
         )?;
         Ok(v0.version)
     }
+    /// Returns the DDNet extensions used by the map, `None` for vanilla
+    /// maps.
+    pub fn ddnet_version(&self) -> Option<DdnetMapVersion> {
+        let sounds = !self.reader.item_type_indices(format::MAP_ITEMTYPE_DDRACE_SOUND).is_empty();
+        DdnetMapVersion::from_items(self.reader.item_type_items(format::ITEMTYPE_EX), sounds)
+    }
+    /// Returns the automapper settings stored in the map, a DDNet extension.
+    ///
+    /// Empty if the map has none.
+    pub fn automapper_configs(&self) -> Result<Vec<AutomapperConfig>, MapError> {
+        let uuid = &format::UUID_MAPITEMTYPE_AUTOMAPPER_CONFIG;
+        let type_id = match self.ddnet_version().and_then(|v| v.ex_item_type(uuid)) {
+            Some(t) => t,
+            None => return Ok(Vec::new()),
+        };
+        self.reader.item_type_indices(type_id).map(|i| {
+            AutomapperConfig::from_raw(self.reader.item(i).data)
+                .map_err(|e| MapError::AutomapperConfig(i, e))
+        }).collect()
+    }
     pub fn info(&self) -> Result<Info, MapError> {
         let raw = self.reader.find_item(format::MAP_ITEMTYPE_INFO, 0)
             .ok_or(MapError::MissingInfo)?;
@@ -820,6 +1054,44 @@ impl Reader {
         Ok(Array2::from_shape_vec((height.usize(), width.usize()), tiles)
             .map_err(|_| MapError::InvalidTilesDimensions(len, height, width))?)
     }
+    pub fn speedup_layer_tiles_raw(&mut self, data_index: usize)
+        -> Result<Vec<format::SpeedupTile>, Error>
+    {
+        let raw = self.reader.read_data(data_index)?;
+        if raw.len() % mem::size_of::<format::SpeedupTile>() != 0 {
+            return Err(Error::Map(MapError::InvalidSpeedupTilesLength(raw.len())));
+        }
+        let tiles: Vec<format::SpeedupTile> = unsafe { vec::transmute(raw) };
+        Ok(tiles)
+    }
+    pub fn speedup_layer_tiles(&mut self, index: LayerTilesIndex)
+        -> Result<Array2<format::SpeedupTile>, Error>
+    {
+        let LayerTilesIndex { data_index, width, height } = index;
+        let tiles = self.speedup_layer_tiles_raw(data_index)?;
+        let len = tiles.len();
+        Ok(Array2::from_shape_vec((height.usize(), width.usize()), tiles)
+            .map_err(|_| MapError::InvalidTilesDimensions(len, height, width))?)
+    }
+    pub fn switch_layer_tiles_raw(&mut self, data_index: usize)
+        -> Result<Vec<format::SwitchTile>, Error>
+    {
+        let raw = self.reader.read_data(data_index)?;
+        if raw.len() % mem::size_of::<format::SwitchTile>() != 0 {
+            return Err(Error::Map(MapError::InvalidSwitchTilesLength(raw.len())));
+        }
+        let tiles: Vec<format::SwitchTile> = unsafe { vec::transmute(raw) };
+        Ok(tiles)
+    }
+    pub fn switch_layer_tiles(&mut self, index: LayerTilesIndex)
+        -> Result<Array2<format::SwitchTile>, Error>
+    {
+        let LayerTilesIndex { data_index, width, height } = index;
+        let tiles = self.switch_layer_tiles_raw(data_index)?;
+        let len = tiles.len();
+        Ok(Array2::from_shape_vec((height.usize(), width.usize()), tiles)
+            .map_err(|_| MapError::InvalidTilesDimensions(len, height, width))?)
+    }
     pub fn layer_tiles_raw(&mut self, data_index: usize)
         -> Result<Vec<format::Tile>, Error>
     {
@@ -839,6 +1111,75 @@ impl Reader {
         Ok(Array2::from_shape_vec((height.usize(), width.usize()), tiles)
             .map_err(|_| MapError::InvalidTilesDimensions(len, height, width))?)
     }
+    /// Returns an iterator over the tiles of a tile layer, in row-major
+    /// order.
+    ///
+    /// Unlike `layer_tiles`, this decompresses the layer incrementally
+    /// instead of holding all of its tiles in memory.
+    pub fn layer_tiles_stream<'a>(&'a mut self, index: LayerTilesIndex)
+        -> Result<impl Iterator<Item=Result<format::Tile, Error>> + 'a, Error>
+    {
+        let LayerTilesIndex { data_index, width, height } = index;
+        let size = self.reader.data_size(data_index);
+        if size % mem::size_of::<format::Tile>() != 0 {
+            return Err(Error::Map(MapError::InvalidTilesLength(size)));
+        }
+        let len = size / mem::size_of::<format::Tile>();
+        if len.u64() != height.u64() * width.u64() {
+            return Err(MapError::InvalidTilesDimensions(len, height, width).into());
+        }
+        Ok(LayerTilesStream {
+            stream: self.reader.data_stream(data_index)?,
+            buffer: vec![0; LAYER_TILES_STREAM_BUFFER_SIZE],
+            pos: 0,
+            len: 0,
+            failed: false,
+        })
+    }
+    /// Returns the positions `(x, y)` of the tiles with the given index in
+    /// the game layer and, if present, the front layer.
+    ///
+    /// The positions of each layer are in row-major order, the ones of the
+    /// game layer come first.
+    pub fn find_tiles(&mut self, index: u8) -> Result<Vec<(u32, u32)>, Error> {
+        let game_layers = self.game_layers()?;
+        let mut result = self.find_tiles_in_layer(game_layers.game(), index)?;
+        if let Some(front) = game_layers.front() {
+            result.extend(self.find_tiles_in_layer(front, index)?);
+        }
+        Ok(result)
+    }
+    /// Returns the start and finish tiles of the game and front layer.
+    ///
+    /// Returns `None` if there are neither start nor finish tiles, i.e. if
+    /// the map isn't a race map. A map with only one of them is reported,
+    /// but isn't complete, see `RaceInfo::is_complete`.
+    pub fn race_info(&mut self) -> Result<Option<RaceInfo>, Error> {
+        let start = self.find_tiles(entities::TILE_START)?;
+        let finish = self.find_tiles(entities::TILE_FINISH)?;
+        if start.is_empty() && finish.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(RaceInfo {
+            start: start,
+            finish: finish,
+        }))
+    }
+    /// Returns the positions `(x, y)` of the tiles with the given index in a
+    /// tile layer, in row-major order.
+    pub fn find_tiles_in_layer(&mut self, layer: LayerTilesIndex, index: u8)
+        -> Result<Vec<(u32, u32)>, Error>
+    {
+        let width = layer.width.u64();
+        let mut result = Vec::new();
+        for (i, tile) in self.layer_tiles_stream(layer)?.enumerate() {
+            if tile?.index == index {
+                let i = i.u64();
+                result.push(((i % width).assert_u32(), (i / width).assert_u32()));
+            }
+        }
+        Ok(result)
+    }
     pub fn string(&mut self, data_index: usize)
         -> Result<Vec<u8>, Error>
     {
@@ -865,3 +1206,425 @@ impl Reader {
         })
     }
 }
+
+/// (De)serializes layer and group names as strings.
+///
+/// Names that aren't valid UTF-8 or contain data after the terminating NUL
+/// are kept as arrays of their 12 bytes, so that they survive a round trip.
+#[cfg(feature = "serde")]
+mod serde_name {
+    use serde::de;
+    use serde::Deserializer;
+    use serde::Serializer;
+    use std::fmt;
+    use std::str;
+
+    pub fn serialize<S: Serializer>(name: &[u8; 12], serializer: S) -> Result<S::Ok, S::Error> {
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        match str::from_utf8(&name[..len]) {
+            Ok(s) if name[len..].iter().all(|&b| b == 0) => serializer.serialize_str(s),
+            _ => serde::Serialize::serialize(name, serializer),
+        }
+    }
+
+    struct NameVisitor;
+
+    impl<'de> de::Visitor<'de> for NameVisitor {
+        type Value = [u8; 12];
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a string of at most 11 bytes or an array of 12 bytes")
+        }
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<[u8; 12], E> {
+            if v.len() >= 12 || v.bytes().any(|b| b == 0) {
+                return Err(E::invalid_value(de::Unexpected::Str(v), &self));
+            }
+            let mut result = [0; 12];
+            result[..v.len()].copy_from_slice(v.as_bytes());
+            Ok(result)
+        }
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<[u8; 12], A::Error> {
+            let mut result = [0; 12];
+            for (i, b) in result.iter_mut().enumerate() {
+                *b = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(i, &self))?;
+            }
+            if seq.next_element::<u8>()?.is_some() {
+                return Err(de::Error::invalid_length(13, &self));
+            }
+            Ok(result)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 12], D::Error> {
+        deserializer.deserialize_any(NameVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use datafile::ItemView;
+    use datafile::Writer;
+    use datafile;
+    use format::Error as MapError;
+    use format;
+    use std::env;
+    use std::error::Error as StdError;
+    use std::fs;
+    use std::process;
+    use super::AutomapperConfig;
+    use super::Color;
+    use super::DdnetMapVersion;
+    use super::Error;
+    use super::Group;
+    use super::Image;
+    use super::Info;
+    use super::Layer;
+    use super::LayerTilemap;
+    use super::LayerTilemapType;
+    use super::RaceInfo;
+    use super::Reader;
+
+    #[test]
+    fn ddnet_version_vanilla() {
+        let map = Reader::open(concat!(env!("CARGO_MANIFEST_DIR"), "/../server/dm1.map")).unwrap();
+        assert_eq!(map.ddnet_version(), None);
+        assert_eq!(DdnetMapVersion::from_items(None.into_iter(), false), None);
+    }
+
+    #[test]
+    fn ddnet_version_ex_items() {
+        let automapper = [0x3e1b2716u32 as i32, 0x178c3978, 0x9bd9b11au32 as i32, 0xe0410dd8u32 as i32];
+        let items = [
+            ItemView { type_id: format::ITEMTYPE_EX, id: 0x8000, data: &automapper },
+            // Too short to contain a UUID.
+            ItemView { type_id: format::ITEMTYPE_EX, id: 0x8001, data: &[0; 3] },
+        ];
+        let version = DdnetMapVersion::from_items(items.iter().cloned(), false).unwrap();
+        assert_eq!(version.ex_item_types, [(0x8000, format::UUID_MAPITEMTYPE_AUTOMAPPER_CONFIG)]);
+        assert!(version.has_automapper_config());
+        assert!(!version.has_group_ex());
+        assert!(!version.sounds);
+    }
+
+    // Writes the datafile to a temporary file named after `name` and opens
+    // it as a map.
+    fn open_written(name: &str, writer: &Writer) -> Result<Reader, Error> {
+        let mut written = Vec::new();
+        writer.write(&mut written).unwrap();
+        let path = env::temp_dir().join(format!("map-{}-{}.map", name, process::id()));
+        fs::write(&path, &written).unwrap();
+        let result = Reader::open(&path);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    // Returns the raw tilemap layer item of a 3x2 layer with the given flags
+    // and data indices of the DDNet layers, in the order teleport, speedup,
+    // front, switch and tune.
+    fn tilemap_3x2(flags: u32, data: i32, race: [i32; 5]) -> Vec<i32> {
+        let mut raw = vec![0, format::MAP_ITEMTYPE_LAYER_V1_TILEMAP, 0];
+        raw.extend(&[3, 3, 2, flags as i32, 255, 255, 255, 255, -1, 0, -1, data, 0, 0, 0]);
+        raw.extend(&race);
+        raw
+    }
+
+    // Opens a map consisting only of a version item with the given version.
+    fn open_with_version(version: i32) -> Result<Reader, Error> {
+        let mut writer = Writer::new();
+        writer.add_item(format::MAP_ITEMTYPE_VERSION, 0, &[version]);
+        open_written(&format!("version-{}", version), &writer)
+    }
+
+    #[test]
+    fn version() {
+        let map = Reader::open(concat!(env!("CARGO_MANIFEST_DIR"), "/../server/dm1.map")).unwrap();
+        assert_eq!(map.version().unwrap(), 1);
+        assert_eq!(open_with_version(1).unwrap().version().unwrap(), 1);
+        match open_with_version(2) {
+            Err(Error::Map(MapError::InvalidVersion(2))) => {},
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unsupported version accepted"),
+        }
+    }
+
+    #[test]
+    fn unsupported_datafile_version() {
+        let mut written = Vec::new();
+        Writer::new().write(&mut written).unwrap();
+        // Doctor the version field following the magic.
+        written[4] = 5;
+        let path = env::temp_dir().join(format!("map-datafile-version-{}.map", process::id()));
+        fs::write(&path, &written).unwrap();
+        let result = Reader::open(&path);
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(Error::Df(datafile::Error::Df(format_error))) => {
+                assert_eq!(format_error, datafile::format::Error::UnsupportedVersion(5));
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unsupported datafile version accepted"),
+        }
+    }
+
+    #[test]
+    fn layer_tiles_stream() {
+        let mut map = Reader::open(concat!(env!("CARGO_MANIFEST_DIR"), "/../server/dm1.map")).unwrap();
+        let game_layers = map.game_layers().unwrap();
+        let tiles = map.layer_tiles(game_layers.game()).unwrap();
+        let stream = map.layer_tiles_stream(game_layers.game()).unwrap();
+        assert_eq!(stream.size_hint(), (tiles.len(), Some(tiles.len())));
+        let streamed: Result<Vec<_>, _> = stream.collect();
+        assert_eq!(&streamed.unwrap()[..], tiles.as_slice().unwrap());
+    }
+
+    #[test]
+    fn find_tiles() {
+        let mut map = Reader::open(concat!(env!("CARGO_MANIFEST_DIR"), "/../server/dm1.map")).unwrap();
+        // The spawns of dm1.
+        assert_eq!(map.find_tiles(192).unwrap(), [
+            (49, 9), (10, 10), (31, 18), (9, 27), (44, 27), (33, 28), (20, 35), (47, 35),
+        ]);
+        let game = map.game_layers().unwrap().game();
+        assert_eq!(map.find_tiles_in_layer(game, 201).unwrap(), [(31, 3)]);
+
+        // A 3x2 map with a game and a front layer.
+        let tiles = |indices: &[u8]| -> Vec<u8> {
+            indices.iter().flat_map(|&i| vec![i, 0, 0, 0]).collect()
+        };
+        let mut writer = Writer::new();
+        writer.add_item(format::MAP_ITEMTYPE_VERSION, 0, &[1]);
+        writer.add_item(format::MAP_ITEMTYPE_GROUP, 0, &[1, 0, 0, 100, 100, 0, 2]);
+        writer.add_item(format::MAP_ITEMTYPE_LAYER, 0,
+            &tilemap_3x2(format::TILELAYERFLAG_GAME, 0, [-1; 5]));
+        writer.add_item(format::MAP_ITEMTYPE_LAYER, 1,
+            &tilemap_3x2(format::TILELAYERFLAG_FRONT, 0, [-1, -1, 1, -1, -1]));
+        writer.add_data(&tiles(&[0, 192, 0, 0, 0, 192])).unwrap();
+        writer.add_data(&tiles(&[0, 0, 0, 192, 5, 0])).unwrap();
+        let mut map = open_written("find-tiles", &writer).unwrap();
+
+        assert_eq!(map.find_tiles(192).unwrap(), [(1, 0), (2, 1), (0, 1)]);
+        assert_eq!(map.find_tiles(5).unwrap(), [(1, 1)]);
+        assert_eq!(map.find_tiles(1).unwrap(), []);
+        let front = map.game_layers().unwrap().front().unwrap();
+        assert_eq!(map.find_tiles_in_layer(front, 192).unwrap(), [(0, 1)]);
+    }
+
+    #[test]
+    fn race_info() {
+        let mut map = Reader::open(concat!(env!("CARGO_MANIFEST_DIR"), "/../server/dm1.map")).unwrap();
+        assert_eq!(map.race_info().unwrap(), None);
+
+        let race_map = |game: &[u8], front: &[u8]| {
+            let tiles = |indices: &[u8]| -> Vec<u8> {
+                indices.iter().flat_map(|&i| vec![i, 0, 0, 0]).collect()
+            };
+            let mut writer = Writer::new();
+            writer.add_item(format::MAP_ITEMTYPE_VERSION, 0, &[1]);
+            writer.add_item(format::MAP_ITEMTYPE_GROUP, 0, &[1, 0, 0, 100, 100, 0, 2]);
+            writer.add_item(format::MAP_ITEMTYPE_LAYER, 0,
+                &tilemap_3x2(format::TILELAYERFLAG_GAME, 0, [-1; 5]));
+            writer.add_item(format::MAP_ITEMTYPE_LAYER, 1,
+                &tilemap_3x2(format::TILELAYERFLAG_FRONT, 0, [-1, -1, 1, -1, -1]));
+            writer.add_data(&tiles(game)).unwrap();
+            writer.add_data(&tiles(front)).unwrap();
+            open_written("race-info", &writer).unwrap()
+        };
+
+        // The finish line is in the front layer.
+        let mut map = race_map(&[33, 0, 0, 33, 0, 0], &[0, 0, 34, 0, 0, 34]);
+        let info = map.race_info().unwrap().unwrap();
+        assert_eq!(info, RaceInfo {
+            start: vec![(0, 0), (0, 1)],
+            finish: vec![(2, 0), (2, 1)],
+        });
+        assert!(info.is_complete());
+
+        let mut map = race_map(&[33, 0, 0, 0, 0, 0], &[0; 6]);
+        let info = map.race_info().unwrap().unwrap();
+        assert_eq!((info.start.len(), info.finish.len()), (1, 0));
+        assert!(!info.is_complete());
+    }
+
+    #[test]
+    fn speedup_and_switch_layer_tiles() {
+        let mut writer = Writer::new();
+        writer.add_item(format::MAP_ITEMTYPE_VERSION, 0, &[1]);
+        writer.add_item(format::MAP_ITEMTYPE_GROUP, 0, &[1, 0, 0, 100, 100, 0, 3]);
+        writer.add_item(format::MAP_ITEMTYPE_LAYER, 0,
+            &tilemap_3x2(format::TILELAYERFLAG_GAME, 0, [-1; 5]));
+        writer.add_item(format::MAP_ITEMTYPE_LAYER, 1,
+            &tilemap_3x2(format::TILELAYERFLAG_SPEEDUP, 0, [-1, 1, -1, -1, -1]));
+        writer.add_item(format::MAP_ITEMTYPE_LAYER, 2,
+            &tilemap_3x2(format::TILELAYERFLAG_SWITCH, 0, [-1, -1, -1, 2, -1]));
+        writer.add_data(&[0; 6 * 4]).unwrap();
+        let mut speedup = vec![0; 6 * 6];
+        speedup[6..12].copy_from_slice(&[50, 0, 28, 0, 0x0e, 0x01]);
+        writer.add_data(&speedup).unwrap();
+        let mut switch = vec![0; 6 * 4];
+        switch[20..24].copy_from_slice(&[3, 22, 0, 5]);
+        writer.add_data(&switch).unwrap();
+        let mut map = open_written("speedup-switch", &writer).unwrap();
+        let game_layers = map.game_layers().unwrap();
+
+        let speedup = map.speedup_layer_tiles(game_layers.speedup().unwrap()).unwrap();
+        let tile = speedup[(0, 1)];
+        assert_eq!((tile.force, tile.max_speed, tile.index, tile.angle()), (50, 0, 28, 270));
+        assert_eq!(speedup.iter().filter(|t| t.index != 0).count(), 1);
+
+        let switch = map.switch_layer_tiles(game_layers.switch().unwrap()).unwrap();
+        assert_eq!(switch[(1, 2)], format::SwitchTile { number: 3, index: 22, flags: 0, delay: 5 });
+        assert_eq!(switch.iter().filter(|t| t.index != 0).count(), 1);
+
+        // The switch layer data only makes up four speedup tiles.
+        match map.speedup_layer_tiles(game_layers.switch().unwrap()) {
+            Err(Error::Map(MapError::InvalidTilesDimensions(4, 2, 3))) => {},
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn automapper_configs() {
+        let map = Reader::open(concat!(env!("CARGO_MANIFEST_DIR"), "/../server/dm1.map")).unwrap();
+        assert_eq!(map.automapper_configs().unwrap(), []);
+
+        let config = AutomapperConfig::from_raw(&[1, 2, 0, 3, 12345, 1]).unwrap();
+        assert_eq!(config, AutomapperConfig { group: 2, layer: 0, config: 3, seed: 12345, flags: 1 });
+        assert!(config.is_automatic());
+        let config = AutomapperConfig::from_raw(&[1, 0, 1, -1, 0, 0]).unwrap();
+        assert_eq!(config.config, -1);
+        assert!(!config.is_automatic());
+
+        assert_eq!(AutomapperConfig::from_raw(&[1, 0, 1]).err(), Some(format::AutomapperConfigError::TooShort(3)));
+        assert_eq!(AutomapperConfig::from_raw(&[0, 0, 1, -1, 0, 0]).err(), Some(format::AutomapperConfigError::InvalidVersion(0)));
+    }
+
+    #[test]
+    fn item_view() {
+        let fields = format::ItemView::new(&[3, 4]);
+        assert_eq!(fields.version(), Some(3));
+        assert_eq!(fields.field(1), Some(4));
+        assert_eq!(fields.field(2), None);
+        assert_eq!(fields.field_or(1, -1), 4);
+        assert_eq!(fields.field_or(2, -1), -1);
+        assert_eq!(format::ItemView::new(&[]).version(), None);
+    }
+
+    #[test]
+    fn short_items() {
+        use format::GroupError;
+        use format::ImageError;
+        use format::InfoError;
+        use format::LayerError;
+        use format::LayerTilemapError;
+
+        assert_eq!(Group::from_raw(&[], 0..1).err(), Some(GroupError::TooShort(0)));
+        assert_eq!(Image::from_raw(&[1, 0], 0..1).err(), Some(ImageError::TooShort(2)));
+        assert_eq!(Info::from_raw(&[1], 0..1).err(), Some(InfoError::TooShort(1)));
+        assert_eq!(Layer::from_raw(&[], 0..1, 0..1, 0..1, 0..1).err(), Some(LayerError::TooShort(0)));
+
+        // Every truncation of a tile layer is rejected.
+        let raw = [3, 4, 2, 0, 255, 128, 0, 200, 1, 250, 0, 7, 0, 0, 0];
+        for len in 0..raw.len() {
+            assert!(LayerTilemap::from_raw(&raw[..len], 0..10, 5..7, 9..10).is_err(), "len={}", len);
+        }
+        assert_eq!(LayerTilemap::from_raw(&raw[..13], 0..10, 5..7, 9..10).err(),
+            Some(LayerTilemapError::TooShortV3(13)));
+
+        // A teleport layer lacking its extra data index.
+        let raw = [3, 4, 2, format::TILELAYERFLAG_TELEPORT as i32, 255, 255, 255, 255, -1, 0, -1, 7, 0, 0, 0];
+        assert_eq!(LayerTilemap::from_raw(&raw, 0..10, 5..7, 9..10).err(),
+            Some(LayerTilemapError::TooShortRaceTeleport(15)));
+    }
+
+    #[test]
+    fn tilemap_color() {
+        // Version 3 layer, 4x2, tinted, with color envelope 1, offset 250
+        // and image 0.
+        let raw = [3, 4, 2, 0, 255, 128, 0, 200, 1, 250, 0, 7, 0, 0, 0];
+        let tilemap = LayerTilemap::from_raw(&raw, 0..10, 5..7, 9..10).unwrap();
+        let normal = match tilemap.type_ {
+            LayerTilemapType::Normal(n) => n,
+            _ => panic!("not a normal tile layer"),
+        };
+        assert_eq!(normal.color, Color { red: 255, green: 128, blue: 0, alpha: 200 });
+        assert_eq!(normal.color_env_and_offset, Some((6, 250)));
+        assert_eq!(normal.image, Some(9));
+        assert_eq!(normal.data, 7);
+
+        // Version 2 layers have no name, and -1 means no envelope or image.
+        let raw = [2, 4, 2, 0, 255, 255, 255, 255, -1, 0, -1, 7];
+        let tilemap = LayerTilemap::from_raw(&raw, 0..10, 5..7, 9..10).unwrap();
+        let normal = tilemap.type_.to_normal().unwrap();
+        assert_eq!(normal.color, Color { red: 255, green: 255, blue: 255, alpha: 255 });
+        assert_eq!(normal.color_env_and_offset, None);
+        assert_eq!(normal.image, None);
+        assert_eq!(tilemap.name, [0; 12]);
+
+        assert!(LayerTilemap::from_raw(&[3, 4, 2, 0, 256, 0, 0, 0, -1, 0, -1, 7], 0..10, 5..7, 9..10).is_err());
+    }
+
+    #[test]
+    fn tile_flags() {
+        for flags in 0..16 {
+            let tile = format::Tile { index: 1, flags: flags, skip: 0, reserved: 0 };
+            assert_eq!(tile.is_flipped_v(), flags & 1 != 0);
+            assert_eq!(tile.is_flipped_h(), flags & 2 != 0);
+            assert_eq!(tile.is_opaque(), flags & 4 != 0);
+            assert_eq!(tile.is_rotated(), flags & 8 != 0);
+            assert_eq!(tile.rotation(), if flags & 8 != 0 { 90 } else { 0 });
+        }
+        let tile = format::Tile { index: 1, flags: 0xf0, skip: 0, reserved: 0 };
+        assert!(!tile.is_flipped_v() && !tile.is_flipped_h() && !tile.is_opaque() && !tile.is_rotated());
+    }
+
+    #[test]
+    fn error_display() {
+        use format::LayerError;
+        use format::LayerTilemapError;
+
+        let inner = LayerTilemapError::InvalidWidth(0);
+        let err = Error::Map(format::Error::Layer(3, LayerError::Tilemap(inner)));
+        assert_eq!(err.to_string(), "map: layer 3: tile layer: invalid width 0");
+        let layer = err.source().unwrap().source().unwrap();
+        assert_eq!(layer.source().unwrap().to_string(), inner.to_string());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        use serde_json;
+        use super::Group;
+        use super::Layer;
+
+        let map = Reader::open(concat!(env!("CARGO_MANIFEST_DIR"), "/../server/dm1.map")).unwrap();
+        for i in map.group_indices() {
+            let group = map.group(i).unwrap();
+            let json = serde_json::to_string(&group).unwrap();
+            let group2: Group = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&group2).unwrap(), json);
+            for k in group.layer_indices.clone() {
+                let json = serde_json::to_string(&map.layer(k).unwrap()).unwrap();
+                let layer: Layer = serde_json::from_str(&json).unwrap();
+                assert_eq!(serde_json::to_string(&layer).unwrap(), json);
+            }
+        }
+
+        let tilemap = LayerTilemap::from_raw(&[2, 4, 2, 0, 255, 255, 255, 255, -1, 0, -1, 7], 0..10, 5..7, 9..10).unwrap();
+        let mut json = serde_json::to_value(&tilemap).unwrap();
+        assert_eq!(json["name"], "");
+        json["name"] = "Game".into();
+        let tilemap: LayerTilemap = serde_json::from_value(json).unwrap();
+        assert_eq!(&tilemap.name, b"Game\0\0\0\0\0\0\0\0");
+
+        // Names that aren't plain strings are kept as bytes.
+        let mut tilemap = tilemap;
+        tilemap.name = *b"Game\0\xff\0\0\0\0\0\0";
+        let json = serde_json::to_value(&tilemap).unwrap();
+        assert_eq!(json["name"], serde_json::json!([71, 97, 109, 101, 0, 255, 0, 0, 0, 0, 0, 0]));
+        let tilemap2: LayerTilemap = serde_json::from_value(json).unwrap();
+        assert_eq!(tilemap2.name, tilemap.name);
+
+        let mut json = serde_json::to_value(&tilemap).unwrap();
+        json["name"] = "twelve bytes".into();
+        assert!(serde_json::from_value::<LayerTilemap>(json).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+//! Accepts all clients and sends their chunks back to them.
+
+use net::Net;
+use net_tokio::Command;
+use net_tokio::Event;
+use std::io;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:8303").await?;
+    let (command_tx, command_rx) = mpsc::channel(64);
+    let (event_tx, mut event_rx) = mpsc::channel(64);
+    let net = tokio::spawn(net_tokio::run(socket, Net::server(), command_rx, event_tx));
+
+    while let Some(event) = event_rx.recv().await {
+        let command = match event {
+            Event::Connect(pid, addr) => {
+                println!("{:?}: connect from {}", pid, addr);
+                Command::Accept(pid)
+            }
+            Event::Chunk { pid, vital, data } => Command::Send {
+                pid: pid,
+                vital: vital,
+                data: data,
+            },
+            Event::Connless { addr, data, .. } => Command::SendConnless(addr, data),
+            Event::Disconnect(pid, reason) => {
+                println!("{:?}: disconnect {:?}", pid, String::from_utf8_lossy(&reason));
+                continue;
+            }
+            Event::Ready(_) => continue,
+        };
+        if command_tx.send(command).await.is_err() {
+            break;
+        }
+    }
+    net.await.unwrap()
+}
@@ -0,0 +1,246 @@
+//! Drives a `net::Net` from a tokio `UdpSocket`.
+//!
+//! `run` owns the socket and the `Net`. Received packets are fed into the
+//! `Net`, the resulting chunks and events are passed to the application
+//! through a channel, and the application controls the connections by
+//! sending `Command`s through another one.
+
+use net::Net;
+use net::Timestamp;
+use net::net::Callback;
+use net::net::Chunk;
+use net::net::ChunkOrEvent;
+use net::net::PeerId;
+use net::protocol;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::time;
+
+/// Owned version of `ChunkOrEvent`, so it can be sent through a channel.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    Chunk {
+        pid: PeerId,
+        vital: bool,
+        data: Vec<u8>,
+    },
+    Connless {
+        addr: SocketAddr,
+        pid: Option<PeerId>,
+        data: Vec<u8>,
+    },
+    // Server side: A client wants to connect, answer with `Command::Accept`
+    // or `Command::Reject`.
+    Connect(PeerId, SocketAddr),
+    // Client side: The server accepted our connect, data can be sent now.
+    Ready(PeerId),
+    Disconnect(PeerId, Vec<u8>),
+}
+
+impl<'a> From<ChunkOrEvent<'a, SocketAddr>> for Event {
+    fn from(chunk: ChunkOrEvent<'a, SocketAddr>) -> Event {
+        match chunk {
+            ChunkOrEvent::Chunk(c) => Event::Chunk {
+                pid: c.pid,
+                vital: c.vital,
+                data: c.data.to_owned(),
+            },
+            ChunkOrEvent::Connless(c) => Event::Connless {
+                addr: c.addr,
+                pid: c.pid,
+                data: c.data.to_owned(),
+            },
+            ChunkOrEvent::Connect(pid, addr) => Event::Connect(pid, addr),
+            ChunkOrEvent::Ready(pid) => Event::Ready(pid),
+            ChunkOrEvent::Disconnect(pid, _, reason) => Event::Disconnect(pid, reason.to_owned()),
+        }
+    }
+}
+
+/// Requests to the `Net` driven by `run`.
+///
+/// Commands for peers that don't exist (anymore) are ignored, peers can
+/// disconnect at any time. `Send` and `Flush` are also ignored while the
+/// handshake with the peer hasn't completed yet.
+#[derive(Debug)]
+pub enum Command {
+    /// Connects to the address, the peer ID is sent back through the
+    /// channel.
+    Connect(SocketAddr, oneshot::Sender<PeerId>),
+    Accept(PeerId),
+    Reject(PeerId, Vec<u8>),
+    Disconnect(PeerId, Vec<u8>),
+    Send {
+        pid: PeerId,
+        vital: bool,
+        data: Vec<u8>,
+    },
+    SendConnless(SocketAddr, Vec<u8>),
+    /// Sends the queued chunks of the peer now instead of on the next tick.
+    Flush(PeerId),
+}
+
+struct Cb<'a> {
+    socket: &'a UdpSocket,
+    start: Instant,
+}
+
+impl<'a> Callback<SocketAddr> for Cb<'a> {
+    type Error = io::Error;
+    fn send(&mut self, addr: SocketAddr, data: &[u8]) -> io::Result<()> {
+        self.socket.try_send_to(data, addr).map(|_| ())
+    }
+    fn time(&mut self) -> Timestamp {
+        Timestamp::from_secs_since_epoch(0) + self.start.elapsed()
+    }
+    fn would_block(&self, e: &io::Error) -> bool {
+        e.kind() == io::ErrorKind::WouldBlock
+    }
+}
+
+// Used if the `Net` has nothing to do until the next packet arrives.
+const IDLE_TICK: Duration = Duration::from_secs(1);
+
+/// Runs the `Net` on the socket until `commands` is closed.
+///
+/// Chunks and events are sent to `events`. If the application doesn't keep
+/// up with them, no further packets are read from the socket until there's
+/// room in the channel again, commands are still executed meanwhile. Packets that can't be sent because the
+/// socket's send buffer is full are queued by the `Net` and sent once the
+/// socket becomes writable.
+///
+/// Returns socket errors and errors returned by the `Net`, e.g. for chunks
+/// that are too long.
+pub async fn run(
+    socket: UdpSocket,
+    mut net: Net<SocketAddr>,
+    mut commands: mpsc::Receiver<Command>,
+    events: mpsc::Sender<Event>,
+) -> io::Result<()> {
+    let start = Instant::now();
+    let mut recv_buf = [0; protocol::MAX_PACKETSIZE];
+    let mut buffer = [0; protocol::MAX_PAYLOAD];
+    let mut received = Vec::new();
+    loop {
+        let mut cb = Cb { socket: &socket, start: start };
+        if let Some(e) = net.tick(&mut cb).next() {
+            return Err(e);
+        }
+        let sleep = net.needs_tick().time_from(cb.time()).unwrap_or(IDLE_TICK);
+
+        tokio::select! {
+            result = socket.recv_from(&mut recv_buf) => {
+                let (len, addr) = result?;
+                let mut cb = Cb { socket: &socket, start: start };
+                let (packet, result) = net.feed(&mut cb, &mut warn::Ignore, addr, &recv_buf[..len], &mut buffer[..]);
+                received.extend(packet.map(Event::from));
+                result?;
+            }
+            command = commands.recv() => {
+                let command = match command {
+                    Some(c) => c,
+                    None => return Ok(()),
+                };
+                let mut cb = Cb { socket: &socket, start: start };
+                execute(&mut net, &mut cb, command)?;
+            }
+            result = socket.writable(), if net.has_blocked() => {
+                result?;
+                let mut cb = Cb { socket: &socket, start: start };
+                net.send_blocked(&mut cb)?;
+            }
+            _ = time::sleep(sleep) => {}
+        }
+
+        for event in received.drain(..) {
+            // Keep executing commands while waiting for room in the channel,
+            // the application might be waiting for the command channel in
+            // turn.
+            loop {
+                tokio::select! {
+                    permit = events.reserve() => {
+                        match permit {
+                            Ok(permit) => permit.send(event),
+                            // Nobody is listening anymore.
+                            Err(_) => return Ok(()),
+                        }
+                        break;
+                    }
+                    command = commands.recv() => {
+                        let command = match command {
+                            Some(c) => c,
+                            None => return Ok(()),
+                        };
+                        let mut cb = Cb { socket: &socket, start: start };
+                        execute(&mut net, &mut cb, command)?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn execute(net: &mut Net<SocketAddr>, cb: &mut Cb, command: Command)
+    -> io::Result<()>
+{
+    match command {
+        Command::Connect(addr, reply) => {
+            let (pid, result) = net.connect(cb, addr);
+            // The application might not be interested in the peer ID.
+            let _ = reply.send(pid);
+            result
+        }
+        Command::SendConnless(addr, data) => Ok(net.send_connless(cb, addr, &data)?),
+        Command::Accept(pid) if net.has_peer(pid) => net.accept(cb, pid),
+        Command::Reject(pid, reason) if net.has_peer(pid) => net.reject(cb, pid, &reason),
+        Command::Disconnect(pid, reason) if net.has_peer(pid) => net.disconnect(cb, pid, &reason),
+        // Peers that are still connecting or already disconnecting can't
+        // take chunks.
+        Command::Send { pid, vital, data } if is_online(net, pid) => {
+            Ok(net.send(cb, Chunk { pid: pid, vital: vital, data: &data })?)
+        }
+        Command::Flush(pid) if is_online(net, pid) => net.flush(cb, pid),
+        // The peer is gone already.
+        _ => Ok(()),
+    }
+}
+
+fn is_online(net: &Net<SocketAddr>, pid: PeerId) -> bool {
+    net.has_peer(pid) && net.is_online(pid)
+}
+
+#[cfg(test)]
+mod test {
+    use net::Net;
+    use super::Command;
+    use super::run;
+    use tokio::net::UdpSocket;
+    use tokio::sync::mpsc;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn send_before_ready() {
+        // Never answers, the client stays in the connecting state.
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (commands, commands_rx) = mpsc::channel(8);
+        let (events, _events_rx) = mpsc::channel(8);
+        let (result, ()) = tokio::join!(
+            run(client, Net::client(), commands_rx, events),
+            async move {
+                let (reply, pid) = oneshot::channel();
+                commands.send(Command::Connect(server.local_addr().unwrap(), reply)).await.unwrap();
+                let pid = pid.await.unwrap();
+                commands.send(Command::Send { pid: pid, vital: true, data: b"hello".to_vec() }).await.unwrap();
+                commands.send(Command::Flush(pid)).await.unwrap();
+                drop(commands);
+            }
+        );
+        result.unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+#[macro_use] extern crate bencher;
+extern crate net;
+extern crate void;
+extern crate warn;
+
+use bencher::Bencher;
+use net::Net;
+use net::Timestamp;
+use net::net::Callback;
+use net::net::ChunkOrEvent;
+use net::protocol;
+use void::ResultVoidExt;
+use void::Void;
+use warn::Panic;
+
+const NUM_PEERS: u32 = 64;
+const NUM_SENDS: u32 = 1000;
+const INFO: &'static [u8] = b"server info";
+
+struct Cb;
+
+impl Callback<u32> for Cb {
+    type Error = Void;
+    fn send(&mut self, _: u32, _: &[u8]) -> Result<(), Void> {
+        Ok(())
+    }
+    fn time(&mut self) -> Timestamp {
+        Timestamp::from_secs_since_epoch(0)
+    }
+}
+
+// A server with `NUM_PEERS` accepted peers at the addresses
+// `0..NUM_PEERS`.
+fn busy_server() -> Net<u32> {
+    let mut buffer = [0; protocol::MAX_PAYLOAD];
+    let mut net = Net::server();
+    for addr in 0..NUM_PEERS {
+        let pid = match net.feed(&mut Cb, &mut Panic, addr, b"\x10\x00\x00\x01", &mut buffer[..]).0.next() {
+            Some(ChunkOrEvent::Connect(pid, _)) => pid,
+            _ => panic!("no connect event"),
+        };
+        net.accept(&mut Cb, pid).void_unwrap();
+    }
+    net
+}
+
+// Answers `NUM_SENDS` server browser queries from addresses that don't
+// belong to peers.
+fn send_connless(bench: &mut Bencher) {
+    let mut net = busy_server();
+    bench.iter(|| {
+        for addr in NUM_PEERS..NUM_PEERS + NUM_SENDS {
+            net.send_connless(&mut Cb, addr, INFO).unwrap();
+        }
+    });
+}
+
+// Same as `send_connless`, but looks up the peers first.
+fn send_to_addr(bench: &mut Bencher) {
+    let mut net = busy_server();
+    bench.iter(|| {
+        for addr in NUM_PEERS..NUM_PEERS + NUM_SENDS {
+            net.send_to_addr(&mut Cb, addr, INFO, false).unwrap();
+        }
+    });
+}
+
+benchmark_group!(connless, send_connless, send_to_addr);
+benchmark_main!(connless);
@@ -0,0 +1,100 @@
+//! Accepts clients and sends their chunks back to them.
+//!
+//! Usage: `udp_echo_server [ADDRESS [MAX_CLIENTS]]`, listening on
+//! `0.0.0.0:8303` for at most 64 clients by default. Further clients are
+//! rejected. Clients joining and leaving are announced to the others, and a
+//! client sending `quit` is disconnected.
+
+extern crate net;
+extern crate warn;
+
+use net::Net;
+use net::net::Chunk;
+use net::net::ChunkOrEvent;
+use net::net::PeerId;
+use net::protocol;
+use net::udp::Udp;
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+
+fn broadcast(net: &mut Net<SocketAddr>, udp: &mut Udp, except: PeerId, message: &[u8])
+    -> io::Result<()>
+{
+    let pids: Vec<_> = net.peer_ids().filter(|&pid| pid != except && net.is_online(pid)).collect();
+    for pid in pids {
+        net.send(udp, Chunk::vital(pid, message)).map_err(|e| e.unwrap_callback())?;
+    }
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    let mut args = env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "0.0.0.0:8303".to_owned());
+    let max_clients: usize = match args.next().map(|m| m.parse()) {
+        Some(Ok(m)) => m,
+        Some(Err(_)) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid MAX_CLIENTS")),
+        None => 64,
+    };
+
+    let mut data = [0; protocol::MAX_PACKETSIZE];
+    let mut buffer = [0; protocol::MAX_PAYLOAD];
+    let mut udp = Udp::bind(&*addr)?;
+    let mut net = Net::server();
+    let mut chunks = vec![];
+    loop {
+        let mut connects = vec![];
+        let mut disconnects = vec![];
+        {
+            let (packet, res) = udp.poll(&mut net, &mut warn::Ignore, None, &mut data, &mut buffer);
+            for chunk in packet {
+                match chunk {
+                    ChunkOrEvent::Connect(pid, addr) => {
+                        println!("{:?}: connect from {}", pid, addr);
+                        connects.push(pid);
+                    }
+                    ChunkOrEvent::Chunk(c) => chunks.push((c.pid, c.vital, c.data.to_owned())),
+                    ChunkOrEvent::Disconnect(pid, _, reason) => {
+                        println!("{:?}: disconnect {:?}", pid, String::from_utf8_lossy(reason));
+                        disconnects.push(pid);
+                    }
+                    _ => {},
+                }
+            }
+            res?;
+        }
+        for pid in connects {
+            // The new peer is already counted.
+            if net.peer_ids().count() > max_clients {
+                println!("{:?}: rejected, server full", pid);
+                net.reject(&mut udp, pid, b"This server is full")?;
+                continue;
+            }
+            net.accept(&mut udp, pid)?;
+            broadcast(&mut net, &mut udp, pid, format!("{:?} joined", pid).as_bytes())?;
+        }
+        for (pid, vital, data) in chunks.drain(..) {
+            let chunk = Chunk { pid: pid, vital: vital, data: &data };
+            // The peer might have quit earlier in this batch.
+            if !net.is_receive_chunk_still_valid(&mut ChunkOrEvent::Chunk(chunk)) {
+                continue;
+            }
+            if data == b"quit" {
+                println!("{:?}: quit", pid);
+                net.disconnect(&mut udp, pid, b"Bye")?;
+                disconnects.push(pid);
+                continue;
+            }
+            net.send(&mut udp, chunk).map_err(|e| e.unwrap_callback())?;
+        }
+        for pid in disconnects {
+            broadcast(&mut net, &mut udp, pid, format!("{:?} left", pid).as_bytes())?;
+        }
+        for pid in net.peer_ids().collect::<Vec<_>>() {
+            if net.is_online(pid) {
+                net.flush(&mut udp, pid)?;
+            }
+        }
+        udp.tick(&mut net)?;
+    }
+}
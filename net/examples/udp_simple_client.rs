@@ -0,0 +1,110 @@
+//! Connects to a server, sends a few chunks and prints what it receives.
+//!
+//! Usage: `udp_simple_client [ADDRESS]`, the address defaults to
+//! `127.0.0.1:8303`, where `udp_echo_server` listens. Ctrl-C disconnects
+//! gracefully.
+
+extern crate net;
+extern crate warn;
+#[cfg(unix)] extern crate libc;
+
+use net::Net;
+use net::net::ChunkOrEvent;
+use net::protocol;
+use net::udp::Udp;
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+const MESSAGES: &'static [&'static [u8]] = &[b"hello", b"from", b"simple_client"];
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn handle_ctrl_c() {
+    extern "C" fn handler(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+    unsafe {
+        libc::signal(libc::SIGINT, handler as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_ctrl_c() {
+    // Without a handler, Ctrl-C terminates the process right away.
+}
+
+fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "address didn't resolve")
+    })
+}
+
+fn main() -> io::Result<()> {
+    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8303".to_owned());
+    let addr = resolve(&addr)?;
+    handle_ctrl_c();
+
+    let mut data = [0; protocol::MAX_PACKETSIZE];
+    let mut buffer = [0; protocol::MAX_PAYLOAD];
+    let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let mut udp = Udp::bind(local)?;
+    let mut net = Net::client();
+    let (pid, res) = net.connect(&mut udp, addr);
+    res?;
+    println!("{:?}: connecting to {}", pid, addr);
+
+    // Wake up regularly to notice Ctrl-C.
+    let timeout = Some(Duration::from_millis(100));
+    loop {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            println!("{:?}: disconnecting", pid);
+            net.disconnect(&mut udp, pid, b"Ctrl-C")?;
+            return Ok(());
+        }
+        let mut ready = false;
+        {
+            let (packet, res) = udp.poll(&mut net, &mut warn::Ignore, timeout, &mut data, &mut buffer);
+            for chunk in packet {
+                match chunk {
+                    ChunkOrEvent::Ready(pid) => {
+                        println!("{:?}: connected", pid);
+                        ready = true;
+                    }
+                    ChunkOrEvent::Chunk(c) => {
+                        println!("{:?}: {} chunk {:?}", c.pid,
+                            if c.vital { "vital" } else { "non-vital" },
+                            String::from_utf8_lossy(c.data));
+                    }
+                    ChunkOrEvent::Connless(c) => {
+                        println!("{}: connless {:?}", c.addr, String::from_utf8_lossy(c.data));
+                    }
+                    ChunkOrEvent::Disconnect(pid, why, reason) => {
+                        println!("{:?}: disconnected ({:?}) {:?}", pid, why, String::from_utf8_lossy(reason));
+                        return Ok(());
+                    }
+                    ChunkOrEvent::Connect(..) => unreachable!(),
+                }
+            }
+            match res {
+                // Ctrl-C interrupted the wait.
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                r => r?,
+            }
+        }
+        if ready {
+            for message in MESSAGES {
+                // The messages are short, so the only possible error is
+                // one of the socket.
+                net.send_vital(&mut udp, pid, message).map_err(|e| e.unwrap_callback())?;
+            }
+            net.flush(&mut udp, pid)?;
+        }
+        udp.tick(&mut net)?;
+    }
+}
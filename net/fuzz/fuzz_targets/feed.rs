@@ -0,0 +1,9 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate net;
+
+use net::Net;
+
+fuzz_target!(|data: &[u8]| {
+    Net::feed_fuzz(data);
+});
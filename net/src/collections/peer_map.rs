@@ -1,14 +1,20 @@
-use linear_map::LinearMap;
-use linear_map;
 use net::PeerId;
 use std::fmt;
 use std::iter::FromIterator;
+use std::mem;
 use std::ops;
-
+use std::slice;
+use std::vec;
+
+/// Map from peer IDs to `T`.
+///
+/// Iteration is in ascending order of the peer IDs, independent of the order
+/// of insertions and removals. The entries are kept sorted in a `Vec`, so
+/// `with_capacity` allocates the storage upfront. Peer IDs are handed out in
+/// ascending order, thus new peers are usually appended at the end.
 #[derive(Clone)]
 pub struct PeerMap<T> {
-    // TODO: Different data structure, HashMap?
-    map: LinearMap<PeerId, T>,
+    entries: Vec<(PeerId, T)>,
 }
 
 impl<T> Default for PeerMap<T> {
@@ -26,58 +32,78 @@ impl<T: fmt::Debug> fmt::Debug for PeerMap<T> {
 impl<T> PeerMap<T> {
     pub fn new() -> PeerMap<T> {
         PeerMap {
-            map: LinearMap::new(),
+            entries: Vec::new(),
         }
     }
     pub fn with_capacity(cap: usize) -> PeerMap<T> {
         PeerMap {
-            map: LinearMap::with_capacity(cap),
+            entries: Vec::with_capacity(cap),
+        }
+    }
+    // `Ok` with the index of the entry, `Err` with the index it would be
+    // inserted at.
+    fn search(&self, pid: PeerId) -> Result<usize, usize> {
+        // Fast path for the most recently added peer.
+        match self.entries.last() {
+            Some(&(last, _)) if last < pid => return Err(self.entries.len()),
+            Some(&(last, _)) if last == pid => return Ok(self.entries.len() - 1),
+            _ => {},
         }
+        self.entries.binary_search_by_key(&pid, |&(p, _)| p)
     }
     pub fn clear(&mut self) {
-        self.map.clear()
+        self.entries.clear()
     }
     pub fn len(&self) -> usize {
-        self.map.len()
+        self.entries.len()
     }
     pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+        self.entries.is_empty()
     }
     pub fn iter(&self) -> Iter<T> {
-        Iter(self.map.iter())
+        Iter(self.entries.iter())
     }
     pub fn iter_mut(&mut self) -> IterMut<T> {
-        IterMut(self.map.iter_mut())
+        IterMut(self.entries.iter_mut())
     }
     pub fn keys(&self) -> Keys<T> {
-        Keys(self.map.keys())
+        Keys(self.entries.iter())
     }
     pub fn values(&self) -> Values<T> {
-        Values(self.map.values())
+        Values(self.entries.iter())
     }
     pub fn drain(&mut self) -> Drain<T> {
-        Drain(self.map.drain())
+        Drain(self.entries.drain(..))
     }
     pub fn insert(&mut self, pid: PeerId, value: T) -> Option<T> {
-        self.map.insert(pid, value)
+        match self.search(pid) {
+            Ok(i) => Some(mem::replace(&mut self.entries[i].1, value)),
+            Err(i) => {
+                self.entries.insert(i, (pid, value));
+                None
+            }
+        }
     }
     pub fn remove(&mut self, pid: PeerId) {
-        self.map.remove(&pid).unwrap_or_else(|| panic!("invalid pid"));
+        self.entry(pid).assert_occupied().remove();
     }
     pub fn get(&self, pid: PeerId) -> Option<&T> {
-        self.map.get(&pid)
+        self.search(pid).ok().map(|i| &self.entries[i].1)
     }
     pub fn get_mut(&mut self, pid: PeerId) -> Option<&mut T> {
-        self.map.get_mut(&pid)
+        match self.search(pid) {
+            Ok(i) => Some(&mut self.entries[i].1),
+            Err(_) => None,
+        }
     }
     pub fn entry(&mut self, pid: PeerId) -> Entry<T> {
-        match self.map.entry(pid) {
-            linear_map::Entry::Occupied(o) => Entry::Occupied(OccupiedEntry(o)),
-            linear_map::Entry::Vacant(v) => Entry::Vacant(VacantEntry(v)),
+        match self.search(pid) {
+            Ok(i) => Entry::Occupied(OccupiedEntry { entries: &mut self.entries, index: i }),
+            Err(i) => Entry::Vacant(VacantEntry { entries: &mut self.entries, index: i, pid: pid }),
         }
     }
     pub fn contains_key(&mut self, pid: PeerId) -> bool {
-        self.map.contains_key(&pid)
+        self.search(pid).is_ok()
     }
 }
 
@@ -96,15 +122,17 @@ impl<T> ops::IndexMut<PeerId> for PeerMap<T> {
 
 impl<T> FromIterator<(PeerId, T)> for PeerMap<T> {
     fn from_iter<I>(iter: I) -> PeerMap<T> where I: IntoIterator<Item=(PeerId, T)> {
-        PeerMap {
-            map: FromIterator::from_iter(iter),
-        }
+        let mut result = PeerMap::new();
+        result.extend(iter);
+        result
     }
 }
 
 impl<T> Extend<(PeerId, T)> for PeerMap<T> {
     fn extend<I>(&mut self, iter: I) where I: IntoIterator<Item=(PeerId, T)> {
-        self.map.extend(iter)
+        for (pid, value) in iter {
+            self.insert(pid, value);
+        }
     }
 }
 
@@ -124,16 +152,16 @@ impl<'a, T: 'a> IntoIterator for &'a mut PeerMap<T> {
     }
 }
 
-pub struct Iter<'a, T: 'a>(linear_map::Iter<'a, PeerId, T>);
-pub struct IterMut<'a, T: 'a>(linear_map::IterMut<'a, PeerId, T>);
-pub struct Drain<'a, T: 'a>(linear_map::Drain<'a, PeerId, T>);
-pub struct Keys<'a, T: 'a>(linear_map::Keys<'a, PeerId, T>);
-pub struct Values<'a, T: 'a>(linear_map::Values<'a, PeerId, T>);
+pub struct Iter<'a, T: 'a>(slice::Iter<'a, (PeerId, T)>);
+pub struct IterMut<'a, T: 'a>(slice::IterMut<'a, (PeerId, T)>);
+pub struct Drain<'a, T: 'a>(vec::Drain<'a, (PeerId, T)>);
+pub struct Keys<'a, T: 'a>(slice::Iter<'a, (PeerId, T)>);
+pub struct Values<'a, T: 'a>(slice::Iter<'a, (PeerId, T)>);
 
 impl<'a, T> Iterator for Iter<'a, T> {
     type Item = (PeerId, &'a T);
     fn next(&mut self) -> Option<(PeerId, &'a T)> {
-        self.0.next().map(|(&pid, e)| (pid, e))
+        self.0.next().map(|&(pid, ref e)| (pid, e))
     }
     fn size_hint(&self) -> (usize, Option<usize>) {
         self.0.size_hint()
@@ -143,7 +171,7 @@ impl<'a, T> Iterator for Iter<'a, T> {
 impl<'a, T> Iterator for IterMut<'a, T> {
     type Item = (PeerId, &'a mut T);
     fn next(&mut self) -> Option<(PeerId, &'a mut T)> {
-        self.0.next().map(|(&pid, e)| (pid, e))
+        self.0.next().map(|&mut (pid, ref mut e)| (pid, e))
     }
     fn size_hint(&self) -> (usize, Option<usize>) {
         self.0.size_hint()
@@ -163,7 +191,7 @@ impl<'a, T> Iterator for Drain<'a, T> {
 impl<'a, T: 'a> Iterator for Keys<'a, T> {
     type Item = PeerId;
     fn next(&mut self) -> Option<PeerId> {
-        self.0.next().cloned()
+        self.0.next().map(|&(pid, _)| pid)
     }
     fn size_hint(&self) -> (usize, Option<usize>) {
         self.0.size_hint()
@@ -173,15 +201,23 @@ impl<'a, T: 'a> Iterator for Keys<'a, T> {
 impl<'a, T: 'a> Iterator for Values<'a, T> {
     type Item = &'a T;
     fn next(&mut self) -> Option<&'a T> {
-        self.0.next()
+        self.0.next().map(|&(_, ref e)| e)
     }
     fn size_hint(&self) -> (usize, Option<usize>) {
         self.0.size_hint()
     }
 }
 
-pub struct OccupiedEntry<'a, T: 'a>(linear_map::OccupiedEntry<'a, PeerId, T>);
-pub struct VacantEntry<'a, T: 'a>(linear_map::VacantEntry<'a, PeerId, T>);
+pub struct OccupiedEntry<'a, T: 'a> {
+    entries: &'a mut Vec<(PeerId, T)>,
+    index: usize,
+}
+
+pub struct VacantEntry<'a, T: 'a> {
+    entries: &'a mut Vec<(PeerId, T)>,
+    index: usize,
+    pid: PeerId,
+}
 
 pub enum Entry<'a, T: 'a> {
     Occupied(OccupiedEntry<'a, T>),
@@ -199,18 +235,19 @@ impl<'a, T: 'a> Entry<'a, T> {
 
 impl<'a, T: 'a> OccupiedEntry<'a, T> {
     pub fn get(&self) -> &T {
-        self.0.get()
+        &self.entries[self.index].1
     }
     pub fn get_mut(&mut self) -> &mut T {
-        self.0.get_mut()
+        &mut self.entries[self.index].1
     }
     pub fn remove(self) -> T {
-        self.0.remove()
+        self.entries.remove(self.index).1
     }
 }
 
 impl<'a, T: 'a> VacantEntry<'a, T> {
     pub fn insert(self, value: T) -> &'a mut T {
-        self.0.insert(value)
+        self.entries.insert(self.index, (self.pid, value));
+        &mut self.entries[self.index].1
     }
 }
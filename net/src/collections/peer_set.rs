@@ -4,6 +4,7 @@ use std::iter::FromIterator;
 use super::PeerMap;
 use super::peer_map;
 
+/// Set of peer IDs, iterated in ascending order.
 #[derive(Clone, Default)]
 pub struct PeerSet {
     set: PeerMap<()>,
@@ -4,17 +4,24 @@ use arrayvec::ArrayVec;
 use buffer::Buffer;
 use buffer::BufferRef;
 use buffer::with_buffer;
+use common::num::Cast;
 use protocol::ChunksIter;
 use protocol::ConnectedPacket;
 use protocol::ConnectedPacketType;
 use protocol::ControlPacket;
+use protocol::HEADER_SIZE;
 use protocol::MAX_PACKETSIZE;
 use protocol::MAX_PAYLOAD;
+use protocol::PADDING_SIZE_CONNLESS;
 use protocol::Packet;
 use protocol;
 use std::cmp;
 use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::io;
 use std::iter;
+use std::mem;
 use std::time::Duration;
 use warn::Warn;
 
@@ -27,6 +34,15 @@ pub trait Callback {
     fn time(&mut self) -> Timestamp;
 }
 
+/// Error returned by the sending operations.
+///
+/// There are only two kinds: `TooLongData` means the passed data doesn't fit
+/// into a single packet, nothing has been queued or sent in that case.
+/// `Callback` wraps an error of `Callback::send`, i.e. of the transport.
+///
+/// Problems of the connection itself are no errors: the peer closing it is
+/// reported as a disconnect when feeding packets, malformed input through
+/// warnings.
 #[derive(Debug)]
 pub enum Error<CE> {
     TooLongData,
@@ -39,6 +55,24 @@ impl<CE> From<CE> for Error<CE> {
     }
 }
 
+impl<CE: error::Error + 'static> error::Error for Error<CE> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::TooLongData => None,
+            Error::Callback(ref e) => Some(e),
+        }
+    }
+}
+
+impl<CE: fmt::Display> fmt::Display for Error<CE> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::TooLongData => f.write_str("data too long to fit into a packet"),
+            Error::Callback(ref e) => write!(f, "callback error: {}", e),
+        }
+    }
+}
+
 impl<CE> Error<CE> {
     pub fn unwrap_callback(self) -> CE {
         match self {
@@ -48,11 +82,33 @@ impl<CE> Error<CE> {
     }
 }
 
+impl<CE: Into<io::Error>> Error<CE> {
+    /// Converts the error for transports whose errors are `io::Error`s.
+    ///
+    /// `TooLongData` becomes an error of kind `InvalidInput`.
+    pub fn into_io(self) -> io::Error {
+        match self {
+            Error::TooLongData =>
+                io::Error::new(io::ErrorKind::InvalidInput, "data too long to fit into a packet"),
+            Error::Callback(e) => e.into(),
+        }
+    }
+}
+
+impl<CE: Into<io::Error>> From<Error<CE>> for io::Error {
+    fn from(e: Error<CE>) -> io::Error {
+        e.into_io()
+    }
+}
+
 #[derive(Debug)]
 pub enum Warning {
     Packet(protocol::Warning),
     Read(protocol::PacketReadError),
     Unexpected,
+    // The chunks that arrived before the connect accept didn't fit into the
+    // buffer next to it, see `Config::buffer_early_chunks`.
+    EarlyChunksDropped,
 }
 
 trait TimeoutExt {
@@ -77,18 +133,180 @@ impl TimeoutExt for Timeout {
     }
 }
 
+/// Parameters of a connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Config {
+    /// Time after which unacknowledged vital chunks are resent.
+    ///
+    /// Default: 1 s.
+    pub resend_interval: Duration,
+    /// Time after which a packet is sent if nothing else was sent, e.g. a
+    /// keep-alive or a repeated connection attempt.
+    ///
+    /// Default: 500 ms.
+    pub keep_alive_interval: Duration,
+    /// Time a graceful disconnect waits for the peer's acknowledgement.
+    ///
+    /// Default: 2 s.
+    pub disconnect_timeout: Duration,
+    /// Whether outgoing packets are Huffman-compressed when that makes them
+    /// smaller. Received packets are decompressed regardless.
+    ///
+    /// Default: `true`.
+    pub compression: bool,
+    /// Whether chunks arriving before the connect accept are kept and
+    /// delivered once the connection is online. At most `MAX_PAYLOAD` bytes of
+    /// them are kept, the peer resends the dropped vital chunks.
+    ///
+    /// Default: `true`.
+    pub buffer_early_chunks: bool,
+    /// Whether lost vital chunks are resent without waiting for the resend
+    /// interval, like TCP's fast retransmit.
+    ///
+    /// A resend is requested as soon as a gap in the received vital chunks
+    /// is noticed, instead of with the next packet that is sent anyway.
+    /// While the same chunk is missing, the request is repeated at most
+    /// once per resend interval. The unacknowledged chunks resent on a
+    /// request of the peer are sent right away instead of with the next
+    /// packet. Both sides should enable it, a peer without it still answers
+    /// the requests, just later.
+    ///
+    /// Default: `false`.
+    pub fast_retransmit: bool,
+    /// Whether the amount of unacknowledged vital data is limited by a
+    /// congestion window that adapts to the link, see
+    /// `Connection::congestion_window`.
+    ///
+    /// The window starts at `INITIAL_WINDOW` bytes. While vital chunks are
+    /// held back because the window is full, every acknowledged window
+    /// worth of chunks grows it by `MAX_PAYLOAD` bytes, up to `MAX_WINDOW`.
+    /// Every resend, because of the resend interval or a request of the
+    /// peer, halves it, down to `MIN_WINDOW`. Held-back chunks are sent on
+    /// a later `flush` or `tick` once acknowledgements make room for them.
+    /// A chunk is always sent if no vital data is unacknowledged, no matter
+    /// its size.
+    ///
+    /// Default: `false`.
+    pub congestion_control: bool,
+}
+
+/// Congestion window of a new connection in payload bytes, see
+/// `Config::congestion_control`.
+pub const INITIAL_WINDOW: usize = 4 * MAX_PAYLOAD;
+/// Smallest congestion window, see `Config::congestion_control`.
+pub const MIN_WINDOW: usize = MAX_PAYLOAD;
+/// Largest congestion window, see `Config::congestion_control`.
+pub const MAX_WINDOW: usize = 64 * MAX_PAYLOAD;
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            resend_interval: Duration::from_millis(1_000),
+            keep_alive_interval: Duration::from_millis(500),
+            disconnect_timeout: Duration::from_millis(2_000),
+            compression: true,
+            buffer_early_chunks: true,
+            fast_retransmit: false,
+            congestion_control: false,
+        }
+    }
+}
+
+/// Statistics of a connection.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// Number of received vital chunks that were dropped because they had
+    /// already been received.
+    pub duplicate_chunks: u64,
+    /// Number of vital chunks that are still missing, counted from the last
+    /// chunk received in order up to the newest chunk seen.
+    pub gap: u16,
+    /// Largest `gap` observed so far.
+    pub max_gap: u16,
+    /// Number of chunk bytes sent, including resends, before compression.
+    pub sent_payload: u64,
+    /// Number of bytes the sent chunks took up in the packets, i.e. after
+    /// compression.
+    pub sent_payload_compressed: u64,
+    /// Number of sent packets containing chunks, control packets aren't
+    /// counted.
+    pub sent_packets: u64,
+    /// Number of chunks in the sent packets, including resends.
+    pub sent_chunks: u64,
+    /// Number of bytes of the sent packets handed to the callback, headers
+    /// included.
+    pub sent_bytes: u64,
+}
+
+impl Stats {
+    /// Returns the size of the sent chunks after compression relative to
+    /// their size before, `None` if nothing has been sent yet.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.sent_payload == 0 {
+            return None;
+        }
+        Some(self.sent_payload_compressed as f64 / self.sent_payload as f64)
+    }
+    /// Returns the average number of chunks per sent packet, `None` if no
+    /// packet has been sent yet.
+    pub fn chunks_per_packet(&self) -> Option<f64> {
+        if self.sent_packets == 0 {
+            return None;
+        }
+        Some(self.sent_chunks as f64 / self.sent_packets as f64)
+    }
+}
+
+/// The state of an online connection, for resuming it later, e.g. after a
+/// restart of the process.
+///
+/// Created by `Connection::save`, consumed by `Connection::resume`. It
+/// contains what's needed to continue the vital chunk sequencing where it
+/// left off: the sequence numbers of both directions and the vital chunks
+/// that the peer hasn't acknowledged yet. Statistics, timeouts and queued
+/// non-vital chunks are not kept.
+///
+/// With the `serde` feature, it can be serialized. The serialized form
+/// consists of exactly the public fields of `SavedConnection` and
+/// `SavedChunk` under their names. It stays readable by later versions of
+/// this crate; if fields are added, they get defaults so that older
+/// serializations can still be resumed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct SavedConnection {
+    /// Sequence number of the last vital chunk received in order from the
+    /// peer.
+    pub ack: u16,
+    /// Sequence number of the last vital chunk sent to the peer.
+    pub sequence: u16,
+    /// Vital chunks sent to the peer that haven't been acknowledged yet,
+    /// oldest first.
+    pub unacked: Vec<SavedChunk>,
+}
+
+/// A vital chunk awaiting acknowledgement, see `SavedConnection`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct SavedChunk {
+    pub sequence: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone)]
 pub struct Connection {
+    config: Config,
     state: State,
     send: Timeout,
-    builder: PacketBuilder,
 }
 
 #[derive(Clone, Debug)]
 enum State {
     Unconnected,
-    Connecting,
+    // Holds the chunks that overtook the connect accept.
+    Connecting(PacketContents),
     Pending,
     Online(OnlineState),
+    Disconnecting(DisconnectingState),
     Disconnected,
 }
 
@@ -101,60 +319,158 @@ impl State {
     }
 }
 
+#[derive(Clone, Debug)]
+struct DisconnectingState {
+    reason: ArrayVec<[u8; 2048]>,
+    // The connection is considered disconnected after this timeout, even if
+    // the peer didn't acknowledge the close.
+    timeout: Timeout,
+}
+
+/// A vital chunk that hasn't been acknowledged by the peer yet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PendingChunk {
+    pub sequence: u16,
+    pub size: usize,
+    /// Number of times the chunk has been sent again.
+    pub resends: u32,
+    /// Time of the last (re)send, compare with `Callback::time`.
+    pub last_send: Timestamp,
+}
+
 #[derive(Clone, Debug)]
 struct ResendChunk {
     next_send: Timeout,
+    last_send: Timestamp,
+    resends: u32,
     sequence: Sequence,
     data: ArrayVec<[u8; 2048]>,
 }
 
 impl ResendChunk {
-    fn new<CB: Callback>(cb: &mut CB, sequence: Sequence, data: &[u8]) -> ResendChunk {
+    fn new<CB: Callback>(cb: &mut CB, sequence: Sequence, data: &[u8], resend_interval: Duration)
+        -> ResendChunk
+    {
         let mut result = ResendChunk {
             next_send: Timeout::inactive(),
+            last_send: Timestamp::default(),
+            resends: 0,
             sequence: sequence,
             data: data.iter().cloned().collect(),
         };
         assert!(result.data.len() == data.len(), "overlong resend packet {}", data.len());
-        result.start_timeout(cb);
+        result.start_timeout(cb, resend_interval);
         result
     }
-    fn start_timeout<CB: Callback>(&mut self, cb: &mut CB) {
-        self.next_send.set(cb, Duration::from_millis(1_000));
+    fn start_timeout<CB: Callback>(&mut self, cb: &mut CB, resend_interval: Duration) {
+        self.last_send = cb.time();
+        self.next_send.set(cb, resend_interval);
+    }
+    fn pending(&self) -> PendingChunk {
+        PendingChunk {
+            sequence: self.sequence.to_u16(),
+            size: self.data.len(),
+            resends: self.resends,
+            last_send: self.last_send,
+        }
     }
 }
 
+/// What feeding a packet did, see `ReceivePacket::progress`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Progress {
+    /// The packet couldn't be read or didn't fit the state of the
+    /// connection, and was ignored.
+    Dropped,
+    /// The packet was a connless one.
+    Connless,
+    /// The packet advanced the handshake, e.g. a connect accept that makes
+    /// the connection online.
+    Handshake,
+    /// The packet carried chunks. They can all be duplicates, or be held back
+    /// until the connection is online.
+    Data,
+    /// The packet only kept the connection alive, e.g. a keep-alive, which
+    /// also carries acknowledgements, or a repeated handshake packet.
+    Ack,
+    /// The packet closed the connection or acknowledged our close.
+    Close,
+}
+
 pub struct ReceivePacket<'a> {
     type_: ReceivePacketType<'a>,
+    progress: Progress,
 }
 
 impl<'a> Clone for ReceivePacket<'a> {
     fn clone(&self) -> ReceivePacket<'a> {
         ReceivePacket {
             type_: self.type_.clone(),
+            progress: self.progress,
         }
     }
 }
 
 impl<'a> ReceivePacket<'a> {
+    /// Returns what the packet did to the connection.
+    ///
+    /// Unlike the chunks, this tells apart e.g. a pure acknowledgement from
+    /// garbage that was dropped.
+    pub fn progress(&self) -> Progress {
+        self.progress
+    }
     fn none() -> ReceivePacket<'a> {
+        ReceivePacket::nothing(Progress::Dropped)
+    }
+    // A packet without any chunks or events.
+    fn nothing(progress: Progress) -> ReceivePacket<'a> {
         ReceivePacket {
             type_: ReceivePacketType::None,
+            progress: progress,
         }
     }
     fn ready() -> ReceivePacket<'a> {
         ReceivePacket {
-            type_: ReceivePacketType::Ready(iter::once(())),
+            type_: ReceivePacketType::Ready(iter::once(()), None),
+            progress: Progress::Handshake,
+        }
+    }
+    fn ready_connected<W>(warn: &mut W, online: &mut OnlineState, num_chunks: u8, data: &'a [u8])
+        -> ReceivePacket<'a>
+        where W: Warn<Warning>,
+    {
+        ReceivePacket {
+            type_: ReceivePacketType::Ready(iter::once(()), Some(ReceiveChunks::new(warn, online, num_chunks, data))),
+            progress: Progress::Handshake,
         }
     }
     fn connless(data: &[u8]) -> ReceivePacket {
         ReceivePacket {
             type_: ReceivePacketType::Connless(iter::once(data)),
+            progress: Progress::Connless,
         }
     }
     fn connected<W>(warn: &mut W, online: &mut OnlineState, num_chunks: u8, data: &'a [u8])
         -> ReceivePacket<'a>
         where W: Warn<Warning>,
+    {
+        ReceivePacket {
+            type_: ReceivePacketType::Connected(ReceiveChunks::new(warn, online, num_chunks, data)),
+            progress: if num_chunks == 0 { Progress::Ack } else { Progress::Data },
+        }
+    }
+    fn disconnect(why: DisconnectReason, reason: &[u8]) -> ReceivePacket {
+        ReceivePacket {
+            type_: ReceivePacketType::Close(why, iter::once(reason)),
+            progress: Progress::Close,
+        }
+    }
+}
+
+impl<'a> ReceiveChunks<'a> {
+    fn new<W>(warn: &mut W, online: &mut OnlineState, num_chunks: u8, data: &'a [u8])
+        -> ReceiveChunks<'a>
+        where W: Warn<Warning>,
     {
         let chunks_iter = ChunksIter::new(data, num_chunks);
         let ack = online.ack.clone();
@@ -162,23 +478,27 @@ impl<'a> ReceivePacket<'a> {
         while let Some(c) = iter.next_warn(&mut w(warn)) {
             if let Some((sequence, resend)) = c.vital {
                 let _ = resend;
-                if online.ack.update(Sequence::from_u16(sequence))
-                    != SequenceOrdering::Current
-                {
-                    online.request_resend = true;
+                let sequence = Sequence::from_u16(sequence);
+                match online.ack.update(sequence) {
+                    SequenceOrdering::Current => {
+                        online.stats.gap = online.stats.gap.saturating_sub(1);
+                    }
+                    SequenceOrdering::Past => {
+                        online.stats.duplicate_chunks += 1;
+                        online.request_resend = true;
+                    }
+                    SequenceOrdering::Future => {
+                        let stats = &mut online.stats;
+                        stats.gap = cmp::max(stats.gap, online.ack.distance(sequence));
+                        stats.max_gap = cmp::max(stats.max_gap, stats.gap);
+                        online.request_resend = true;
+                    }
                 }
             }
         }
-        ReceivePacket {
-            type_: ReceivePacketType::Connected(ReceiveChunks {
-                ack: ack,
-                chunks: chunks_iter,
-            }),
-        }
-    }
-    fn disconnect(reason: &[u8]) -> ReceivePacket {
-        ReceivePacket {
-            type_: ReceivePacketType::Close(iter::once(reason)),
+        ReceiveChunks {
+            ack: ack,
+            chunks: chunks_iter,
         }
     }
 }
@@ -188,8 +508,10 @@ enum ReceivePacketType<'a> {
     None,
     Connless(iter::Once<&'a [u8]>),
     Connected(ReceiveChunks<'a>),
-    Ready(iter::Once<()>),
-    Close(iter::Once<&'a [u8]>),
+    // Possibly followed by the chunks that arrived before the connection
+    // was online.
+    Ready(iter::Once<()>, Option<ReceiveChunks<'a>>),
+    Close(DisconnectReason, iter::Once<&'a [u8]>),
 }
 
 impl<'a> Iterator for ReceivePacket<'a> {
@@ -197,13 +519,14 @@ impl<'a> Iterator for ReceivePacket<'a> {
     fn next(&mut self) -> Option<ReceiveChunk<'a>> {
         match self.type_ {
             ReceivePacketType::None => None,
-            ReceivePacketType::Ready(ref mut once) =>
-                once.next().map(|()| ReceiveChunk::Ready),
+            ReceivePacketType::Ready(ref mut once, ref mut chunks) =>
+                once.next().map(|()| ReceiveChunk::Ready)
+                    .or_else(|| chunks.as_mut().and_then(|c| c.next())),
             ReceivePacketType::Connless(ref mut once) =>
                 once.next().map(ReceiveChunk::Connless),
             ReceivePacketType::Connected(ref mut chunks) => chunks.next(),
-            ReceivePacketType::Close(ref mut once) =>
-                once.next().map(ReceiveChunk::Disconnect),
+            ReceivePacketType::Close(why, ref mut once) =>
+                once.next().map(|r| ReceiveChunk::Disconnect(why, r)),
         }
     }
     fn size_hint(&self) -> (usize, Option<usize>) {
@@ -249,7 +572,27 @@ pub enum ReceiveChunk<'a> {
     // Connected(data, vital)
     Connected(&'a [u8], bool),
     Ready,
-    Disconnect(&'a [u8]),
+    // Disconnect(why, reason)
+    Disconnect(DisconnectReason, &'a [u8]),
+}
+
+/// Why a connection was closed.
+///
+/// The reason string sent by the peer is reported alongside, it's empty if
+/// the connection was closed by us.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum DisconnectReason {
+    /// The peer closed the connection after the connection attempt, e.g.
+    /// because it was kicked or the peer left.
+    Closed,
+    /// The peer refused our connection attempt before it accepted it, e.g.
+    /// because the server is full or we're banned.
+    Rejected,
+    /// Our graceful disconnect finished, see `Net::tick_to_queue`.
+    Graceful,
+    /// The peer didn't complete the handshake in time, see
+    /// `Net::tick_to_queue`.
+    HandshakeTimeout,
 }
 
 #[derive(Clone, Debug)]
@@ -259,6 +602,10 @@ struct OnlineState {
     // `sequence` is the vital chunk from us that the peer acknowledged.
     sequence: Sequence,
     request_resend: bool,
+    // Ack and time of the last resend request sent immediately because of
+    // `Config::fast_retransmit`.
+    fast_resend_request: Option<(Sequence, Timestamp)>,
+    stats: Stats,
     // `packet` contains all the queued chunks, `packet_nonvital` only the
     // non-vital ones. This is important for resending.
     packet: PacketContents,
@@ -266,6 +613,15 @@ struct OnlineState {
     // This contains the unacked chunks that we sent, starting from the most
     // recently sent chunk.
     resend_queue: VecDeque<ResendChunk>,
+    // Payload bytes of the chunks in `packet_nonvital` and `resend_queue`.
+    queued_nonvital_bytes: usize,
+    unacked_bytes: usize,
+    // Congestion window in payload bytes, see `Config::congestion_control`.
+    window: usize,
+    // Vital chunks held back because the congestion window was full, oldest
+    // first. They get their sequence numbers once they're sent.
+    backlog: VecDeque<ArrayVec<[u8; 2048]>>,
+    backlog_bytes: usize,
 }
 
 impl OnlineState {
@@ -274,36 +630,65 @@ impl OnlineState {
             ack: Sequence::new(),
             sequence: Sequence::new(),
             request_resend: false,
+            fast_resend_request: None,
+            stats: Stats::default(),
             packet: PacketContents::new(),
             packet_nonvital: PacketContents::new(),
             resend_queue: VecDeque::new(),
+            queued_nonvital_bytes: 0,
+            unacked_bytes: 0,
+            window: INITIAL_WINDOW,
+            backlog: VecDeque::new(),
+            backlog_bytes: 0,
         }
     }
     fn can_send(&self) -> bool {
         self.packet.num_chunks != 0 || self.request_resend
     }
-    fn ack_chunks(&mut self, ack: Sequence) {
+    // Returns the number of acknowledged payload bytes.
+    fn ack_chunks(&mut self, ack: Sequence) -> usize {
         let index = self.resend_queue.iter().position(|chunk| chunk.sequence == ack);
-        // FIXME(rust#27788): Replace with `truncate`.
-        index.map(|i| self.resend_queue.drain(i..));
+        if let Some(i) = index {
+            let acked: usize = self.resend_queue.drain(i..).map(|chunk| chunk.data.len()).sum();
+            self.unacked_bytes -= acked;
+            acked
+        } else {
+            0
+        }
+    }
+    // Whether the congestion window has room for a vital chunk of the given
+    // size.
+    fn window_fits(&self, len: usize) -> bool {
+        self.unacked_bytes == 0 || self.unacked_bytes + len <= self.window
     }
-    fn flush<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder)
+    fn can_send_backlog(&self) -> bool {
+        self.backlog.front().map(|c| self.window_fits(c.len())).unwrap_or(false)
+    }
+    fn flush<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder, compression: bool)
         -> Result<(), CB::Error>
     {
         if !self.can_send() {
             return Ok(());
         }
-        let result = builder.send(cb, Packet::Connected(ConnectedPacket {
+        let packet = ConnectedPacket {
             ack: self.ack.to_u16(),
             type_: ConnectedPacketType::Chunks(
                 self.request_resend,
                 self.packet.num_chunks,
                 &self.packet.data,
             ),
-        })).map_err(|e| e.unwrap_callback());
+        };
+        let result = builder.send_connected(cb, packet, compression).map(|len| {
+            self.stats.sent_payload += self.packet.data.len().u64();
+            self.stats.sent_payload_compressed += (len - HEADER_SIZE).u64();
+            self.stats.sent_packets += 1;
+            self.stats.sent_chunks += self.packet.num_chunks.u64();
+            self.stats.sent_bytes += len.u64();
+        }).map_err(|e| e.unwrap_callback());
         self.request_resend = false;
         self.packet.clear();
         self.packet_nonvital.clear();
+        self.queued_nonvital_bytes = 0;
         result
     }
 }
@@ -329,6 +714,16 @@ impl PacketContents {
         // current size + chunk header + chunk length
         self.data.len() + protocol::chunk_header_size(vital) + data.len() <= MAX_PAYLOAD
     }
+    // Appends already encoded chunks, they're dropped if they don't fit.
+    fn append(&mut self, num_chunks: u8, chunks: &[u8]) {
+        match self.num_chunks.checked_add(num_chunks) {
+            Some(n) if self.data.len() + chunks.len() <= MAX_PAYLOAD => {
+                self.num_chunks = n;
+                self.data.extend(chunks.iter().cloned());
+            }
+            _ => {},
+        }
+    }
     fn clear(&mut self) {
         *self = PacketContents::new();
     }
@@ -363,6 +758,11 @@ impl Sequence {
         self.seq = (self.seq + 1) % protocol::SEQUENCE_MODULUS;
         *self
     }
+    /// Returns how far `other` is ahead of `self`, modulo the sequence
+    /// modulus.
+    fn distance(self, other: Sequence) -> u16 {
+        (other.seq + protocol::SEQUENCE_MODULUS - self.seq) % protocol::SEQUENCE_MODULUS
+    }
     fn update(&mut self, other: Sequence) -> SequenceOrdering {
         let mut next_self = *self;
         next_self.next();
@@ -373,6 +773,10 @@ impl Sequence {
         result
     }
     /// Returns what `other` is in relation to `self`.
+    ///
+    /// Uses serial number arithmetic (RFC 1982): sequence numbers less than
+    /// half the modulus ahead are in the future, so ordering keeps working
+    /// across the wraparound.
     fn compare(self, other: Sequence) -> SequenceOrdering {
         let half = protocol::SEQUENCE_MODULUS / 2;
         let less;
@@ -389,19 +793,27 @@ impl Sequence {
     }
 }
 
-struct PacketBuilder {
+/// Scratch space for assembling outgoing packets.
+///
+/// It is only used during a single call, so one instance can be shared
+/// between all connections.
+#[derive(Clone)]
+pub struct PacketBuilder {
     compression_buffer: [u8; MAX_PACKETSIZE],
     buffer: [u8; MAX_PACKETSIZE],
+    // Connless packets can be larger than `MAX_PACKETSIZE`.
+    connless_buffer: Vec<u8>,
 }
 
 impl PacketBuilder {
-    fn new() -> PacketBuilder {
+    pub fn new() -> PacketBuilder {
         PacketBuilder {
             compression_buffer: [0; MAX_PACKETSIZE],
             buffer: [0; MAX_PACKETSIZE],
+            connless_buffer: Vec::new(),
         }
     }
-    fn send<CB: Callback>(&mut self, cb: &mut CB, packet: Packet)
+    pub fn send<CB: Callback>(&mut self, cb: &mut CB, packet: Packet)
         -> Result<(), Error<CB::Error>>
     {
         let data = match packet.write(&mut self.compression_buffer[..], &mut self.buffer[..]) {
@@ -412,6 +824,57 @@ impl PacketBuilder {
         cb.send(data)?;
         Ok(())
     }
+    /// Sends a connected packet, compressing its payload only if
+    /// `compression` is set.
+    ///
+    /// Returns the size of the sent packet.
+    pub fn send_connected<CB: Callback>(&mut self, cb: &mut CB, packet: ConnectedPacket, compression: bool)
+        -> Result<usize, Error<CB::Error>>
+    {
+        let result = if compression {
+            packet.write(&mut self.compression_buffer[..], &mut self.buffer[..])
+        } else {
+            packet.write_uncompressed(&mut self.buffer[..])
+        };
+        let data = match result {
+            Ok(d) => d,
+            Err(protocol::Error::Capacity(_)) => unreachable!("too short buffer provided"),
+            Err(protocol::Error::TooLongData) => return Err(Error::TooLongData),
+        };
+        cb.send(data)?;
+        Ok(data.len())
+    }
+    /// Sends a connless packet of at most `max_packet_size` bytes, header
+    /// included.
+    ///
+    /// Unlike connected packets, connless ones may exceed `MAX_PACKETSIZE`.
+    pub fn send_connless<CB: Callback>(&mut self, cb: &mut CB, data: &[u8], max_packet_size: usize)
+        -> Result<(), Error<CB::Error>>
+    {
+        let header_size = HEADER_SIZE + PADDING_SIZE_CONNLESS;
+        if header_size + data.len() > max_packet_size {
+            return Err(Error::TooLongData);
+        }
+        self.connless_buffer.clear();
+        self.connless_buffer.extend(iter::repeat(b'\xff').take(header_size));
+        self.connless_buffer.extend_from_slice(data);
+        cb.send(&self.connless_buffer)?;
+        Ok(())
+    }
+    /// Sends `prefix` followed by `data` as a single datagram of at most
+    /// `max_packet_size` bytes, without adding any header.
+    pub fn send_raw<CB: Callback>(&mut self, cb: &mut CB, prefix: &[u8], data: &[u8], max_packet_size: usize)
+        -> Result<(), Error<CB::Error>>
+    {
+        if prefix.len() + data.len() > max_packet_size {
+            return Err(Error::TooLongData);
+        }
+        self.connless_buffer.clear();
+        self.connless_buffer.extend_from_slice(prefix);
+        self.connless_buffer.extend_from_slice(data);
+        cb.send(&self.connless_buffer)?;
+        Ok(())
+    }
 }
 
 struct WarnCallback<'a, W: Warn<Warning>+'a> {
@@ -431,23 +894,163 @@ impl<'a, W: Warn<Warning>> Warn<protocol::Warning> for WarnCallback<'a, W> {
 }
 
 impl Connection {
+    /// Creates an unconnected connection.
+    ///
+    /// The protocol doesn't use random initial sequence numbers, both sides
+    /// start at zero, so no source of randomness is needed.
     pub fn new() -> Connection {
+        Connection::with_config(Config::default())
+    }
+    pub fn with_config(config: Config) -> Connection {
         Connection {
+            config: config,
             state: State::Unconnected,
             send: Timeout::inactive(),
-            builder: PacketBuilder::new(),
         }
     }
+    /// Resets the connection to its initial state, allowing it to be
+    /// reused.
+    ///
+    /// The packet buffers and the configuration are kept.
     pub fn reset(&mut self) {
-        assert_matches!(self.state, State::Disconnected);
-        *self = Connection::new();
+        self.state = State::Unconnected;
+        self.send = Timeout::inactive();
+    }
+    pub fn config(&self) -> Config {
+        self.config
+    }
+    /// Changes the configuration, taking effect for timeouts started
+    /// afterwards.
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+    /// Returns the number of duplicate vital chunks received while online.
+    pub fn duplicate_chunks(&self) -> u64 {
+        self.stats().duplicate_chunks
+    }
+    /// Returns the statistics, all zero if not online.
+    pub fn stats(&self) -> Stats {
+        match self.state {
+            State::Online(ref online) => online.stats,
+            _ => Stats::default(),
+        }
+    }
+    /// Returns the current congestion window in payload bytes, `None` if
+    /// `Config::congestion_control` is disabled or the connection isn't
+    /// online.
+    pub fn congestion_window(&self) -> Option<usize> {
+        match self.state {
+            State::Online(ref online) if self.config.congestion_control => Some(online.window),
+            _ => None,
+        }
+    }
+    /// Returns the vital chunks awaiting acknowledgement, oldest first.
+    ///
+    /// Meant for diagnostics, the list is built on every call.
+    pub fn pending_chunks(&self) -> Vec<PendingChunk> {
+        match self.state {
+            State::Online(ref online) =>
+                online.resend_queue.iter().rev().map(ResendChunk::pending).collect(),
+            _ => Vec::new(),
+        }
+    }
+    /// Saves the state of the connection, `None` if it isn't online.
+    ///
+    /// Flush the connection before, queued non-vital chunks are not part of
+    /// the saved state. Vital chunks held back by the congestion window are
+    /// saved as unacknowledged ones.
+    pub fn save(&self) -> Option<SavedConnection> {
+        let online = match self.state {
+            State::Online(ref online) => online,
+            _ => return None,
+        };
+        let mut unacked: Vec<_> = online.resend_queue.iter().rev().map(|chunk| SavedChunk {
+            sequence: chunk.sequence.to_u16(),
+            data: chunk.data.to_vec(),
+        }).collect();
+        // Chunks held back by the congestion window get the sequence numbers
+        // they would have been sent with.
+        let mut sequence = online.sequence;
+        for chunk in &online.backlog {
+            unacked.push(SavedChunk {
+                sequence: sequence.next().to_u16(),
+                data: chunk.to_vec(),
+            });
+        }
+        Some(SavedConnection {
+            ack: online.ack.to_u16(),
+            sequence: sequence.to_u16(),
+            unacked: unacked,
+        })
+    }
+    /// Creates an online connection from a saved state.
+    ///
+    /// The unacknowledged vital chunks are resent once the resend interval
+    /// has passed. Returns `None` if the state is invalid, i.e. a sequence
+    /// number is out of range or a chunk is too large.
+    pub fn resume<CB: Callback>(cb: &mut CB, config: Config, saved: &SavedConnection)
+        -> Option<Connection>
+    {
+        let valid_sequence = |s: u16| s < protocol::SEQUENCE_MODULUS;
+        if !valid_sequence(saved.ack) || !valid_sequence(saved.sequence)
+            || !saved.unacked.iter().all(|c| valid_sequence(c.sequence) && c.data.len() <= MAX_PAYLOAD)
+        {
+            return None;
+        }
+        let mut online = OnlineState::new();
+        online.ack = Sequence::from_u16(saved.ack);
+        online.sequence = Sequence::from_u16(saved.sequence);
+        for chunk in &saved.unacked {
+            let sequence = Sequence::from_u16(chunk.sequence);
+            online.resend_queue.push_front(ResendChunk::new(cb, sequence, &chunk.data, config.resend_interval));
+            online.unacked_bytes += chunk.data.len();
+        }
+        let mut result = Connection::with_config(config);
+        result.state = State::Online(online);
+        result.send.set(cb, config.keep_alive_interval);
+        Some(result)
+    }
+    /// Returns the number of payload bytes that still have to reach the
+    /// peer.
+    ///
+    /// These are the non-vital chunks that haven't been flushed yet and the
+    /// vital chunks that haven't been acknowledged yet, including the ones
+    /// held back by the congestion window, chunk headers not included.
+    pub fn pending_bytes(&self) -> usize {
+        match self.state {
+            State::Online(ref online) =>
+                online.queued_nonvital_bytes + online.unacked_bytes + online.backlog_bytes,
+            _ => 0,
+        }
     }
     pub fn is_unconnected(&self) -> bool {
         matches!(self.state, State::Unconnected)
     }
+    /// Returns whether an incoming connection was accepted, but the peer
+    /// hasn't completed the handshake yet.
+    pub fn is_pending(&self) -> bool {
+        matches!(self.state, State::Pending)
+    }
+    /// Returns whether the handshake has completed and chunks can be sent.
+    pub fn is_online(&self) -> bool {
+        matches!(self.state, State::Online(_))
+    }
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self.state, State::Disconnected)
+    }
+    /// Returns whether a disconnect is complete, i.e. the connection is
+    /// disconnected or the timeout of a graceful disconnect has passed.
+    pub fn is_disconnect_finished<CB: Callback>(&self, cb: &mut CB) -> bool {
+        match self.state {
+            State::Disconnecting(ref d) => d.timeout.has_triggered_level(cb),
+            State::Disconnected => true,
+            _ => false,
+        }
+    }
     pub fn needs_tick(&self) -> Timeout {
         match self.state {
             State::Unconnected | State::Disconnected => return Timeout::inactive(),
+            State::Disconnecting(ref d) => return cmp::min(self.send, d.timeout),
             _ => {},
         }
         let resends = match self.state {
@@ -457,32 +1060,64 @@ impl Connection {
         };
         cmp::min(self.send, resends)
     }
-    pub fn connect<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+    pub fn connect<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder)
+        -> Result<(), CB::Error>
+    {
         assert_matches!(self.state, State::Unconnected);
-        self.state = State::Connecting;
-        self.tick_action(cb)?;
+        self.state = State::Connecting(PacketContents::new());
+        self.tick_action(cb, builder)?;
         Ok(())
     }
-    pub fn disconnect<CB: Callback>(&mut self, cb: &mut CB, reason: &[u8])
+    pub fn disconnect<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder, reason: &[u8])
         -> Result<(), CB::Error>
     {
         if let State::Disconnected = self.state {
             assert!(false, "Can't call disconnect on an already disconnected connection");
         }
         assert!(reason.iter().all(|&b| b != 0), "reason must not contain NULs");
-        let result = self.send_control(cb, ControlPacket::Close(reason));
+        let result = self.send_control(cb, builder, ControlPacket::Close(reason));
         self.state = State::Disconnected;
         result
     }
-    fn resend<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+    /// Disconnects, resending the close packet until the peer acknowledges it
+    /// or the timeout is reached.
+    ///
+    /// Peers acknowledge by closing the connection as well. Until then, the
+    /// connection is in a disconnecting state, see `is_disconnect_finished`.
+    pub fn disconnect_graceful<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder, reason: &[u8])
+        -> Result<(), CB::Error>
+    {
+        match self.state {
+            State::Disconnecting(_) | State::Disconnected =>
+                assert!(false, "Can't call disconnect on an already disconnected connection"),
+            _ => {},
+        }
+        assert!(reason.iter().all(|&b| b != 0), "reason must not contain NULs");
+        let result = self.send_control(cb, builder, ControlPacket::Close(reason));
+        let mut timeout = Timeout::inactive();
+        timeout.set(cb, self.config.disconnect_timeout);
+        self.state = State::Disconnecting(DisconnectingState {
+            reason: reason.iter().cloned().collect(),
+            timeout: timeout,
+        });
+        self.send.set(cb, self.config.keep_alive_interval);
+        result
+    }
+    fn resend<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder)
+        -> Result<(), CB::Error>
+    {
         let online = self.state.assert_online();
         if online.resend_queue.is_empty() {
             return Ok(());
         }
+        if self.config.congestion_control {
+            online.window = cmp::max(online.window / 2, MIN_WINDOW);
+        }
         online.packet = online.packet_nonvital.clone();
         let mut i = 0;
         for chunk in &mut online.resend_queue {
-            chunk.start_timeout(cb);
+            chunk.resends += 1;
+            chunk.start_timeout(cb, self.config.resend_interval);
         }
         while i < online.resend_queue.len() {
             let can_fit;
@@ -496,31 +1131,72 @@ impl Connection {
                 }
             }
             if !can_fit {
-                self.send.set(cb, Duration::from_millis(500));
-                online.flush(cb, &mut self.builder)?;
+                self.send.set(cb, self.config.keep_alive_interval);
+                online.flush(cb, builder, self.config.compression)?;
             }
         }
         Ok(())
     }
-    pub fn flush<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
-        self.send.set(cb, Duration::from_millis(500));
-        self.state.assert_online().flush(cb, &mut self.builder)
+    pub fn flush<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder)
+        -> Result<(), CB::Error>
+    {
+        self.send.set(cb, self.config.keep_alive_interval);
+        self.send_backlog(cb, builder)?;
+        self.state.assert_online().flush(cb, builder, self.config.compression)
+    }
+    // Queues the held-back vital chunks that fit into the congestion window.
+    fn send_backlog<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder)
+        -> Result<(), CB::Error>
+    {
+        loop {
+            let chunk = {
+                let online = self.state.assert_online();
+                if !online.can_send_backlog() && self.config.congestion_control {
+                    return Ok(());
+                }
+                let chunk = match online.backlog.pop_front() {
+                    Some(c) => c,
+                    None => return Ok(()),
+                };
+                online.backlog_bytes -= chunk.len();
+                if !online.packet.can_fit_chunk(&chunk, true) {
+                    online.flush(cb, builder, self.config.compression)?;
+                }
+                chunk
+            };
+            self.queue_sequenced(cb, &chunk, true);
+        }
     }
     fn queue<CB: Callback>(&mut self, cb: &mut CB, buffer: &[u8], vital: bool) {
+        {
+            let online = self.state.assert_online();
+            let held_back = vital && self.config.congestion_control
+                && (!online.backlog.is_empty() || !online.window_fits(buffer.len()));
+            if held_back {
+                online.backlog.push_back(buffer.iter().cloned().collect());
+                online.backlog_bytes += buffer.len();
+                return;
+            }
+        }
+        self.queue_sequenced(cb, buffer, vital)
+    }
+    fn queue_sequenced<CB: Callback>(&mut self, cb: &mut CB, buffer: &[u8], vital: bool) {
         let online = self.state.assert_online();
         let vital = if vital {
             let sequence = online.sequence.next();
-            online.resend_queue.push_front(ResendChunk::new(cb, sequence, buffer));
+            online.resend_queue.push_front(ResendChunk::new(cb, sequence, buffer, self.config.resend_interval));
+            online.unacked_bytes += buffer.len();
             Some((sequence.to_u16(), false))
         } else {
             None
         };
         if vital.is_none() {
             online.packet_nonvital.write_chunk(buffer, vital);
+            online.queued_nonvital_bytes += buffer.len();
         }
         online.packet.write_chunk(buffer, vital)
     }
-    pub fn send<CB: Callback>(&mut self, cb: &mut CB, buffer: &[u8], vital: bool)
+    pub fn send<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder, buffer: &[u8], vital: bool)
         -> Result<(), Error<CB::Error>>
     {
         let result;
@@ -530,7 +1206,7 @@ impl Connection {
                 return Err(Error::TooLongData);
             }
             if !online.packet.can_fit_chunk(buffer, vital) {
-                result = online.flush(cb, &mut self.builder).map_err(Error::from);
+                result = online.flush(cb, builder, self.config.compression).map_err(Error::from);
             } else {
                 result = Ok(());
             }
@@ -538,24 +1214,26 @@ impl Connection {
         self.queue(cb, buffer, vital);
         result
     }
-    pub fn send_connless<CB: Callback>(&mut self, cb: &mut CB, data: &[u8])
+    pub fn send_connless<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder, data: &[u8])
         -> Result<(), Error<CB::Error>>
     {
         self.state.assert_online();
-        self.send.set(cb, Duration::from_millis(500));
-        self.builder.send(cb, Packet::Connless(data))
+        self.send.set(cb, self.config.keep_alive_interval);
+        builder.send(cb, Packet::Connless(data))
     }
-    fn send_control<CB: Callback>(&mut self, cb: &mut CB, control: ControlPacket) -> Result<(), CB::Error> {
+    fn send_control<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder, control: ControlPacket)
+        -> Result<(), CB::Error>
+    {
         let ack = match self.state {
             State::Online(ref mut online) => online.ack.to_u16(),
             _ => 0,
         };
-        self.builder.send(cb, Packet::Connected(ConnectedPacket {
+        builder.send(cb, Packet::Connected(ConnectedPacket {
             ack: ack,
             type_: ConnectedPacketType::Control(control),
         })).map_err(|e| e.unwrap_callback())
     }
-    pub fn tick<CB: Callback>(&mut self, cb: &mut CB)
+    pub fn tick<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder)
         -> Result<(), CB::Error>
     {
         let do_resend = match self.state {
@@ -568,43 +1246,73 @@ impl Connection {
             _ => false,
         };
         if do_resend {
-            self.resend(cb)
+            self.resend(cb, builder)
         } else if self.send.has_triggered_edge(cb) {
-            self.tick_action(cb)
+            self.tick_action(cb, builder)
         } else {
             Ok(())
         }
     }
-    fn tick_action<CB: Callback>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+    fn tick_action<CB: Callback>(&mut self, cb: &mut CB, builder: &mut PacketBuilder)
+        -> Result<(), CB::Error>
+    {
+        if let State::Disconnecting(ref d) = self.state {
+            self.send.set(cb, self.config.keep_alive_interval);
+            return builder.send(cb, Packet::Connected(ConnectedPacket {
+                ack: 0,
+                type_: ConnectedPacketType::Control(ControlPacket::Close(&d.reason)),
+            })).map_err(|e| e.unwrap_callback());
+        }
         let control = match self.state {
-            State::Connecting => ControlPacket::Connect,
+            State::Connecting(_) => ControlPacket::Connect,
             State::Pending => ControlPacket::ConnectAccept,
             State::Online(ref mut online) => {
-                if online.can_send() {
+                if online.can_send() || online.can_send_backlog() {
                     // TODO: Warn if this happens on reliable networks.
-                    self.send.set(cb, Duration::from_millis(500));
-                    return online.flush(cb, &mut self.builder);
+                    return self.flush(cb, builder);
                 }
                 ControlPacket::KeepAlive
             },
             _ => return Ok(()),
         };
-        self.send.set(cb, Duration::from_millis(500));
-        self.send_control(cb, control)
+        self.send.set(cb, self.config.keep_alive_interval);
+        self.send_control(cb, builder, control)
     }
     /// Notifies the connection of incoming data.
     ///
     /// `buffer` must have at least size `MAX_PAYLOAD`.
-    pub fn feed<'a, B, CB, W>(&mut self, cb: &mut CB, warn: &mut W, data: &'a [u8], buf: B)
+    ///
+    /// Vital chunks that have already been received, i.e. whose sequence
+    /// number lies in the past, are not returned. They are counted in
+    /// `duplicate_chunks` and cause a resend request, just like vital chunks
+    /// from the future.
+    pub fn feed<'a, B, CB, W>(&mut self, cb: &mut CB, builder: &mut PacketBuilder, warn: &mut W, data: &'a [u8], buf: B)
         -> (ReceivePacket<'a>, Result<(), CB::Error>)
         where B: Buffer<'a>,
               CB: Callback,
               W: Warn<Warning>,
     {
-        with_buffer(buf, |b| self.feed_impl(cb, warn, data, b))
+        with_buffer(buf, |b| self.feed_impl(cb, builder, warn, data, b))
+    }
+
+    pub fn feed_impl<'d, 's, CB, W>(&mut self, cb: &mut CB, builder: &mut PacketBuilder, warn: &mut W, data: &'d [u8], mut buffer: BufferRef<'d, 's>)
+        -> (ReceivePacket<'d>, Result<(), CB::Error>)
+        where CB: Callback,
+              W: Warn<Warning>,
+    {
+        let packet = match Packet::read(&mut w(warn), data, &mut buffer) {
+            Ok(p) => p,
+            Err(e) => {
+                warn.warn(Warning::Read(e));
+                return (ReceivePacket::none(), Ok(()));
+            }
+        };
+        self.feed_packet(cb, builder, warn, packet, buffer)
     }
 
-    pub fn feed_impl<'d, 's, CB, W>(&mut self, cb: &mut CB, warn: &mut W, data: &'d [u8], mut buffer: BufferRef<'d, 's>)
+    /// Like `feed`, for a packet that has already been read from `buffer`
+    /// with `Packet::read`.
+    pub fn feed_packet<'d, 's, CB, W>(&mut self, cb: &mut CB, builder: &mut PacketBuilder, warn: &mut W, packet: Packet<'d>, mut buffer: BufferRef<'d, 's>)
         -> (ReceivePacket<'d>, Result<(), CB::Error>)
         where CB: Callback,
               W: Warn<Warning>,
@@ -614,14 +1322,6 @@ impl Connection {
             use protocol::ConnectedPacketType::*;
             use protocol::ControlPacket::*;
 
-            let packet = match Packet::read(&mut w(warn), data, &mut buffer) {
-                Ok(p) => p,
-                Err(e) => {
-                    warn.warn(Warning::Read(e));
-                    return none;
-                }
-            };
-
             let connected = match packet {
                 Packet::Connless(data) => return (ReceivePacket::connless(data), Ok(())),
                 Packet::Connected(c) => c,
@@ -629,19 +1329,45 @@ impl Connection {
             let ConnectedPacket { ack, type_ } = connected;
             // TODO: Check ack for sanity.
             if let State::Online(ref mut online) = self.state {
-                online.ack_chunks(Sequence::from_u16(ack));
+                let window_limited = !online.backlog.is_empty();
+                let acked = online.ack_chunks(Sequence::from_u16(ack));
+                if self.config.congestion_control && acked != 0 {
+                    if window_limited {
+                        let increase = cmp::max(MAX_PAYLOAD * acked / online.window, 1);
+                        online.window = cmp::min(online.window + increase, MAX_WINDOW);
+                    }
+                    // Send the held-back chunks with the next tick.
+                    if online.can_send_backlog() {
+                        self.send.set(cb, Duration::from_secs(0));
+                    }
+                }
             }
 
             match type_ {
                 Chunks(request_resend, num_chunks, chunks) => {
-                    let _ = num_chunks;
-                    if let State::Pending = self.state {
-                        self.state = State::Online(OnlineState::new());
+                    if let State::Connecting(ref mut early) = self.state {
+                        if self.config.buffer_early_chunks {
+                            early.append(num_chunks, chunks);
+                            return (ReceivePacket::nothing(Progress::Data), Ok(()));
+                        }
+                        return none;
                     }
+                    let handshake = if let State::Pending = self.state {
+                        self.state = State::Online(OnlineState::new());
+                        true
+                    } else {
+                        false
+                    };
                     let result;
                     if request_resend {
                         if let State::Online(_) = self.state {
-                            result = self.resend(cb);
+                            result = self.resend(cb, builder).and_then(|()| {
+                                if self.config.fast_retransmit {
+                                    self.flush(cb, builder)
+                                } else {
+                                    Ok(())
+                                }
+                            });
                         } else {
                             result = Ok(());
                         }
@@ -650,14 +1376,38 @@ impl Connection {
                     }
                     match self.state {
                         State::Online(ref mut online) => {
-                            return (ReceivePacket::connected(warn, online, num_chunks, chunks), result);
+                            let mut packet = ReceivePacket::connected(warn, online, num_chunks, chunks);
+                            if handshake {
+                                packet.progress = Progress::Handshake;
+                            }
+                            if self.config.fast_retransmit && online.request_resend && online.stats.gap != 0 {
+                                let now = cb.time();
+                                let ack = online.ack;
+                                let interval = self.config.resend_interval;
+                                let repeated = online.fast_resend_request
+                                    .map(|(a, t)| a == ack && now.duration_since(t) < interval)
+                                    .unwrap_or(false);
+                                if !repeated {
+                                    online.fast_resend_request = Some((ack, now));
+                                    self.send.set(cb, self.config.keep_alive_interval);
+                                    let compression = self.config.compression;
+                                    let result = result.and_then(|()| online.flush(cb, builder, compression));
+                                    return (packet, result);
+                                }
+                            }
+                            return (packet, result);
                         }
                         State::Pending => unreachable!(),
                         // WARN: packet received while not online.
                         _ => return none,
                     }
                 }
-                Control(KeepAlive) => return none,
+                Control(KeepAlive) => {
+                    if let State::Online(_) = self.state {
+                        return (ReceivePacket::nothing(Progress::Ack), Ok(()));
+                    }
+                    return none;
+                }
                 Control(Connect) => {
                     if let State::Unconnected = self.state {
                         self.state = State::Pending;
@@ -667,22 +1417,69 @@ impl Connection {
                     }
                 }
                 Control(ConnectAccept) => {
-                    if let State::Connecting = self.state {
-                        self.state = State::Online(OnlineState::new());
-                        return (ReceivePacket::ready(), self.send_control(cb, ControlPacket::Accept));
-                    } else {
-                        return none;
+                    match self.state {
+                        State::Connecting(_) => {
+                            let early = match mem::replace(&mut self.state, State::Online(OnlineState::new())) {
+                                State::Connecting(early) => early,
+                                _ => unreachable!(),
+                            };
+                            let result = self.send_control(cb, builder, ControlPacket::Accept);
+                            if early.num_chunks == 0 {
+                                return (ReceivePacket::ready(), result);
+                            }
+                            // The early chunks are handed out from the part
+                            // of the buffer the connect accept didn't use.
+                            // If it was compressed, its payload might leave
+                            // too little room. The vital ones among them
+                            // haven't been acknowledged yet, so the peer
+                            // resends them.
+                            if buffer.remaining() < early.data.len() {
+                                warn.warn(Warning::EarlyChunksDropped);
+                                return (ReceivePacket::ready(), result);
+                            }
+                            buffer.write(&early.data).unwrap();
+                            let initialized = buffer.initialized();
+                            let data = &initialized[initialized.len() - early.data.len()..];
+                            let online = self.state.assert_online();
+                            return (ReceivePacket::ready_connected(warn, online, early.num_chunks, data), result);
+                        }
+                        // Our accept got lost, the peer is still resending
+                        // the connect accept.
+                        State::Online(_) => {
+                            let result = self.send_control(cb, builder, ControlPacket::Accept);
+                            return (ReceivePacket::nothing(Progress::Ack), result);
+                        }
+                        _ => return none,
+                    }
+                }
+                Control(Accept) => {
+                    // Duplicate accepts are ignored.
+                    match self.state {
+                        State::Pending => {
+                            self.state = State::Online(OnlineState::new());
+                            return (ReceivePacket::nothing(Progress::Handshake), Ok(()));
+                        }
+                        State::Online(_) => return (ReceivePacket::nothing(Progress::Ack), Ok(())),
+                        _ => return none,
                     }
                 }
-                Control(Accept) => return none,
                 Control(Close(reason)) => {
+                    if let State::Disconnecting(_) = self.state {
+                        // The peer acknowledged our close.
+                        self.state = State::Disconnected;
+                        return (ReceivePacket::nothing(Progress::Close), Ok(()));
+                    }
+                    let why = match self.state {
+                        State::Connecting(_) => DisconnectReason::Rejected,
+                        _ => DisconnectReason::Closed,
+                    };
                     self.state = State::Disconnected;
-                    return (ReceivePacket::disconnect(reason), Ok(()));
+                    return (ReceivePacket::disconnect(why, reason), Ok(()));
                 }
             }
         }
         // Fall-through from `Control(Connect)`
-        (ReceivePacket::none(), self.tick_action(cb))
+        (ReceivePacket::nothing(Progress::Handshake), self.tick_action(cb, builder))
     }
 }
 
@@ -692,16 +1489,47 @@ mod test {
     use hexdump::hexdump;
     use itertools::Itertools;
     use protocol;
+    use std::cmp;
     use std::collections::VecDeque;
+    use std::io;
+    use std::mem;
+    use std::time::Duration;
     use super::Callback;
+    use super::Config;
     use super::Connection;
+    use super::DisconnectReason;
+    use super::Error;
+    use super::INITIAL_WINDOW;
+    use super::MAX_WINDOW;
+    use super::MIN_WINDOW;
+    use super::OnlineState;
+    use super::PacketBuilder;
+    use super::PendingChunk;
+    use super::Progress;
     use super::ReceiveChunk;
     use super::Sequence;
     use super::SequenceOrdering;
+    use super::State;
+    use super::Stats;
+    use super::Warning;
     use void::ResultVoidExt;
     use void::Void;
+    use warn::Ignore;
     use warn::Panic;
 
+    struct Cb(VecDeque<Vec<u8>>, Timestamp);
+    impl Cb { fn new() -> Cb { Cb(VecDeque::new(), Timestamp::from_secs_since_epoch(0)) } }
+    impl Callback for Cb {
+        type Error = Void;
+        fn send(&mut self, data: &[u8]) -> Result<(), Void> {
+            self.0.push_back(data.to_owned());
+            Ok(())
+        }
+        fn time(&mut self) -> Timestamp {
+            self.1
+        }
+    }
+
     #[test]
     fn sequence_compare() {
         use super::SequenceOrdering::*;
@@ -724,77 +1552,692 @@ mod test {
 
     #[test]
     fn establish_connection() {
-        struct Cb(VecDeque<Vec<u8>>);
-        impl Cb { fn new() -> Cb { Cb(VecDeque::new()) } }
-        impl Callback for Cb {
-            type Error = Void;
-            fn send(&mut self, data: &[u8]) -> Result<(), Void> {
-                self.0.push_back(data.to_owned());
-                Ok(())
-            }
-            fn time(&mut self) -> Timestamp {
-                Timestamp::from_secs_since_epoch(0)
-            }
-        }
         let mut buffer = [0; protocol::MAX_PAYLOAD];
         let mut cb = Cb::new();
         let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
         println!("");
 
         let mut client = Connection::new();
         let mut server = Connection::new();
 
         // Connect
-        client.connect(cb).void_unwrap();
+        client.connect(cb, builder).void_unwrap();
         let packet = cb.0.pop_front().unwrap();
         assert!(cb.0.is_empty());
         hexdump(&packet);
         assert!(&packet == b"\x10\x00\x00\x01");
 
         // ConnectAccept
-        assert!(server.feed(cb, &mut Panic, &packet, &mut buffer[..]).0.next().is_none());
+        assert!(server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.next().is_none());
         let packet = cb.0.pop_front().unwrap();
         assert!(cb.0.is_empty());
         hexdump(&packet);
         assert!(&packet == b"\x10\x00\x00\x02");
 
         // Accept
-        assert!(client.feed(cb, &mut Panic, &packet, &mut buffer[..]).0.collect_vec()
+        assert!(client.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.collect_vec()
                 == &[ReceiveChunk::Ready]);
         let packet = cb.0.pop_front().unwrap();
         assert!(cb.0.is_empty());
         hexdump(&packet);
         assert!(&packet == b"\x10\x00\x00\x03");
 
-        assert!(server.feed(cb, &mut Panic, &packet, &mut buffer[..]).0.next().is_none());
+        assert!(server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.next().is_none());
         assert!(cb.0.is_empty());
 
         // Send
-        client.send(cb, b"\x42", true).unwrap();
+        client.send(cb, builder, b"\x42", true).unwrap();
         assert!(cb.0.is_empty());
 
         // Flush
-        client.flush(cb).void_unwrap();
+        client.flush(cb, builder).void_unwrap();
         let packet = cb.0.pop_front().unwrap();
         assert!(cb.0.is_empty());
         hexdump(&packet);
         assert!(&packet == b"\x00\x00\x01\x40\x01\x01\x42");
 
         // Receive
-        assert!(server.feed(cb, &mut Panic, &packet, &mut buffer[..]).0.collect_vec()
+        assert!(server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.collect_vec()
                 == &[ReceiveChunk::Connected(b"\x42", true)]);
         assert!(cb.0.is_empty());
 
         // Disconnect
-        server.disconnect(cb, b"42").void_unwrap();
+        server.disconnect(cb, builder, b"42").void_unwrap();
         let packet = cb.0.pop_front().unwrap();
         hexdump(&packet);
         assert!(&packet == b"\x10\x01\x00\x0442\0");
 
-        assert!(client.feed(cb, &mut Panic, &packet, &mut buffer[..]).0.collect_vec()
-                == &[ReceiveChunk::Disconnect(b"42")]);
+        assert!(client.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.collect_vec()
+                == &[ReceiveChunk::Disconnect(DisconnectReason::Closed, b"42")]);
+
+        client.reset();
+        server.reset();
+    }
+
+    #[test]
+    fn compression() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+
+        let mut client = Connection::new();
+        let mut server = Connection::with_config(Config {
+            compression: false,
+            ..Config::default()
+        });
+        client.connect(cb, builder).void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]);
+        let packet = cb.0.pop_front().unwrap();
+        client.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]);
+        let packet = cb.0.pop_front().unwrap();
+        server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]);
+        assert!(cb.0.is_empty());
+        assert_eq!(client.stats().compression_ratio(), None);
+
+        // The client compresses, the server doesn't.
+        let data = [0; 100];
+        client.send(cb, builder, &data, false).unwrap();
+        client.flush(cb, builder).void_unwrap();
+        let compressed = cb.0.pop_front().unwrap();
+        assert!(compressed[0] & 0x80 != 0);
+        assert!(client.stats().compression_ratio().unwrap() < 0.5);
+
+        server.send(cb, builder, &data, false).unwrap();
+        server.flush(cb, builder).void_unwrap();
+        let uncompressed = cb.0.pop_front().unwrap();
+        assert!(uncompressed[0] & 0x80 == 0);
+        assert_eq!(uncompressed.len(), protocol::HEADER_SIZE + 2 + data.len());
+        assert_eq!(server.stats().compression_ratio(), Some(1.0));
+
+        // Both kinds of packets are understood, regardless of the setting.
+        assert!(server.feed(cb, builder, &mut Panic, &compressed, &mut buffer[..]).0.collect_vec()
+                == &[ReceiveChunk::Connected(&data[..], false)]);
+        assert!(client.feed(cb, builder, &mut Panic, &uncompressed, &mut buffer[..]).0.collect_vec()
+                == &[ReceiveChunk::Connected(&data[..], false)]);
+        assert!(cb.0.is_empty());
+    }
+
+    #[test]
+    fn pending_chunks() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+
+        let mut client = Connection::new();
+        let mut server = Connection::new();
+        client.connect(cb, builder).void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]);
+        let packet = cb.0.pop_front().unwrap();
+        client.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]);
+        let packet = cb.0.pop_front().unwrap();
+        server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]);
+        assert!(cb.0.is_empty());
+        assert!(client.pending_chunks().is_empty());
+
+        let start = cb.1;
+        client.send(cb, builder, b"1", true).unwrap();
+        client.send(cb, builder, b"234", true).unwrap();
+        client.flush(cb, builder).void_unwrap();
+        cb.0.clear();
+        let pending = |sequence, size, resends, last_send| PendingChunk {
+            sequence: sequence,
+            size: size,
+            resends: resends,
+            last_send: last_send,
+        };
+        assert_eq!(client.pending_chunks(), [pending(1, 1, 0, start), pending(2, 3, 0, start)]);
+
+        // The packet got lost, the chunks are resent.
+        cb.1 = start + Config::default().resend_interval;
+        client.tick(cb, builder).void_unwrap();
+        assert_eq!(client.pending_chunks(), [pending(1, 1, 1, cb.1), pending(2, 3, 1, cb.1)]);
+        client.flush(cb, builder).void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        assert!(cb.0.is_empty());
+
+        // Acknowledged chunks are no longer pending.
+        server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.count();
+        server.send(cb, builder, b"ack", false).unwrap();
+        server.flush(cb, builder).void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        client.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.count();
+        assert!(client.pending_chunks().is_empty());
+    }
+
+    #[test]
+    fn early_chunks() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+        let connect_accept = b"\x10\x00\x00\x02";
+        let chunk = b"\x00\x00\x01\x40\x01\x01\x42";
+
+        // The chunk overtakes the connect accept, it's delivered after the
+        // handshake completes.
+        let mut client = Connection::new();
+        client.connect(cb, builder).void_unwrap();
+        cb.0.clear();
+        assert!(client.feed(cb, builder, &mut Panic, chunk, &mut buffer[..]).0.next().is_none());
+        assert!(cb.0.is_empty());
+        assert!(client.feed(cb, builder, &mut Panic, connect_accept, &mut buffer[..]).0.collect_vec()
+                == &[ReceiveChunk::Ready, ReceiveChunk::Connected(b"\x42", true)]);
+        assert!(&cb.0.pop_front().unwrap() == b"\x10\x00\x00\x03");
+        assert!(cb.0.is_empty());
+
+        // The resent chunk is recognized as a duplicate.
+        assert!(client.feed(cb, builder, &mut Panic, chunk, &mut buffer[..]).0.next().is_none());
+        assert_eq!(client.stats().duplicate_chunks, 1);
+
+        // Without buffering, the chunk is lost until it's resent.
+        let mut client = Connection::with_config(Config {
+            buffer_early_chunks: false,
+            ..Config::default()
+        });
+        client.connect(cb, builder).void_unwrap();
+        assert!(client.feed(cb, builder, &mut Panic, chunk, &mut buffer[..]).0.next().is_none());
+        assert!(client.feed(cb, builder, &mut Panic, connect_accept, &mut buffer[..]).0.collect_vec()
+                == &[ReceiveChunk::Ready]);
+    }
+
+    #[test]
+    fn early_chunks_compressed_connect_accept() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+        let chunk = b"\x00\x00\x01\x40\x01\x01\x42";
+
+        // A compressed connect accept whose payload fills the whole buffer.
+        let mut payload = vec![protocol::CTRLMSG_CONNECTACCEPT];
+        payload.resize(protocol::MAX_PAYLOAD, 0);
+        let mut compressed = [0; protocol::MAX_PACKETSIZE];
+        let flags = protocol::PACKETFLAG_CONTROL | protocol::PACKETFLAG_COMPRESSION;
+        let mut connect_accept = vec![flags << 4, 0, 0];
+        connect_accept.extend(protocol::compress(&payload, &mut compressed[..]).unwrap());
+        assert!(connect_accept.len() <= protocol::MAX_PACKETSIZE);
+
+        // The early chunk doesn't fit next to it and is dropped.
+        let mut client = Connection::new();
+        client.connect(cb, builder).void_unwrap();
+        assert!(client.feed(cb, builder, &mut Panic, chunk, &mut buffer[..]).0.next().is_none());
+        let mut warnings = vec![];
+        assert!(client.feed(cb, builder, &mut warnings, &connect_accept, &mut buffer[..]).0.collect_vec()
+                == &[ReceiveChunk::Ready]);
+        assert!(warnings.iter().any(|w| matches!(*w, Warning::EarlyChunksDropped)));
+        assert!(client.is_online());
+
+        // It wasn't acknowledged, the resent chunk is delivered.
+        assert!(client.feed(cb, builder, &mut Panic, chunk, &mut buffer[..]).0.collect_vec()
+                == &[ReceiveChunk::Connected(b"\x42", true)]);
+    }
+
+    #[test]
+    fn progress() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+        let keep_alive = b"\x10\x00\x00\x00";
+
+        let mut client = Connection::new();
+        let mut server = Connection::new();
+        client.connect(cb, builder).void_unwrap();
+        let connect = cb.0.pop_front().unwrap();
+        assert_eq!(server.feed(cb, builder, &mut Panic, &connect, &mut buffer[..]).0.progress(), Progress::Handshake);
+        let connect_accept = cb.0.pop_front().unwrap();
+        // Not online yet.
+        assert_eq!(server.feed(cb, builder, &mut Panic, keep_alive, &mut buffer[..]).0.progress(), Progress::Dropped);
+        assert_eq!(server.feed(cb, builder, &mut Panic, &connect, &mut buffer[..]).0.progress(), Progress::Dropped);
+        assert!(cb.0.is_empty());
+
+        assert_eq!(client.feed(cb, builder, &mut Ignore, b"\xff", &mut buffer[..]).0.progress(), Progress::Dropped);
+        let connless = b"\xff\xff\xff\xff\xff\xffconnless";
+        assert_eq!(client.feed(cb, builder, &mut Panic, connless, &mut buffer[..]).0.progress(), Progress::Connless);
+        assert_eq!(client.feed(cb, builder, &mut Panic, &connect_accept, &mut buffer[..]).0.progress(), Progress::Handshake);
+        let accept = cb.0.pop_front().unwrap();
+        // A repeated connect accept is answered again.
+        assert_eq!(client.feed(cb, builder, &mut Panic, &connect_accept, &mut buffer[..]).0.progress(), Progress::Ack);
+        assert_eq!(cb.0.pop_front().unwrap(), accept);
+        assert_eq!(server.feed(cb, builder, &mut Panic, &accept, &mut buffer[..]).0.progress(), Progress::Handshake);
+        assert_eq!(server.feed(cb, builder, &mut Panic, &accept, &mut buffer[..]).0.progress(), Progress::Ack);
+        assert!(cb.0.is_empty());
+
+        client.send(cb, builder, b"\x42", true).unwrap();
+        client.flush(cb, builder).void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        let p = server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0;
+        assert_eq!(p.progress(), Progress::Data);
+        assert!(p.collect_vec() == &[ReceiveChunk::Connected(b"\x42", true)]);
+        // Duplicates still count as data, even though they're not delivered.
+        let p = server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0;
+        assert_eq!(p.progress(), Progress::Data);
+        assert_eq!(p.count(), 0);
+        // Keep-alives carry acknowledgements, but nothing to deliver.
+        assert_eq!(client.feed(cb, builder, &mut Panic, b"\x10\x01\x00\x00", &mut buffer[..]).0.progress(), Progress::Ack);
+        assert!(client.pending_chunks().is_empty());
+        cb.0.clear();
+
+        server.disconnect_graceful(cb, builder, b"bye").void_unwrap();
+        let close = cb.0.pop_front().unwrap();
+        assert_eq!(client.feed(cb, builder, &mut Panic, &close, &mut buffer[..]).0.progress(), Progress::Close);
+        // The client's close acknowledges the server's.
+        assert_eq!(server.feed(cb, builder, &mut Panic, b"\x10\x00\x00\x04", &mut buffer[..]).0.progress(), Progress::Close);
+        assert!(server.is_disconnected());
+    }
+
+    #[test]
+    fn lost_accept() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+
+        let mut client = Connection::new();
+        let mut server = Connection::new();
+        client.connect(cb, builder).void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]);
+        let packet = cb.0.pop_front().unwrap();
+        assert!(client.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.collect_vec()
+                == &[ReceiveChunk::Ready]);
+        // The accept gets lost.
+        assert!(&cb.0.pop_front().unwrap() == b"\x10\x00\x00\x03");
+        assert!(cb.0.is_empty());
+
+        // The server resends the connect accept.
+        cb.1 = cb.1 + Duration::from_secs(1);
+        server.tick(cb, builder).void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        assert!(cb.0.is_empty());
+        assert!(&packet == b"\x10\x00\x00\x02");
+
+        // The client answers it again without another ready event.
+        assert!(client.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.next().is_none());
+        let packet = cb.0.pop_front().unwrap();
+        assert!(cb.0.is_empty());
+        assert!(&packet == b"\x10\x00\x00\x03");
+
+        // The server is online now and only sends keep-alives.
+        assert!(server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.next().is_none());
+        cb.1 = cb.1 + Duration::from_secs(1);
+        server.tick(cb, builder).void_unwrap();
+        assert!(&cb.0.pop_front().unwrap() == b"\x10\x00\x00\x00");
+        assert!(cb.0.is_empty());
+        server.send(cb, builder, b"\x42", true).unwrap();
+    }
+
+    #[test]
+    fn duplicate_accept() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+
+        let mut server = Connection::new();
+        server.feed(cb, builder, &mut Panic, b"\x10\x00\x00\x01", &mut buffer[..]);
+        cb.0.clear();
+        let chunk = b"\x00\x00\x01\x40\x01\x01\x42";
+        server.feed(cb, builder, &mut Panic, chunk, &mut buffer[..]);
+
+        // Accepts arriving late or twice don't affect the connection.
+        for _ in 0..2 {
+            assert!(server.feed(cb, builder, &mut Panic, b"\x10\x00\x00\x03", &mut buffer[..]).0.next().is_none());
+            assert!(cb.0.is_empty());
+        }
+        cb.1 = cb.1 + Duration::from_secs(1);
+        server.tick(cb, builder).void_unwrap();
+        assert!(&cb.0.pop_front().unwrap() == b"\x10\x01\x00\x00");
+        assert!(cb.0.is_empty());
+    }
+
+    #[test]
+    fn reset() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+
+        let mut client = Connection::new();
+        let mut server = Connection::new();
+        client.connect(cb, builder).void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        server.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]);
+        let packet = cb.0.pop_front().unwrap();
+        client.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]);
+        cb.0.clear();
+        client.send(cb, builder, b"\x42", true).unwrap();
 
+        // Reset in the middle of a connection.
         client.reset();
+        assert!(client.is_unconnected());
+        assert!(!client.needs_tick().is_active());
+        client.connect(cb, builder).void_unwrap();
+        assert_eq!(cb.0.pop_front().unwrap(), b"\x10\x00\x00\x01");
+        assert!(cb.0.is_empty());
+
+        // The handshake continues as with a fresh connection.
         server.reset();
+        assert!(server.feed(cb, builder, &mut Panic, b"\x10\x00\x00\x01", &mut buffer[..]).0.next().is_none());
+        assert_eq!(cb.0.pop_front().unwrap(), b"\x10\x00\x00\x02");
+        assert!(client.feed(cb, builder, &mut Panic, b"\x10\x00\x00\x02", &mut buffer[..]).0.collect_vec()
+                == &[ReceiveChunk::Ready]);
+        assert_eq!(cb.0.pop_front().unwrap(), b"\x10\x00\x00\x03");
+
+        // Sequence numbers start from the beginning again.
+        client.send(cb, builder, b"\x42", true).unwrap();
+        client.flush(cb, builder).void_unwrap();
+        assert_eq!(cb.0.pop_front().unwrap(), b"\x00\x00\x01\x40\x01\x01\x42");
+    }
+
+    #[test]
+    fn duplicate_chunks() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+
+        let mut client = Connection::new();
+        client.connect(cb, builder).void_unwrap();
+        client.feed(cb, builder, &mut Panic, b"\x10\x00\x00\x02", &mut buffer[..]);
+        cb.0.clear();
+        assert_eq!(client.duplicate_chunks(), 0);
+
+        let packet = b"\x00\x00\x01\x40\x01\x01\x42";
+        assert!(client.feed(cb, builder, &mut Panic, packet, &mut buffer[..]).0.collect_vec()
+                == &[ReceiveChunk::Connected(b"\x42", true)]);
+        assert_eq!(client.duplicate_chunks(), 0);
+
+        // The same chunk again is dropped and counted.
+        assert!(client.feed(cb, builder, &mut Panic, packet, &mut buffer[..]).0.next().is_none());
+        assert_eq!(client.duplicate_chunks(), 1);
+    }
+
+    #[test]
+    fn gap() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+
+        let mut client = Connection::new();
+        client.connect(cb, builder).void_unwrap();
+        client.feed(cb, builder, &mut Panic, b"\x10\x00\x00\x02", &mut buffer[..]);
+        cb.0.clear();
+
+        let chunk1 = b"\x00\x00\x01\x40\x01\x01\x41";
+        let chunk2 = b"\x00\x00\x01\x40\x01\x02\x42";
+        let chunk3 = b"\x00\x00\x01\x40\x01\x03\x43";
+
+        // The first chunk is withheld, the later ones are dropped.
+        assert!(client.feed(cb, builder, &mut Panic, chunk2, &mut buffer[..]).0.next().is_none());
+        assert_eq!(client.stats(), Stats { duplicate_chunks: 0, gap: 2, max_gap: 2, ..client.stats() });
+        assert!(client.feed(cb, builder, &mut Panic, chunk3, &mut buffer[..]).0.next().is_none());
+        assert_eq!(client.stats(), Stats { duplicate_chunks: 0, gap: 3, max_gap: 3, ..client.stats() });
+
+        // The gap closes as the chunks are resent.
+        assert!(client.feed(cb, builder, &mut Panic, chunk1, &mut buffer[..]).0.collect_vec()
+                == &[ReceiveChunk::Connected(b"\x41", true)]);
+        assert_eq!(client.stats().gap, 2);
+        client.feed(cb, builder, &mut Panic, chunk2, &mut buffer[..]).0.count();
+        client.feed(cb, builder, &mut Panic, chunk3, &mut buffer[..]).0.count();
+        assert_eq!(client.stats(), Stats { duplicate_chunks: 0, gap: 0, max_gap: 3, ..client.stats() });
+    }
+
+    #[test]
+    fn error_into_io() {
+        let err: Error<io::Error> = Error::TooLongData;
+        assert_eq!(err.into_io().kind(), io::ErrorKind::InvalidInput);
+        let err = Error::Callback(io::Error::new(io::ErrorKind::WouldBlock, "test"));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn no_scratch() {
+        // Besides the state, a connection must not hold a packet-sized
+        // buffer, those are shared via `PacketBuilder`.
+        let state = mem::size_of::<State>();
+        assert!(mem::size_of::<Connection>() < state + protocol::MAX_PACKETSIZE);
+    }
+
+    #[test]
+    fn sequence_update_wraparound() {
+        use super::SequenceOrdering::*;
+
+        let end = protocol::SEQUENCE_MODULUS - 1;
+        let mut seq = Sequence::from_u16(end - 1);
+        assert_eq!(seq.update(Sequence::from_u16(end)), Current);
+        assert_eq!(seq.update(Sequence::from_u16(0)), Current);
+        assert_eq!(seq, Sequence::from_u16(0));
+        assert_eq!(seq.update(Sequence::from_u16(end)), Past);
+        assert_eq!(seq.update(Sequence::from_u16(2)), Future);
+        assert_eq!(seq.update(Sequence::from_u16(1)), Current);
+        assert_eq!(seq, Sequence::from_u16(1));
+    }
+
+    #[test]
+    fn send_receive_wraparound() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+
+        // Start both sides shortly before the sequence numbers wrap.
+        let start = Sequence::from_u16(protocol::SEQUENCE_MODULUS - 3);
+        let mut sender = Connection::new();
+        let mut receiver = Connection::new();
+        let mut online = OnlineState::new();
+        online.sequence = start;
+        sender.state = State::Online(online);
+        let mut online = OnlineState::new();
+        online.ack = start;
+        receiver.state = State::Online(online);
+
+        let mut packets = vec![];
+        for &data in &[b"\x00", b"\x01", b"\x02", b"\x03", b"\x04"] {
+            sender.send(cb, builder, data, true).unwrap();
+            sender.flush(cb, builder).void_unwrap();
+            packets.push(cb.0.pop_front().unwrap());
+        }
+        assert!(cb.0.is_empty());
+
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(receiver.feed(cb, builder, &mut Panic, packet, &mut buffer[..]).0.collect_vec()
+                    == &[ReceiveChunk::Connected(&[i as u8], true)]);
+        }
+        assert!(cb.0.is_empty());
+
+        // Chunks from before the wrap are still recognized as duplicates.
+        assert!(receiver.feed(cb, builder, &mut Panic, &packets[1], &mut buffer[..]).0.next().is_none());
+        assert_eq!(receiver.duplicate_chunks(), 1);
+
+        // The acknowledgement covers all chunks, none are resent.
+        receiver.flush(cb, builder).void_unwrap();
+        let ack = cb.0.pop_front().unwrap();
+        sender.feed(cb, builder, &mut Panic, &ack, &mut buffer[..]);
+        assert!(sender.state.assert_online().resend_queue.is_empty());
+    }
+
+    #[test]
+    fn packing_stats() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+
+        let mut conn = Connection::with_config(Config {
+            compression: false,
+            ..Config::default()
+        });
+        conn.state = State::Online(OnlineState::new());
+        assert_eq!(conn.stats().chunks_per_packet(), None);
+
+        // Small chunks are coalesced into one packet.
+        for _ in 0..5 {
+            conn.send(cb, builder, b"\x42", true).unwrap();
+        }
+        conn.flush(cb, builder).void_unwrap();
+        assert_eq!(cb.0.len(), 1);
+        let stats = conn.stats();
+        assert_eq!((stats.sent_packets, stats.sent_chunks), (1, 5));
+        let first = cb.0[0].len() as u64;
+        assert_eq!(stats.sent_bytes, first);
+        assert_eq!(stats.chunks_per_packet(), Some(5.0));
+
+        // Large ones need a packet each.
+        cb.0.clear();
+        for _ in 0..3 {
+            conn.send(cb, builder, &[0; 1000], false).unwrap();
+        }
+        conn.flush(cb, builder).void_unwrap();
+        assert_eq!(cb.0.len(), 3);
+        let stats = conn.stats();
+        assert_eq!((stats.sent_packets, stats.sent_chunks), (4, 8));
+        assert_eq!(stats.sent_bytes, first + cb.0.iter().map(|p| p.len() as u64).sum::<u64>());
+        assert_eq!(stats.chunks_per_packet(), Some(2.0));
+    }
+
+    #[test]
+    fn fast_retransmit() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+
+        let config = Config {
+            fast_retransmit: true,
+            ..Config::default()
+        };
+        let mut sender = Connection::with_config(config);
+        sender.state = State::Online(OnlineState::new());
+        let mut packets = vec![];
+        for &data in &[b"\x01", b"\x02", b"\x03"] {
+            sender.send(cb, builder, data, true).unwrap();
+            sender.flush(cb, builder).void_unwrap();
+            packets.push(cb.0.pop_front().unwrap());
+        }
+
+        // Without fast retransmit, the resend request waits for the next
+        // packet.
+        let mut receiver = Connection::new();
+        receiver.state = State::Online(OnlineState::new());
+        assert!(receiver.feed(cb, builder, &mut Panic, &packets[1], &mut buffer[..]).0.next().is_none());
+        assert!(cb.0.is_empty());
+
+        // The first chunk is lost.
+        let mut receiver = Connection::with_config(config);
+        receiver.state = State::Online(OnlineState::new());
+        assert!(receiver.feed(cb, builder, &mut Panic, &packets[1], &mut buffer[..]).0.next().is_none());
+        let request = cb.0.pop_front().unwrap();
+        assert!(cb.0.is_empty());
+        assert_eq!(&request[..], &[protocol::PACKETFLAG_REQUEST_RESEND << 4, 0, 0][..]);
+        // Only one request for the same gap.
+        assert!(receiver.feed(cb, builder, &mut Panic, &packets[2], &mut buffer[..]).0.next().is_none());
+        assert!(cb.0.is_empty());
+
+        // The sender resends immediately, long before the resend interval.
+        sender.feed(cb, builder, &mut Panic, &request, &mut buffer[..]);
+        let resend = cb.0.pop_front().unwrap();
+        assert!(cb.0.is_empty());
+        assert!(receiver.feed(cb, builder, &mut Panic, &resend, &mut buffer[..]).0.collect_vec() == &[
+            ReceiveChunk::Connected(b"\x01", true),
+            ReceiveChunk::Connected(b"\x02", true),
+            ReceiveChunk::Connected(b"\x03", true),
+        ]);
+        assert_eq!(receiver.stats().gap, 0);
+
+        // Another lost chunk leads to another request.
+        sender.send(cb, builder, b"\x04", true).unwrap();
+        sender.flush(cb, builder).void_unwrap();
+        cb.0.clear();
+        sender.send(cb, builder, b"\x05", true).unwrap();
+        sender.flush(cb, builder).void_unwrap();
+        let packet = cb.0.pop_front().unwrap();
+        assert!(receiver.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.next().is_none());
+        assert_eq!(cb.0.len(), 1);
+    }
+
+    #[test]
+    fn congestion_control() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let builder = &mut PacketBuilder::new();
+
+        let mut receiver = Connection::new();
+        receiver.state = State::Online(OnlineState::new());
+        assert_eq!(receiver.congestion_window(), None);
+
+        let config = Config {
+            congestion_control: true,
+            ..Config::default()
+        };
+        let mut sender = Connection::with_config(config);
+        sender.state = State::Online(OnlineState::new());
+        assert_eq!(sender.congestion_window(), Some(INITIAL_WINDOW));
+        let chunk = [0x42; 1000];
+        for _ in 0..20 {
+            sender.send(cb, builder, &chunk, true).unwrap();
+            sender.flush(cb, builder).void_unwrap();
+        }
+        // Only five chunks fit into the initial window, the others are held
+        // back.
+        assert_eq!(cb.0.len(), 5);
+        assert_eq!(sender.pending_chunks().len(), 5);
+        assert_eq!(sender.pending_bytes(), 20 * 1000);
+        let mut received = 0;
+        for packet in cb.0.drain(..).collect_vec() {
+            received += receiver.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.count();
+        }
+
+        // Acknowledgements grow the window and make room for more chunks,
+        // sent with the next tick.
+        let ack = [0x10, 5, 0, 0];
+        sender.feed(cb, builder, &mut Panic, &ack, &mut buffer[..]);
+        let window = sender.congestion_window().unwrap();
+        assert!(window > INITIAL_WINDOW);
+        assert!(cb.0.is_empty());
+        assert!(sender.needs_tick().to_opt().unwrap() <= cb.1);
+        sender.tick(cb, builder).void_unwrap();
+        assert_eq!(sender.pending_chunks().len(), window / 1000);
+        for packet in cb.0.drain(..).collect_vec() {
+            received += receiver.feed(cb, builder, &mut Panic, &packet, &mut buffer[..]).0.count();
+        }
+        assert_eq!(received, 5 + window / 1000);
+
+        // The chunks are lost, every resend halves the window.
+        let mut expected = window;
+        for _ in 0..4 {
+            cb.1 = cb.1 + config.resend_interval;
+            sender.tick(cb, builder).void_unwrap();
+            assert!(!cb.0.is_empty());
+            cb.0.clear();
+            expected = cmp::max(expected / 2, MIN_WINDOW);
+            assert_eq!(sender.congestion_window(), Some(expected));
+        }
+        assert_eq!(expected, MIN_WINDOW);
+        // Held-back chunks are saved like sent ones.
+        let saved = sender.save().unwrap();
+        assert_eq!(saved.sequence, 20);
+        assert_eq!(saved.unacked.iter().map(|c| c.sequence).collect_vec(), (6..21).collect_vec());
+
+        // Once everything is acknowledged, the rest is sent, and the window
+        // doesn't grow beyond its maximum.
+        let mut sequence = 5 + window / 1000;
+        while sequence < 20 {
+            let ack = [0x10, sequence as u8, 0, 0];
+            sender.feed(cb, builder, &mut Panic, &ack, &mut buffer[..]);
+            sender.tick(cb, builder).void_unwrap();
+            assert!(sender.congestion_window().unwrap() <= MAX_WINDOW);
+            sequence += sender.pending_chunks().len();
+            cb.0.clear();
+        }
+        let ack = [0x10, 20, 0, 0];
+        sender.feed(cb, builder, &mut Panic, &ack, &mut buffer[..]);
+        assert_eq!(sender.pending_bytes(), 0);
     }
 }
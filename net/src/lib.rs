@@ -1,23 +1,28 @@
 #[cfg(test)] extern crate hexdump;
 #[cfg(test)] extern crate itertools;
 #[cfg(test)] #[macro_use] extern crate quickcheck;
+#[cfg(all(test, feature = "serde"))] extern crate serde_json;
 
 extern crate arrayvec;
 extern crate assert_matches;
 extern crate buffer;
 #[macro_use] extern crate common;
 extern crate huffman;
-extern crate linear_map;
+#[macro_use] extern crate log;
 #[macro_use] extern crate matches;
 extern crate optional;
+#[cfg(feature = "serde")] #[macro_use] extern crate serde;
 extern crate void;
 extern crate warn;
 
 pub mod collections;
 pub mod connection;
 pub mod net;
+pub mod prelude;
 pub mod protocol;
+pub mod punch;
 pub mod time;
+#[cfg(feature = "udp")] pub mod udp;
 
 pub use connection::Connection;
 pub use net::Net;
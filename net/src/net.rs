@@ -5,19 +5,38 @@ use arrayvec::ArrayVec;
 use buffer::Buffer;
 use buffer::BufferRef;
 use buffer::with_buffer;
+use connection::DisconnectReason;
+use connection::PacketBuilder;
+use connection::Progress;
 use connection::ReceiveChunk;
 use connection;
 use collections::PeerMap;
 use collections::peer_map;
+use log::LogLevel;
 use protocol::ConnectedPacket;
 use protocol::ConnectedPacketType;
 use protocol::ControlPacket;
 use protocol::Packet;
+use protocol::PacketHeader;
+use protocol::PacketHeaderPacked;
 use protocol;
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::fmt;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
 use std::hash::Hash;
+use std::hash::Hasher;
 use std::iter;
+use std::mem;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
 use std::ops;
+use std::time::Duration;
 use warn::Panic;
 use warn::Warn;
 
@@ -27,25 +46,127 @@ pub trait Callback<A: Address> {
     type Error;
     fn send(&mut self, addr: A, data: &[u8]) -> Result<(), Self::Error>;
     fn time(&mut self) -> Timestamp;
+    /// Returns whether the error of `send` only means that the packet can't
+    /// be sent right now, like `WouldBlock` on a non-blocking socket.
+    ///
+    /// Such packets are queued and sent again by `Net::send_blocked`.
+    fn would_block(&self, _: &Self::Error) -> bool {
+        false
+    }
 }
 
 #[derive(Debug)]
 pub enum Warning<A: Address> {
     Peer(A, PeerId, connection::Warning),
     Connless(A, connection::Warning),
+    // A close packet from an address we don't have a peer for, e.g. a late
+    // duplicate after the peer has already been removed.
+    CloseFromUnknownPeer(A),
+    // A control packet that only the other side of a connection sends, see
+    // `NetConfig::role`. Contains the `protocol::CTRLMSG_*` value.
+    ControlForOtherRole(A, u8),
+    // A connection attempt dropped because of `NetConfig::half_open_limit`.
+    HalfOpenLimit(A),
 }
 
 impl<A: Address> Warning<A> {
     pub fn addr(&self) -> A {
         match *self {
-            Warning::Peer(addr, _, _) => addr,
-            Warning::Connless(addr, _) => addr,
+            Warning::Peer(ref addr, _, _) => addr.clone(),
+            Warning::Connless(ref addr, _) => addr.clone(),
+            Warning::CloseFromUnknownPeer(ref addr) => addr.clone(),
+            Warning::ControlForOtherRole(ref addr, _) => addr.clone(),
+            Warning::HalfOpenLimit(ref addr) => addr.clone(),
+        }
+    }
+}
+
+/// Identifies a peer.
+///
+/// Peers are looked up by comparing the whole address, so it can contain
+/// more than the socket address, see `AddressWithToken`.
+///
+/// Addresses are passed around by reference internally and only cloned when
+/// they're stored or reported, so they needn't be `Copy`.
+pub trait Address: Clone + Eq + Hash + Ord { }
+impl<A: Clone + Eq + Hash + Ord> Address for A { }
+
+/// Maps IPv4-mapped IPv6 addresses like `[::ffff:1.2.3.4]:8303` to the
+/// plain IPv4 address, for use with `Net::set_address_normalization`.
+///
+/// A dual-stack socket reports IPv4 clients in the mapped form, while
+/// addresses given by the user are usually plain IPv4.
+pub fn normalize_socket_addr(addr: &SocketAddr) -> SocketAddr {
+    match *addr {
+        SocketAddr::V6(ref v6) => match v6.ip().segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                let v4 = Ipv4Addr::new((hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8);
+                SocketAddr::new(v4.into(), v6.port())
+            }
+            _ => *addr,
+        },
+        SocketAddr::V4(_) => *addr,
+    }
+}
+
+/// An address together with a connection token.
+///
+/// In 0.7, a connection is identified by the socket address and a token. Using
+/// this as the address of a `Net` keeps clients behind the same NAT apart.
+///
+/// The `Net` doesn't generate tokens, they're chosen by the caller. To keep
+/// off-path attackers from spoofing connections, they should come from a
+/// cryptographically secure random number generator.
+///
+/// Neither are tokens verified: the 0.7 token handshake isn't implemented,
+/// peers are only told apart by comparing the whole address. Policies like
+/// not requiring tokens from LAN addresses are up to the code building the
+/// addresses.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct AddressWithToken<A> {
+    pub addr: A,
+    pub token: u32,
+}
+
+/// An address that contains an IP address, for `Net::ban_subnet`.
+pub trait IpAddress: Address {
+    fn ip(&self) -> IpAddr;
+}
+
+impl IpAddress for SocketAddr {
+    fn ip(&self) -> IpAddr {
+        SocketAddr::ip(self)
+    }
+}
+
+impl<A: IpAddress> IpAddress for AddressWithToken<A> {
+    fn ip(&self) -> IpAddr {
+        self.addr.ip()
+    }
+}
+
+// Clears all but the first `prefix_len` bits of the address.
+fn mask_ip(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            assert!(prefix_len <= 32, "invalid prefix length");
+            let mask = if prefix_len == 0 { 0 } else { !0u32 << (32 - prefix_len) };
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        }
+        IpAddr::V6(v6) => {
+            assert!(prefix_len <= 128, "invalid prefix length");
+            let mask = if prefix_len == 0 { 0 } else { !0u128 << (128 - prefix_len) };
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
         }
     }
 }
 
-pub trait Address: Copy + Eq + Hash + Ord { }
-impl<A: Copy + Eq + Hash + Ord> Address for A { }
+// `subnet` must already be masked. IPv4 addresses are never part of IPv6
+// subnets and vice versa.
+fn in_subnet(ip: IpAddr, subnet: IpAddr, prefix_len: u8) -> bool {
+    ip.is_ipv4() == subnet.is_ipv4() && mask_ip(ip, prefix_len) == subnet
+}
 
 #[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct PeerId(pub u32);
@@ -70,80 +191,481 @@ impl fmt::Display for PeerId {
     }
 }
 
+/// Which side initiated a connection.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub enum Direction {
+    /// The peer connected to us, see `ChunkOrEvent::Connect`.
+    Incoming,
+    /// We connected to the peer, see `Net::connect`.
+    Outgoing,
+}
+
+/// Which side of the connections a `Net` takes, see `NetConfig::role`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Role {
+    /// Only accepts connections, `Net::try_connect` fails.
+    Server,
+    /// Only connects, connection attempts are never accepted.
+    Client,
+    Both,
+}
+
+impl Role {
+    // Returns whether a peer can legitimately send the control message to
+    // us.
+    fn receives_control(self, control: u8) -> bool {
+        match self {
+            Role::Server => control != protocol::CTRLMSG_CONNECTACCEPT,
+            Role::Client => control != protocol::CTRLMSG_CONNECT && control != protocol::CTRLMSG_ACCEPT,
+            Role::Both => true,
+        }
+    }
+}
+
+/// Why `Net::try_connect` didn't connect.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ConnectError {
+    /// The maximum number of peers has been reached, see `Net::is_full`.
+    Full,
+    /// The role is `Role::Server`.
+    ServerRole,
+}
+
+/// How `Net::send_to_addr` delivered a packet.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum SendPath {
+    /// Sent as a chunk over the established connection to the peer.
+    PeerConnected(PeerId),
+    /// Sent as a connless packet to a peer whose connection isn't
+    /// established yet or is being closed.
+    PeerConnless(PeerId),
+    /// Sent as a connless packet to an address without a peer.
+    NonPeerConnless,
+}
+
+/// Why `Net::send_connless_to_peer` didn't send the packet.
+#[derive(Debug)]
+pub enum PeerSendError<CE> {
+    /// There's no peer with the given ID.
+    NoSuchPeer,
+    /// The connection to the peer isn't online, see `Net::is_online`.
+    NotOnline,
+    /// Sending the packet failed.
+    Send(Error<CE>),
+}
+
+impl<CE> From<Error<CE>> for PeerSendError<CE> {
+    fn from(e: Error<CE>) -> PeerSendError<CE> {
+        PeerSendError::Send(e)
+    }
+}
+
+impl<CE: fmt::Display> fmt::Display for PeerSendError<CE> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PeerSendError::NoSuchPeer => f.write_str("no such peer"),
+            PeerSendError::NotOnline => f.write_str("peer not online"),
+            PeerSendError::Send(ref e) => e.fmt(f),
+        }
+    }
+}
+
+/// Priority of a chunk sent with `Net::send_with_priority`.
+///
+/// Chunks of higher priority are sequenced and sent before chunks of lower
+/// priority that were queued for the same flush.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    /// For bulk transfers like map downloads.
+    Low,
+    Normal,
+    /// For chunks that must not wait for others, like game input.
+    High,
+}
+
+/// The state of a peer, for resuming its connection in another `Net`, see
+/// `Net::save_peer`.
+///
+/// With the `serde` feature, it can be serialized if the address can. The
+/// same compatibility rules as for `connection::SavedConnection` apply.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct SavedPeer<A> {
+    pub addr: A,
+    pub direction: Direction,
+    pub connection: connection::SavedConnection,
+}
+
 const CONNECT_PACKET: &'static [u8; 4] = b"\x10\x00\x00\x01";
+const DEFAULT_FULL_REASON: &'static [u8] = b"This server is full";
 
-struct Peer<A: Address> {
+#[derive(Clone)]
+struct Peer<A: Address, T> {
     conn: Connection,
     addr: A,
+    direction: Direction,
+    connected_at: Timestamp,
+    // Last time a packet was received from the peer or a chunk was sent to
+    // it.
+    last_activity: Timestamp,
+    // Chunks sent with a priority, handed to the connection on flush:
+    // (priority, data, vital).
+    prioritized: Vec<(Priority, Vec<u8>, bool)>,
+    // Whether the packets to and from the peer are logged, see
+    // `Net::debug_dump_peer`.
+    debug_dump: bool,
+    // Whether the peer is counted in `Peers::num_half_open`.
+    half_open: bool,
+    // See `Net::set_peer_userdata`.
+    userdata: Option<T>,
 }
 
-impl<A: Address> Peer<A> {
-    fn new(addr: A) -> Peer<A> {
+impl<A: Address, T> Peer<A, T> {
+    fn new(conn: Connection, addr: A, direction: Direction, time: Timestamp) -> Peer<A, T> {
         Peer {
-            conn: Connection::new(),
+            conn: conn,
             addr: addr,
+            direction: direction,
+            connected_at: time,
+            last_activity: time,
+            prioritized: Vec::new(),
+            debug_dump: false,
+            half_open: false,
+            userdata: None,
+        }
+    }
+    fn dump(&self, pid: PeerId) -> Option<(PeerId, Direction)> {
+        if self.debug_dump { Some((pid, self.direction)) } else { None }
+    }
+    fn pending_bytes(&self) -> usize {
+        self.conn.pending_bytes() + self.prioritized.iter().map(|&(_, ref d, _)| d.len()).sum::<usize>()
+    }
+}
+
+/// Default number of connections kept for reuse after their peers have been
+/// removed.
+pub const DEFAULT_PEER_POOL_SIZE: usize = 16;
+
+/// Default time after which half-open connections are removed, see
+/// `NetConfig::half_open_timeout`.
+pub const DEFAULT_HALF_OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default number of packets queued while the callback would block.
+pub const DEFAULT_BLOCKED_QUEUE_SIZE: usize = 64;
+
+/// Default number of connless packets remembered for deduplication.
+pub const DEFAULT_CONNLESS_DEDUP_CAPACITY: usize = 1024;
+
+/// Default number of events kept in the queue of `Net::feed_to_queue`.
+pub const DEFAULT_EVENT_QUEUE_SIZE: usize = 1024;
+
+// Recently received connless packets, see `NetConfig::connless_dedup_window`.
+#[derive(Clone)]
+struct ConnlessDedup<A: Address, S = RandomState> {
+    // Payload by address and hash of the payload. The payload is compared
+    // on a hash hit, so that colliding packets aren't dropped.
+    seen: HashMap<(A, u64), Vec<u8>>,
+    // The keys of `seen` and their time of reception, oldest first.
+    order: VecDeque<((A, u64), Timestamp)>,
+    hasher: S,
+    num_deduped: u64,
+}
+
+impl<A: Address> ConnlessDedup<A> {
+    fn new() -> ConnlessDedup<A> {
+        ConnlessDedup::with_hasher(RandomState::new())
+    }
+}
+
+impl<A: Address, S: BuildHasher> ConnlessDedup<A, S> {
+    fn with_hasher(hasher: S) -> ConnlessDedup<A, S> {
+        ConnlessDedup {
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            hasher: hasher,
+            num_deduped: 0,
+        }
+    }
+    // Returns whether the same payload was received from the address less
+    // than `window` before `now`, remembering it otherwise.
+    fn is_duplicate(&mut self, window: Duration, capacity: usize, now: Timestamp, addr: &A, data: &[u8]) -> bool {
+        while self.order.front().map(|&(_, t)| now.duration_since(t) >= window).unwrap_or(false) {
+            let (key, _) = self.order.pop_front().unwrap();
+            self.seen.remove(&key);
+        }
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write(data);
+        let key = (addr.clone(), hasher.finish());
+        if let Some(seen) = self.seen.get(&key) {
+            if &seen[..] == data {
+                self.num_deduped += 1;
+                return true;
+            }
+            // A different payload with the same hash. It's let through
+            // without being remembered.
+            return false;
+        }
+        if capacity == 0 {
+            return false;
+        }
+        if self.order.len() >= capacity {
+            let (oldest, _) = self.order.pop_front().unwrap();
+            self.seen.remove(&oldest);
+        }
+        self.seen.insert(key.clone(), data.to_owned());
+        self.order.push_back((key, now));
+        false
+    }
+}
+
+// Packets that couldn't be sent because the callback would have blocked.
+#[derive(Clone)]
+struct Blocked<A: Address> {
+    packets: VecDeque<(A, Vec<u8>)>,
+    // Total size of `packets`.
+    bytes: usize,
+    capacity: usize,
+}
+
+impl<A: Address> Blocked<A> {
+    fn new(capacity: usize) -> Blocked<A> {
+        Blocked {
+            packets: VecDeque::new(),
+            bytes: 0,
+            capacity: capacity,
+        }
+    }
+    fn push(&mut self, addr: &A, data: &[u8]) {
+        // If the queue is full, the packet is lost just like on a congested
+        // network.
+        if self.packets.len() < self.capacity {
+            self.packets.push_back((addr.clone(), data.to_owned()));
+            self.bytes += data.len();
+        }
+    }
+    fn send<CB: Callback<A>>(&mut self, cb: &mut CB, addr: &A, data: &[u8])
+        -> Result<(), CB::Error>
+    {
+        // Don't overtake the packets that are already waiting.
+        if !self.packets.is_empty() {
+            self.push(addr, data);
+            return Ok(());
+        }
+        match cb.send(addr.clone(), data) {
+            Err(ref e) if cb.would_block(e) => {
+                self.push(addr, data);
+                Ok(())
+            }
+            r => r,
+        }
+    }
+    fn flush<CB: Callback<A>>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+        while let Some((addr, data)) = self.packets.pop_front() {
+            match cb.send(addr.clone(), &data) {
+                Ok(()) => self.bytes -= data.len(),
+                Err(ref e) if cb.would_block(e) => {
+                    self.packets.push_front((addr, data));
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
         }
+        Ok(())
     }
 }
 
-struct Peers<A: Address> {
-    peers: PeerMap<Peer<A>>,
+#[derive(Clone)]
+struct Peers<A: Address, T> {
+    peers: PeerMap<Peer<A, T>>,
     next_peer_id: PeerId,
+    // Connections of removed peers, already reset. Holds at most `pool_size`
+    // entries.
+    pool: Vec<Connection>,
+    pool_size: usize,
+    // Maximum number of peers, unbounded if `None`.
+    capacity: Option<usize>,
+    // Number of peers with `half_open` set.
+    num_half_open: usize,
+    // The half-open peers and the time they were added, oldest first, if
+    // they expire. Also contains peers that aren't half-open anymore, but
+    // never at the front, see `pop_stale_half_open`.
+    half_open: VecDeque<(PeerId, Timestamp)>,
+    half_open_timeout: Option<Duration>,
+    conn_config: connection::Config,
+    // See `Net::set_address_normalization`.
+    normalize: Option<fn(&A) -> A>,
 }
 
-impl<A: Address> Peers<A> {
-    fn new() -> Peers<A> {
+impl<A: Address, T> Peers<A, T> {
+    fn with_config(config: &NetConfig) -> Peers<A, T> {
         Peers {
-            peers: PeerMap::new(),
+            peers: config.capacity.map(PeerMap::with_capacity).unwrap_or_else(PeerMap::new),
             next_peer_id: PeerId(0),
+            pool: Vec::new(),
+            pool_size: config.peer_pool_size,
+            capacity: config.capacity,
+            num_half_open: 0,
+            half_open: VecDeque::new(),
+            half_open_timeout: config.half_open_timeout,
+            conn_config: config.connection,
+            normalize: None,
+        }
+    }
+    fn normalized(&self, addr: &A) -> A {
+        match self.normalize {
+            Some(normalize) => normalize(addr),
+            None => addr.clone(),
+        }
+    }
+    fn is_full(&self) -> bool {
+        self.capacity.map(|c| self.peers.len() >= c).unwrap_or(false)
+    }
+    // Stops counting the peer as half-open once its handshake is complete
+    // or it's disconnecting.
+    fn update_half_open(&mut self, pid: PeerId) {
+        if let Some(peer) = self.peers.get_mut(pid) {
+            if peer.half_open && !peer.conn.is_unconnected() && !peer.conn.is_pending() {
+                peer.half_open = false;
+                self.num_half_open -= 1;
+                self.pop_stale_half_open();
+            }
+        }
+    }
+    fn pop_stale_half_open(&mut self) {
+        while let Some(&(pid, _)) = self.half_open.front() {
+            if self.peers.get(pid).map(|p| p.half_open).unwrap_or(false) {
+                break;
+            }
+            self.half_open.pop_front();
+        }
+    }
+    // Removes the half-open peers that were added `half_open_timeout` or
+    // longer before `now`.
+    fn remove_expired_half_open(&mut self, now: Timestamp) {
+        while let Some(pid) = self.expired_half_open(now) {
+            // Also removes it from `half_open`.
+            self.remove_peer(pid);
+        }
+    }
+    // Returns the oldest half-open peer if it was added `half_open_timeout`
+    // or longer before `now`.
+    fn expired_half_open(&self, now: Timestamp) -> Option<PeerId> {
+        let timeout = match self.half_open_timeout {
+            Some(t) => t,
+            None => return None,
+        };
+        self.half_open.front()
+            .filter(|&&(_, time)| now.duration_since(time) >= timeout)
+            .map(|&(pid, _)| pid)
+    }
+    // Returns when the oldest half-open peer expires.
+    fn needs_tick_half_open(&self) -> Timeout {
+        match (self.half_open.front(), self.half_open_timeout) {
+            (Some(&(_, time)), Some(timeout)) => Timeout::active(time + timeout),
+            _ => Timeout::inactive(),
+        }
+    }
+    fn set_pool_size(&mut self, pool_size: usize) {
+        self.pool_size = pool_size;
+        self.pool.truncate(pool_size);
+    }
+    /// Returns `None` if the maximum number of peers has been reached.
+    fn new_peer(&mut self, addr: A, direction: Direction, time: Timestamp)
+        -> Option<(PeerId, &mut Peer<A, T>)>
+    {
+        if self.is_full() {
+            return None;
+        }
+        let mut conn = self.pool.pop().unwrap_or_else(Connection::new);
+        conn.set_config(self.conn_config);
+        let pid = self.insert_peer(conn, addr, direction, time)?.0;
+        let peer = self.peers.get_mut(pid).unwrap();
+        if direction == Direction::Incoming {
+            peer.half_open = true;
+            self.num_half_open += 1;
+            if self.half_open_timeout.is_some() {
+                self.half_open.push_back((pid, time));
+            }
         }
+        Some((pid, peer))
     }
-    fn new_peer(&mut self, addr: A) -> (PeerId, &mut Peer<A>) {
+    /// Returns `None` if the maximum number of peers has been reached.
+    fn insert_peer(&mut self, conn: Connection, addr: A, direction: Direction, time: Timestamp)
+        -> Option<(PeerId, &mut Peer<A, T>)>
+    {
+        if self.is_full() {
+            return None;
+        }
         // FIXME(rust-lang/rfcs#811): Work around missing non-lexical borrows.
-        let raw_self: *mut Peers<A> = self;
+        let raw_self: *mut Peers<A, T> = self;
         unsafe {
             loop {
                 let peer_id = self.next_peer_id.get_and_increment();
                 if let peer_map::Entry::Vacant(v) = (*raw_self).peers.entry(peer_id) {
-                    return (peer_id, v.insert(Peer::new(addr)));
+                    return Some((peer_id, v.insert(Peer::new(conn, addr, direction, time))));
                 }
             }
         }
     }
-    fn iter(&self) -> peer_map::Iter<Peer<A>> {
+    fn iter(&self) -> peer_map::Iter<Peer<A, T>> {
         self.peers.iter()
     }
-    fn iter_mut(&mut self) -> peer_map::IterMut<Peer<A>> {
+    fn iter_mut(&mut self) -> peer_map::IterMut<Peer<A, T>> {
         self.peers.iter_mut()
     }
     fn remove_peer(&mut self, pid: PeerId) {
-        self.peers.remove(pid)
+        let mut peer = self.peers.entry(pid).assert_occupied().remove();
+        if peer.half_open {
+            self.num_half_open -= 1;
+            self.pop_stale_half_open();
+        }
+        if self.pool.len() < self.pool_size {
+            peer.conn.reset();
+            self.pool.push(peer.conn);
+        }
+    }
+    fn remove_finished_disconnects<CB: Callback<A>>(&mut self, cb: &mut CB, blocked: &mut Blocked<A>) {
+        while let Some(pid) = self.finished_disconnect(cb, blocked) {
+            self.remove_peer(pid);
+        }
+    }
+    fn finished_disconnect<CB: Callback<A>>(&self, cb: &mut CB, blocked: &mut Blocked<A>)
+        -> Option<PeerId>
+    {
+        self.peers.iter()
+            .find(|&(pid, p)| p.conn.is_disconnect_finished(&mut cc(cb, blocked, &p.addr, p.dump(pid))))
+            .map(|(pid, _)| pid)
     }
-    fn pid_from_addr(&mut self, addr: A) -> Option<PeerId> {
+    fn pid_from_addr(&mut self, addr: &A) -> Option<PeerId> {
+        if let Some(normalize) = self.normalize {
+            let addr = normalize(addr);
+            return self.peers.iter().find(|&(_, p)| normalize(&p.addr) == addr).map(|(pid, _)| pid);
+        }
         for (pid, p) in self.peers.iter() {
-            if p.addr == addr {
+            if p.addr == *addr {
                 return Some(pid);
             }
         }
         None
     }
-    fn get(&self, pid: PeerId) -> Option<&Peer<A>> {
+    fn get(&self, pid: PeerId) -> Option<&Peer<A, T>> {
         self.peers.get(pid)
     }
-    fn get_mut(&mut self, pid: PeerId) -> Option<&mut Peer<A>> {
+    fn get_mut(&mut self, pid: PeerId) -> Option<&mut Peer<A, T>> {
         self.peers.get_mut(pid)
     }
 }
 
-impl<A: Address> ops::Index<PeerId> for Peers<A> {
-    type Output = Peer<A>;
-    fn index(&self, pid: PeerId) -> &Peer<A> {
+impl<A: Address, T> ops::Index<PeerId> for Peers<A, T> {
+    type Output = Peer<A, T>;
+    fn index(&self, pid: PeerId) -> &Peer<A, T> {
         self.get(pid).unwrap_or_else(|| panic!("invalid pid"))
     }
 }
 
-impl<A: Address> ops::IndexMut<PeerId> for Peers<A> {
-    fn index_mut(&mut self, pid: PeerId) -> &mut Peer<A> {
+impl<A: Address, T> ops::IndexMut<PeerId> for Peers<A, T> {
+    fn index_mut(&mut self, pid: PeerId) -> &mut Peer<A, T> {
         self.get_mut(pid).unwrap_or_else(|| panic!("invalid pid"))
     }
 }
@@ -154,9 +676,13 @@ impl<A: Address> ops::IndexMut<PeerId> for Peers<A> {
 pub enum ChunkOrEvent<'a, A: Address> {
     Chunk(Chunk<'a>),
     Connless(ConnlessChunk<'a, A>),
-    Connect(PeerId),
+    // Server side: A client wants to connect, it needs to be `accept`ed or
+    // `reject`ed.
+    Connect(PeerId, A),
+    // Client side: The server accepted our `connect`, data can be sent now.
     Ready(PeerId),
-    Disconnect(PeerId, &'a [u8]),
+    // The peer closed the connection, with the reason it sent.
+    Disconnect(PeerId, DisconnectReason, &'a [u8]),
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -166,6 +692,36 @@ pub struct Chunk<'a> {
     pub data: &'a [u8],
 }
 
+impl<'a> Chunk<'a> {
+    /// A chunk that is resent until the peer acknowledges it.
+    pub fn vital(pid: PeerId, data: &'a [u8]) -> Chunk<'a> {
+        Chunk {
+            pid: pid,
+            vital: true,
+            data: data,
+        }
+    }
+    /// A chunk that is sent only once, it might get lost.
+    pub fn unreliable(pid: PeerId, data: &'a [u8]) -> Chunk<'a> {
+        Chunk {
+            pid: pid,
+            vital: false,
+            data: data,
+        }
+    }
+    /// A chunk for the same peer that is sent the same way as this one.
+    ///
+    /// For answering a received chunk in kind: vital chunks with a vital
+    /// chunk, unreliable ones with an unreliable one.
+    pub fn reply<'b>(&self, data: &'b [u8]) -> Chunk<'b> {
+        Chunk {
+            pid: self.pid,
+            vital: self.vital,
+            data: data,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct ConnlessChunk<'a, A: Address> {
     pub addr: A,
@@ -173,27 +729,49 @@ pub struct ConnlessChunk<'a, A: Address> {
     pub data: &'a [u8],
 }
 
-struct ConnlessBuilder {
-    buffer: [u8; protocol::MAX_PACKETSIZE],
-}
-
-impl ConnlessBuilder {
-    fn new() -> ConnlessBuilder {
-        ConnlessBuilder {
-            buffer: [0; protocol::MAX_PACKETSIZE],
+impl<'a, A: Address> ChunkOrEvent<'a, A> {
+    /// Copies the data, so that the event can be kept around.
+    pub fn into_owned(self) -> OwnedChunkOrEvent<A> {
+        match self {
+            ChunkOrEvent::Chunk(c) => OwnedChunkOrEvent::Chunk(OwnedChunk {
+                pid: c.pid,
+                vital: c.vital,
+                data: c.data.to_owned(),
+            }),
+            ChunkOrEvent::Connless(c) => OwnedChunkOrEvent::Connless(OwnedConnlessChunk {
+                addr: c.addr,
+                pid: c.pid,
+                data: c.data.to_owned(),
+            }),
+            ChunkOrEvent::Connect(pid, addr) => OwnedChunkOrEvent::Connect(pid, addr),
+            ChunkOrEvent::Ready(pid) => OwnedChunkOrEvent::Ready(pid),
+            ChunkOrEvent::Disconnect(pid, why, reason) => OwnedChunkOrEvent::Disconnect(pid, why, reason.to_owned()),
         }
     }
-    fn send<A: Address, CB: Callback<A>>(&mut self, cb: &mut CB, addr: A, packet: Packet)
-        -> Result<(), Error<CB::Error>>
-    {
-        let send_data = match packet.write(&mut [0u8; 0][..], &mut self.buffer[..]) {
-            Ok(d) => d,
-            Err(protocol::Error::Capacity(_)) => unreachable!("too short buffer provided"),
-            Err(protocol::Error::TooLongData) => return Err(Error::TooLongData),
-        };
-        cb.send(addr, send_data)?;
-        Ok(())
-    }
+}
+
+/// Owned version of `ChunkOrEvent`, see `ChunkOrEvent::into_owned`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OwnedChunkOrEvent<A: Address> {
+    Chunk(OwnedChunk),
+    Connless(OwnedConnlessChunk<A>),
+    Connect(PeerId, A),
+    Ready(PeerId),
+    Disconnect(PeerId, DisconnectReason, Vec<u8>),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OwnedChunk {
+    pub pid: PeerId,
+    pub vital: bool,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OwnedConnlessChunk<A: Address> {
+    pub addr: A,
+    pub pid: Option<PeerId>,
+    pub data: Vec<u8>,
 }
 
 #[derive(Clone)]
@@ -209,11 +787,11 @@ impl<'a, A: Address> Iterator for ReceivePacket<'a, A> {
         use self::ReceivePacketType::Connless;
         match self.type_ {
             ReceivePacketType::None => None,
-            Connect(ref mut once) => once.next().map(|pid| ChunkOrEvent::Connect(pid)),
-            Connected(addr, pid, ref mut receive_packet) => receive_packet.next().map(|chunk| {
+            Connect(ref addr, ref mut once) => once.next().map(|pid| ChunkOrEvent::Connect(pid, addr.clone())),
+            Connected(ref addr, pid, ref mut receive_packet) => receive_packet.next().map(|chunk| {
                 match chunk {
                     ReceiveChunk::Connless(d) => ChunkOrEvent::Connless(ConnlessChunk {
-                        addr: addr,
+                        addr: addr.clone(),
                         pid: Some(pid),
                         data: d,
                     }),
@@ -223,12 +801,12 @@ impl<'a, A: Address> Iterator for ReceivePacket<'a, A> {
                         data: d,
                     }),
                     ReceiveChunk::Ready => ChunkOrEvent::Ready(pid),
-                    ReceiveChunk::Disconnect(r) => ChunkOrEvent::Disconnect(pid, r),
+                    ReceiveChunk::Disconnect(why, r) => ChunkOrEvent::Disconnect(pid, why, r),
                 }
             }),
-            Connless(addr, ref mut once) => once.next().map(|data| {
+            Connless(ref addr, ref mut once) => once.next().map(|data| {
                 ChunkOrEvent::Connless(ConnlessChunk {
-                    addr: addr,
+                    addr: addr.clone(),
                     pid: None,
                     data: data,
                 })
@@ -244,17 +822,40 @@ impl<'a, A: Address> Iterator for ReceivePacket<'a, A> {
 impl<'a, A: Address> ExactSizeIterator for ReceivePacket<'a, A> { }
 
 impl<'a, A: Address> ReceivePacket<'a, A> {
-    fn none() -> ReceivePacket<'a, A> {
+    /// Returns an iterator over the vital chunks of this packet only.
+    pub fn vital_only(self) -> VitalOnly<'a, A> {
+        VitalOnly(self)
+    }
+    /// Returns an iterator over the connection events of this packet only,
+    /// i.e. `Connect`, `Ready` and `Disconnect`.
+    pub fn events_only(self) -> EventsOnly<'a, A> {
+        EventsOnly(self)
+    }
+    /// Returns what the packet did, see `connection::ReceivePacket::progress`.
+    ///
+    /// Packets that were ignored before reaching a connection, e.g. because
+    /// of a ban, count as `Progress::Dropped`, connection attempts as
+    /// `Progress::Handshake`.
+    pub fn progress(&self) -> Progress {
+        match self.type_ {
+            ReceivePacketType::None => Progress::Dropped,
+            ReceivePacketType::Connect(..) => Progress::Handshake,
+            ReceivePacketType::Connected(_, _, ref p) => p.progress(),
+            ReceivePacketType::Connless(..) => Progress::Connless,
+        }
+    }
+    /// Returns a packet without any chunks or events.
+    pub fn none() -> ReceivePacket<'a, A> {
         ReceivePacket {
             type_: ReceivePacketType::None,
         }
     }
-    fn connect(pid: PeerId) -> ReceivePacket<'a, A> {
+    fn connect(addr: A, pid: PeerId) -> ReceivePacket<'a, A> {
         ReceivePacket {
-            type_: ReceivePacketType::Connect(iter::once(pid)),
+            type_: ReceivePacketType::Connect(addr, iter::once(pid)),
         }
     }
-    fn connected(addr: A, pid: PeerId, receive_packet: connection::ReceivePacket<'a>, net: &mut Net<A>)
+    fn connected<T>(addr: A, pid: PeerId, receive_packet: connection::ReceivePacket<'a>, net: &mut Net<A, T>)
         -> ReceivePacket<'a, A>
     {
         for chunk in receive_packet.clone() {
@@ -274,51 +875,259 @@ impl<'a, A: Address> ReceivePacket<'a, A> {
     }
 }
 
+#[derive(Clone)]
+pub struct VitalOnly<'a, A: Address>(ReceivePacket<'a, A>);
+
+impl<'a, A: Address> Iterator for VitalOnly<'a, A> {
+    type Item = Chunk<'a>;
+    fn next(&mut self) -> Option<Chunk<'a>> {
+        while let Some(c) = self.0.next() {
+            match c {
+                ChunkOrEvent::Chunk(chunk @ Chunk { vital: true, .. }) => return Some(chunk),
+                _ => {},
+            }
+        }
+        None
+    }
+}
+
+#[derive(Clone)]
+pub struct EventsOnly<'a, A: Address>(ReceivePacket<'a, A>);
+
+impl<'a, A: Address> Iterator for EventsOnly<'a, A> {
+    type Item = ChunkOrEvent<'a, A>;
+    fn next(&mut self) -> Option<ChunkOrEvent<'a, A>> {
+        while let Some(c) = self.0.next() {
+            match c {
+                ChunkOrEvent::Chunk(_) | ChunkOrEvent::Connless(_) => {},
+                e => return Some(e),
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over the peer IDs of a `Net`, see `Net::peer_ids`.
+pub struct PeerIds<'a, A: Address+'a, T: 'a = ()>(peer_map::Keys<'a, Peer<A, T>>);
+
+impl<'a, A: Address, T> Iterator for PeerIds<'a, A, T> {
+    type Item = PeerId;
+    fn next(&mut self) -> Option<PeerId> {
+        self.0.next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
 #[derive(Clone)]
 enum ReceivePacketType<'a, A: Address> {
     None,
-    Connect(iter::Once<PeerId>),
+    Connect(A, iter::Once<PeerId>),
     Connected(A, PeerId, connection::ReceivePacket<'a>),
     Connless(A, iter::Once<&'a [u8]>),
 }
 
-pub struct Net<A: Address> {
-    peers: Peers<A>,
-    builder: ConnlessBuilder,
-    accept_connections: bool,
+/// Tunables of a `Net`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NetConfig {
+    /// Whether incoming connections are reported, otherwise the connection
+    /// attempts are ignored.
+    ///
+    /// Default: `true`.
+    pub accept_connections: bool,
+    /// Maximum number of peers, unbounded if `None`. The peer storage is
+    /// allocated upfront.
+    ///
+    /// Default: `None`.
+    pub capacity: Option<usize>,
+    /// Number of connections kept around for reuse after their peers have
+    /// been removed.
+    ///
+    /// Default: `DEFAULT_PEER_POOL_SIZE`.
+    pub peer_pool_size: usize,
+    /// Reason sent to clients that are rejected because the server is full.
+    ///
+    /// Default: `"This server is full"`.
+    pub full_reason: Vec<u8>,
+    /// Maximum number of half-open connections, unbounded if `None`.
+    ///
+    /// A connection is half-open from the connect packet until the client
+    /// completes the handshake, whether it has been `accept`ed or not. While
+    /// the limit is reached, further connect packets are dropped without
+    /// allocating a peer or answering, and reported as
+    /// `Warning::HalfOpenLimit`. The clients repeat their connection
+    /// attempts, so they get in once the half-open connections have been
+    /// dealt with or have expired, see `half_open_timeout`.
+    ///
+    /// Default: `None`.
+    pub half_open_limit: Option<usize>,
+    /// Time after which half-open connections are removed, never if `None`.
+    ///
+    /// Expired connections are removed during `Net::tick` and when a connect
+    /// packet arrives, without sending anything to the peer and without an
+    /// event, like with `Net::drop_peer`. This way, connection attempts from
+    /// spoofed addresses don't occupy the `half_open_limit` for good.
+    ///
+    /// Default: `Some(DEFAULT_HALF_OPEN_TIMEOUT)`.
+    pub half_open_timeout: Option<Duration>,
+    /// Maximum number of packets queued while the callback would block, see
+    /// `Callback::would_block`. Further packets are dropped.
+    ///
+    /// Default: `DEFAULT_BLOCKED_QUEUE_SIZE`.
+    pub blocked_queue_size: usize,
+    /// Maximum size of sent connless packets, header included. Extended
+    /// connless formats like large server info responses may need more than
+    /// the connected packets, which are always limited to `MAX_PACKETSIZE`.
+    /// Received packets of either kind must fit into `MAX_PACKETSIZE`.
+    ///
+    /// Default: `MAX_PACKETSIZE`, like the reference implementation.
+    pub max_connless_packet_size: usize,
+    /// Whether the header of each fed packet is kept for analysis, see
+    /// `Net::last_packet_header`.
+    ///
+    /// Default: `false`.
+    pub record_packet_headers: bool,
+    /// Maximum number of events queued by `Net::feed_to_queue`. While the
+    /// queue is full, fed packets are dropped without being processed, as
+    /// if lost on the network.
+    ///
+    /// Default: `DEFAULT_EVENT_QUEUE_SIZE`.
+    pub event_queue_size: usize,
+    /// Time in which connless packets identical to an earlier one from the
+    /// same address are dropped, no deduplication if `None`.
+    ///
+    /// Meant for stateless responders like server info endpoints, so that
+    /// retransmitted requests aren't answered several times. Only packets
+    /// from addresses without a peer are deduplicated. The payload includes
+    /// the request token, so requests with different tokens are kept. See
+    /// also `Net::num_deduped_connless`.
+    ///
+    /// Default: `None`.
+    pub connless_dedup_window: Option<Duration>,
+    /// Maximum number of connless packets remembered for deduplication,
+    /// each together with its payload. If exceeded, the oldest ones are
+    /// forgotten first.
+    ///
+    /// Default: `DEFAULT_CONNLESS_DEDUP_CAPACITY`.
+    pub connless_dedup_capacity: usize,
+    /// Which side of the connections this `Net` takes.
+    ///
+    /// With `Role::Server` or `Role::Client`, control packets that only the
+    /// other side sends are dropped before they reach a connection and
+    /// reported as `Warning::ControlForOtherRole`. E.g. a server never
+    /// receives a connect accept unless it's spoofed or reflected.
+    ///
+    /// Default: `Role::Both`.
+    pub role: Role,
+    /// Timing parameters of the connections, see `connection::Config` for
+    /// the defaults.
+    pub connection: connection::Config,
+}
+
+impl Default for NetConfig {
+    fn default() -> NetConfig {
+        NetConfig {
+            accept_connections: true,
+            capacity: None,
+            peer_pool_size: DEFAULT_PEER_POOL_SIZE,
+            full_reason: DEFAULT_FULL_REASON.to_owned(),
+            half_open_limit: None,
+            half_open_timeout: Some(DEFAULT_HALF_OPEN_TIMEOUT),
+            blocked_queue_size: DEFAULT_BLOCKED_QUEUE_SIZE,
+            max_connless_packet_size: protocol::MAX_PACKETSIZE,
+            record_packet_headers: false,
+            event_queue_size: DEFAULT_EVENT_QUEUE_SIZE,
+            connless_dedup_window: None,
+            connless_dedup_capacity: DEFAULT_CONNLESS_DEDUP_CAPACITY,
+            role: Role::Both,
+            connection: connection::Config::default(),
+        }
+    }
+}
+
+/// A set of connections sharing one socket.
+///
+/// Cloning copies the state of all peers, the clone behaves exactly like the
+/// original when fed the same input.
+#[derive(Clone)]
+pub struct Net<A: Address, T = ()> {
+    peers: Peers<A, T>,
+    // Shared by all connections.
+    builder: PacketBuilder,
+    blocked: Blocked<A>,
+    // Addresses whose packets are dropped before parsing.
+    banned: HashSet<A>,
+    // Subnets whose packets are dropped before parsing, masked to their
+    // prefix length. `subnet_ip` is set by `ban_subnet`, it is only
+    // available for addresses containing an IP address.
+    banned_subnets: Vec<(IpAddr, u8)>,
+    subnet_ip: Option<fn(&A) -> IpAddr>,
+    // Only set if `config.record_packet_headers` is enabled.
+    last_packet_header: Option<PacketHeader>,
+    connless_dedup: ConnlessDedup<A>,
+    // Filled by `feed_to_queue` and `tick_to_queue`, emptied by `poll_event`.
+    events: VecDeque<OwnedChunkOrEvent<A>>,
+    num_dropped_packets: u64,
+    config: NetConfig,
 }
 
-struct ConnectionCallback<'a, A: Address, CB: Callback<A>+'a> {
+struct ConnectionCallback<'a, A: Address+'a, CB: Callback<A>+'a> {
     cb: &'a mut CB,
-    addr: A,
+    blocked: &'a mut Blocked<A>,
+    addr: &'a A,
+    // Set if the sent packets are logged, see `Net::debug_dump_peer`.
+    dump: Option<(PeerId, Direction)>,
 }
 
 // Create `ConnectionCallback`.
-fn cc<A: Address, CB: Callback<A>>(cb: &mut CB, addr: A) -> ConnectionCallback<A, CB> {
+fn cc<'a, A: Address, CB: Callback<A>>(
+    cb: &'a mut CB,
+    blocked: &'a mut Blocked<A>,
+    addr: &'a A,
+    dump: Option<(PeerId, Direction)>,
+) -> ConnectionCallback<'a, A, CB> {
     ConnectionCallback {
         cb: cb,
+        blocked: blocked,
         addr: addr,
+        dump: dump,
+    }
+}
+
+// Logs a packet sent to or received from a peer, see
+// `Net::debug_dump_peer`.
+fn dump_packet(pid: PeerId, direction: Direction, action: &str, data: &[u8]) {
+    if log_enabled!(LogLevel::Debug) {
+        debug!("{:?} ({:?}): {} {} bytes", pid, direction, action, data.len());
+        for (i, line) in data.chunks(16).enumerate() {
+            let hex: Vec<_> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = line.iter()
+                .map(|&b| if b' ' <= b && b <= b'~' { b as char } else { '.' })
+                .collect();
+            debug!("{:04x}: {:47} |{}|", i * 16, hex.join(" "), ascii);
+        }
     }
 }
 
 impl<'a, A: Address, W: Warn<Warning<A>>> Warn<connection::Warning> for WarnCallback<'a, A, W> {
     fn warn(&mut self, warning: connection::Warning) {
-        self.warn.warn(Warning::Connless(self.addr, warning))
+        self.warn.warn(Warning::Connless(self.addr.clone(), warning))
     }
 }
 
 impl<'a, A: Address, W: Warn<Warning<A>>> Warn<protocol::Warning> for WarnCallback<'a, A, W> {
     fn warn(&mut self, warning: protocol::Warning) {
-        self.warn.warn(Warning::Connless(self.addr, connection::Warning::Packet(warning)))
+        self.warn.warn(Warning::Connless(self.addr.clone(), connection::Warning::Packet(warning)))
     }
 }
 
-struct WarnCallback<'a, A: Address, W: Warn<Warning<A>>+'a> {
+struct WarnCallback<'a, A: Address+'a, W: Warn<Warning<A>>+'a> {
     warn: &'a mut W,
-    addr: A,
+    addr: &'a A,
 }
 
-fn w<A: Address, W: Warn<Warning<A>>>(warn: &mut W, addr: A) -> WarnCallback<A, W> {
+fn w<'a, A: Address, W: Warn<Warning<A>>>(warn: &'a mut W, addr: &'a A) -> WarnCallback<'a, A, W> {
     WarnCallback {
         warn: warn,
         addr: addr,
@@ -327,18 +1136,24 @@ fn w<A: Address, W: Warn<Warning<A>>>(warn: &mut W, addr: A) -> WarnCallback<A,
 
 impl<'a, A: Address, W: Warn<Warning<A>>> Warn<connection::Warning> for WarnPeerCallback<'a, A, W> {
     fn warn(&mut self, warning: connection::Warning) {
-        self.warn.warn(Warning::Peer(self.addr, self.pid, warning))
+        self.warn.warn(Warning::Peer(self.addr.clone(), self.pid, warning))
     }
 }
 
-struct WarnPeerCallback<'a, A: Address, W: Warn<Warning<A>>+'a> {
+impl<'a, A: Address, W: Warn<Warning<A>>> Warn<protocol::Warning> for WarnPeerCallback<'a, A, W> {
+    fn warn(&mut self, warning: protocol::Warning) {
+        self.warn.warn(Warning::Peer(self.addr.clone(), self.pid, connection::Warning::Packet(warning)))
+    }
+}
+
+struct WarnPeerCallback<'a, A: Address+'a, W: Warn<Warning<A>>+'a> {
     warn: &'a mut W,
-    addr: A,
+    addr: &'a A,
     pid: PeerId,
 }
 
-fn wp<A: Address, W: Warn<Warning<A>>>(warn: &mut W, addr: A, pid: PeerId)
-    -> WarnPeerCallback<A, W>
+fn wp<'a, A: Address, W: Warn<Warning<A>>>(warn: &'a mut W, addr: &'a A, pid: PeerId)
+    -> WarnPeerCallback<'a, A, W>
 {
     WarnPeerCallback {
         warn: warn,
@@ -350,7 +1165,10 @@ fn wp<A: Address, W: Warn<Warning<A>>>(warn: &mut W, addr: A, pid: PeerId)
 impl<'a, A: Address, CB: Callback<A>> connection::Callback for ConnectionCallback<'a, A, CB> {
     type Error = CB::Error;
     fn send(&mut self, data: &[u8]) -> Result<(), CB::Error> {
-        self.cb.send(self.addr, data)
+        if let Some((pid, direction)) = self.dump {
+            dump_packet(pid, direction, "sent", data);
+        }
+        self.blocked.send(self.cb, self.addr, data)
     }
     fn time(&mut self) -> Timestamp {
         self.cb.time()
@@ -358,21 +1176,143 @@ impl<'a, A: Address, CB: Callback<A>> connection::Callback for ConnectionCallbac
 }
 
 impl<A: Address> Net<A> {
-    fn new(accept_connections: bool) -> Net<A> {
-        Net {
-            peers: Peers::new(),
-            builder: ConnlessBuilder::new(),
-            accept_connections: accept_connections,
-        }
+    pub fn with_config(config: NetConfig) -> Net<A> {
+        Net::with_userdata(config)
     }
+    /// Creates a `Net` that accepts connections, but doesn't connect
+    /// itself, see `Role::Server`.
     pub fn server() -> Net<A> {
-        Net::new(true)
+        Net::with_config(NetConfig {
+            role: Role::Server,
+            ..NetConfig::default()
+        })
     }
+    /// Creates a `Net` that connects, but doesn't accept connections, see
+    /// `Role::Client`.
     pub fn client() -> Net<A> {
-        Net::new(false)
+        Net::with_config(NetConfig {
+            accept_connections: false,
+            role: Role::Client,
+            ..NetConfig::default()
+        })
+    }
+    /// Creates a `Net` that both accepts connections and connects, like
+    /// `with_config` with the default configuration.
+    pub fn both() -> Net<A> {
+        Net::with_config(NetConfig::default())
+    }
+    /// Creates a server that holds at most `capacity` peers.
+    ///
+    /// The peer storage is allocated upfront. Connection attempts beyond the
+    /// capacity are answered with a close packet, see `set_full_reason`.
+    pub fn with_capacity(capacity: usize) -> Net<A> {
+        Net::with_config(NetConfig {
+            capacity: Some(capacity),
+            ..NetConfig::default()
+        })
+    }
+}
+
+impl<A: IpAddress, T> Net<A, T> {
+    /// Drops all packets from IP addresses in the subnet, regardless of the
+    /// port, see `ban_address`.
+    ///
+    /// `prefix_len` is the number of leading bits of `ip` that are compared,
+    /// e.g. `32` bans a single IPv4 address and `24` the range
+    /// `1.2.3.0`–`1.2.3.255` for `ip` `1.2.3.0`. Panics if it is larger than
+    /// the length of the address.
+    ///
+    /// The peers in the subnet are removed without sending anything to
+    /// them, their IDs are returned.
+    pub fn ban_subnet(&mut self, ip: IpAddr, prefix_len: u8) -> Vec<PeerId> {
+        let subnet = (mask_ip(ip, prefix_len), prefix_len);
+        if !self.banned_subnets.contains(&subnet) {
+            self.banned_subnets.push(subnet);
+        }
+        self.subnet_ip = Some(A::ip);
+        let pids: Vec<_> = self.peers.iter()
+            .filter(|&(_, p)| in_subnet(self.peers.normalized(&p.addr).ip(), subnet.0, subnet.1))
+            .map(|(pid, _)| pid)
+            .collect();
+        for &pid in &pids {
+            self.peers.remove_peer(pid);
+        }
+        pids
+    }
+    /// Lets packets from the subnet through again. Returns `false` if it
+    /// wasn't banned with the same prefix length.
+    pub fn unban_subnet(&mut self, ip: IpAddr, prefix_len: u8) -> bool {
+        let subnet = (mask_ip(ip, prefix_len), prefix_len);
+        let len = self.banned_subnets.len();
+        self.banned_subnets.retain(|&s| s != subnet);
+        self.banned_subnets.len() != len
+    }
+}
+
+impl<A: Address, T> Net<A, T> {
+    /// Like `with_config`, for a `Net` whose peers can carry data of type
+    /// `T`, see `set_peer_userdata`.
+    pub fn with_userdata(config: NetConfig) -> Net<A, T> {
+        assert!(config.full_reason.iter().all(|&b| b != 0), "reason must not contain NULs");
+        Net {
+            peers: Peers::with_config(&config),
+            builder: PacketBuilder::new(),
+            blocked: Blocked::new(config.blocked_queue_size),
+            banned: HashSet::new(),
+            banned_subnets: Vec::new(),
+            subnet_ip: None,
+            last_packet_header: None,
+            connless_dedup: ConnlessDedup::new(),
+            events: VecDeque::new(),
+            num_dropped_packets: 0,
+            config: config,
+        }
+    }
+    pub fn config(&self) -> &NetConfig {
+        &self.config
+    }
+    /// Sets the reason sent to clients that are rejected because the server
+    /// is full.
+    pub fn set_full_reason(&mut self, reason: &[u8]) {
+        assert!(reason.iter().all(|&b| b != 0), "reason must not contain NULs");
+        self.config.full_reason = reason.to_owned();
+    }
+    /// Returns whether the maximum number of peers has been reached.
+    pub fn is_full(&self) -> bool {
+        self.peers.is_full()
+    }
+    /// Sets the maximum number of half-open connections, see
+    /// `NetConfig::half_open_limit`.
+    ///
+    /// Lowering the limit doesn't affect the connections that are already
+    /// half-open.
+    pub fn set_half_open_limit(&mut self, limit: Option<usize>) {
+        self.config.half_open_limit = limit;
+    }
+    /// Returns the number of incoming connections that haven't completed the
+    /// handshake yet.
+    ///
+    /// Connection attempts that never complete, e.g. from spoofed
+    /// addresses, are removed after `NetConfig::half_open_timeout`.
+    pub fn num_half_open(&self) -> usize {
+        self.peers.num_half_open
+    }
+    /// Sets the number of connections kept around for reuse after their
+    /// peers have been removed, `DEFAULT_PEER_POOL_SIZE` by default.
+    pub fn set_peer_pool_size(&mut self, size: usize) {
+        self.config.peer_pool_size = size;
+        self.peers.set_pool_size(size);
+    }
+    /// Returns the IDs of all peers, in ascending order.
+    ///
+    /// The order only depends on the peer IDs, so iterating over them to
+    /// e.g. broadcast a chunk sends the packets in the same order every time.
+    pub fn peer_ids<'a>(&'a self) -> PeerIds<'a, A, T> {
+        PeerIds(self.peers.peers.keys())
     }
     pub fn needs_tick(&self) -> Timeout {
-        self.peers.iter().map(|(_, p)| p.conn.needs_tick()).min().unwrap_or_default()
+        let half_open = self.peers.needs_tick_half_open();
+        self.peers.iter().map(|(_, p)| p.conn.needs_tick()).fold(half_open, cmp::min)
     }
     pub fn is_receive_chunk_still_valid(&self, chunk: &mut ChunkOrEvent<A>) -> bool {
         if let ChunkOrEvent::Chunk(Chunk { pid, .. }) = *chunk {
@@ -381,11 +1321,31 @@ impl<A: Address> Net<A> {
             true
         }
     }
+    /// Connects to the given address.
+    ///
+    /// Panics if the maximum number of peers has been reached, see `is_full`,
+    /// or if the role is `Role::Server`. See `try_connect` for a version
+    /// that returns these as errors.
     pub fn connect<CB: Callback<A>>(&mut self, cb: &mut CB, addr: A)
         -> (PeerId, Result<(), CB::Error>)
     {
-        let (pid, peer) = self.peers.new_peer(addr);
-        (pid, peer.conn.connect(&mut cc(cb, peer.addr)))
+        match self.try_connect(cb, addr) {
+            Ok(result) => result,
+            Err(ConnectError::Full) => panic!("too many peers"),
+            Err(ConnectError::ServerRole) => panic!("servers don't connect"),
+        }
+    }
+    /// Connects to the given address, unless the maximum number of peers has
+    /// been reached or the role is `Role::Server`.
+    pub fn try_connect<CB: Callback<A>>(&mut self, cb: &mut CB, addr: A)
+        -> Result<(PeerId, Result<(), CB::Error>), ConnectError>
+    {
+        if self.config.role == Role::Server {
+            return Err(ConnectError::ServerRole);
+        }
+        let time = cb.time();
+        let (pid, peer) = self.peers.new_peer(addr, Direction::Outgoing, time).ok_or(ConnectError::Full)?;
+        Ok((pid, peer.conn.connect(&mut cc(cb, &mut self.blocked, &peer.addr, peer.dump(pid)), &mut self.builder)))
     }
     pub fn disconnect<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId, reason: &[u8])
         -> Result<(), CB::Error>
@@ -394,39 +1354,430 @@ impl<A: Address> Net<A> {
         {
             let peer = &mut self.peers[pid];
             assert!(!peer.conn.is_unconnected());
-            result = peer.conn.disconnect(&mut cc(cb, peer.addr), reason);
+            result = peer.conn.disconnect(&mut cc(cb, &mut self.blocked, &peer.addr, peer.dump(pid)), &mut self.builder, reason);
         }
         self.peers.remove_peer(pid);
         result
     }
+    /// Disconnects from the peer, resending the close packet until the peer
+    /// acknowledges it or a timeout is reached.
+    ///
+    /// Unlike `disconnect`, the peer is only removed afterwards, during
+    /// `feed` or `tick`. No further events are reported for it.
+    pub fn disconnect_graceful<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId, reason: &[u8])
+        -> Result<(), CB::Error>
+    {
+        let result = {
+            let peer = &mut self.peers[pid];
+            assert!(!peer.conn.is_unconnected());
+            peer.conn.disconnect_graceful(&mut cc(cb, &mut self.blocked, &peer.addr, peer.dump(pid)), &mut self.builder, reason)
+        };
+        self.peers.update_half_open(pid);
+        result
+    }
+    /// Sends a connless packet to `addr`.
+    ///
+    /// The peers aren't looked up, the packet is sent as is even if `addr`
+    /// belongs to a connected peer. This makes it suitable for answering
+    /// large amounts of connless requests such as server info queries.
+    ///
+    /// Returns `TooLongData` if the packet would exceed
+    /// `NetConfig::max_connless_packet_size`.
     pub fn send_connless<CB: Callback<A>>(&mut self, cb: &mut CB, addr: A, data: &[u8])
         -> Result<(), Error<CB::Error>>
     {
-        self.builder.send(cb, addr, Packet::Connless(data))
+        let max_size = self.config.max_connless_packet_size;
+        self.builder.send_connless(&mut cc(cb, &mut self.blocked, &addr, None), data, max_size)
     }
-    pub fn send<CB: Callback<A>>(&mut self, cb: &mut CB, chunk: Chunk)
+    /// Sends `prefix` followed by `data` as a single datagram.
+    ///
+    /// Unlike `send_connless`, this doesn't add the connless packet header,
+    /// so `prefix` is expected to contain it. This allows implementing
+    /// connless message families with their own header bytes.
+    ///
+    /// Like `send_connless`, returns `TooLongData` if the datagram would
+    /// exceed `NetConfig::max_connless_packet_size`.
+    pub fn send_connless_raw<CB: Callback<A>>(&mut self, cb: &mut CB, addr: A, prefix: &[u8], data: &[u8])
         -> Result<(), Error<CB::Error>>
     {
-        let peer = &mut self.peers[chunk.pid];
-        peer.conn.send(&mut cc(cb, peer.addr), chunk.data, chunk.vital)
+        let max_size = self.config.max_connless_packet_size;
+        self.builder.send_raw(&mut cc(cb, &mut self.blocked, &addr, None), prefix, data, max_size)
     }
-    pub fn flush<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId)
-        -> Result<(), CB::Error>
+    /// Sends a connless packet over the connection of the given peer.
+    ///
+    /// Unlike `send_connless`, this counts as activity on the connection,
+    /// postponing its next keep-alive packet.
+    ///
+    /// Returns `NoSuchPeer` if the peer doesn't exist and `NotOnline` if
+    /// its connection isn't established.
+    pub fn send_connless_to_peer<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId, data: &[u8])
+        -> Result<(), PeerSendError<CB::Error>>
     {
-        let peer = &mut self.peers[pid];
-        peer.conn.flush(&mut cc(cb, peer.addr))
+        let peer = self.peers.get_mut(pid).ok_or(PeerSendError::NoSuchPeer)?;
+        if !peer.conn.is_online() {
+            return Err(PeerSendError::NotOnline);
+        }
+        peer.last_activity = cb.time();
+        peer.conn.send_connless(&mut cc(cb, &mut self.blocked, &peer.addr, peer.dump(pid)), &mut self.builder, data)?;
+        Ok(())
     }
-    pub fn ignore(&mut self, pid: PeerId) {
-        self.peers.remove_peer(pid);
+    pub fn send<CB: Callback<A>>(&mut self, cb: &mut CB, chunk: Chunk)
+        -> Result<(), Error<CB::Error>>
+    {
+        let peer = &mut self.peers[chunk.pid];
+        peer.last_activity = cb.time();
+        peer.conn.send(&mut cc(cb, &mut self.blocked, &peer.addr, peer.dump(chunk.pid)), &mut self.builder, chunk.data, chunk.vital)
     }
-    pub fn accept<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId)
-        -> Result<(), CB::Error>
+    /// Queues a chunk for the peer, to be sent on the next `flush`.
+    ///
+    /// Chunks of a higher priority are sent before those of a lower one,
+    /// chunks of the same priority in the order they were queued. The
+    /// priorities act as independent streams: vital chunks arrive in order
+    /// relative to the other vital chunks of their priority, but not
+    /// necessarily relative to other priorities. Chunks sent with `send`
+    /// don't wait for `flush` and thus go out before all of them.
+    ///
+    /// Returns `TooLongData` if the chunk is larger than
+    /// `protocol::MAX_PAYLOAD`. Panics if the peer isn't online.
+    pub fn send_with_priority<CB: Callback<A>>(&mut self, cb: &mut CB, chunk: Chunk, priority: Priority)
+        -> Result<(), Error<CB::Error>>
     {
-        let peer = &mut self.peers[pid];
+        let peer = &mut self.peers[chunk.pid];
+        assert!(peer.conn.is_online(), "peer not online");
+        if chunk.data.len() > protocol::MAX_PAYLOAD {
+            return Err(Error::TooLongData);
+        }
+        peer.last_activity = cb.time();
+        peer.prioritized.push((priority, chunk.data.to_vec(), chunk.vital));
+        Ok(())
+    }
+    /// Sends `data` to `addr`, over the connection if it belongs to an
+    /// online peer and as a connless packet otherwise.
+    ///
+    /// `vital` only applies to chunks sent over a connection. The returned
+    /// `SendPath` tells which way was taken.
+    pub fn send_to_addr<CB: Callback<A>>(&mut self, cb: &mut CB, addr: A, data: &[u8], vital: bool)
+        -> Result<SendPath, Error<CB::Error>>
+    {
+        match self.peers.pid_from_addr(&addr) {
+            Some(pid) if self.peers[pid].conn.is_online() => {
+                self.send(cb, Chunk { pid: pid, vital: vital, data: data })?;
+                Ok(SendPath::PeerConnected(pid))
+            }
+            Some(pid) => {
+                self.send_connless(cb, addr, data)?;
+                Ok(SendPath::PeerConnless(pid))
+            }
+            None => {
+                self.send_connless(cb, addr, data)?;
+                Ok(SendPath::NonPeerConnless)
+            }
+        }
+    }
+    /// Queues a vital chunk for the given peer.
+    ///
+    /// Shorthand for `send` with `vital` set to `true`.
+    pub fn send_vital<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId, data: &[u8])
+        -> Result<(), Error<CB::Error>>
+    {
+        self.send(cb, Chunk::vital(pid, data))
+    }
+    /// Queues a non-vital chunk for the given peer.
+    ///
+    /// Shorthand for `send` with `vital` set to `false`.
+    pub fn send_unreliable<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId, data: &[u8])
+        -> Result<(), Error<CB::Error>>
+    {
+        self.send(cb, Chunk::unreliable(pid, data))
+    }
+    /// Enables or disables the compression of packets sent to the peer.
+    ///
+    /// Packets received from the peer are decompressed either way.
+    pub fn set_compression(&mut self, pid: PeerId, compression: bool) {
+        let conn = &mut self.peers[pid].conn;
+        let mut config = conn.config();
+        config.compression = compression;
+        conn.set_config(config);
+    }
+    /// Enables or disables logging the packets sent to and received from
+    /// the peer.
+    ///
+    /// The packets are hex-dumped at the debug level of the `log` crate,
+    /// together with the peer ID and the direction of the connection. Off
+    /// by default.
+    pub fn debug_dump_peer(&mut self, pid: PeerId, enabled: bool) {
+        self.peers[pid].debug_dump = enabled;
+    }
+    /// Attaches data to the peer, returning the data attached before.
+    ///
+    /// The data is dropped together with the peer. Note that peers that
+    /// disconnect are already removed when their `Disconnect` event is
+    /// returned, use `take_peer_userdata` before disconnecting them to keep
+    /// it.
+    pub fn set_peer_userdata(&mut self, pid: PeerId, userdata: T) -> Option<T> {
+        self.peers[pid].userdata.replace(userdata)
+    }
+    /// Returns the data attached to the peer, see `set_peer_userdata`.
+    pub fn peer_userdata(&self, pid: PeerId) -> Option<&T> {
+        self.peers[pid].userdata.as_ref()
+    }
+    pub fn peer_userdata_mut(&mut self, pid: PeerId) -> Option<&mut T> {
+        self.peers[pid].userdata.as_mut()
+    }
+    /// Removes the data attached to the peer and returns it.
+    pub fn take_peer_userdata(&mut self, pid: PeerId) -> Option<T> {
+        self.peers[pid].userdata.take()
+    }
+    /// Returns the number of duplicate vital chunks received from the peer.
+    pub fn duplicate_chunks(&self, pid: PeerId) -> u64 {
+        self.peers[pid].conn.duplicate_chunks()
+    }
+    /// Returns the statistics of the peer, see `connection::Stats`.
+    pub fn stats(&self, pid: PeerId) -> connection::Stats {
+        self.peers[pid].conn.stats()
+    }
+    /// Returns the congestion window of the peer in payload bytes, see
+    /// `connection::Config::congestion_control`.
+    pub fn congestion_window(&self, pid: PeerId) -> Option<usize> {
+        self.peers[pid].conn.congestion_window()
+    }
+    /// Returns whether the peer exists, i.e. it hasn't disconnected or been
+    /// removed yet.
+    pub fn has_peer(&self, pid: PeerId) -> bool {
+        self.peers.get(pid).is_some()
+    }
+    /// Returns whether the handshake with the peer has completed, i.e.
+    /// chunks can be sent to it.
+    ///
+    /// Incoming peers become online once a packet from them arrives after
+    /// `accept`, there's no event for this.
+    pub fn is_online(&self, pid: PeerId) -> bool {
+        self.peers[pid].conn.is_online()
+    }
+    /// Returns whether the connection to the peer was initiated by us or by
+    /// the peer.
+    pub fn peer_direction(&self, pid: PeerId) -> Direction {
+        self.peers[pid].direction
+    }
+    /// Returns how long the peer has existed at the time `now`.
+    ///
+    /// Counts from the connection attempt, i.e. `connect` or the arrival of
+    /// the peer's connect packet, or from `resume_peer`.
+    pub fn peer_uptime(&self, pid: PeerId, now: Timestamp) -> Duration {
+        now.duration_since(self.peers[pid].connected_at)
+    }
+    /// Returns the last time a connected packet was received from the peer or
+    /// a chunk was queued for it.
+    ///
+    /// Keep-alives and resends don't count as activity on our side, but
+    /// packets from the peer do, including its keep-alives. Packets that
+    /// can't be parsed don't count.
+    pub fn peer_last_activity(&self, pid: PeerId) -> Timestamp {
+        self.peers[pid].last_activity
+    }
+    /// Returns the number of payload bytes queued for the peer or sent but
+    /// not acknowledged yet, see `Connection::pending_bytes`.
+    pub fn peer_pending_bytes(&self, pid: PeerId) -> usize {
+        self.peers[pid].pending_bytes()
+    }
+    /// Returns the number of bytes that still have to be sent or
+    /// acknowledged, summed over all peers.
+    ///
+    /// Includes the packets waiting for `send_blocked`. A game loop can use
+    /// this to produce less data while the network can't keep up.
+    pub fn total_pending_bytes(&self) -> usize {
+        self.peers.iter().map(|(_, p)| p.pending_bytes()).sum::<usize>() + self.blocked.bytes
+    }
+    /// Saves the state of the peer's connection, `None` if it isn't online.
+    ///
+    /// Together with `resume_peer`, this allows handing connections over to
+    /// another `Net`, e.g. in a restarted process. See
+    /// `Connection::save`, and `hand_off_peer` for doing so while the peer
+    /// is still sending.
+    pub fn save_peer(&self, pid: PeerId) -> Option<SavedPeer<A>> {
+        let peer = &self.peers[pid];
+        peer.conn.save().map(|connection| SavedPeer {
+            addr: peer.addr.clone(),
+            direction: peer.direction,
+            connection: connection,
+        })
+    }
+    /// Adds a peer with a saved connection, returning its new ID.
+    ///
+    /// The peer ID is not kept, it is assigned anew. Returns `None` if the
+    /// maximum number of peers has been reached, a peer with the same
+    /// address exists already or the saved state is invalid.
+    pub fn resume_peer<CB: Callback<A>>(&mut self, cb: &mut CB, saved: &SavedPeer<A>) -> Option<PeerId> {
+        if self.peers.is_full() || self.peers.pid_from_addr(&saved.addr).is_some() {
+            return None;
+        }
+        let conn = {
+            let blocked = &mut self.blocked;
+            Connection::resume(&mut cc(cb, blocked, &saved.addr, None), self.peers.conn_config, &saved.connection)?
+        };
+        let time = cb.time();
+        self.peers.insert_peer(conn, saved.addr.clone(), saved.direction, time).map(|(pid, _)| pid)
+    }
+    /// Flushes the peer, saves its state and removes it, for resuming its
+    /// connection in another `Net` with `resume_peer`.
+    ///
+    /// Returns `None` and keeps the peer if it isn't online. On a callback
+    /// error, the peer is kept as well and the handoff can be retried. No
+    /// close packet is sent, the peer doesn't notice the handoff.
+    ///
+    /// Doing this in one call makes sure that the saved state contains
+    /// everything that was queued for the peer and that nothing received
+    /// afterwards is acknowledged by this `Net`. Packets from the peer that
+    /// still arrive here until the traffic reaches the new `Net` are not
+    /// attributed to the connection anymore and should be dropped: the vital
+    /// chunks among them are resent by the peer since they weren't
+    /// acknowledged, non-vital ones are lost. Likewise, vital chunks sent
+    /// but lost before the handoff are resent by the new `Net`.
+    ///
+    /// To keep the loss small, stop sending to the peer, hand it off and
+    /// redirect its traffic to the new `Net` right after, before feeding
+    /// further packets to this one.
+    pub fn hand_off_peer<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId)
+        -> Result<Option<SavedPeer<A>>, CB::Error>
+    {
+        if !self.peers[pid].conn.is_online() {
+            return Ok(None);
+        }
+        self.flush(cb, pid)?;
+        let saved = self.save_peer(pid);
+        if saved.is_some() {
+            self.peers.remove_peer(pid);
+        }
+        Ok(saved)
+    }
+    /// Returns the vital chunks sent to the peer that haven't been
+    /// acknowledged yet, oldest first. See `Connection::pending_chunks`.
+    pub fn peer_pending_chunks(&self, pid: PeerId) -> Vec<connection::PendingChunk> {
+        self.peers[pid].conn.pending_chunks()
+    }
+    /// Sends the chunks queued for the peer.
+    ///
+    /// Chunks queued with `send_with_priority` are handed to the connection
+    /// first, highest priority first.
+    pub fn flush<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId)
+        -> Result<(), CB::Error>
+    {
+        self.blocked.flush(cb)?;
+        let peer = &mut self.peers[pid];
+        let mut result = Ok(());
+        if !peer.prioritized.is_empty() {
+            let mut prioritized = mem::replace(&mut peer.prioritized, Vec::new());
+            // Stable, keeps the order of chunks with the same priority.
+            prioritized.sort_by_key(|&(priority, _, _)| cmp::Reverse(priority));
+            if peer.conn.is_online() {
+                for (_, data, vital) in prioritized {
+                    let res = peer.conn.send(&mut cc(cb, &mut self.blocked, &peer.addr, peer.dump(pid)), &mut self.builder, &data, vital)
+                        .map_err(Error::unwrap_callback);
+                    result = result.and(res);
+                }
+            }
+        }
+        let res = peer.conn.flush(&mut cc(cb, &mut self.blocked, &peer.addr, peer.dump(pid)), &mut self.builder);
+        result.and(res)
+    }
+    /// Sends the packets that were queued because the callback would have
+    /// blocked, see `Callback::would_block`.
+    ///
+    /// Stops at the first packet that would block again. `flush` does this
+    /// too, before flushing the peer.
+    pub fn send_blocked<CB: Callback<A>>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+        self.blocked.flush(cb)
+    }
+    /// Returns whether there are packets waiting for `send_blocked`.
+    pub fn has_blocked(&self) -> bool {
+        !self.blocked.packets.is_empty()
+    }
+    pub fn ignore(&mut self, pid: PeerId) {
+        self.peers.remove_peer(pid);
+    }
+    /// Removes the peer without sending anything to it.
+    ///
+    /// Queued chunks are discarded. Returns `false` if there is no such peer.
+    /// Packets that arrive from the peer's address afterwards are treated as
+    /// coming from a new peer.
+    pub fn drop_peer(&mut self, pid: PeerId) -> bool {
+        if self.peers.get(pid).is_none() {
+            return false;
+        }
+        self.peers.remove_peer(pid);
+        true
+    }
+    /// Returns the transport header of the packet last passed to `feed`.
+    ///
+    /// The header contains the packet flags (`protocol::PACKETFLAG_*`), the
+    /// acknowledged sequence number and the number of chunks, as they appear
+    /// on the wire. It is only recorded if
+    /// `NetConfig::record_packet_headers` is enabled, and `None` if the
+    /// packet was too short or came from a banned address.
+    pub fn last_packet_header(&self) -> Option<PacketHeader> {
+        self.last_packet_header
+    }
+    /// Returns the number of connless packets dropped as duplicates, see
+    /// `NetConfig::connless_dedup_window`.
+    pub fn num_deduped_connless(&self) -> u64 {
+        self.connless_dedup.num_deduped
+    }
+    /// Drops all packets from the address, without parsing them.
+    ///
+    /// The peer with that address is removed without sending anything to
+    /// it, like with `drop_peer`, and its ID is returned. Only packets from
+    /// exactly this address are dropped, i.e. with `SocketAddr`, other ports
+    /// of the same IP address are still let through. Use `ban_subnet` to
+    /// ban IP addresses or ranges of them.
+    pub fn ban_address(&mut self, addr: A) -> Option<PeerId> {
+        self.banned.insert(self.peers.normalized(&addr));
+        let pid = self.peers.pid_from_addr(&addr);
+        if let Some(pid) = pid {
+            self.peers.remove_peer(pid);
+        }
+        pid
+    }
+    /// Lets packets from the address through again. Returns `false` if it
+    /// wasn't banned.
+    pub fn unban_address(&mut self, addr: A) -> bool {
+        self.banned.remove(&self.peers.normalized(&addr))
+    }
+    /// Returns whether the address is banned, see `ban_address` and
+    /// `ban_subnet`.
+    pub fn is_banned(&self, addr: A) -> bool {
+        self.is_banned_normalized(&self.peers.normalized(&addr))
+    }
+    fn is_banned_normalized(&self, addr: &A) -> bool {
+        if self.banned.contains(addr) {
+            return true;
+        }
+        match self.subnet_ip {
+            Some(subnet_ip) => {
+                let ip = subnet_ip(addr);
+                self.banned_subnets.iter().any(|&(subnet, len)| in_subnet(ip, subnet, len))
+            }
+            None => false,
+        }
+    }
+    /// Sets a function mapping equivalent addresses to the same one, e.g.
+    /// `normalize_socket_addr`.
+    ///
+    /// Peers, bans and duplicate connless packets are then looked up by the
+    /// normalized address, so that a client appearing under different
+    /// forms of its address stays one peer. Packets are still sent to the
+    /// address the peer was created with.
+    pub fn set_address_normalization(&mut self, normalize: Option<fn(&A) -> A>) {
+        self.peers.normalize = normalize;
+        self.banned = self.banned.iter().map(|a| self.peers.normalized(a)).collect();
+    }
+    pub fn accept<CB: Callback<A>>(&mut self, cb: &mut CB, pid: PeerId)
+        -> Result<(), CB::Error>
+    {
+        let peer = &mut self.peers[pid];
         assert!(peer.conn.is_unconnected());
         let mut buf: ArrayVec<[u8; 2048]> = ArrayVec::new();
         let (mut none, res) =
-            peer.conn.feed(&mut cc(cb, peer.addr), &mut Panic, CONNECT_PACKET, &mut buf);
+            peer.conn.feed(&mut cc(cb, &mut self.blocked, &peer.addr, peer.dump(pid)), &mut self.builder, &mut Panic, CONNECT_PACKET, &mut buf);
         assert!(none.next().is_none());
         res
     }
@@ -437,19 +1788,61 @@ impl<A: Address> Net<A> {
         {
             let peer = &mut self.peers[pid];
             assert!(peer.conn.is_unconnected());
-            result = peer.conn.disconnect(&mut cc(cb, peer.addr), reason);
+            result = peer.conn.disconnect(&mut cc(cb, &mut self.blocked, &peer.addr, peer.dump(pid)), &mut self.builder, reason);
         }
         self.peers.remove_peer(pid);
         result
     }
     pub fn tick<'a, CB: Callback<A>>(&'a mut self, cb: &'a mut CB)
-        -> Tick<A, CB>
+        -> Tick<A, CB, T>
+    {
+        self.peers.remove_finished_disconnects(cb, &mut self.blocked);
+        if !self.peers.half_open.is_empty() {
+            self.peers.remove_expired_half_open(cb.time());
+        }
+        self.tick_connections(cb)
+    }
+    fn tick_connections<'a, CB: Callback<A>>(&'a mut self, cb: &'a mut CB)
+        -> Tick<'a, A, CB, T>
     {
         Tick {
             iter_mut: self.peers.iter_mut(),
+            builder: &mut self.builder,
+            blocked: &mut self.blocked,
             cb: cb,
         }
     }
+    /// Like `tick`, but queues a `Disconnect` event for each peer it removes,
+    /// see `poll_event`. Use this instead of `tick` together with
+    /// `feed_to_queue`.
+    ///
+    /// Peers are removed with `DisconnectReason::Graceful` once their
+    /// `disconnect_graceful` has finished and with
+    /// `DisconnectReason::HandshakeTimeout` if they didn't complete the
+    /// handshake within `NetConfig::half_open_timeout`. If the queue already
+    /// holds `NetConfig::event_queue_size` events, the remaining peers are
+    /// kept until a later call finds room for their events.
+    ///
+    /// Returns the first error of the callback.
+    pub fn tick_to_queue<CB: Callback<A>>(&mut self, cb: &mut CB) -> Result<(), CB::Error> {
+        let now = cb.time();
+        while self.events.len() < self.config.event_queue_size {
+            let (pid, why) = match self.peers.finished_disconnect(cb, &mut self.blocked) {
+                Some(pid) => (pid, DisconnectReason::Graceful),
+                None => match self.peers.expired_half_open(now) {
+                    Some(pid) => (pid, DisconnectReason::HandshakeTimeout),
+                    None => break,
+                },
+            };
+            self.peers.remove_peer(pid);
+            self.events.push_back(OwnedChunkOrEvent::Disconnect(pid, why, Vec::new()));
+        }
+        let mut result = Ok(());
+        for e in self.tick_connections(cb) {
+            result = result.and(Err(e));
+        }
+        result
+    }
     pub fn feed<'a, CB, B, W>(&mut self, cb: &mut CB, warn: &mut W, addr: A, data: &'a [u8], buf: B)
         -> (ReceivePacket<'a, A>, Result<(), CB::Error>)
         where CB: Callback<A>,
@@ -458,53 +1851,210 @@ impl<A: Address> Net<A> {
     {
         with_buffer(buf, |b| self.feed_impl(cb, warn, addr, data, b))
     }
+    /// Like `feed`, but queues the chunks and events instead of returning
+    /// them, see `poll_event`.
+    ///
+    /// If the queue already holds `NetConfig::event_queue_size` events, the
+    /// packet is dropped without being processed. Its vital chunks are
+    /// resent by the peer, just like when the packet is lost.
+    pub fn feed_to_queue<CB, W>(&mut self, cb: &mut CB, warn: &mut W, addr: A, data: &[u8])
+        -> Result<(), CB::Error>
+        where CB: Callback<A>,
+              W: Warn<Warning<A>>,
+    {
+        if self.events.len() >= self.config.event_queue_size {
+            self.num_dropped_packets += 1;
+            return Ok(());
+        }
+        let mut buf: ArrayVec<[u8; 2048]> = ArrayVec::new();
+        let (packet, result) = self.feed(cb, warn, addr, data, &mut buf);
+        self.events.extend(packet.map(ChunkOrEvent::into_owned));
+        result
+    }
+    /// Takes the oldest chunk or event queued by `feed_to_queue` or
+    /// `tick_to_queue`.
+    ///
+    /// The events carry their peer ID themselves, just like the ones
+    /// returned by `feed`, because connless chunks don't necessarily belong
+    /// to a peer.
+    pub fn poll_event(&mut self) -> Option<OwnedChunkOrEvent<A>> {
+        self.events.pop_front()
+    }
+    /// Returns the number of chunks and events queued by `feed_to_queue` and
+    /// `tick_to_queue`.
+    pub fn num_queued_events(&self) -> usize {
+        self.events.len()
+    }
+    /// Returns the number of packets `feed_to_queue` dropped because the
+    /// queue was full.
+    pub fn num_dropped_packets(&self) -> u64 {
+        self.num_dropped_packets
+    }
     fn feed_impl<'d, 's, CB, W>(&mut self, cb: &mut CB, warn: &mut W, addr: A, data: &'d [u8], mut buf: BufferRef<'d, 's>)
         -> (ReceivePacket<'d, A>, Result<(), CB::Error>)
         where CB: Callback<A>,
               W: Warn<Warning<A>>,
     {
-        if let Some(pid) = self.peers.pid_from_addr(addr) {
-            let (packet, e) = self.peers[pid].conn.feed(&mut cc(cb, addr), &mut wp(warn, addr, pid), data, &mut buf);
-            (ReceivePacket::connected(addr, pid, packet, self), e)
-        } else {
-            let packet = match Packet::read(&mut w(warn, addr), data, &mut buf) {
-                Ok(p) => p,
-                Err(e) => {
-                    w(warn, addr).warn(connection::Warning::Read(e));
-                    return (ReceivePacket::none(), Ok(()));
+        if self.config.record_packet_headers {
+            self.last_packet_header = None;
+        }
+        if (!self.banned.is_empty() || !self.banned_subnets.is_empty())
+            && self.is_banned_normalized(&self.peers.normalized(&addr))
+        {
+            return (ReceivePacket::none(), Ok(()));
+        }
+        if self.config.record_packet_headers {
+            self.last_packet_header = PacketHeaderPacked::from_byte_slice(data)
+                .map(|(h, _)| h.unpack());
+        }
+        let pid = self.peers.pid_from_addr(&addr);
+        if let Some(pid) = pid {
+            if let Some((pid, direction)) = self.peers[pid].dump(pid) {
+                dump_packet(pid, direction, "received", data);
+            }
+        }
+        let read = match pid {
+            Some(pid) => Packet::read(&mut wp(warn, &addr, pid), data, &mut buf),
+            None => Packet::read(&mut w(warn, &addr), data, &mut buf),
+        };
+        let packet = match read {
+            Ok(p) => p,
+            Err(e) => {
+                match pid {
+                    Some(pid) => wp(warn, &addr, pid).warn(connection::Warning::Read(e)),
+                    None => w(warn, &addr).warn(connection::Warning::Read(e)),
                 }
+                return (ReceivePacket::none(), Ok(()));
+            }
+        };
+        if let Packet::Connected(ConnectedPacket { type_: ConnectedPacketType::Control(ref c), .. }) = packet {
+            let control = c.control_msg();
+            if !self.config.role.receives_control(control) {
+                warn.warn(Warning::ControlForOtherRole(addr, control));
+                return (ReceivePacket::none(), Ok(()));
+            }
+        }
+        if let Some(pid) = pid {
+            let connected = match packet {
+                Packet::Connected(_) => true,
+                Packet::Connless(_) => false,
             };
+            let dump = self.peers[pid].dump(pid);
+            let (packet, e) = self.peers[pid].conn.feed_packet(&mut cc(cb, &mut self.blocked, &addr, dump), &mut self.builder, &mut wp(warn, &addr, pid), packet, buf);
+            if connected {
+                self.peers[pid].last_activity = cb.time();
+            }
+            self.peers.update_half_open(pid);
+            let packet = ReceivePacket::connected(addr, pid, packet, self);
+            if self.peers.get(pid).map(|p| p.conn.is_disconnected()).unwrap_or(false) {
+                // The peer acknowledged a graceful disconnect.
+                self.peers.remove_peer(pid);
+            }
+            (packet, e)
+        } else {
             if let Packet::Connless(d) = packet {
+                if let Some(window) = self.config.connless_dedup_window {
+                    let capacity = self.config.connless_dedup_capacity;
+                    let key = self.peers.normalized(&addr);
+                    if self.connless_dedup.is_duplicate(window, capacity, cb.time(), &key, d) {
+                        return (ReceivePacket::none(), Ok(()));
+                    }
+                }
                 (ReceivePacket::connless(addr, d), Ok(()))
             } else if let Packet::Connected(ConnectedPacket {
                     type_: ConnectedPacketType::Control(ControlPacket::Connect), ..
                 }) = packet
             {
-                if self.accept_connections {
-                    let (pid, _) = self.peers.new_peer(addr);
-                    (ReceivePacket::connect(pid), Ok(()))
+                if self.config.accept_connections && self.config.role != Role::Client {
+                    self.peers.remove_expired_half_open(cb.time());
+                    if let Some(limit) = self.config.half_open_limit {
+                        if self.peers.num_half_open >= limit {
+                            warn.warn(Warning::HalfOpenLimit(addr));
+                            return (ReceivePacket::none(), Ok(()));
+                        }
+                    }
+                    let time = cb.time();
+                    if let Some((pid, _)) = self.peers.new_peer(addr.clone(), Direction::Incoming, time) {
+                        return (ReceivePacket::connect(addr, pid), Ok(()));
+                    }
+                    let close = Packet::Connected(ConnectedPacket {
+                        ack: 0,
+                        type_: ConnectedPacketType::Control(
+                            ControlPacket::Close(&self.config.full_reason)
+                        ),
+                    });
+                    let result = self.builder.send(&mut cc(cb, &mut self.blocked, &addr, None), close)
+                        .map_err(Error::unwrap_callback);
+                    (ReceivePacket::none(), result)
                 } else {
-                    w(warn, addr).warn(connection::Warning::Unexpected);
+                    w(warn, &addr).warn(connection::Warning::Unexpected);
                     (ReceivePacket::none(), Ok(()))
                 }
+            } else if let Packet::Connected(ConnectedPacket {
+                    type_: ConnectedPacketType::Control(ControlPacket::Close(_)), ..
+                }) = packet
+            {
+                // Ignore, the peer is already gone.
+                warn.warn(Warning::CloseFromUnknownPeer(addr));
+                (ReceivePacket::none(), Ok(()))
             } else {
-                w(warn, addr).warn(connection::Warning::Unexpected);
+                w(warn, &addr).warn(connection::Warning::Unexpected);
                 (ReceivePacket::none(), Ok(()))
             }
         }
     }
 }
 
-pub struct Tick<'a, A: Address+'a, CB: Callback<A>+'a> {
-    iter_mut: peer_map::IterMut<'a, Peer<A>>,
+#[cfg(feature = "fuzzing")]
+impl Net<()> {
+    /// Feeds arbitrary data to a server, for use in fuzz targets.
+    ///
+    /// The data is fed twice, first as coming from an unknown address, then
+    /// again to the connection that was accepted in case the first packet
+    /// was a connection attempt. Should not panic on any input.
+    pub fn feed_fuzz(data: &[u8]) {
+        use warn::Ignore;
+
+        struct NoopCallback;
+        impl Callback<()> for NoopCallback {
+            type Error = ();
+            fn send(&mut self, addr: (), data: &[u8]) -> Result<(), ()> {
+                let _ = (addr, data);
+                Ok(())
+            }
+            fn time(&mut self) -> Timestamp {
+                Timestamp::from_secs_since_epoch(0)
+            }
+        }
+
+        let mut net = Net::server();
+        let mut cb = NoopCallback;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut connect = None;
+        for chunk in net.feed(&mut cb, &mut Ignore, (), data, &mut buffer[..]).0 {
+            if let ChunkOrEvent::Connect(pid, ()) = chunk {
+                connect = Some(pid);
+            }
+        }
+        if let Some(pid) = connect {
+            let _ = net.accept(&mut cb, pid);
+            for _ in net.feed(&mut cb, &mut Ignore, (), data, &mut buffer[..]).0 { }
+        }
+    }
+}
+
+pub struct Tick<'a, A: Address+'a, CB: Callback<A>+'a, T: 'a = ()> {
+    iter_mut: peer_map::IterMut<'a, Peer<A, T>>,
+    builder: &'a mut PacketBuilder,
+    blocked: &'a mut Blocked<A>,
     cb: &'a mut CB,
 }
 
-impl<'a, A: Address+'a, CB: Callback<A>+'a> Iterator for Tick<'a, A, CB> {
+impl<'a, A: Address+'a, CB: Callback<A>+'a, T> Iterator for Tick<'a, A, CB, T> {
     type Item = CB::Error;
     fn next(&mut self) -> Option<CB::Error> {
-        while let Some((_, p)) = self.iter_mut.next() {
-            match p.conn.tick(&mut cc(self.cb, p.addr)) {
+        while let Some((pid, p)) = self.iter_mut.next() {
+            match p.conn.tick(&mut cc(self.cb, self.blocked, &p.addr, p.dump(pid)), self.builder) {
                 Ok(()) => {},
                 Err(e) => return Some(e),
             }
@@ -516,51 +2066,161 @@ impl<'a, A: Address+'a, CB: Callback<A>+'a> Iterator for Tick<'a, A, CB> {
 #[cfg(test)]
 mod test {
     use Timestamp;
+    use connection::DisconnectReason;
+    use connection::SavedChunk;
+    use connection::SavedConnection;
+    use connection;
     use itertools::Itertools;
+    use log;
     use protocol;
+    use std::cell::RefCell;
     use std::collections::VecDeque;
+    use std::hash::BuildHasherDefault;
+    use std::hash::Hasher;
+    use std::net::SocketAddr;
+    use std::sync::Once;
+    use std::time::Duration;
+    use super::AddressWithToken;
+    use super::CONNECT_PACKET;
+    use super::DEFAULT_HALF_OPEN_TIMEOUT;
     use super::Callback;
+    use super::Chunk;
     use super::ChunkOrEvent;
+    use super::ConnlessChunk;
+    use super::ConnlessDedup;
+    use super::ConnectError;
+    use super::Direction;
+    use super::Error;
     use super::Net;
+    use super::NetConfig;
+    use super::OwnedChunk;
+    use super::OwnedChunkOrEvent;
+    use super::OwnedConnlessChunk;
+    use super::PacketHeader;
+    use super::PeerId;
+    use super::PeerSendError;
+    use super::Peers;
+    use super::Priority;
+    use super::Progress;
+    use super::Role;
+    use super::SavedPeer;
+    use super::SendPath;
+    use super::Warning;
+    use super::normalize_socket_addr;
     use void::ResultVoidExt;
     use void::Void;
+    use warn::Ignore;
     use warn::Panic;
+    use warn::Warn;
 
-    #[test]
-    fn establish_connection() {
-        #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-        enum Address {
-            Client,
-            Server,
+    #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+    enum Address {
+        Client,
+        Server,
+    }
+
+    struct WarnVec<'a>(&'a mut Vec<Warning<Address>>);
+
+    impl<'a> Warn<Warning<Address>> for WarnVec<'a> {
+        fn warn(&mut self, warning: Warning<Address>) {
+            self.0.push(warning);
         }
-        struct Cb {
-            packets: VecDeque<Vec<u8>>,
-            recipient: Address,
-        }
-        impl Cb {
-            fn new() -> Cb {
-                Cb {
-                    packets: VecDeque::new(),
-                    recipient: Address::Server,
-                }
+    }
+
+    struct Cb {
+        packets: VecDeque<Vec<u8>>,
+        recipient: Address,
+    }
+    impl Cb {
+        fn new() -> Cb {
+            Cb {
+                packets: VecDeque::new(),
+                recipient: Address::Server,
             }
         }
-        impl Callback<Address> for Cb {
-            type Error = Void;
-            fn send(&mut self, addr: Address, data: &[u8]) -> Result<(), Void> {
-                assert!(self.recipient == addr);
-                self.packets.push_back(data.to_owned());
-                Ok(())
-            }
-            fn time(&mut self) -> Timestamp {
-                Timestamp::from_secs_since_epoch(0)
+    }
+    impl Callback<Address> for Cb {
+        type Error = Void;
+        fn send(&mut self, addr: Address, data: &[u8]) -> Result<(), Void> {
+            assert!(self.recipient == addr);
+            self.packets.push_back(data.to_owned());
+            Ok(())
+        }
+        fn time(&mut self) -> Timestamp {
+            Timestamp::from_secs_since_epoch(0)
+        }
+    }
+
+    // Feeds a connect packet from `Address::Client`, returning the new peer.
+    fn connect_client<T>(cb: &mut Cb, net: &mut Net<Address, T>) -> PeerId {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        cb.recipient = Address::Client;
+        let p = net.feed(cb, &mut Panic, Address::Client, CONNECT_PACKET, &mut buffer[..]).0.collect_vec();
+        match p[..] {
+            [ChunkOrEvent::Connect(pid, Address::Client)] => pid,
+            _ => panic!("expected connect, got {:?}", p),
+        }
+    }
+
+    // Like `connect_client`, but also accepts the peer, dropping the sent
+    // packets.
+    fn accept_client<T>(cb: &mut Cb, net: &mut Net<Address, T>) -> PeerId {
+        let pid = connect_client(cb, net);
+        net.accept(cb, pid).void_unwrap();
+        cb.packets.clear();
+        pid
+    }
+
+    // Records the packets together with their recipients, for tests with
+    // several `Net`s or time passing.
+    struct TimeCb<A = Address> {
+        packets: VecDeque<(A, Vec<u8>)>,
+        time: Timestamp,
+    }
+    impl<A> TimeCb<A> {
+        fn new() -> TimeCb<A> {
+            TimeCb {
+                packets: VecDeque::new(),
+                time: Timestamp::from_secs_since_epoch(0),
             }
         }
+        fn recipients(&self) -> Vec<A> where A: Clone {
+            self.packets.iter().map(|&(ref a, _)| a.clone()).collect()
+        }
+    }
+    impl<A: super::Address> Callback<A> for TimeCb<A> {
+        type Error = Void;
+        fn send(&mut self, addr: A, data: &[u8]) -> Result<(), Void> {
+            self.packets.push_back((addr, data.to_owned()));
+            Ok(())
+        }
+        fn time(&mut self) -> Timestamp {
+            self.time
+        }
+    }
+
+    // Connects `client` to `server` and completes the handshake, returning
+    // the peer IDs on both sides.
+    fn handshake<T>(cb: &mut TimeCb, client: &mut Net<Address>, server: &mut Net<Address, T>) -> (PeerId, PeerId) {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let (c_pid, res) = client.connect(cb, Address::Server);
+        res.void_unwrap();
+        let (_, packet) = cb.packets.pop_front().unwrap();
+        let p = server.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..]).0.collect_vec();
+        let s_pid = match p[..] { [ChunkOrEvent::Connect(pid, Address::Client)] => pid, _ => panic!("expected connect, got {:?}", p) };
+        server.accept(cb, s_pid).void_unwrap();
+        deliver(cb, client, Address::Server, Address::Client);
+        deliver(cb, server, Address::Client, Address::Server);
+        (c_pid, s_pid)
+    }
+
+    #[test]
+    fn establish_connection() {
         let mut cb = Cb::new();
         let cb = &mut cb;
         let mut buffer = [0; protocol::MAX_PAYLOAD];
 
-        let mut net = Net::server();
+        let mut net = Net::both();
 
         // Connect
         cb.recipient = Address::Server;
@@ -575,7 +2235,8 @@ mod test {
         {
             let p = net.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..]).0.collect_vec();
             assert!(p.len() == 1);
-            if let ChunkOrEvent::Connect(s) = p[0] {
+            if let ChunkOrEvent::Connect(s, addr) = p[0] {
+                assert_eq!(addr, Address::Client);
                 s_pid = s;
             } else {
                 panic!();
@@ -588,17 +2249,30 @@ mod test {
         let packet = cb.packets.pop_front().unwrap();
         assert!(cb.packets.is_empty());
 
-        // Accept
+        // Accept, the client side is notified that the connection is ready.
         cb.recipient = Address::Server;
-        assert!(net.feed(cb, &mut Panic, Address::Server, &packet, &mut buffer[..]).0.collect_vec()
-                == &[ChunkOrEvent::Ready(c_pid)]);
+        let p = net.feed(cb, &mut Panic, Address::Server, &packet, &mut buffer[..]).0;
+        assert!(p.clone().collect_vec() == &[ChunkOrEvent::Ready(c_pid)]);
+        assert!(p.events_only().collect_vec() == &[ChunkOrEvent::Ready(c_pid)]);
         let packet = cb.packets.pop_front().unwrap();
         assert!(cb.packets.is_empty());
 
+        // The server side doesn't get another event, it already knows about
+        // the connection since `Connect`.
         cb.recipient = Address::Client;
         assert!(net.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..]).0.next().is_none());
         assert!(cb.packets.is_empty());
 
+        // The client can send data now.
+        cb.recipient = Address::Server;
+        net.send_vital(cb, c_pid, b"\x42").unwrap();
+        net.flush(cb, c_pid).void_unwrap();
+        let packet = cb.packets.pop_front().unwrap();
+        cb.recipient = Address::Client;
+        assert!(net.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..]).0.collect_vec()
+                == &[ChunkOrEvent::Chunk(Chunk::vital(s_pid, b"\x42"))]);
+        assert!(cb.packets.is_empty());
+
         // Disconnect
         cb.recipient = Address::Server;
         net.disconnect(cb, c_pid, b"foobar").void_unwrap();
@@ -607,7 +2281,1416 @@ mod test {
 
         cb.recipient = Address::Client;
         assert!(net.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..]).0.collect_vec()
-                == &[ChunkOrEvent::Disconnect(s_pid, b"foobar")]);
+                == &[ChunkOrEvent::Disconnect(s_pid, DisconnectReason::Closed, b"foobar")]);
+        assert!(cb.packets.is_empty());
+    }
+
+    #[test]
+    fn clone() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let chunk = b"\x00\x00\x01\x40\x01\x01\x42";
+
+        let mut net = Net::server();
+        let pid = accept_client(cb, &mut net);
+
+        // Both copies react to the same input with the same packets.
+        let mut forked = net.clone();
+        let mut run = |net: &mut Net<Address>| {
+            let p = net.feed(cb, &mut Panic, Address::Client, chunk, &mut buffer[..]).0.collect_vec();
+            assert_eq!(p, &[ChunkOrEvent::Chunk(Chunk { pid: pid, vital: true, data: b"\x42" })]);
+            net.send_vital(cb, pid, b"\x13\x37").unwrap();
+            net.tick(cb).count();
+            net.flush(cb, pid).void_unwrap();
+            cb.packets.drain(..).collect_vec()
+        };
+        let packets = run(&mut net);
+        assert!(!packets.is_empty());
+        assert_eq!(run(&mut forked), packets);
+    }
+
+    #[test]
+    fn close_from_unknown_peer() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut warnings = vec![];
+
+        let mut net = Net::server();
+        let close = b"\x10\x00\x00\x04foobar\0";
+        assert!(net.feed(cb, &mut WarnVec(&mut warnings), Address::Client, close, &mut buffer[..]).0.next().is_none());
+        assert!(cb.packets.is_empty());
+        assert_matches!(&warnings[..], &[Warning::CloseFromUnknownPeer(Address::Client)]);
+    }
+
+    #[test]
+    fn send_connless_to_peer() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+
+        let mut net = Net::server();
+        accept_client(cb, &mut net);
+
+        // The packet goes out as is, the connection isn't involved.
+        net.send_connless(cb, Address::Client, b"info").unwrap();
+        assert_eq!(cb.packets.pop_front().unwrap(), b"\xff\xff\xff\xff\xff\xffinfo");
+        assert!(cb.packets.is_empty());
+    }
+
+    #[test]
+    fn send_connless_to_peer_pid() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut net = Net::server();
+        let pid = connect_client(cb, &mut net);
+        assert_matches!(net.send_connless_to_peer(cb, pid, b"info"), Err(PeerSendError::NotOnline));
+        net.accept(cb, pid).void_unwrap();
+        assert!(net.feed(cb, &mut Panic, Address::Client, b"\x10\x00\x00\x03", &mut buffer[..]).0.next().is_none());
+        cb.packets.clear();
+
+        net.send_connless_to_peer(cb, pid, b"info").unwrap();
+        assert_eq!(cb.packets.pop_front().unwrap(), b"\xff\xff\xff\xff\xff\xffinfo");
+        assert!(cb.packets.is_empty());
+
+        net.disconnect(cb, pid, b"").unwrap();
+        assert_matches!(net.send_connless_to_peer(cb, pid, b"info"), Err(PeerSendError::NoSuchPeer));
+    }
+
+    #[test]
+    fn would_block() {
+        // The error is `true` for would-block errors.
+        struct Cb {
+            packets: Vec<Vec<u8>>,
+            result: Result<(), bool>,
+        }
+        impl Callback<Address> for Cb {
+            type Error = bool;
+            fn send(&mut self, _: Address, data: &[u8]) -> Result<(), bool> {
+                if self.result.is_ok() {
+                    self.packets.push(data.to_owned());
+                }
+                self.result
+            }
+            fn time(&mut self) -> Timestamp {
+                Timestamp::from_secs_since_epoch(0)
+            }
+            fn would_block(&self, &e: &bool) -> bool {
+                e
+            }
+        }
+        let mut cb = Cb { packets: vec![], result: Err(true) };
+        let cb = &mut cb;
+        let mut net = Net::with_config(NetConfig {
+            blocked_queue_size: 2,
+            ..NetConfig::default()
+        });
+
+        // The packet is queued instead of failing. Later packets are queued
+        // behind it, packets exceeding the queue size are dropped.
+        net.send_connless(cb, Address::Server, b"1").unwrap();
+        cb.result = Ok(());
+        net.send_connless(cb, Address::Server, b"2").unwrap();
+        net.send_connless(cb, Address::Server, b"3").unwrap();
+        assert!(cb.packets.is_empty());
+        assert!(net.has_blocked());
+        assert_eq!(net.total_pending_bytes(), 14);
+
+        net.send_blocked(cb).unwrap();
+        assert_eq!(cb.packets, [b"\xff\xff\xff\xff\xff\xff1", b"\xff\xff\xff\xff\xff\xff2"]);
+        assert!(!net.has_blocked());
+        assert_eq!(net.total_pending_bytes(), 0);
+
+        // Other errors are passed through.
+        cb.result = Err(false);
+        assert_matches!(net.send_connless(cb, Address::Server, b"4"), Err(Error::Callback(false)));
+        assert!(!net.has_blocked());
+    }
+
+    #[test]
+    fn max_connless_packet_size() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let data = [0x42; 2048];
+
+        // By default, connless packets are limited like connected ones.
+        let mut net = Net::<Address>::server();
+        net.send_connless(cb, Address::Server, &data[..protocol::MAX_PAYLOAD]).unwrap();
+        assert_eq!(cb.packets.pop_front().unwrap().len(), protocol::MAX_PACKETSIZE);
+        assert_matches!(net.send_connless(cb, Address::Server, &data[..protocol::MAX_PAYLOAD+1]),
+                        Err(Error::TooLongData));
+        assert!(cb.packets.is_empty());
+
+        let mut net = Net::<Address>::with_config(NetConfig {
+            max_connless_packet_size: 2048,
+            ..NetConfig::default()
+        });
+        net.send_connless(cb, Address::Server, &data[..2042]).unwrap();
+        let packet = cb.packets.pop_front().unwrap();
+        assert_eq!(&packet[..6], b"\xff\xff\xff\xff\xff\xff");
+        assert_eq!(packet.len(), 2048);
+        assert_matches!(net.send_connless(cb, Address::Server, &data[..2043]),
+                        Err(Error::TooLongData));
+        assert!(cb.packets.is_empty());
+
+        // Raw connless packets are subject to the same limit.
+        net.send_connless_raw(cb, Address::Server, b"\xff\xff", &data[..2046]).unwrap();
+        assert_eq!(cb.packets.pop_front().unwrap().len(), 2048);
+        assert_matches!(net.send_connless_raw(cb, Address::Server, b"\xff\xff", &data[..2047]),
+                        Err(Error::TooLongData));
+        let mut net = Net::<Address>::with_config(NetConfig {
+            max_connless_packet_size: 100,
+            ..NetConfig::default()
+        });
+        assert_matches!(net.send_connless_raw(cb, Address::Server, b"\xff", &data[..100]),
+                        Err(Error::TooLongData));
+        assert!(cb.packets.is_empty());
+    }
+
+    #[test]
+    fn send_connless_raw() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+
+        let mut net = Net::<Address>::server();
+        net.send_connless_raw(cb, Address::Server, b"\xff\xff\xff\xff", b"punch").unwrap();
+        assert_eq!(cb.packets.pop_front().unwrap(), b"\xff\xff\xff\xffpunch");
+        assert!(cb.packets.is_empty());
+
+        let data = [0; protocol::MAX_PACKETSIZE];
+        assert_matches!(net.send_connless_raw(cb, Address::Server, b"\xff", &data),
+                        Err(Error::TooLongData));
         assert!(cb.packets.is_empty());
     }
+
+    #[test]
+    fn peer_pool() {
+        let mut peers: Peers<_, ()> = Peers::with_config(&NetConfig::default());
+        peers.set_pool_size(1);
+        let (pid1, _) = peers.new_peer(Address::Client, Direction::Incoming, Timestamp::from_secs_since_epoch(0)).unwrap();
+        let (pid2, _) = peers.new_peer(Address::Server, Direction::Outgoing, Timestamp::from_secs_since_epoch(0)).unwrap();
+        assert!(peers.pool.is_empty());
+
+        // Only one connection is kept, the pool is bounded.
+        peers.remove_peer(pid1);
+        peers.remove_peer(pid2);
+        assert_eq!(peers.pool.len(), 1);
+
+        let (pid3, peer) = peers.new_peer(Address::Client, Direction::Incoming, Timestamp::from_secs_since_epoch(0)).unwrap();
+        assert!(pid3 != pid1 && pid3 != pid2);
+        assert!(peer.conn.is_unconnected());
+        assert!(peers.pool.is_empty());
+
+        peers.set_pool_size(0);
+        peers.remove_peer(pid3);
+        assert!(peers.pool.is_empty());
+    }
+
+    #[test]
+    fn peer_ids_sorted() {
+        let mut peers: Peers<_, ()> = Peers::with_config(&NetConfig::default());
+        let pids: Vec<_> = (0..4).map(|_| peers.new_peer(Address::Client, Direction::Incoming, Timestamp::from_secs_since_epoch(0)).unwrap().0).collect();
+        peers.remove_peer(pids[1]);
+        peers.remove_peer(pids[0]);
+        let (pid, _) = peers.new_peer(Address::Server, Direction::Outgoing, Timestamp::from_secs_since_epoch(0)).unwrap();
+
+        // Removals don't change the order of the remaining peers.
+        assert_eq!(peers.peers.keys().collect_vec(), [pids[2], pids[3], pid]);
+    }
+
+    #[test]
+    fn capacity() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut net = Net::with_capacity(1);
+        assert!(!net.is_full());
+        let p = net.feed(cb, &mut Panic, Address::Client, CONNECT_PACKET, &mut buffer[..]).0.collect_vec();
+        assert_matches!(&p[..], &[ChunkOrEvent::Connect(_, Address::Client)]);
+        assert!(cb.packets.is_empty());
+        assert!(net.is_full());
+
+        // The second connection attempt is rejected without creating a peer.
+        cb.recipient = Address::Server;
+        assert!(net.feed(cb, &mut Panic, Address::Server, CONNECT_PACKET, &mut buffer[..]).0.next().is_none());
+        assert_eq!(cb.packets.pop_front().unwrap(), &b"\x10\x00\x00\x04This server is full\0"[..]);
+        assert!(cb.packets.is_empty());
+        assert_eq!(net.peers.peers.len(), 1);
+    }
+
+    #[test]
+    fn peer_direction() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut server = Net::server();
+        let mut client = Net::client();
+
+        cb.recipient = Address::Server;
+        let (c_pid, res) = client.connect(cb, Address::Server);
+        res.void_unwrap();
+        assert_eq!(client.peer_direction(c_pid), Direction::Outgoing);
+        let packet = cb.packets.pop_front().unwrap();
+
+        cb.recipient = Address::Client;
+        let p = server.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..]).0.collect_vec();
+        let s_pid = match &p[..] {
+            &[ChunkOrEvent::Connect(pid, Address::Client)] => pid,
+            _ => panic!("expected connect, got {:?}", p),
+        };
+        assert_eq!(server.peer_direction(s_pid), Direction::Incoming);
+        server.accept(cb, s_pid).void_unwrap();
+        assert_eq!(server.peer_direction(s_pid), Direction::Incoming);
+    }
+
+    #[test]
+    fn control_for_other_role() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let connect_accept = b"\x10\x00\x00\x02";
+        let accept = b"\x10\x00\x00\x03";
+
+        let mut server = Net::server();
+        cb.recipient = Address::Client;
+        let mut warnings = vec![];
+        assert!(server.feed(cb, &mut WarnVec(&mut warnings), Address::Client, connect_accept, &mut buffer[..]).0.next().is_none());
+        accept_client(cb, &mut server);
+        // Reflected to the peer, it would trigger an accept.
+        assert!(server.feed(cb, &mut WarnVec(&mut warnings), Address::Client, connect_accept, &mut buffer[..]).0.next().is_none());
+        assert!(cb.packets.is_empty());
+        server.feed(cb, &mut Panic, Address::Client, accept, &mut buffer[..]);
+        assert_matches!(&warnings[..], &[
+            Warning::ControlForOtherRole(Address::Client, protocol::CTRLMSG_CONNECTACCEPT),
+            Warning::ControlForOtherRole(Address::Client, protocol::CTRLMSG_CONNECTACCEPT),
+        ]);
+
+        let mut client = Net::client();
+        let mut warnings = vec![];
+        for packet in &[&CONNECT_PACKET[..], accept] {
+            assert!(client.feed(cb, &mut WarnVec(&mut warnings), Address::Server, packet, &mut buffer[..]).0.next().is_none());
+        }
+        assert!(client.peer_ids().next().is_none());
+        assert!(cb.packets.is_empty());
+        assert_matches!(&warnings[..], &[
+            Warning::ControlForOtherRole(Address::Server, protocol::CTRLMSG_CONNECT),
+            Warning::ControlForOtherRole(Address::Server, protocol::CTRLMSG_ACCEPT),
+        ]);
+
+        // With both roles, the connect accept falls through to the generic
+        // handling.
+        let mut net: Net<Address> = Net::both();
+        let mut warnings = vec![];
+        net.feed(cb, &mut WarnVec(&mut warnings), Address::Server, connect_accept, &mut buffer[..]);
+        assert_matches!(&warnings[..], &[Warning::Connless(Address::Server, connection::Warning::Unexpected)]);
+    }
+
+    #[test]
+    fn half_open_limit() {
+        let mut cb = TimeCb::<u32>::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut net = Net::server();
+        assert_eq!(net.config().half_open_limit, None);
+        net.set_half_open_limit(Some(4));
+
+        // A flood of connection attempts from different addresses.
+        let mut connects = vec![];
+        for addr in 0..1000 {
+            for chunk in net.feed(cb, &mut Ignore, addr, CONNECT_PACKET, &mut buffer[..]).0 {
+                match chunk {
+                    ChunkOrEvent::Connect(pid, a) => connects.push((pid, a)),
+                    c => panic!("unexpected chunk {:?}", c),
+                }
+            }
+        }
+        assert_eq!(connects.iter().map(|&(_, a)| a).collect_vec(), [0, 1, 2, 3]);
+        assert_eq!(net.num_half_open(), 4);
+        assert_eq!(net.peer_ids().count(), 4);
+        // The dropped attempts weren't answered.
+        assert!(cb.packets.is_empty());
+
+        let mut warnings = vec![];
+        {
+            struct W<'a>(&'a mut Vec<Warning<u32>>);
+            impl<'a> Warn<Warning<u32>> for W<'a> {
+                fn warn(&mut self, warning: Warning<u32>) {
+                    self.0.push(warning);
+                }
+            }
+            assert!(net.feed(cb, &mut W(&mut warnings), 1000, CONNECT_PACKET, &mut buffer[..]).0.next().is_none());
+        }
+        assert_matches!(&warnings[..], &[Warning::HalfOpenLimit(1000)]);
+
+        // Accepted connections stay half-open until the client answers.
+        net.accept(cb, connects[0].0).void_unwrap();
+        assert_eq!(net.num_half_open(), 4);
+        net.feed(cb, &mut Panic, 0, b"\x10\x00\x00\x03", &mut buffer[..]).0.count();
+        assert!(net.is_online(connects[0].0));
+        assert_eq!(net.num_half_open(), 3);
+
+        // Rejecting another one makes room as well.
+        net.reject(cb, connects[1].0, b"").void_unwrap();
+        assert_eq!(net.num_half_open(), 2);
+        assert_matches!(net.feed(cb, &mut Panic, 1000, CONNECT_PACKET, &mut buffer[..]).0.next(),
+                        Some(ChunkOrEvent::Connect(_, 1000)));
+        assert_matches!(net.feed(cb, &mut Panic, 1001, CONNECT_PACKET, &mut buffer[..]).0.next(),
+                        Some(ChunkOrEvent::Connect(_, 1001)));
+        assert_eq!(net.feed(cb, &mut Ignore, 1002, CONNECT_PACKET, &mut buffer[..]).0.progress(), Progress::Dropped);
+        assert_eq!(net.num_half_open(), 4);
+
+        net.set_half_open_limit(None);
+        assert_eq!(net.feed(cb, &mut Panic, 1002, CONNECT_PACKET, &mut buffer[..]).0.progress(), Progress::Handshake);
+        assert_eq!(net.num_half_open(), 5);
+        assert_eq!(net.peer_ids().count(), 6);
+    }
+
+    #[test]
+    fn half_open_timeout() {
+        let start = Timestamp::from_secs_since_epoch(0);
+        let mut cb = TimeCb::<u32>::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let accept = b"\x10\x00\x00\x03";
+
+        let mut net = Net::server();
+        assert_eq!(net.config().half_open_timeout, Some(DEFAULT_HALF_OPEN_TIMEOUT));
+        net.set_half_open_limit(Some(4));
+
+        // Flood with connection attempts, some of them accepted, that
+        // never complete the handshake.
+        for round in 0..3 {
+            for addr in 0..1000 {
+                for chunk in net.feed(cb, &mut Ignore, addr, CONNECT_PACKET, &mut buffer[..]).0 {
+                    if let ChunkOrEvent::Connect(pid, _) = chunk {
+                        if addr % 2 == 0 {
+                            net.accept(cb, pid).void_unwrap();
+                        }
+                    }
+                }
+                assert!(net.peer_ids().count() <= 4);
+            }
+            assert_eq!(net.num_half_open(), 4);
+            assert!(net.peers.half_open.len() <= 4 * (round + 1));
+            cb.time = cb.time + Duration::from_secs(1);
+            net.tick(cb).count();
+        }
+        assert_eq!(net.num_half_open(), 4);
+        assert!(net.needs_tick().to_opt().unwrap() <= start + DEFAULT_HALF_OPEN_TIMEOUT);
+
+        // Once they have expired, a real client gets in.
+        cb.time = start + DEFAULT_HALF_OPEN_TIMEOUT;
+        let p = net.feed(cb, &mut Panic, 5000, CONNECT_PACKET, &mut buffer[..]).0.collect_vec();
+        let pid = match &p[..] { &[ChunkOrEvent::Connect(pid, 5000)] => pid, _ => panic!() };
+        assert_eq!(net.num_half_open(), 1);
+        assert_eq!(net.peer_ids().collect_vec(), [pid]);
+        net.accept(cb, pid).void_unwrap();
+        net.feed(cb, &mut Panic, 5000, accept, &mut buffer[..]).0.count();
+        assert!(net.is_online(pid));
+        assert_eq!(net.num_half_open(), 0);
+
+        // Online peers don't expire, and `tick` removes expired half-open
+        // ones as well.
+        net.feed(cb, &mut Ignore, 5001, CONNECT_PACKET, &mut buffer[..]).0.count();
+        assert_eq!(net.num_half_open(), 1);
+        cb.time = cb.time + DEFAULT_HALF_OPEN_TIMEOUT;
+        net.tick(cb).count();
+        assert_eq!(net.num_half_open(), 0);
+        assert_eq!(net.peer_ids().collect_vec(), [pid]);
+        assert!(net.peers.half_open.is_empty());
+
+        // Without a timeout, half-open connections stay.
+        let mut net: Net<u32> = Net::with_config(NetConfig {
+            half_open_timeout: None,
+            ..NetConfig::default()
+        });
+        net.feed(cb, &mut Ignore, 0, CONNECT_PACKET, &mut buffer[..]).0.count();
+        cb.time = cb.time + Duration::from_secs(1000);
+        net.tick(cb).count();
+        assert_eq!(net.num_half_open(), 1);
+        assert!(net.peers.half_open.is_empty());
+    }
+
+    #[test]
+    fn roles() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        assert_eq!(Net::<Address>::server().config().role, Role::Server);
+        assert_eq!(Net::<Address>::client().config().role, Role::Client);
+        assert_eq!(Net::<Address>::both().config().role, Role::Both);
+        assert_eq!(NetConfig::default().role, Role::Both);
+
+        let mut server = Net::server();
+        assert_eq!(server.try_connect(cb, Address::Server).err(), Some(ConnectError::ServerRole));
+        assert!(server.peer_ids().next().is_none());
+        assert!(cb.packets.is_empty());
+        // `both` and the default configuration keep connecting.
+        cb.recipient = Address::Server;
+        Net::both().connect(cb, Address::Server).1.void_unwrap();
+        assert_eq!(cb.packets.pop_front().unwrap(), CONNECT_PACKET);
+        Net::with_config(NetConfig::default()).connect(cb, Address::Server).1.void_unwrap();
+        assert_eq!(cb.packets.pop_front().unwrap(), CONNECT_PACKET);
+
+        // A client ignores connection attempts even if told to accept them.
+        let mut client = Net::with_config(NetConfig {
+            role: Role::Client,
+            ..NetConfig::default()
+        });
+        let mut warnings = vec![];
+        assert!(client.feed(cb, &mut WarnVec(&mut warnings), Address::Server, CONNECT_PACKET, &mut buffer[..]).0.next().is_none());
+        assert_matches!(&warnings[..], &[Warning::ControlForOtherRole(Address::Server, protocol::CTRLMSG_CONNECT)]);
+        cb.recipient = Address::Server;
+        let (pid, res) = client.try_connect(cb, Address::Server).unwrap();
+        res.void_unwrap();
+        assert_eq!(client.peer_direction(pid), Direction::Outgoing);
+
+        let mut both = Net::with_capacity(1);
+        assert_eq!(both.config().role, Role::Both);
+        both.try_connect(cb, Address::Server).unwrap().1.void_unwrap();
+        assert_eq!(both.try_connect(cb, Address::Server).err(), Some(ConnectError::Full));
+        cb.recipient = Address::Client;
+        let mut both = Net::both();
+        let p = both.feed(cb, &mut Panic, Address::Client, CONNECT_PACKET, &mut buffer[..]).0.collect_vec();
+        assert_matches!(&p[..], &[ChunkOrEvent::Connect(_, Address::Client)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "servers don't connect")]
+    fn server_role_connect() {
+        Net::server().connect(&mut Cb::new(), Address::Server);
+    }
+
+    #[test]
+    fn ban_address() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut net = Net::server();
+        let pid = accept_client(cb, &mut net);
+
+        // Banning drops the peer, later packets are ignored without a reply.
+        assert_eq!(net.ban_address(Address::Client), Some(pid));
+        assert!(net.is_banned(Address::Client));
+        assert!(net.peer_ids().next().is_none());
+        assert!(net.feed(cb, &mut Panic, Address::Client, CONNECT_PACKET, &mut buffer[..]).0.next().is_none());
+        assert!(net.feed(cb, &mut Panic, Address::Client, b"\xff\xff\xff\xff\xff\xffinfo", &mut buffer[..]).0.next().is_none());
+        assert!(net.feed(cb, &mut Panic, Address::Client, b"garbage", &mut buffer[..]).0.next().is_none());
+        assert!(cb.packets.is_empty());
+        assert!(net.peer_ids().next().is_none());
+        assert_eq!(net.ban_address(Address::Client), None);
+
+        // Other addresses are unaffected.
+        assert!(!net.is_banned(Address::Server));
+        cb.recipient = Address::Server;
+        let p = net.feed(cb, &mut Panic, Address::Server, CONNECT_PACKET, &mut buffer[..]).0.collect_vec();
+        assert_matches!(&p[..], &[ChunkOrEvent::Connect(_, Address::Server)]);
+
+        assert!(net.unban_address(Address::Client));
+        assert!(!net.unban_address(Address::Client));
+        let p = net.feed(cb, &mut Panic, Address::Client, CONNECT_PACKET, &mut buffer[..]).0.collect_vec();
+        assert_matches!(&p[..], &[ChunkOrEvent::Connect(_, Address::Client)]);
+    }
+
+    #[test]
+    fn ban_subnet() {
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let addr = |s: &str| -> SocketAddr { s.parse().unwrap() };
+
+        let mut net = Net::server();
+        net.feed(cb, &mut Panic, addr("1.2.3.4:1000"), CONNECT_PACKET, &mut buffer[..]).0.count();
+        net.feed(cb, &mut Panic, addr("1.2.3.4:2000"), CONNECT_PACKET, &mut buffer[..]).0.count();
+        net.feed(cb, &mut Panic, addr("1.2.4.1:1000"), CONNECT_PACKET, &mut buffer[..]).0.count();
+        let pids = net.peer_ids().collect_vec();
+        assert_eq!(pids.len(), 3);
+
+        // Both ports and the rest of the subnet are banned.
+        assert_eq!(net.ban_subnet("1.2.3.99".parse().unwrap(), 24), &pids[..2]);
+        assert_eq!(net.peer_ids().collect_vec(), &pids[2..]);
+        for a in &["1.2.3.4:1000", "1.2.3.4:3000", "1.2.3.255:1"] {
+            assert!(net.is_banned(addr(a)));
+            assert!(net.feed(cb, &mut Panic, addr(a), CONNECT_PACKET, &mut buffer[..]).0.next().is_none());
+        }
+        assert!(!net.is_banned(addr("1.2.4.2:1000")));
+        assert!(!net.is_banned(addr("[::1.2.3.4]:1000")));
+        assert_eq!(net.peer_ids().count(), 1);
+        cb.packets.clear();
+
+        // A single IP address, regardless of the port.
+        assert_eq!(net.ban_subnet("1.2.4.1".parse().unwrap(), 32), &pids[2..]);
+        assert!(net.is_banned(addr("1.2.4.1:5000")));
+        assert!(!net.is_banned(addr("1.2.4.2:1000")));
+        net.ban_subnet("2001:db8::".parse().unwrap(), 32);
+        assert!(net.is_banned(addr("[2001:db8:1::1]:8303")));
+        assert!(!net.is_banned(addr("[2001:db9::1]:8303")));
+
+        assert!(!net.unban_subnet("1.2.3.4".parse().unwrap(), 16));
+        assert!(net.unban_subnet("1.2.3.4".parse().unwrap(), 24));
+        assert!(!net.unban_subnet("1.2.3.4".parse().unwrap(), 24));
+        let p = net.feed(cb, &mut Panic, addr("1.2.3.4:3000"), CONNECT_PACKET, &mut buffer[..]).0.collect_vec();
+        assert_matches!(&p[..], &[ChunkOrEvent::Connect(..)]);
+        assert!(cb.packets.is_empty());
+    }
+
+    #[test]
+    fn record_packet_headers() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        // Not recorded by default.
+        let mut net = Net::server();
+        cb.recipient = Address::Client;
+        net.feed(cb, &mut Panic, Address::Client, CONNECT_PACKET, &mut buffer[..]);
+        assert_eq!(net.last_packet_header(), None);
+
+        let mut net = Net::with_config(NetConfig {
+            record_packet_headers: true,
+            ..NetConfig::default()
+        });
+        let p = net.feed(cb, &mut Panic, Address::Client, CONNECT_PACKET, &mut buffer[..]).0.collect_vec();
+        assert_eq!(net.last_packet_header(), Some(PacketHeader {
+            flags: protocol::PACKETFLAG_CONTROL,
+            ack: 0,
+            num_chunks: 0,
+        }));
+        let pid = match &p[..] {
+            &[ChunkOrEvent::Connect(pid, Address::Client)] => pid,
+            _ => panic!("expected connect, got {:?}", p),
+        };
+        net.accept(cb, pid).void_unwrap();
+
+        // Two chunks, requesting a resend and acknowledging sequence 0x123.
+        let chunks = b"\x41\x23\x02\x40\x01\x01\x42\x00\x01\x43";
+        let mut warnings = vec![];
+        let p = net.feed(cb, &mut WarnVec(&mut warnings), Address::Client, chunks, &mut buffer[..]).0;
+        assert_eq!(p.len(), 2);
+        assert_eq!(net.last_packet_header(), Some(PacketHeader {
+            flags: protocol::PACKETFLAG_REQUEST_RESEND,
+            ack: 0x123,
+            num_chunks: 2,
+        }));
+
+        let mut warnings = vec![];
+        net.feed(cb, &mut WarnVec(&mut warnings), Address::Client, b"\x00", &mut buffer[..]);
+        assert_eq!(net.last_packet_header(), None);
+    }
+
+    #[test]
+    fn send_to_addr() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut net = Net::server();
+        cb.recipient = Address::Client;
+        assert_eq!(net.send_to_addr(cb, Address::Client, b"1", true).unwrap(), SendPath::NonPeerConnless);
+        assert_eq!(cb.packets.pop_front().unwrap(), b"\xff\xff\xff\xff\xff\xff1");
+
+        let pid = connect_client(cb, &mut net);
+        assert_eq!(net.send_to_addr(cb, Address::Client, b"2", true).unwrap(), SendPath::PeerConnless(pid));
+        assert_eq!(cb.packets.pop_front().unwrap(), b"\xff\xff\xff\xff\xff\xff2");
+
+        net.accept(cb, pid).void_unwrap();
+        cb.packets.clear();
+        // Still connless until the client acknowledges the connect accept.
+        assert!(!net.is_online(pid));
+        assert_eq!(net.send_to_addr(cb, Address::Client, b"3", true).unwrap(), SendPath::PeerConnless(pid));
+        cb.packets.clear();
+        assert!(net.feed(cb, &mut Panic, Address::Client, b"\x10\x00\x00\x03", &mut buffer[..]).0.next().is_none());
+        assert!(net.is_online(pid));
+        assert_eq!(net.send_to_addr(cb, Address::Client, b"3", true).unwrap(), SendPath::PeerConnected(pid));
+        net.flush(cb, pid).void_unwrap();
+        assert_eq!(cb.packets.pop_front().unwrap(), b"\x00\x00\x01\x40\x01\x013");
+        assert!(cb.packets.is_empty());
+    }
+
+    #[test]
+    fn pending_bytes() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut net = Net::client();
+        let (pid, res) = net.connect(cb, Address::Server);
+        res.void_unwrap();
+        net.feed(cb, &mut Panic, Address::Server, b"\x10\x00\x00\x02", &mut buffer[..]).0.count();
+        cb.packets.clear();
+        assert_eq!(net.peer_pending_bytes(pid), 0);
+
+        net.send_vital(cb, pid, b"ab").unwrap();
+        net.send_unreliable(cb, pid, b"cde").unwrap();
+        assert_eq!(net.peer_pending_bytes(pid), 5);
+        assert_eq!(net.total_pending_bytes(), 5);
+
+        // Flushed non-vital chunks are done, vital ones wait for the ack.
+        net.flush(cb, pid).void_unwrap();
+        assert_eq!(cb.packets.len(), 1);
+        assert_eq!(net.peer_pending_bytes(pid), 2);
+
+        // A keep-alive acknowledging the vital chunk.
+        net.feed(cb, &mut Panic, Address::Server, b"\x10\x01\x00\x00", &mut buffer[..]).0.count();
+        assert_eq!(net.peer_pending_bytes(pid), 0);
+        assert_eq!(net.total_pending_bytes(), 0);
+    }
+
+    #[test]
+    fn clone_address() {
+        // An address type that isn't `Copy`.
+        let mut cb = TimeCb::<String>::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let server_addr = "server".to_owned();
+        let client_addr = "client".to_owned();
+
+        let mut server = Net::server();
+        let mut client = Net::client();
+        let (c_pid, res) = client.connect(cb, server_addr.clone());
+        res.void_unwrap();
+        let (addr, packet) = cb.packets.pop_front().unwrap();
+        assert_eq!(addr, server_addr);
+        let p = server.feed(cb, &mut Panic, client_addr.clone(), &packet, &mut buffer[..]).0.collect_vec();
+        let s_pid = match &p[..] {
+            &[ChunkOrEvent::Connect(pid, ref addr)] if *addr == client_addr => pid,
+            _ => panic!("expected connect, got {:?}", p),
+        };
+        server.accept(cb, s_pid).void_unwrap();
+        let (addr, packet) = cb.packets.pop_front().unwrap();
+        assert_eq!(addr, client_addr);
+        assert!(client.feed(cb, &mut Panic, server_addr.clone(), &packet, &mut buffer[..]).0.collect_vec()
+                == &[ChunkOrEvent::Ready(c_pid)]);
+        let (addr, packet) = cb.packets.pop_front().unwrap();
+        assert_eq!(addr, server_addr);
+        server.feed(cb, &mut Panic, client_addr.clone(), &packet, &mut buffer[..]).0.count();
+
+        server.send_vital(cb, s_pid, b"hello").unwrap();
+        server.flush(cb, s_pid).void_unwrap();
+        let (addr, packet) = cb.packets.pop_front().unwrap();
+        assert_eq!(addr, client_addr);
+        assert!(client.feed(cb, &mut Panic, server_addr.clone(), &packet, &mut buffer[..]).0.collect_vec()
+                == &[ChunkOrEvent::Chunk(Chunk { pid: c_pid, vital: true, data: b"hello" })]);
+        assert!(cb.packets.is_empty());
+    }
+
+    #[test]
+    fn full_reason() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut server = Net::with_capacity(0);
+        server.set_full_reason(b"no slots left");
+        let mut client = Net::client();
+
+        cb.recipient = Address::Server;
+        let (c_pid, res) = client.connect(cb, Address::Server);
+        res.void_unwrap();
+        let packet = cb.packets.pop_front().unwrap();
+
+        cb.recipient = Address::Client;
+        assert!(server.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..]).0.next().is_none());
+        let packet = cb.packets.pop_front().unwrap();
+        assert!(cb.packets.is_empty());
+
+        assert!(client.feed(cb, &mut Panic, Address::Server, &packet, &mut buffer[..]).0.collect_vec()
+                == &[ChunkOrEvent::Disconnect(c_pid, DisconnectReason::Rejected, b"no slots left")]);
+        assert!(cb.packets.is_empty());
+    }
+
+    #[test]
+    fn filter_receive_packet() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut net = Net::both();
+        cb.recipient = Address::Server;
+        let (c_pid, res) = net.connect(cb, Address::Server);
+        res.void_unwrap();
+        cb.packets.clear();
+        let accept = b"\x10\x00\x00\x02";
+        let p = net.feed(cb, &mut Panic, Address::Server, accept, &mut buffer[..]).0;
+        assert!(p.clone().vital_only().next().is_none());
+        assert!(p.events_only().collect_vec() == &[ChunkOrEvent::Ready(c_pid)]);
+        cb.packets.clear();
+
+        // One vital chunk "\x42" and one non-vital chunk "\x43".
+        let chunks = b"\x00\x01\x02\x40\x01\x01\x42\x00\x01\x43";
+        let p = net.feed(cb, &mut Panic, Address::Server, chunks, &mut buffer[..]).0;
+        assert_eq!(p.len(), 2);
+        assert!(p.clone().events_only().next().is_none());
+        assert!(p.vital_only().collect_vec() == &[Chunk { pid: c_pid, vital: true, data: b"\x42" }]);
+    }
+
+    #[cfg(feature = "fuzzing")]
+    quickcheck! {
+        fn feed_fuzz(data: Vec<u8>) -> bool {
+            Net::feed_fuzz(&data);
+            true
+        }
+    }
+
+    #[cfg(feature = "fuzzing")]
+    #[test]
+    fn feed_fuzz_corpus() {
+        use std::fs;
+        use std::io::Read;
+
+        let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/feed");
+        for entry in fs::read_dir(corpus).unwrap() {
+            let mut data = vec![];
+            fs::File::open(entry.unwrap().path()).unwrap().read_to_end(&mut data).unwrap();
+            Net::feed_fuzz(&data);
+        }
+    }
+
+    #[test]
+    fn address_with_token() {
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let addr1 = AddressWithToken { addr: Address::Client, token: 1 };
+        let addr2 = AddressWithToken { addr: Address::Client, token: 2 };
+
+        // Two clients from the same socket address get separate peers.
+        let mut net = Net::server();
+        let p = net.feed(cb, &mut Panic, addr1, CONNECT_PACKET, &mut buffer[..]).0.collect_vec();
+        let pid1 = match p[..] { [ChunkOrEvent::Connect(pid, a)] if a == addr1 => pid, _ => panic!() };
+        let p = net.feed(cb, &mut Panic, addr2, CONNECT_PACKET, &mut buffer[..]).0.collect_vec();
+        let pid2 = match p[..] { [ChunkOrEvent::Connect(pid, a)] if a == addr2 => pid, _ => panic!() };
+        assert!(pid1 != pid2);
+
+        net.accept(cb, pid2).void_unwrap();
+        assert_eq!(cb.recipients(), [addr2]);
+    }
+
+    #[test]
+    fn address_normalization() {
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:8303".parse().unwrap();
+        let native: SocketAddr = "1.2.3.4:8303".parse().unwrap();
+        assert_eq!(normalize_socket_addr(&mapped), native);
+        assert_eq!(normalize_socket_addr(&native), native);
+        let v6: SocketAddr = "[2001:db8::1]:8303".parse().unwrap();
+        assert_eq!(normalize_socket_addr(&v6), v6);
+
+        // Without normalization, the two forms are different peers.
+        let mut net = Net::server();
+        net.feed(cb, &mut Panic, mapped, CONNECT_PACKET, &mut buffer[..]).0.count();
+        net.feed(cb, &mut Panic, native, CONNECT_PACKET, &mut buffer[..]).0.count();
+        assert_eq!(net.peer_ids().count(), 2);
+
+        let mut net = Net::server();
+        net.set_address_normalization(Some(normalize_socket_addr));
+        let p = net.feed(cb, &mut Panic, mapped, CONNECT_PACKET, &mut buffer[..]).0.collect_vec();
+        let pid = match p[..] { [ChunkOrEvent::Connect(pid, a)] if a == mapped => pid, _ => panic!() };
+        net.accept(cb, pid).void_unwrap();
+        assert!(net.feed(cb, &mut Panic, native, CONNECT_PACKET, &mut buffer[..]).0.next().is_none());
+        assert_eq!(net.peer_ids().collect_vec(), [pid]);
+        // Replies go to the address the peer was created with.
+        assert!(cb.recipients().iter().all(|&a| a == mapped));
+
+        assert_eq!(net.ban_address(native), Some(pid));
+        assert!(net.is_banned(mapped));
+        assert!(net.feed(cb, &mut Panic, mapped, CONNECT_PACKET, &mut buffer[..]).0.next().is_none());
+        assert!(net.peer_ids().next().is_none());
+    }
+
+    #[test]
+    fn disconnect_graceful() {
+        let start = Timestamp::from_secs_since_epoch(0);
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let close = &b"\x10\x00\x00\x04foobar\0"[..];
+
+        let mut net = Net::client();
+        let (pid, res) = net.connect(cb, Address::Server);
+        res.void_unwrap();
+        net.feed(cb, &mut Panic, Address::Server, b"\x10\x00\x00\x02", &mut buffer[..]).0.count();
+        cb.packets.clear();
+
+        // The first close gets lost, it is resent until the timeout.
+        net.disconnect_graceful(cb, pid, b"foobar").void_unwrap();
+        assert_eq!(cb.packets.pop_front().unwrap().1, close);
+        let mut resends = 0;
+        loop {
+            cb.time = net.needs_tick().to_opt().unwrap();
+            assert!(net.tick(cb).next().is_none());
+            if net.peers.get(pid).is_none() {
+                break;
+            }
+            assert_eq!(cb.packets.pop_front().unwrap(), (Address::Server, close.to_owned()));
+            resends += 1;
+        }
+        assert_eq!(resends, 3);
+        assert!(cb.packets.is_empty());
+        assert_eq!(cb.time, start + Duration::from_secs(2));
+        assert!(!net.needs_tick().is_active());
+
+        // A close from the peer acknowledges the disconnect.
+        let (pid, res) = net.connect(cb, Address::Server);
+        res.void_unwrap();
+        net.feed(cb, &mut Panic, Address::Server, b"\x10\x00\x00\x02", &mut buffer[..]).0.count();
+        net.disconnect_graceful(cb, pid, b"foobar").void_unwrap();
+        assert!(net.feed(cb, &mut Panic, Address::Server, close, &mut buffer[..]).0.next().is_none());
+        assert!(net.peers.get(pid).is_none());
+    }
+
+    #[test]
+    fn drop_peer() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut net = Net::server();
+        let pid = connect_client(cb, &mut net);
+        net.accept(cb, pid).void_unwrap();
+        let chunk = b"\x00\x00\x01\x40\x01\x01\x42";
+        net.feed(cb, &mut Panic, Address::Client, chunk, &mut buffer[..]).0.count();
+        net.send_vital(cb, pid, b"\x42").unwrap();
+        cb.packets.clear();
+
+        // Nothing is sent, not even the queued chunk.
+        assert!(net.drop_peer(pid));
+        assert!(!net.drop_peer(pid));
+        assert!(!net.needs_tick().is_active());
+        assert!(cb.packets.is_empty());
+
+        // The next packet from the same address starts a fresh connection.
+        let new_pid = connect_client(cb, &mut net);
+        assert!(new_pid != pid);
+    }
+
+    #[test]
+    fn config() {
+        let mut cb = Cb::new();
+        let cb = &mut cb;
+
+        let mut config = NetConfig::default();
+        config.accept_connections = false;
+        config.connection.keep_alive_interval = Duration::from_millis(100);
+        let mut net = Net::with_config(config.clone());
+        assert_eq!(net.config(), &config);
+
+        // Connection attempts are repeated at the configured interval.
+        net.connect(cb, Address::Server).1.void_unwrap();
+        assert_eq!(net.needs_tick().to_opt(),
+                   Some(Timestamp::from_secs_since_epoch(0) + Duration::from_millis(100)));
+
+        net.set_peer_pool_size(0);
+        assert_eq!(net.config().peer_pool_size, 0);
+    }
+
+    // Delivers the packets sent to `addr` to `net`, returning the chunks.
+    fn deliver<T>(cb: &mut TimeCb, net: &mut Net<Address, T>, from: Address, to: Address) -> Vec<Vec<u8>> {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut result = vec![];
+        let packets: Vec<_> = cb.packets.drain(..).collect();
+        for (addr, packet) in packets {
+            assert_eq!(addr, to);
+            for chunk in net.feed(cb, &mut Panic, from, &packet, &mut buffer[..]).0 {
+                if let ChunkOrEvent::Chunk(c) = chunk {
+                    result.push(c.data.to_owned());
+                }
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn resume_peer() {
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+
+        let mut client = Net::client();
+        let mut server = Net::server();
+        let (c_pid, s_pid) = handshake(cb, &mut client, &mut server);
+        assert!(server.save_peer(s_pid).is_some());
+
+        // The server's chunk gets lost, the client's arrives.
+        server.send_vital(cb, s_pid, b"a").unwrap();
+        server.flush(cb, s_pid).void_unwrap();
+        cb.packets.clear();
+        client.send_vital(cb, c_pid, b"x").unwrap();
+        client.flush(cb, c_pid).void_unwrap();
+        assert_eq!(deliver(cb, &mut server, Address::Client, Address::Server), [b"x"]);
+
+        let saved = server.save_peer(s_pid).unwrap();
+        assert_eq!(saved, SavedPeer {
+            addr: Address::Client,
+            direction: Direction::Incoming,
+            connection: SavedConnection {
+                ack: 1,
+                sequence: 1,
+                unacked: vec![SavedChunk { sequence: 1, data: b"a".to_vec() }],
+            },
+        });
+        drop(server);
+
+        // A new server continues the connection.
+        let mut server = Net::server();
+        let s_pid = server.resume_peer(cb, &saved).unwrap();
+        assert_eq!(server.peer_direction(s_pid), Direction::Incoming);
+        assert_eq!(server.save_peer(s_pid).unwrap(), saved);
+        assert_eq!(server.resume_peer(cb, &saved), None);
+
+        cb.time = cb.time + Duration::from_secs(1);
+        server.tick(cb).count();
+        server.send_vital(cb, s_pid, b"b").unwrap();
+        server.flush(cb, s_pid).void_unwrap();
+        assert_eq!(deliver(cb, &mut client, Address::Server, Address::Client), [b"a", b"b"]);
+        client.send_vital(cb, c_pid, b"y").unwrap();
+        client.flush(cb, c_pid).void_unwrap();
+        assert_eq!(deliver(cb, &mut server, Address::Client, Address::Server), [b"y"]);
+        assert!(server.save_peer(s_pid).unwrap().connection.unacked.is_empty());
+
+        let mut invalid = saved.clone();
+        invalid.connection.sequence = protocol::SEQUENCE_MODULUS;
+        assert_eq!(Net::server().resume_peer(cb, &invalid), None);
+    }
+
+    #[test]
+    fn hand_off_peer() {
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut client = Net::client();
+        let mut old = Net::server();
+        let (c_pid, o_pid) = handshake(cb, &mut client, &mut old);
+
+        // Queued chunks are sent as part of the handoff.
+        old.send_vital(cb, o_pid, b"a").unwrap();
+        old.send_with_priority(cb, Chunk::vital(o_pid, b"b"), Priority::High).unwrap();
+        let saved = old.hand_off_peer(cb, o_pid).void_unwrap().unwrap();
+        assert_eq!(old.peer_ids().count(), 0);
+        assert_eq!(saved.connection.sequence, 2);
+        assert_eq!(deliver(cb, &mut client, Address::Server, Address::Client), [b"a", b"b"]);
+
+        // A chunk arriving at the old server in the meantime is lost and
+        // resent to the new one.
+        client.send_vital(cb, c_pid, b"x").unwrap();
+        client.flush(cb, c_pid).void_unwrap();
+        let (_, packet) = cb.packets.pop_front().unwrap();
+        let mut warnings = vec![];
+        assert!(old.feed(cb, &mut WarnVec(&mut warnings), Address::Client, &packet, &mut buffer[..]).0.next().is_none());
+        assert!(!warnings.is_empty());
+
+        let mut new = Net::server();
+        let n_pid = new.resume_peer(cb, &saved).unwrap();
+        cb.time = cb.time + Duration::from_secs(1);
+        client.tick(cb).count();
+        client.flush(cb, c_pid).void_unwrap();
+        assert_eq!(deliver(cb, &mut new, Address::Client, Address::Server), [b"x"]);
+        new.send_vital(cb, n_pid, b"c").unwrap();
+        new.flush(cb, n_pid).void_unwrap();
+        assert_eq!(deliver(cb, &mut client, Address::Server, Address::Client), [b"c"]);
+
+        // Connections that aren't online can't be handed off.
+        let p = old.feed(cb, &mut Panic, Address::Client, CONNECT_PACKET, &mut buffer[..]).0.collect_vec();
+        let o_pid = match p[..] { [ChunkOrEvent::Connect(pid, Address::Client)] => pid, _ => panic!() };
+        assert_eq!(old.hand_off_peer(cb, o_pid).void_unwrap(), None);
+        assert_eq!(old.peer_ids().count(), 1);
+    }
+
+    #[test]
+    fn chunk_reply() {
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut client = Net::client();
+        let mut server = Net::server();
+        let (c_pid, s_pid) = handshake(cb, &mut client, &mut server);
+
+        client.send(cb, Chunk::vital(c_pid, b"vital")).unwrap();
+        client.send(cb, Chunk::unreliable(c_pid, b"unreliable")).unwrap();
+        client.flush(cb, c_pid).void_unwrap();
+
+        // Echo the chunks in upper case.
+        let (_, packet) = cb.packets.pop_front().unwrap();
+        let received: Vec<_> = server.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..]).0
+            .filter_map(|c| match c { ChunkOrEvent::Chunk(c) => Some(c), _ => None })
+            .map(|c| (c, c.data.to_ascii_uppercase()))
+            .collect();
+        for &(chunk, ref upper) in &received {
+            let reply = chunk.reply(upper);
+            assert_eq!((reply.pid, reply.vital), (s_pid, chunk.vital));
+            server.send(cb, reply).unwrap();
+        }
+        server.flush(cb, s_pid).void_unwrap();
+
+        let (_, packet) = cb.packets.pop_front().unwrap();
+        let echoed: Vec<_> = client.feed(cb, &mut Panic, Address::Server, &packet, &mut buffer[..]).0
+            .filter_map(|c| match c { ChunkOrEvent::Chunk(c) => Some((c.vital, c.data.to_owned())), _ => None })
+            .collect();
+        assert_eq!(echoed, [(true, b"VITAL".to_vec()), (false, b"UNRELIABLE".to_vec())]);
+    }
+
+    thread_local! {
+        static LOGGED: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    }
+
+    // Collects the log messages of the current thread.
+    struct ThreadLogger;
+
+    impl log::Log for ThreadLogger {
+        fn enabled(&self, _: &log::LogMetadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::LogRecord) {
+            let message = format!("{}", record.args());
+            let _ = LOGGED.try_with(|l| l.borrow_mut().push(message));
+        }
+    }
+
+    fn logged() -> Vec<String> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            let _ = log::set_logger(|max| {
+                max.set(log::LogLevelFilter::Debug);
+                Box::new(ThreadLogger)
+            });
+        });
+        LOGGED.with(|l| l.borrow_mut().drain(..).collect())
+    }
+
+    #[test]
+    fn debug_dump_peer() {
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+        logged();
+
+        let mut client = Net::client();
+        let mut server = Net::server();
+        let (c_pid, s_pid) = handshake(cb, &mut client, &mut server);
+        // Off by default.
+        assert!(logged().is_empty());
+
+        server.debug_dump_peer(s_pid, true);
+        client.send_vital(cb, c_pid, b"ping").unwrap();
+        client.flush(cb, c_pid).void_unwrap();
+        assert_eq!(deliver(cb, &mut server, Address::Client, Address::Server), [b"ping"]);
+        server.send_vital(cb, s_pid, b"pong").unwrap();
+        server.flush(cb, s_pid).void_unwrap();
+        assert_eq!(deliver(cb, &mut client, Address::Server, Address::Client), [b"pong"]);
+        let lines = logged();
+        let headers: Vec<_> = lines.iter().filter(|l| l.contains(" bytes")).collect();
+        assert_eq!(headers.len(), 2);
+        assert!(headers[0].starts_with(&format!("{:?} (Incoming): received ", s_pid)), "{}", headers[0]);
+        assert!(headers[1].starts_with(&format!("{:?} (Incoming): sent ", s_pid)), "{}", headers[1]);
+        assert!(lines.iter().any(|l| l.contains("ping")));
+        assert!(lines.iter().any(|l| l.contains("pong")));
+
+        server.debug_dump_peer(s_pid, false);
+        server.send_vital(cb, s_pid, b"pong").unwrap();
+        server.flush(cb, s_pid).void_unwrap();
+        assert!(logged().is_empty());
+    }
+
+    #[test]
+    fn peer_userdata() {
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut client = Net::client();
+        let mut server: Net<Address, Vec<&str>> = Net::with_userdata(NetConfig::default());
+        let (c_pid, res) = client.connect(cb, Address::Server);
+        res.void_unwrap();
+        let (_, packet) = cb.packets.pop_front().unwrap();
+        let p = server.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..]).0.collect_vec();
+        let s_pid = match p[..] { [ChunkOrEvent::Connect(pid, Address::Client)] => pid, _ => panic!() };
+        assert_eq!(server.peer_userdata(s_pid), None);
+        assert_eq!(server.set_peer_userdata(s_pid, vec!["connected"]), None);
+        server.accept(cb, s_pid).void_unwrap();
+        deliver(cb, &mut client, Address::Server, Address::Client);
+        deliver(cb, &mut server, Address::Client, Address::Server);
+
+        server.peer_userdata_mut(s_pid).unwrap().push("accepted");
+        assert_eq!(server.peer_userdata(s_pid).unwrap(), &["connected", "accepted"]);
+        assert_eq!(server.take_peer_userdata(s_pid), Some(vec!["connected", "accepted"]));
+        assert_eq!(server.peer_userdata(s_pid), None);
+
+        // The data goes away with the peer.
+        server.set_peer_userdata(s_pid, vec!["online"]);
+        client.disconnect(cb, c_pid, b"bye").unwrap();
+        let (_, packet) = cb.packets.pop_front().unwrap();
+        let p = server.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..]).0.collect_vec();
+        assert_matches!(p[..], [ChunkOrEvent::Disconnect(pid, ..)] if pid == s_pid);
+        assert_eq!(server.peer_ids().count(), 0);
+    }
+
+    #[test]
+    fn peer_uptime_and_activity() {
+        let start = Timestamp::from_secs_since_epoch(0);
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut client = Net::client();
+        let mut server = Net::server();
+        let (c_pid, res) = client.connect(cb, Address::Server);
+        res.void_unwrap();
+        cb.time = start + Duration::from_secs(1);
+        let (_, packet) = cb.packets.pop_front().unwrap();
+        let p = server.feed(cb, &mut Panic, Address::Client, &packet, &mut buffer[..]).0.collect_vec();
+        let s_pid = match p[..] { [ChunkOrEvent::Connect(pid, Address::Client)] => pid, _ => panic!() };
+        server.accept(cb, s_pid).void_unwrap();
+        cb.time = start + Duration::from_secs(3);
+        deliver(cb, &mut client, Address::Server, Address::Client);
+        cb.time = start + Duration::from_secs(5);
+        deliver(cb, &mut server, Address::Client, Address::Server);
+
+        let now = start + Duration::from_secs(10);
+        assert_eq!(client.peer_uptime(c_pid, now), Duration::from_secs(10));
+        assert_eq!(server.peer_uptime(s_pid, now), Duration::from_secs(9));
+        assert_eq!(client.peer_last_activity(c_pid), start + Duration::from_secs(3));
+        assert_eq!(server.peer_last_activity(s_pid), start + Duration::from_secs(5));
+        assert_eq!(server.peer_uptime(s_pid, start), Duration::from_secs(0));
+
+        // Sending counts as activity, ticking doesn't.
+        cb.time = start + Duration::from_secs(6);
+        server.send_vital(cb, s_pid, b"a").unwrap();
+        assert_eq!(server.peer_last_activity(s_pid), start + Duration::from_secs(6));
+        cb.time = start + Duration::from_secs(7);
+        server.tick(cb).count();
+        assert_eq!(server.peer_last_activity(s_pid), start + Duration::from_secs(6));
+        cb.packets.clear();
+        client.send_vital(cb, c_pid, b"x").unwrap();
+        client.flush(cb, c_pid).void_unwrap();
+        assert_eq!(deliver(cb, &mut server, Address::Client, Address::Server), [b"x"]);
+        assert_eq!(server.peer_last_activity(s_pid), start + Duration::from_secs(7));
+
+        // Malformed packets don't count.
+        cb.time = start + Duration::from_secs(8);
+        server.feed(cb, &mut Ignore, Address::Client, b"\x10", &mut buffer[..]).0.count();
+        assert_eq!(server.peer_last_activity(s_pid), start + Duration::from_secs(7));
+    }
+
+    #[test]
+    fn send_with_priority() {
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+
+        let mut client = Net::client();
+        let mut server = Net::server();
+        let (c_pid, _) = handshake(cb, &mut client, &mut server);
+
+        client.send_with_priority(cb, Chunk::vital(c_pid, b"bulk1"), Priority::Low).unwrap();
+        client.send_with_priority(cb, Chunk::vital(c_pid, b"normal"), Priority::Normal).unwrap();
+        client.send_with_priority(cb, Chunk::unreliable(c_pid, b"input"), Priority::High).unwrap();
+        client.send_with_priority(cb, Chunk::vital(c_pid, b"bulk2"), Priority::Low).unwrap();
+        client.send_vital(cb, c_pid, b"direct").unwrap();
+        assert_eq!(client.peer_pending_bytes(c_pid), 27);
+        assert!(cb.packets.is_empty());
+        assert_matches!(client.send_with_priority(cb, Chunk::vital(c_pid, &[0; protocol::MAX_PAYLOAD + 1]), Priority::Low),
+            Err(Error::TooLongData));
+
+        client.flush(cb, c_pid).void_unwrap();
+        assert_eq!(deliver(cb, &mut server, Address::Client, Address::Server),
+            [&b"direct"[..], b"input", b"normal", b"bulk1", b"bulk2"]);
+        // The unreliable chunk is gone, the vital ones wait for their ack.
+        assert_eq!(client.peer_pending_bytes(c_pid), 22);
+    }
+
+    #[test]
+    fn event_queue() {
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+
+        let mut client = Net::client();
+        let mut server = Net::with_config(NetConfig {
+            event_queue_size: 2,
+            role: Role::Server,
+            ..NetConfig::default()
+        });
+        let (c_pid, res) = client.connect(cb, Address::Server);
+        res.void_unwrap();
+        let (_, packet) = cb.packets.pop_front().unwrap();
+        server.feed_to_queue(cb, &mut Panic, Address::Client, &packet).void_unwrap();
+        server.feed_to_queue(cb, &mut Panic, Address::Client, b"\xff\xff\xff\xff\xff\xffinfo").void_unwrap();
+        assert_eq!(server.num_queued_events(), 2);
+        let s_pid = match server.poll_event() {
+            Some(OwnedChunkOrEvent::Connect(pid, Address::Client)) => pid,
+            e => panic!("expected connect, got {:?}", e),
+        };
+        server.accept(cb, s_pid).void_unwrap();
+        deliver(cb, &mut client, Address::Server, Address::Client);
+        deliver(cb, &mut server, Address::Client, Address::Server);
+
+        client.send_vital(cb, c_pid, b"1").unwrap();
+        client.flush(cb, c_pid).void_unwrap();
+        let (_, first) = cb.packets.pop_front().unwrap();
+        server.feed_to_queue(cb, &mut Panic, Address::Client, &first).void_unwrap();
+
+        // The queue is full, the second chunk is dropped.
+        client.send_vital(cb, c_pid, b"2").unwrap();
+        client.flush(cb, c_pid).void_unwrap();
+        let (_, second) = cb.packets.pop_front().unwrap();
+        server.feed_to_queue(cb, &mut Panic, Address::Client, &second).void_unwrap();
+        assert_eq!(server.num_dropped_packets(), 1);
+
+        assert_eq!(server.poll_event(), Some(OwnedChunkOrEvent::Connless(OwnedConnlessChunk {
+            addr: Address::Client,
+            pid: Some(s_pid),
+            data: b"info".to_vec(),
+        })));
+        assert_eq!(server.poll_event(), Some(OwnedChunkOrEvent::Chunk(OwnedChunk {
+            pid: s_pid,
+            vital: true,
+            data: b"1".to_vec(),
+        })));
+        assert_eq!(server.poll_event(), None);
+
+        // Once there's room again, the resent chunk gets through.
+        server.feed_to_queue(cb, &mut Panic, Address::Client, &second).void_unwrap();
+        assert_eq!(server.poll_event(), Some(OwnedChunkOrEvent::Chunk(OwnedChunk {
+            pid: s_pid,
+            vital: true,
+            data: b"2".to_vec(),
+        })));
+        assert_eq!(server.num_queued_events(), 0);
+    }
+
+    #[test]
+    fn tick_to_queue() {
+        let start = Timestamp::from_secs_since_epoch(0);
+        let mut cb = TimeCb::<u32>::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+
+        let mut server = Net::with_config(NetConfig {
+            event_queue_size: 1,
+            role: Role::Server,
+            ..NetConfig::default()
+        });
+        let mut pids = Vec::new();
+        for addr in 0..2 {
+            for chunk in server.feed(cb, &mut Panic, addr, CONNECT_PACKET, &mut buffer[..]).0 {
+                if let ChunkOrEvent::Connect(pid, _) = chunk {
+                    pids.push(pid);
+                }
+            }
+        }
+        server.accept(cb, pids[0]).void_unwrap();
+
+        // Only one of the expired peers fits into the queue, the other one
+        // is kept until there's room again.
+        cb.time = start + DEFAULT_HALF_OPEN_TIMEOUT;
+        server.tick_to_queue(cb).void_unwrap();
+        assert_eq!(server.peer_ids().collect_vec(), [pids[1]]);
+        server.tick_to_queue(cb).void_unwrap();
+        assert_eq!(server.poll_event(), Some(OwnedChunkOrEvent::Disconnect(pids[0], DisconnectReason::HandshakeTimeout, vec![])));
+        server.tick_to_queue(cb).void_unwrap();
+        assert_eq!(server.poll_event(), Some(OwnedChunkOrEvent::Disconnect(pids[1], DisconnectReason::HandshakeTimeout, vec![])));
+        assert_eq!(server.peer_ids().count(), 0);
+
+        let mut client = Net::client();
+        let (pid, res) = client.connect(cb, 0);
+        res.void_unwrap();
+        client.feed(cb, &mut Panic, 0, b"\x10\x00\x00\x02", &mut buffer[..]).0.count();
+        client.disconnect_graceful(cb, pid, b"foobar").void_unwrap();
+        while client.peers.get(pid).is_some() {
+            cb.time = client.needs_tick().to_opt().unwrap();
+            client.tick_to_queue(cb).void_unwrap();
+        }
+        assert_eq!(client.poll_event(), Some(OwnedChunkOrEvent::Disconnect(pid, DisconnectReason::Graceful, vec![])));
+        assert_eq!(client.poll_event(), None);
+    }
+
+    #[test]
+    fn connless_dedup() {
+        let mut cb = TimeCb::new();
+        let cb = &mut cb;
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let request = b"\xff\xff\xff\xff\xff\xffgie3\x01";
+        let other_token = b"\xff\xff\xff\xff\xff\xffgie3\x02";
+
+        let mut net: Net<Address> = Net::server();
+        let feed = |net: &mut Net<Address>, cb: &mut TimeCb, addr, data: &[u8]| {
+            let mut buffer = [0; protocol::MAX_PAYLOAD];
+            net.feed(cb, &mut Panic, addr, data, &mut buffer[..]).0.count()
+        };
+        // Disabled by default.
+        assert_eq!(feed(&mut net, cb, Address::Client, request), 1);
+        assert_eq!(feed(&mut net, cb, Address::Client, request), 1);
+        assert_eq!(net.num_deduped_connless(), 0);
+
+        let mut net: Net<Address> = Net::with_config(NetConfig {
+            connless_dedup_window: Some(Duration::from_secs(1)),
+            connless_dedup_capacity: 2,
+            ..NetConfig::default()
+        });
+        let p = net.feed(cb, &mut Panic, Address::Client, request, &mut buffer[..]).0.collect_vec();
+        assert_matches!(&p[..], &[ChunkOrEvent::Connless(ConnlessChunk { addr: Address::Client, data: b"gie3\x01", .. })]);
+        assert_eq!(feed(&mut net, cb, Address::Client, request), 0);
+        assert_eq!(net.num_deduped_connless(), 1);
+        // Other tokens and addresses aren't duplicates.
+        assert_eq!(feed(&mut net, cb, Address::Client, other_token), 1);
+        assert_eq!(feed(&mut net, cb, Address::Server, request), 1);
+        assert_eq!(net.num_deduped_connless(), 1);
+
+        // Only the two most recent packets are remembered.
+        assert_eq!(feed(&mut net, cb, Address::Client, request), 1);
+        assert_eq!(feed(&mut net, cb, Address::Client, request), 0);
+        assert_eq!(net.num_deduped_connless(), 2);
+
+        // After the window, the request is answered again.
+        cb.time = cb.time + Duration::from_secs(1);
+        assert_eq!(feed(&mut net, cb, Address::Client, request), 1);
+        assert_eq!(feed(&mut net, cb, Address::Client, request), 0);
+        assert_eq!(net.num_deduped_connless(), 3);
+    }
+
+    #[test]
+    fn connless_dedup_collision() {
+        // Hashes every payload to the same value.
+        #[derive(Default)]
+        struct Collide;
+        impl Hasher for Collide {
+            fn finish(&self) -> u64 { 0 }
+            fn write(&mut self, _: &[u8]) { }
+        }
+
+        let window = Duration::from_secs(1);
+        let now = Timestamp::from_secs_since_epoch(0);
+        let mut dedup = ConnlessDedup::with_hasher(BuildHasherDefault::<Collide>::default());
+        assert!(!dedup.is_duplicate(window, 16, now, &Address::Client, b"a"));
+        assert!(dedup.is_duplicate(window, 16, now, &Address::Client, b"a"));
+        // Same hash, different payload.
+        assert!(!dedup.is_duplicate(window, 16, now, &Address::Client, b"b"));
+        assert!(!dedup.is_duplicate(window, 16, now, &Address::Client, b"b"));
+        assert!(dedup.is_duplicate(window, 16, now, &Address::Client, b"a"));
+        assert_eq!(dedup.num_deduped, 2);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn saved_peer_serde() {
+        let saved = SavedPeer {
+            addr: AddressWithToken { addr: 1u32, token: 2 },
+            direction: Direction::Outgoing,
+            connection: SavedConnection {
+                ack: 3,
+                sequence: 4,
+                unacked: vec![SavedChunk { sequence: 4, data: b"a".to_vec() }],
+            },
+        };
+        let json = serde_json::to_string(&saved).unwrap();
+        assert_eq!(json, r#"{"addr":{"addr":1,"token":2},"direction":"Outgoing","connection":{"ack":3,"sequence":4,"unacked":[{"sequence":4,"data":[97]}]}}"#);
+        assert_eq!(serde_json::from_str::<SavedPeer<AddressWithToken<u32>>>(&json).unwrap(), saved);
+    }
 }
@@ -0,0 +1,18 @@
+//! Re-exports of the types needed for typical use of `Net`, intended to be
+//! glob-imported: `use net::prelude::*;`.
+
+pub use connection::DisconnectReason;
+pub use net::Address;
+pub use net::AddressWithToken;
+pub use net::Callback;
+pub use net::Chunk;
+pub use net::ChunkOrEvent;
+pub use net::ConnlessChunk;
+pub use net::Error;
+pub use net::Net;
+pub use net::NetConfig;
+pub use net::OwnedChunkOrEvent;
+pub use net::PeerId;
+pub use net::Warning;
+pub use time::Timeout;
+pub use time::Timestamp;
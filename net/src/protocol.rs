@@ -7,6 +7,7 @@ use common::pretty;
 use huffman::instances::TEEWORLDS as HUFFMAN;
 use huffman;
 use std::cmp;
+use std::error;
 use std::fmt;
 use warn::Ignore;
 use warn::Warn;
@@ -14,6 +15,8 @@ use warn::Warn;
 pub const CHUNK_HEADER_SIZE: usize = 2;
 pub const CHUNK_HEADER_SIZE_VITAL: usize = 3;
 pub const HEADER_SIZE: usize = 3;
+/// Maximum size of a packet. Connected packets never exceed it, connless
+/// ones only by default, see `NetConfig::max_connless_packet_size`.
 pub const MAX_PACKETSIZE: usize = 1400;
 pub const PADDING_SIZE_CONNLESS: usize = 3;
 
@@ -64,6 +67,17 @@ impl From<buffer::CapacityError> for Error {
     }
 }
 
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::Capacity(_) => "buffer too small",
+            Error::TooLongData => "data too long to fit into a packet",
+        })
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub enum Warning {
     ChunkHeaderPadding,
@@ -83,12 +97,31 @@ pub enum Warning {
 pub enum PacketReadError {
     Compression,
     ControlMissing,
+    // More chunks declared than fit into the payload.
+    NumChunks,
     ShortConnless,
     TooLong,
     TooShort,
     UnknownControl,
 }
 
+impl error::Error for PacketReadError {}
+
+impl fmt::Display for PacketReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PacketReadError::*;
+        f.write_str(match *self {
+            Compression => "invalid compressed payload",
+            ControlMissing => "control packet without control message",
+            NumChunks => "more chunks declared than fit into the payload",
+            ShortConnless => "connless packet too short",
+            TooLong => "packet too long",
+            TooShort => "packet too short",
+            UnknownControl => "unknown control message",
+        })
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum ControlPacket<'a> {
     KeepAlive,
@@ -281,7 +314,9 @@ fn write_connless_packet<'a, B: Buffer<'a>>(bytes: &[u8], buffer: B)
 impl<'a> Packet<'a> {
     /// Parse a packet.
     ///
-    /// `buffer` needs to have at least size `MAX_PAYLOAD`.
+    /// `buffer` needs to have at least size `MAX_PAYLOAD`. Malformed input is
+    /// reported through the returned error or warnings, it never causes a
+    /// panic.
     pub fn read<'b, B, W>(warn: &mut W, bytes: &'b [u8], buffer: B)
         -> Result<Packet<'b>, PacketReadError>
         where B: Buffer<'b>,
@@ -307,7 +342,7 @@ impl<'a> Packet<'a> {
             }
             let (padding, payload) = payload.split_at(PADDING_SIZE_CONNLESS);
             if !padding.iter().all(|&b| b == 0xff)
-                || !bytes[..3].iter().all(|&b| b == 0xff)
+                || !bytes[..HEADER_SIZE].iter().all(|&b| b == 0xff)
             {
                 warn.warn(Warning::ConnlessPadding);
             }
@@ -370,6 +405,10 @@ impl<'a> Packet<'a> {
             if header.num_chunks == 0 && !request_resend {
                 warn.warn(Warning::ChunksNoChunks);
             }
+            // Each chunk takes at least its header.
+            if header.num_chunks.usize() * CHUNK_HEADER_SIZE > payload.len() {
+                return Err(NumChunks);
+            }
             ConnectedPacketType::Chunks(request_resend, header.num_chunks, payload)
         };
 
@@ -384,12 +423,7 @@ impl<'a> Packet<'a> {
         -> Result<&'c [u8], Error>
     {
         match *self {
-            Packet::Connected(ref p) =>
-                with_buffer(compression_buffer, |cb|
-                    with_buffer(buffer, |b|
-                        p.write_impl(cb, b)
-                    )
-                ),
+            Packet::Connected(ref p) => p.write(compression_buffer, buffer),
             Packet::Connless(ref d) => write_connless_packet(d, buffer),
         }
     }
@@ -403,24 +437,36 @@ impl<'a> ConnectedPacket<'a> {
     {
         with_buffer(compression_buffer, |cb|
             with_buffer(buffer, |b|
-                self.write_impl(cb, b)
+                self.write_impl(Some(cb), b)
             )
         )
     }
+    /// Like `write`, but never compresses the payload.
+    pub fn write_uncompressed<'c, B: Buffer<'c>>(&self, buffer: B)
+        -> Result<&'c [u8], Error>
+    {
+        with_buffer(buffer, |b| self.write_impl(None, b))
+    }
 
     fn write_impl<'d1, 's1, 'd2, 's2>(&self,
-                                      mut compression_buffer: BufferRef<'d1, 's1>,
+                                      compression_buffer: Option<BufferRef<'d1, 's1>>,
                                       mut buffer: BufferRef<'d2, 's2>)
         -> Result<&'d2 [u8], Error>
     {
         match self.type_ {
             ConnectedPacketType::Chunks(request_resend, num_chunks, payload) => {
-                assert!(compression_buffer.remaining() >= MAX_PAYLOAD);
-                let mut compression = 0;
-                let comp_result = compress(payload, &mut compression_buffer);
-                if comp_result.map(|s| s.len() < payload.len()).unwrap_or(false) {
-                    compression = PACKETFLAG_COMPRESSION;
-                }
+                // Only use the compressed payload if it's actually smaller.
+                let compressed = compression_buffer.and_then(|mut cb| {
+                    assert!(cb.remaining() >= MAX_PAYLOAD);
+                    let smaller = compress(payload, &mut cb)
+                        .map(|s| s.len() < payload.len()).unwrap_or(false);
+                    if smaller { Some(cb.initialized()) } else { None }
+                });
+                let compression = if compressed.is_some() {
+                    PACKETFLAG_COMPRESSION
+                } else {
+                    0
+                };
                 let request_resend = if request_resend {
                     PACKETFLAG_REQUEST_RESEND
                 } else {
@@ -431,11 +477,7 @@ impl<'a> ConnectedPacket<'a> {
                     ack: self.ack,
                     num_chunks: num_chunks,
                 }.pack().as_bytes())?;
-                buffer.write(if compression != 0 {
-                    compression_buffer.initialized()
-                } else {
-                    payload
-                })?;
+                buffer.write(compressed.unwrap_or(payload))?;
                 Ok(buffer.initialized())
             }
             ConnectedPacketType::Control(c) => {
@@ -446,6 +488,16 @@ impl<'a> ConnectedPacket<'a> {
 }
 
 impl<'a> ControlPacket<'a> {
+    /// Returns the `CTRLMSG_*` value of the control message.
+    pub fn control_msg(&self) -> u8 {
+        match *self {
+            ControlPacket::KeepAlive => CTRLMSG_KEEPALIVE,
+            ControlPacket::Connect => CTRLMSG_CONNECT,
+            ControlPacket::ConnectAccept => CTRLMSG_CONNECTACCEPT,
+            ControlPacket::Accept => CTRLMSG_ACCEPT,
+            ControlPacket::Close(..) => CTRLMSG_CLOSE,
+        }
+    }
     fn write<'d, 's>(&self, ack: u16, mut buffer: BufferRef<'d, 's>)
         -> Result<&'d [u8], Error>
     {
@@ -454,14 +506,7 @@ impl<'a> ControlPacket<'a> {
             ack: ack,
             num_chunks: 0,
         }.pack().as_bytes())?;
-        let magic = match *self {
-            ControlPacket::KeepAlive => CTRLMSG_KEEPALIVE,
-            ControlPacket::Connect => CTRLMSG_CONNECT,
-            ControlPacket::ConnectAccept => CTRLMSG_CONNECTACCEPT,
-            ControlPacket::Accept => CTRLMSG_ACCEPT,
-            ControlPacket::Close(..) => CTRLMSG_CLOSE,
-        };
-        buffer.write(&[magic])?;
+        buffer.write(&[self.control_msg()])?;
         match *self {
             ControlPacket::Close(m) => {
                 assert!(m.iter().all(|&b| b != 0));
@@ -650,6 +695,7 @@ mod test {
     use super::ConnectedPacket;
     use super::ConnectedPacketType;
     use super::MAX_PACKETSIZE;
+    use super::MAX_PAYLOAD;
     use super::PACKET_FLAGS_BITS;
     use super::Packet;
     use super::PacketHeader;
@@ -707,7 +753,7 @@ mod test {
     #[test] fn w_chs3() { assert_no_warn(b"\x00\x00\x01\x40\x70\xcf") }
     #[test] fn w_cud1() { assert_warn(b"\x00\x00\x00\xff", ChunksUnknownData) }
     #[test] fn w_cud2() { assert_warn(b"\x00\x00\x01\x00\x00\x00", ChunksUnknownData) }
-    #[test] fn w_cnc1() { assert_warn(b"\x00\x00\x01", ChunksNumChunks) }
+    #[test] fn w_cnc1() { assert_warn(b"\x00\x00\x02\x00\x02\x42\x43", ChunksNumChunks) }
     #[test] fn w_cnc2() { assert_warn(b"\x00\x00\x00\x00\x00", ChunksNumChunks) }
     #[test] fn w_cnc_() { assert_warn(b"\x00\x00\x00", ChunksNoChunks) }
     #[test] fn w_cp1() { assert_warn(b"xe\x01\x02\x03\x04", ConnlessPadding) }
@@ -732,6 +778,46 @@ mod test {
     #[test] fn e_uc1() { assert_err(b"\x10\x00\x00\x05", UnknownControl) }
     #[test] fn e_uc2() { assert_err(b"\x10\x00\x00\xff", UnknownControl) }
     #[test] fn e_c() { assert_err(b"\x80\x00\x00", Compression) }
+    #[test] fn e_nc1() { assert_err(b"\x00\x00\x01", NumChunks) }
+    #[test] fn e_nc2() { assert_err(b"\x00\x00\x02\x00\x01\x42", NumChunks) }
+    #[test] fn e_nc3() { assert_err(b"\x00\x00\xff\x40\x01\x01\x42", NumChunks) }
+
+    #[test]
+    fn truncated_packets() {
+        let packets: &[&[u8]] = &[
+            b"\x00\x00\x01\x40\x01\x01\x42",
+            b"\x00\x01\x02\x40\x01\x01\x42\x00\x01\x43",
+            b"\x10\x00\x00\x04foobar\0",
+            b"\xff\xff\xff\xff\xff\xffgie3",
+            b"\x00\x00\x01\x7f\xff\xff",
+        ];
+        for packet in packets {
+            for len in 0..packet.len() + 1 {
+                let mut buffer = [0; MAX_PACKETSIZE];
+                match Packet::read(&mut Ignore, &packet[..len], &mut buffer[..]) {
+                    Ok(Packet::Connected(ConnectedPacket {
+                        type_: ConnectedPacketType::Chunks(_, num_chunks, chunk_data),
+                        ..
+                    })) => {
+                        for c in ChunksIter::new(chunk_data, num_chunks) {
+                            assert!(c.data.len() <= chunk_data.len());
+                        }
+                    }
+                    Ok(_) | Err(_) => {},
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_chunks() {
+        // A chunk claiming more data than is available.
+        assert_warn(b"\x00\x00\x01\x3f\x0f\x00", ChunksUnknownData);
+        // A vital chunk header cut short.
+        assert_warn(b"\x00\x00\x01\x40\x01", ChunksUnknownData);
+        // Maximum sequence number.
+        assert_no_warn(b"\x00\x00\x01\x40\xf1\xff\x00");
+    }
 
     quickcheck! {
         fn packet_header_roundtrip(flags: u8, ack: u16, num_chunks: u8) -> bool {
@@ -782,5 +868,26 @@ mod test {
             let _ = Packet::read(&mut Ignore, &data, &mut buffer[..]);
             true
         }
+
+        fn chunks_iter_no_panic(data: Vec<u8>, num_chunks: u8) -> bool {
+            ChunksIter::new(&data, num_chunks).all(|c| c.data.len() <= data.len())
+        }
+
+        fn compressed_packet_read_no_panic(data: Vec<u8>) -> bool {
+            let mut packet = vec![0x80, 0x00, 0x01];
+            packet.extend(data);
+            let mut buffer = [0; MAX_PACKETSIZE];
+            match Packet::read(&mut Ignore, &packet, &mut buffer[..]) {
+                Ok(Packet::Connected(ConnectedPacket {
+                    type_: ConnectedPacketType::Chunks(_, num_chunks, chunk_data),
+                    ..
+                })) => {
+                    let _ = ChunksIter::new(chunk_data, num_chunks).count();
+                    chunk_data.len() <= MAX_PAYLOAD
+                }
+                Ok(_) => false,
+                Err(e) => e == Compression || e == NumChunks,
+            }
+        }
     }
 }
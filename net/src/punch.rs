@@ -0,0 +1,242 @@
+use Net;
+use Timeout;
+use net::Address;
+use net::Callback;
+use net::ConnlessChunk;
+use net::Error;
+use net::PeerId;
+use std::cmp;
+use std::time::Duration;
+
+// Hole punching isn't part of the Teeworlds protocol, the packet is modeled
+// after the other connless messages (four `\xff` bytes followed by a four
+// byte magic).
+pub const PUNCH: &'static [u8; 8] = b"\xff\xff\xff\xffpnch";
+
+const RESEND_INTERVAL_MS: u64 = 500;
+const TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum State {
+    Punching,
+    // The other side's punch arrived. For the initiating side, `Net::connect`
+    // has been called and the peer ID of the new connection is contained.
+    Success(Option<PeerId>),
+    TimedOut,
+}
+
+/// Establishes a path to an address behind a NAT.
+///
+/// Both sides send punch packets to each other until one of them arrives or
+/// the attempt times out. Only the initiating side connects afterwards, the
+/// other side needs to `accept` the incoming connection as usual.
+pub struct Punch<A: Address> {
+    addr: A,
+    initiator: bool,
+    state: State,
+    resend: Timeout,
+    timeout: Timeout,
+}
+
+impl<A: Address> Punch<A> {
+    pub fn start<T, CB: Callback<A>>(net: &mut Net<A, T>, cb: &mut CB, addr: A, initiator: bool)
+        -> (Punch<A>, Result<(), Error<CB::Error>>)
+    {
+        let now = cb.time();
+        let mut result = Punch {
+            addr: addr,
+            initiator: initiator,
+            state: State::Punching,
+            resend: Timeout::inactive(),
+            timeout: Timeout::active(now + Duration::from_millis(TIMEOUT_MS)),
+        };
+        let res = result.send_punch(net, cb);
+        (result, res)
+    }
+    pub fn addr(&self) -> A {
+        self.addr.clone()
+    }
+    pub fn state(&self) -> State {
+        self.state
+    }
+    pub fn needs_tick(&self) -> Timeout {
+        match self.state {
+            State::Punching => cmp::min(self.resend, self.timeout),
+            _ => Timeout::inactive(),
+        }
+    }
+    fn send_punch<T, CB: Callback<A>>(&mut self, net: &mut Net<A, T>, cb: &mut CB)
+        -> Result<(), Error<CB::Error>>
+    {
+        let now = cb.time();
+        self.resend = Timeout::active(now + Duration::from_millis(RESEND_INTERVAL_MS));
+        net.send_connless(cb, self.addr.clone(), PUNCH)
+    }
+    pub fn tick<T, CB: Callback<A>>(&mut self, net: &mut Net<A, T>, cb: &mut CB)
+        -> Result<(), Error<CB::Error>>
+    {
+        if self.state != State::Punching {
+            return Ok(());
+        }
+        let now = cb.time();
+        if self.timeout.to_opt().map(|t| t <= now).unwrap_or(false) {
+            self.state = State::TimedOut;
+            return Ok(());
+        }
+        if self.resend.to_opt().map(|t| t <= now).unwrap_or(false) {
+            return self.send_punch(net, cb);
+        }
+        Ok(())
+    }
+    /// Processes a received connless chunk.
+    ///
+    /// Returns whether the chunk was a punch packet from the address of this
+    /// attempt. Such chunks should not be processed further.
+    pub fn feed<T, CB: Callback<A>>(&mut self, net: &mut Net<A, T>, cb: &mut CB, chunk: &ConnlessChunk<A>)
+        -> (bool, Result<(), Error<CB::Error>>)
+    {
+        if chunk.addr != self.addr || chunk.data != PUNCH {
+            return (false, Ok(()));
+        }
+        if self.state != State::Punching {
+            return (true, Ok(()));
+        }
+        // Answer immediately, so the other side doesn't have to wait for our
+        // next resend.
+        let mut result = net.send_connless(cb, self.addr.clone(), PUNCH);
+        let pid = if self.initiator {
+            let (pid, res) = net.connect(cb, self.addr.clone());
+            result = result.and(res.map_err(Error::from));
+            Some(pid)
+        } else {
+            None
+        };
+        self.state = State::Success(pid);
+        (true, result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use Net;
+    use Timestamp;
+    use itertools::Itertools;
+    use net::Callback;
+    use net::ChunkOrEvent;
+    use protocol;
+    use std::collections::VecDeque;
+    use std::time::Duration;
+    use super::PUNCH;
+    use super::Punch;
+    use super::State;
+    use void::Void;
+    use warn::Panic;
+
+    #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+    enum Address {
+        A,
+        B,
+    }
+
+    struct Cb {
+        packets: VecDeque<(Address, Vec<u8>)>,
+        time: Timestamp,
+    }
+
+    impl Callback<Address> for Cb {
+        type Error = Void;
+        fn send(&mut self, addr: Address, data: &[u8]) -> Result<(), Void> {
+            self.packets.push_back((addr, data.to_owned()));
+            Ok(())
+        }
+        fn time(&mut self) -> Timestamp {
+            self.time
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut cb = Cb {
+            packets: VecDeque::new(),
+            time: Timestamp::from_secs_since_epoch(0),
+        };
+        let cb = &mut cb;
+
+        // `a` has address `A` and initiates, `b` has address `B`.
+        let mut a = Net::client();
+        let mut b = Net::server();
+        let (mut punch_a, res) = Punch::start(&mut a, cb, Address::B, true);
+        res.unwrap();
+        let (mut punch_b, res) = Punch::start(&mut b, cb, Address::A, false);
+        res.unwrap();
+
+        // Both punches were sent, the first one from `a` gets lost.
+        assert_eq!(cb.packets.pop_front().unwrap().0, Address::B);
+        let (to, packet) = cb.packets.pop_front().unwrap();
+        assert_eq!(to, Address::A);
+        assert!(cb.packets.is_empty());
+
+        // `a` receives the punch, answers and connects.
+        let chunks = a.feed(cb, &mut Panic, Address::B, &packet, &mut buffer[..]).0.collect_vec();
+        assert_eq!(chunks.len(), 1);
+        match chunks[0] {
+            ChunkOrEvent::Connless(ref c) => {
+                let (consumed, res) = punch_a.feed(&mut a, cb, c);
+                res.unwrap();
+                assert!(consumed);
+            }
+            ref c => panic!("unexpected chunk {:?}", c),
+        }
+        assert_matches!(punch_a.state(), State::Success(Some(_)));
+        let (to, punch) = cb.packets.pop_front().unwrap();
+        assert_eq!(to, Address::B);
+        let (to, connect) = cb.packets.pop_front().unwrap();
+        assert_eq!(to, Address::B);
+        assert!(cb.packets.is_empty());
+
+        // `b` receives the answer, then the connection attempt.
+        let chunks = b.feed(cb, &mut Panic, Address::A, &punch, &mut buffer[..]).0.collect_vec();
+        match chunks[0] {
+            ChunkOrEvent::Connless(ref c) => {
+                assert_eq!(c.data, PUNCH);
+                let (consumed, res) = punch_b.feed(&mut b, cb, c);
+                res.unwrap();
+                assert!(consumed);
+            }
+            ref c => panic!("unexpected chunk {:?}", c),
+        }
+        assert_eq!(punch_b.state(), State::Success(None));
+        assert_eq!(cb.packets.pop_front().unwrap().0, Address::A);
+        assert!(cb.packets.is_empty());
+
+        let chunks = b.feed(cb, &mut Panic, Address::A, &connect, &mut buffer[..]).0.collect_vec();
+        assert_matches!(&chunks[..], &[ChunkOrEvent::Connect(_, Address::A)]);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut cb = Cb {
+            packets: VecDeque::new(),
+            time: Timestamp::from_secs_since_epoch(0),
+        };
+        let cb = &mut cb;
+        let mut net = Net::client();
+        let (mut punch, res) = Punch::start(&mut net, cb, Address::B, true);
+        res.unwrap();
+        assert_eq!(cb.packets.len(), 1);
+
+        // Punches are resent until the attempt times out.
+        for _ in 0..19 {
+            cb.time = punch.needs_tick().to_opt().unwrap();
+            punch.tick(&mut net, cb).unwrap();
+            assert_eq!(punch.state(), State::Punching);
+        }
+        assert_eq!(cb.packets.len(), 20);
+        cb.time = Timestamp::from_secs_since_epoch(0) + Duration::from_secs(10);
+        punch.tick(&mut net, cb).unwrap();
+        assert_eq!(punch.state(), State::TimedOut);
+        assert!(!punch.needs_tick().is_active());
+        assert_eq!(cb.packets.len(), 20);
+    }
+}
@@ -33,6 +33,16 @@ impl Timestamp {
     pub fn as_usecs_since_epoch(&self) -> u64 {
         self.usec
     }
+    /// Returns the time elapsed from `earlier` to `self`, zero if `earlier`
+    /// is later.
+    pub fn duration_since(self, earlier: Timestamp) -> Duration {
+        if self > earlier {
+            let us = self.usec - earlier.usec;
+            Duration::new(us / 1_000_000_000, (us % 1_000_000_000).assert_u32())
+        } else {
+            Duration::from_millis(0)
+        }
+    }
 }
 
 impl ops::Add<Duration> for Timestamp {
@@ -92,14 +102,7 @@ impl Timeout {
         self.timeout.into()
     }
     pub fn time_from(self, time: Timestamp) -> Option<Duration> {
-        self.to_opt().map(|t| {
-            if t > time {
-                let us = t.as_usecs_since_epoch() - time.as_usecs_since_epoch();
-                Duration::new(us / 1_000_000_000, (us % 1_000_000_000).assert_u32())
-            } else {
-                Duration::from_millis(0)
-            }
-        })
+        self.to_opt().map(|t| t.duration_since(time))
     }
 }
 
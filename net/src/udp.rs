@@ -0,0 +1,131 @@
+use Net;
+use Timestamp;
+use net::Callback;
+use net::ReceivePacket;
+use net::Warning;
+use std::cmp;
+use std::io;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::net::UdpSocket;
+use std::time::Duration;
+use std::time::Instant;
+use warn::Warn;
+
+/// A blocking UDP socket to drive a `Net` with.
+///
+/// A typical loop waits with `poll` until the next packet arrives or
+/// `Net::needs_tick` says so, processes the returned chunks and events and
+/// calls `tick` afterwards.
+pub struct Udp {
+    socket: UdpSocket,
+    start: Instant,
+}
+
+impl Udp {
+    pub fn new(socket: UdpSocket) -> Udp {
+        Udp {
+            socket: socket,
+            start: Instant::now(),
+        }
+    }
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Udp> {
+        UdpSocket::bind(addr).map(Udp::new)
+    }
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+    /// Waits for a datagram and feeds it into `net`.
+    ///
+    /// Waits at most `timeout`, or until the next tick of `net` is due if
+    /// `timeout` is `None`. Returns an empty packet if nothing arrived in
+    /// time. `data` must have at least size `MAX_PACKETSIZE`, `buf` at least
+    /// size `MAX_PAYLOAD`.
+    pub fn poll<'a, T, W>(&mut self, net: &mut Net<SocketAddr, T>, warn: &mut W, timeout: Option<Duration>, data: &'a mut [u8], buf: &'a mut [u8])
+        -> (ReceivePacket<'a, SocketAddr>, io::Result<()>)
+        where W: Warn<Warning<SocketAddr>>,
+    {
+        let timeout = timeout.or_else(|| net.needs_tick().time_from(self.time()));
+        // A zero timeout is rejected by the socket.
+        let timeout = timeout.map(|t| cmp::max(t, Duration::from_millis(1)));
+        if let Err(e) = self.socket.set_read_timeout(timeout) {
+            return (ReceivePacket::none(), Err(e));
+        }
+        let (len, addr) = match self.socket.recv_from(data) {
+            Ok(r) => r,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                return (ReceivePacket::none(), Ok(()));
+            }
+            Err(e) => return (ReceivePacket::none(), Err(e)),
+        };
+        let data: &'a [u8] = data;
+        net.feed(self, warn, addr, &data[..len], buf)
+    }
+    /// Ticks `net`, returning the first error.
+    ///
+    /// Also retries the packets that couldn't be sent before, see
+    /// `Net::send_blocked`.
+    pub fn tick<T>(&mut self, net: &mut Net<SocketAddr, T>) -> io::Result<()> {
+        net.send_blocked(self)?;
+        match net.tick(self).next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Callback<SocketAddr> for Udp {
+    type Error = io::Error;
+    fn send(&mut self, addr: SocketAddr, data: &[u8]) -> io::Result<()> {
+        self.socket.send_to(data, addr).map(|_| ())
+    }
+    fn time(&mut self) -> Timestamp {
+        Timestamp::from_secs_since_epoch(0) + self.start.elapsed()
+    }
+    fn would_block(&self, e: &io::Error) -> bool {
+        e.kind() == io::ErrorKind::WouldBlock
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use Net;
+    use itertools::Itertools;
+    use net::ChunkOrEvent;
+    use protocol;
+    use std::time::Duration;
+    use super::Udp;
+    use warn::Panic;
+
+    #[test]
+    fn loopback() {
+        let mut data = [0; protocol::MAX_PACKETSIZE];
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut server_net = Net::server();
+        let mut client_net = Net::client();
+        let mut server = Udp::bind("127.0.0.1:0").unwrap();
+        let mut client = Udp::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.socket().local_addr().unwrap();
+        let timeout = Some(Duration::from_secs(5));
+
+        let (c_pid, res) = client_net.connect(&mut client, server_addr);
+        res.unwrap();
+        let (p, res) = server.poll(&mut server_net, &mut Panic, timeout, &mut data, &mut buffer);
+        res.unwrap();
+        let s_pid = match p.collect_vec()[..] {
+            [ChunkOrEvent::Connect(pid, _)] => pid,
+            ref p => panic!("unexpected chunks {:?}", p),
+        };
+        server_net.accept(&mut server, s_pid).unwrap();
+        let (p, res) = client.poll(&mut client_net, &mut Panic, timeout, &mut data, &mut buffer);
+        res.unwrap();
+        assert_eq!(p.collect_vec(), [ChunkOrEvent::Ready(c_pid)]);
+
+        // Nothing arrives in time.
+        let (p, res) = client.poll(&mut client_net, &mut Panic, Some(Duration::from_millis(0)), &mut data, &mut buffer);
+        res.unwrap();
+        assert!(p.collect_vec().is_empty());
+    }
+}
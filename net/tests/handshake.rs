@@ -0,0 +1,138 @@
+//! Compares the handshake packets against the Teeworlds 0.6 reference
+//! implementation.
+//!
+//! The vectors in `data/handshake` were not captured from a live session,
+//! they were derived by hand from the source of the reference
+//! implementation at the `0.6.4` tag of teeworlds/teeworlds:
+//!
+//! - `CNetBase::SendControlMsg` in `src/engine/shared/network.cpp` builds
+//!   a packet with `m_Flags = NET_PACKETFLAG_CONTROL`, no chunks and the
+//!   control message followed by the extra data as payload.
+//! - `CNetBase::SendPacket` in the same file writes the three byte header,
+//!   `((m_Flags<<4)&0xf0)|((m_Ack>>8)&0xf)`, `m_Ack&0xff` and
+//!   `m_NumChunks`.
+//! - `CNetConnection::Connect`, `CNetConnection::Feed` and
+//!   `CNetConnection::Disconnect` in `src/engine/shared/network_conn.cpp`
+//!   send `NET_CTRLMSG_CONNECT`, `NET_CTRLMSG_CONNECTACCEPT`,
+//!   `NET_CTRLMSG_ACCEPT` and `NET_CTRLMSG_CLOSE`, the latter with the
+//!   reason including its null terminator. The close packet carries the
+//!   sequence number of the last received vital chunk as ack.
+//!
+//! Each line contains the name of the packet followed by its bytes in
+//! hexadecimal, lines starting with `#` are comments.
+//!
+//! The 0.6 handshake carries no tokens, so the packets don't depend on any
+//! randomness.
+
+extern crate net;
+extern crate void;
+extern crate warn;
+
+use net::Net;
+use net::Timestamp;
+use net::net::Callback;
+use net::net::ChunkOrEvent;
+use net::net::PeerId;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use void::ResultVoidExt;
+use void::Void;
+use warn::Panic;
+
+const CLIENT: u32 = 1;
+const SERVER: u32 = 2;
+
+fn vectors() -> HashMap<String, Vec<u8>> {
+    BufReader::new(File::open("data/handshake").unwrap())
+        .lines()
+        .map(|l| l.unwrap())
+        .filter(|l| !l.starts_with('#'))
+        .map(|l| {
+            let mut words = l.split(' ');
+            let name = words.next().unwrap().to_owned();
+            let bytes = words.map(|hex| u8::from_str_radix(hex, 16).unwrap()).collect();
+            (name, bytes)
+        })
+        .collect()
+}
+
+struct Cb {
+    packets: VecDeque<(u32, Vec<u8>)>,
+}
+
+impl Callback<u32> for Cb {
+    type Error = Void;
+    fn send(&mut self, addr: u32, data: &[u8]) -> Result<(), Void> {
+        self.packets.push_back((addr, data.to_owned()));
+        Ok(())
+    }
+    fn time(&mut self) -> Timestamp {
+        Timestamp::from_secs_since_epoch(0)
+    }
+}
+
+impl Cb {
+    fn packet(&mut self, to: u32) -> Vec<u8> {
+        let (addr, packet) = self.packets.pop_front().unwrap();
+        assert_eq!(addr, to);
+        assert!(self.packets.is_empty());
+        packet
+    }
+}
+
+fn handshake(cb: &mut Cb, client: &mut Net<u32>, server: &mut Net<u32>) -> (PeerId, PeerId) {
+    let mut buffer = [0; 2048];
+    let vectors = vectors();
+
+    let (c_pid, res) = client.connect(cb, SERVER);
+    res.void_unwrap();
+    let packet = cb.packet(SERVER);
+    assert_eq!(packet, vectors["connect"]);
+
+    let s_pid = match server.feed(cb, &mut Panic, CLIENT, &packet, &mut buffer[..]).0.next() {
+        Some(ChunkOrEvent::Connect(pid, CLIENT)) => pid,
+        _ => panic!("no connect event"),
+    };
+    server.accept(cb, s_pid).void_unwrap();
+    let packet = cb.packet(CLIENT);
+    assert_eq!(packet, vectors["connect_accept"]);
+
+    client.feed(cb, &mut Panic, SERVER, &packet, &mut buffer[..]).0.count();
+    let packet = cb.packet(SERVER);
+    assert_eq!(packet, vectors["accept"]);
+    server.feed(cb, &mut Panic, CLIENT, &packet, &mut buffer[..]).0.count();
+    assert!(cb.packets.is_empty());
+
+    (c_pid, s_pid)
+}
+
+#[test]
+fn client_close() {
+    let mut cb = Cb { packets: VecDeque::new() };
+    let mut client = Net::client();
+    let mut server = Net::server();
+    let (c_pid, _) = handshake(&mut cb, &mut client, &mut server);
+
+    client.disconnect(&mut cb, c_pid, b"foobar").void_unwrap();
+    assert_eq!(cb.packet(SERVER), vectors()["close_reason"]);
+}
+
+#[test]
+fn server_close_after_chunk() {
+    let mut buffer = [0; 2048];
+    let mut cb = Cb { packets: VecDeque::new() };
+    let mut client = Net::client();
+    let mut server = Net::server();
+    let (c_pid, s_pid) = handshake(&mut cb, &mut client, &mut server);
+
+    // The close packet acknowledges the received vital chunk.
+    client.send_vital(&mut cb, c_pid, b"\x42").unwrap();
+    client.flush(&mut cb, c_pid).void_unwrap();
+    let packet = cb.packet(SERVER);
+    server.feed(&mut cb, &mut Panic, CLIENT, &packet, &mut buffer[..]).0.count();
+    server.disconnect(&mut cb, s_pid, b"bye").void_unwrap();
+    assert_eq!(cb.packet(CLIENT), vectors()["close_ack"]);
+}
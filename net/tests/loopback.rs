@@ -0,0 +1,284 @@
+//! Runs whole sessions between a client and a server over a simulated lossy
+//! link and checks the guarantees of the vital chunks.
+//!
+//! The protocol neither uses tokens nor random initial sequence numbers, so
+//! given the same script and the same loss pattern, a session consists of the
+//! exact same packets every time. quickcheck generates the scripts and loss
+//! patterns, and reports the inputs of failing sessions, which can then be
+//! replayed with `session`.
+
+extern crate net;
+#[macro_use] extern crate quickcheck;
+extern crate void;
+extern crate warn;
+
+use net::Net;
+use net::Timestamp;
+use net::connection;
+use net::net::Callback;
+use net::net::ChunkOrEvent;
+use net::net::NetConfig;
+use net::net::PeerId;
+use net::net::Role;
+use net::protocol;
+use std::collections::VecDeque;
+use std::time::Duration;
+use void::ResultVoidExt;
+use void::Void;
+
+const CLIENT: u32 = 1;
+const SERVER: u32 = 2;
+
+// Simulated time between two rounds of ticking both sides.
+const STEP: Duration = Duration::from_millis(50);
+// Upper bound on the simulated duration of a session.
+const ROUNDS: u32 = 2_000;
+
+struct Link {
+    packets: VecDeque<(u32, Vec<u8>)>,
+    time: Timestamp,
+}
+
+impl Callback<u32> for Link {
+    type Error = Void;
+    fn send(&mut self, addr: u32, data: &[u8]) -> Result<(), Void> {
+        self.packets.push_back((addr, data.to_owned()));
+        Ok(())
+    }
+    fn time(&mut self) -> Timestamp {
+        self.time
+    }
+}
+
+// Decides which packets get lost.
+//
+// The `n`th packet on the link is dropped if `loss[n]` is set, except if the
+// previous packet in the same direction was dropped as well. This keeps the
+// connection from timing out, and since the pattern is finite, every chunk
+// eventually gets through.
+struct Loss {
+    pattern: Vec<bool>,
+    num_packets: usize,
+    // Whether the last packet to the client or server was dropped.
+    dropped: [bool; 2],
+}
+
+impl Loss {
+    fn drop(&mut self, to: u32) -> bool {
+        let n = self.num_packets;
+        self.num_packets += 1;
+        let dir = (to == SERVER) as usize;
+        let drop = self.pattern.get(n).cloned().unwrap_or(false) && !self.dropped[dir];
+        self.dropped[dir] = drop;
+        drop
+    }
+}
+
+struct Session {
+    // All packets put onto the link, and whether they were dropped.
+    transcript: Vec<(u32, Vec<u8>, bool)>,
+    // The vital chunks received by the client and the server.
+    received: [Vec<Vec<u8>>; 2],
+}
+
+struct Side {
+    net: Net<u32>,
+    pid: Option<PeerId>,
+    received: Vec<Vec<u8>>,
+    disconnected: bool,
+}
+
+impl Side {
+    fn new(net: Net<u32>) -> Side {
+        Side {
+            net: net,
+            pid: None,
+            received: vec![],
+            disconnected: false,
+        }
+    }
+    fn feed(&mut self, link: &mut Link, from: u32, packet: &[u8]) {
+        let mut buffer = [0; protocol::MAX_PAYLOAD];
+        let mut accept = None;
+        for chunk in self.net.feed(link, &mut warn::Ignore, from, packet, &mut buffer[..]).0 {
+            match chunk {
+                ChunkOrEvent::Chunk(c) => {
+                    assert_eq!(Some(c.pid), self.pid);
+                    assert!(c.vital, "unexpected non-vital chunk");
+                    self.received.push(c.data.to_owned());
+                }
+                ChunkOrEvent::Connect(pid, addr) => {
+                    assert_eq!((self.pid, addr), (None, from));
+                    accept = Some(pid);
+                }
+                ChunkOrEvent::Ready(pid) => assert_eq!(Some(pid), self.pid),
+                ChunkOrEvent::Disconnect(pid, _, reason) => {
+                    assert_eq!(Some(pid), self.pid);
+                    assert_eq!(reason, b"done");
+                    assert!(!self.disconnected);
+                    self.disconnected = true;
+                }
+                ChunkOrEvent::Connless(_) => panic!("unexpected connless chunk"),
+            }
+        }
+        if let Some(pid) = accept {
+            self.pid = Some(pid);
+            self.net.accept(link, pid).void_unwrap();
+        }
+    }
+}
+
+// Delivers the packets on the link, subject to loss.
+fn transmit(link: &mut Link, loss: &mut Loss, transcript: &mut Vec<(u32, Vec<u8>, bool)>,
+            client: &mut Side, server: &mut Side)
+{
+    while let Some((to, packet)) = link.packets.pop_front() {
+        let dropped = loss.drop(to);
+        if !dropped {
+            match to {
+                CLIENT => client.feed(link, SERVER, &packet),
+                SERVER => server.feed(link, CLIENT, &packet),
+                _ => unreachable!(),
+            }
+        }
+        transcript.push((to, packet, dropped));
+    }
+}
+
+// Message `i` of a side, `len` bytes long.
+fn message(side: u8, i: usize, len: u8) -> Vec<u8> {
+    let mut result = vec![side, i as u8, (i >> 8) as u8];
+    result.extend((0..len).map(|j| j ^ i as u8));
+    result
+}
+
+/// Runs a session with the given script and loss pattern.
+///
+/// The client connects, then the two sides send the messages of the script,
+/// `(true, len)` from the client to server, `(false, len)` the other way
+/// around. Once everything has arrived, the client disconnects.
+fn session(script: &[(bool, u8)], loss: &[bool]) -> Session {
+    session_with_config(connection::Config::default(), script, loss)
+}
+
+/// Like `session`, with the given configuration of the connections on both
+/// sides.
+fn session_with_config(config: connection::Config, script: &[(bool, u8)], loss: &[bool])
+    -> Session
+{
+    let mut link = Link {
+        packets: VecDeque::new(),
+        time: Timestamp::from_secs_since_epoch(0),
+    };
+    let link = &mut link;
+    let mut loss = Loss {
+        pattern: loss.to_owned(),
+        num_packets: 0,
+        dropped: [false; 2],
+    };
+    let mut transcript = vec![];
+    let mut client = Side::new(Net::with_config(NetConfig {
+        accept_connections: false,
+        role: Role::Client,
+        connection: config,
+        ..NetConfig::default()
+    }));
+    let mut server = Side::new(Net::with_config(NetConfig {
+        role: Role::Server,
+        connection: config,
+        ..NetConfig::default()
+    }));
+
+    let (pid, res) = client.net.connect(link, SERVER);
+    res.void_unwrap();
+    client.pid = Some(pid);
+
+    let num_to_server = script.iter().filter(|&&(c, _)| c).count();
+    let num_to_client = script.len() - num_to_server;
+    let mut script = script.iter().enumerate().peekable();
+    let mut disconnecting = false;
+    for round in 0.. {
+        assert!(round < ROUNDS, "session didn't finish");
+        transmit(link, &mut loss, &mut transcript, &mut client, &mut server);
+        if client.net.peer_ids().next().is_none() && server.disconnected {
+            break;
+        }
+        // Send a message per round, once the sending side is online.
+        if !disconnecting {
+            if let Some(&(i, &(from_client, len))) = script.peek() {
+                let (side, id) = if from_client { (&mut client, 0) } else { (&mut server, 1) };
+                if let Some(pid) = side.pid.filter(|&pid| side.net.is_online(pid)) {
+                    side.net.send_vital(link, pid, &message(id, i, len)).unwrap();
+                    side.net.flush(link, pid).void_unwrap();
+                    script.next();
+                }
+            } else if server.pid.map(|pid| server.net.is_online(pid)).unwrap_or(false)
+                && server.received.len() == num_to_server
+                && client.received.len() == num_to_client
+            {
+                client.net.disconnect_graceful(link, pid, b"done").void_unwrap();
+                disconnecting = true;
+            }
+        }
+        link.time = link.time + STEP;
+        client.net.tick(link).count();
+        server.net.tick(link).count();
+    }
+    assert!(!client.disconnected);
+
+    Session {
+        transcript: transcript,
+        received: [client.received, server.received],
+    }
+}
+
+fn expected(script: &[(bool, u8)], from_client: bool) -> Vec<Vec<u8>> {
+    script.iter().enumerate()
+        .filter(|&(_, &(c, _))| c == from_client)
+        .map(|(i, &(_, len))| message(if from_client { 0 } else { 1 }, i, len))
+        .collect()
+}
+
+#[test]
+fn lossless() {
+    let script = [(true, 0), (false, 1), (true, 200), (true, 3)];
+    let s = session(&script, &[]);
+    assert!(s.transcript.iter().all(|&(_, _, dropped)| !dropped));
+    assert_eq!(s.received[0], expected(&script, false));
+    assert_eq!(s.received[1], expected(&script, true));
+}
+
+#[test]
+fn lossy() {
+    // Every other packet gets lost for a while.
+    let script = [(true, 0), (false, 1), (true, 200), (true, 3), (false, 50)];
+    let loss: Vec<_> = (0..40).map(|i| i % 2 == 0).collect();
+    let s = session(&script, &loss);
+    assert!(s.transcript.iter().filter(|&&(_, _, dropped)| dropped).count() >= 10);
+    assert_eq!(s.received[0], expected(&script, false));
+    assert_eq!(s.received[1], expected(&script, true));
+}
+
+quickcheck! {
+    // All vital chunks arrive exactly once and in order, no matter which
+    // packets are lost.
+    fn vital_delivery(script: Vec<(bool, u8)>, loss: Vec<bool>) -> bool {
+        let s = session(&script, &loss);
+        s.received[0] == expected(&script, false) && s.received[1] == expected(&script, true)
+    }
+
+    // The same holds if congestion control holds back chunks.
+    fn vital_delivery_congestion_control(script: Vec<(bool, u8)>, loss: Vec<bool>) -> bool {
+        let config = connection::Config {
+            congestion_control: true,
+            ..connection::Config::default()
+        };
+        let s = session_with_config(config, &script, &loss);
+        s.received[0] == expected(&script, false) && s.received[1] == expected(&script, true)
+    }
+
+    // The same inputs lead to the same packets.
+    fn reproducible(script: Vec<(bool, u8)>, loss: Vec<bool>) -> bool {
+        session(&script, &loss).transcript == session(&script, &loss).transcript
+    }
+}
@@ -62,6 +62,7 @@ impl Rect {
 struct Config {
     size: u32,
     render_detail: bool,
+    render_parallax: bool,
     crop: Option<Rect>,
 }
 
@@ -133,9 +134,14 @@ struct Layer {
     color: Color,
     image: Option<usize>,
     tiles: Array2<format::Tile>,
+    // Offset of the group, in world units.
+    offset_x: i32,
+    offset_y: i32,
 }
 
 const TILE_NUM: u32 = 16;
+// World units per tile.
+const TILE_WORLD_LEN: i64 = 32;
 
 /// Scales `tileset` to `tile_len` * TILE_NUM pixels, clears first (air) tile.
 fn normalize_tileset(tileset: Array2<Color>, tile_len: u32)
@@ -229,10 +235,14 @@ fn select_layers(map: &mut map::Reader, config: &Config)
     for group_idx in map.group_indices() {
         let group = map.group(group_idx)?;
 
-        if group.parallax_x != 100 || group.parallax_y != 100
-            || group.offset_x != 0 || group.offset_y != 0
-            || group.clipping.is_some()
-        {
+        if group.clipping.is_some() {
+            continue;
+        }
+        // The map is rendered with the camera at the origin, where the
+        // parallax has no effect and only the offset shifts the layers.
+        let parallax = group.parallax_x != 100 || group.parallax_y != 100
+            || group.offset_x != 0 || group.offset_y != 0;
+        if parallax && !config.render_parallax {
             continue;
         }
 
@@ -248,6 +258,8 @@ fn select_layers(map: &mut map::Reader, config: &Config)
                 color: normal.color.into(),
                 image: normal.image,
                 tiles: tiles,
+                offset_x: group.offset_x,
+                offset_y: group.offset_y,
             });
         }
     }
@@ -306,6 +318,18 @@ fn prepare_tilesets<E>(
     Ok(tilesets)
 }
 
+/// Returns the range of tiles of a layer shifted by `shift` pixels that are
+/// visible in the cropped range.
+fn visible_tiles(crop_min: u32, crop_max: u32, shift: i64, tile_len: u32, layer_len: u32)
+    -> (u32, u32)
+{
+    let tile_len = tile_len.i64();
+    let min = (crop_min.i64() * tile_len - shift).div_euclid(tile_len);
+    let max = (crop_max.i64() * tile_len - shift + tile_len - 1).div_euclid(tile_len);
+    let clamp = |t: i64| cmp::max(0, cmp::min(t, layer_len.i64())).assert_u32();
+    (clamp(min), clamp(max))
+}
+
 fn crop_to_fit_nonair_tiles(layers: &[Layer]) -> Rect {
     let mut crop = Rect {
         min_x: u32::max_value(),
@@ -315,6 +339,10 @@ fn crop_to_fit_nonair_tiles(layers: &[Layer]) -> Rect {
     };
 
     for layer in layers {
+        // Shifted layers are usually backgrounds, don't crop to them.
+        if layer.offset_x != 0 || layer.offset_y != 0 {
+            continue;
+        }
         for ((y, x), tile) in layer.tiles.indexed_iter() {
             if tile.index != 0 {
                 crop.min_y = cmp::min(crop.min_y, y.assert_u32());
@@ -351,19 +379,22 @@ fn render_layers(
 
     for l in layers {
         let tileset = &tilesets[&l.image];
-        let layer_max_y = cmp::min(l.tiles.dim().0.assert_u32(), crop.max_y);
-        let layer_max_x = cmp::min(l.tiles.dim().1.assert_u32(), crop.max_x);
+        let shift_y = -l.offset_y.i64() * tile_len.i64() / TILE_WORLD_LEN;
+        let shift_x = -l.offset_x.i64() * tile_len.i64() / TILE_WORLD_LEN;
+        let (layer_min_y, layer_max_y) = visible_tiles(crop.min_y, crop.max_y, shift_y, tile_len, l.tiles.dim().0.assert_u32());
+        let (layer_min_x, layer_max_x) = visible_tiles(crop.min_x, crop.max_x, shift_x, tile_len, l.tiles.dim().1.assert_u32());
 
-        for layer_y in crop.min_y..layer_max_y {
-            for layer_x in crop.min_x..layer_max_x {
-                let target_y = layer_y - crop.min_y;
-                let target_x = layer_x - crop.min_x;
+        for layer_y in layer_min_y..layer_max_y {
+            for layer_x in layer_min_x..layer_max_x {
+                // Position of the tile in the result, in pixels.
+                let target_y = (layer_y.i64() - crop.min_y.i64()) * tile_len.i64() + shift_y;
+                let target_x = (layer_x.i64() - crop.min_x.i64()) * tile_len.i64() + shift_x;
 
                 let tile = l.tiles[(layer_y.usize(), layer_x.usize())];
 
-                let rotate = tile.flags & format::TILEFLAG_ROTATE != 0;
-                let vflip = tile.flags & format::TILEFLAG_VFLIP != 0;
-                let hflip = tile.flags & format::TILEFLAG_HFLIP != 0;
+                let rotate = tile.is_rotated();
+                let vflip = tile.is_flipped_v();
+                let hflip = tile.is_flipped_h();
                 let tile_x = tile.index.u32() % TILE_NUM;
                 let tile_y = tile.index.u32() / TILE_NUM;
 
@@ -373,8 +404,16 @@ fn render_layers(
                 }
 
                 for iy in 0..tile_len {
+                    let y = target_y + iy.i64();
+                    if y < 0 || y >= result_height.i64() {
+                        continue;
+                    }
                     for ix in 0..tile_len {
-                        let p_target = &mut result[((target_y * tile_len + iy).usize(), (target_x * tile_len + ix).usize())];
+                        let x = target_x + ix.i64();
+                        if x < 0 || x >= result_width.i64() {
+                            continue;
+                        }
+                        let p_target = &mut result[(y.assert_usize(), x.assert_usize())];
                         let (ty, tx) = transform_coordinates((iy, ix), rotate, vflip, hflip, tile_len);
                         let p_tile = tileset[((tile_y * tile_len + ty).usize(), (tile_x * tile_len + tx).usize())];
                         *p_target = p_target.overlay_with(p_tile.mask(l.color));
@@ -590,6 +629,10 @@ fn main() {
             .help("Don't render layers marked as \"Detail\" in the map editor")
             .long("no-detail")
         )
+        .arg(Arg::with_name("no-parallax")
+            .help("Don't render groups with parallax or an offset, usually backgrounds")
+            .long("no-parallax")
+        )
         .arg(Arg::with_name("map")
             .help("Map to render (output file is the same with \".png\" appended)")
             .multiple(true)
@@ -632,6 +675,7 @@ fn main() {
     let config = Config {
         size: value_t!(matches, "size", u32).unwrap_or_else(|e| e.exit()),
         render_detail: !matches.is_present("no-detail"),
+        render_parallax: !matches.is_present("no-parallax"),
         crop: crop,
     };
 
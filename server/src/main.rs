@@ -265,11 +265,57 @@ impl Server {
         let millis = tick.u64() * 1000 / TICKS_PER_SECOND.u64();
         self.game_start + Duration::from_millis(millis)
     }
+    /// Answers a map data request of the peer, keeping track of its
+    /// download.
+    ///
+    /// Returns `None` for invalid requests and if the download has been
+    /// cancelled.
+    fn serve_map_request<'a>(&'a mut self, pid: PeerId, rmd: system::RequestMapData)
+        -> Option<system::MapData<'a>>
+    {
+        let peer = &mut self.peers[pid];
+        if peer.map_download_cancelled {
+            return None;
+        }
+        let md = unwrap_or_return!(self.map.data.serve_request(rmd));
+        if md.last != 0 {
+            peer.map_download = None;
+        } else {
+            let total = self.map.data.contents.len().u64();
+            peer.map_download.get_or_insert(MapDownload {
+                bytes_sent: 0,
+                total: total,
+            }).bytes_sent += md.data.len().u64();
+        }
+        Some(md)
+    }
+    /// Returns the peers currently downloading the map.
+    fn map_downloads<'a>(&'a self) -> impl Iterator<Item=(PeerId, MapDownload)> + 'a {
+        self.peers.iter().filter_map(|(pid, p)| p.map_download.map(|d| (pid, d)))
+    }
+    /// Stops sending the map to the peer, its further requests are ignored.
+    ///
+    /// Returns the progress of the download if one was active.
+    fn cancel_map_download(&mut self, pid: PeerId) -> Option<MapDownload> {
+        let peer = &mut self.peers[pid];
+        peer.map_download_cancelled = true;
+        peer.map_download.take()
+    }
+}
+
+/// Progress of a peer downloading the map.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct MapDownload {
+    bytes_sent: u64,
+    total: u64,
 }
 
 #[derive(Default)]
 struct Peer {
     state: PeerState,
+    // Set from the first map data request until the last chunk is sent.
+    map_download: Option<MapDownload>,
+    map_download_cancelled: bool,
 }
 
 impl Default for PeerState {
@@ -462,9 +508,14 @@ impl<'a, L: Loop> ServerLoop<'a, L> {
                 processed = true;
             }
             (&SystemReady, SystemOrGame::System(System::RequestMapData(rmd))) => {
-                if let Some(md) = self.server.map.data.serve_request(rmd) {
+                let started = peer.map_download.is_none();
+                if let Some(md) = self.server.serve_map_request(pid, rmd) {
                     self.loop_.sends(pid, md);
                 }
+                if started && self.server.peers[pid].map_download.is_some() {
+                    info!("{} starts downloading the map, {} downloads active", pid,
+                        self.server.map_downloads().count());
+                }
             }
             (&SystemReady, SystemOrGame::System(System::Ready(system::Ready))) => {
                 self.loop_.sendg(pid, game::SvMotd {
@@ -588,6 +639,13 @@ impl<'a, L: Loop> ServerLoop<'a, L> {
                     clients: msg::ClientsData::from_bytes(&clients_buf),
                 });
             },
+            Connless::RequestVersion(_) => {
+                processed = true;
+                // Lets clients tell which protocol version to connect with.
+                self.loop_.sendc(addr, connless::Version {
+                    version: VERSION.as_bytes(),
+                });
+            },
             _ => {},
         }
         if !processed {
@@ -614,6 +672,9 @@ impl<'a, L: Loop> ServerLoop<'a, L> {
         } else {
             info!("{} leaves the game", pid);
         }
+        if let Some(d) = self.server.cancel_map_download(pid) {
+            info!("{} left during the map download, {}/{} bytes sent", pid, d.bytes_sent, d.total);
+        }
         self.server.peers.remove(pid);
         for i in 0..self.server.players.len() {
             if self.server.players[i].pid == pid {
@@ -759,3 +820,39 @@ fn main() {
     logger::init();
     Server::run::<SocketLoop>();
 }
+
+#[cfg(test)]
+mod test {
+    use common::num::Cast;
+    use event_loop::PeerId;
+    use gamenet::msg::system::RequestMapData;
+    use super::MAPDOWNLOAD_CHUNK_SIZE;
+    use super::MapDownload;
+    use super::Peer;
+    use super::Server;
+
+    #[test]
+    fn cancel_map_download() {
+        let mut server = Server::default();
+        let total = server.map.data.contents.len().u64();
+        let (downloading, idle) = (PeerId(0), PeerId(1));
+        server.peers.insert(downloading, Peer::default());
+        server.peers.insert(idle, Peer::default());
+        assert_eq!(server.map_downloads().count(), 0);
+
+        for chunk in 0..2 {
+            assert!(server.serve_map_request(downloading, RequestMapData { chunk: chunk }).is_some());
+        }
+        let progress = MapDownload {
+            bytes_sent: 2 * MAPDOWNLOAD_CHUNK_SIZE,
+            total: total,
+        };
+        assert_eq!(server.map_downloads().collect::<Vec<_>>(), [(downloading, progress)]);
+
+        // No more chunks are sent and the download is forgotten.
+        assert_eq!(server.cancel_map_download(downloading), Some(progress));
+        assert!(server.serve_map_request(downloading, RequestMapData { chunk: 2 }).is_none());
+        assert_eq!(server.map_downloads().count(), 0);
+        assert_eq!(server.cancel_map_download(idle), None);
+    }
+}
@@ -6,3 +6,4 @@ extern crate rustc_serialize;
 extern crate warn;
 
 pub mod protocol;
+pub mod reassembly;
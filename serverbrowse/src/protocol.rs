@@ -43,6 +43,8 @@ pub const PONG:              Header = b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff
 pub const PACKETFLAG_CONNLESS: u8 = 1 << 6;
 pub const REQUEST_INFO_6_EX_FLAG_PONG: u16 = 1 << 0;
 
+pub const SERVERINFO_FLAG_PASSWORD: i32 = 1 << 0;
+
 pub const IPV4_MAPPING: [u8; 12] = [
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff,
 ];
@@ -91,6 +93,13 @@ pub struct ClientInfo {
     pub is_player: i32,
 }
 
+impl ClientInfo {
+    /// Whether the client is only watching the game.
+    pub fn is_spectator(&self) -> bool {
+        self.is_player == 0
+    }
+}
+
 impl fmt::Debug for ClientInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?} {:?} {:?} {:?} {:?}",
@@ -207,6 +216,16 @@ impl ServerInfo {
     pub fn sort_clients(&mut self) {
         self.clients.sort();
     }
+    /// Whether joining the server requires a password
+    /// (`SERVERINFO_FLAG_PASSWORD`).
+    pub fn has_password(&self) -> bool {
+        self.flags & SERVERINFO_FLAG_PASSWORD != 0
+    }
+    /// Returns the clients that take part in the game, see
+    /// `ClientInfo::is_spectator`.
+    pub fn players(&self) -> impl Iterator<Item=&ClientInfo> {
+        self.clients.iter().filter(|c| !c.is_spectator())
+    }
 }
 
 impl fmt::Debug for ServerInfo {
@@ -252,9 +271,15 @@ impl PartialServerInfo {
             received: Default::default(),
         }
     }
+    pub fn token(&self) -> i32 {
+        self.info.token
+    }
+    /// Merges the clients of another part into this one.
+    ///
+    /// Returns `false` if `other` contained nothing new.
     // TODO: What to do when the infos don't match?
     // Currently the other info is just ignored.
-    pub fn merge(&mut self, mut other: PartialServerInfo) -> Result<(),MergeError> {
+    pub fn merge(&mut self, mut other: PartialServerInfo) -> Result<bool,MergeError> {
         if self.info.token != other.info.token {
             return Err(MergeError::DifferingTokens);
         }
@@ -269,7 +294,7 @@ impl PartialServerInfo {
         if self.received & other.received == other.received {
             // We already have that server info.
             // TODO: What to do if it doesn't match?
-            return Ok(());
+            return Ok(false);
         }
         if self.received & other.received != 0 {
             return Err(MergeError::OverlappingInfos);
@@ -281,7 +306,7 @@ impl PartialServerInfo {
         }
         self.info.clients.extend(other.info.clients.into_iter());
 
-        Ok(())
+        Ok(true)
     }
     pub fn get_info(&mut self) -> Option<&ServerInfo> {
         if self.info.clients.len().assert_i32() != self.info.num_clients {
@@ -541,6 +566,76 @@ impl<'a> Info6ExMoreResponse<'a> {
     }
 }
 
+fn info_write_str(packet: &mut Vec<u8>, string: &[u8]) {
+    packet.extend_from_slice(string);
+    packet.push(0);
+}
+
+fn info_write_int_v5(packet: &mut Vec<u8>, int: i32) {
+    info_write_str(packet, int.to_string().as_bytes());
+}
+
+fn info_write_client_v6_ex(packet: &mut Vec<u8>, client: &ClientInfo) {
+    info_write_str(packet, &client.name);
+    info_write_str(packet, &client.clan);
+    info_write_int_v5(packet, client.country);
+    info_write_int_v5(packet, client.score);
+    info_write_int_v5(packet, client.is_player);
+    info_write_str(packet, b""); // extra_info
+}
+
+/// Writes the server info as an `INFO_6_EX` response, followed by as many
+/// `INFO_6_EX_MORE` responses as needed to fit in the clients.
+///
+/// Each returned packet is a complete datagram of at most
+/// `max_packet_size` bytes, headers included. The connless packet limit of
+/// the `net` crate is 1400 bytes, like in the reference implementation.
+/// Returns `None` if the info doesn't fit, because the general part or a
+/// single client exceeds a packet or because more than 64 additional
+/// packets would be needed.
+pub fn info_6_ex_responses(info: &ServerInfo, max_packet_size: usize) -> Option<Vec<Vec<u8>>> {
+    let mut first = INFO_6_EX.to_vec();
+    info_write_int_v5(&mut first, info.token);
+    info_write_str(&mut first, &info.version);
+    info_write_str(&mut first, &info.name);
+    info_write_str(&mut first, &info.map);
+    info_write_int_v5(&mut first, info.map_crc.unwrap_or(0) as i32);
+    info_write_int_v5(&mut first, info.map_size.unwrap_or(0).assert_i32());
+    info_write_str(&mut first, &info.game_type);
+    info_write_int_v5(&mut first, info.flags);
+    info_write_int_v5(&mut first, info.num_players);
+    info_write_int_v5(&mut first, info.max_players);
+    info_write_int_v5(&mut first, info.num_clients);
+    info_write_int_v5(&mut first, info.max_clients);
+    info_write_str(&mut first, b""); // extra_info
+    if first.len() > max_packet_size {
+        return None;
+    }
+
+    let mut result = vec![first];
+    let mut client_buffer = Vec::new();
+    for client in &info.clients {
+        client_buffer.clear();
+        info_write_client_v6_ex(&mut client_buffer, client);
+        if result.last().unwrap().len() + client_buffer.len() > max_packet_size {
+            let packet_no = result.len().assert_i32();
+            if packet_no > 64 {
+                return None;
+            }
+            let mut more = INFO_6_EX_MORE.to_vec();
+            info_write_int_v5(&mut more, info.token);
+            info_write_int_v5(&mut more, packet_no);
+            info_write_str(&mut more, b""); // extra_info
+            if more.len() + client_buffer.len() > max_packet_size {
+                return None;
+            }
+            result.push(more);
+        }
+        result.last_mut().unwrap().extend_from_slice(&client_buffer);
+    }
+    Some(result)
+}
+
 #[derive(Copy, Clone)] pub struct Info5Response<'a>(pub &'a [u8]);
 #[derive(Copy, Clone)] pub struct Info6Response<'a>(pub &'a [u8]);
 #[derive(Copy, Clone)] pub struct Info664Response<'a>(pub &'a [u8]);
@@ -738,8 +833,10 @@ mod test {
     use super::Info6ExMoreResponse;
     use super::Info6ExResponse;
     use super::Info6Response;
+    use super::Response;
     use super::ServerInfo;
     use super::ServerInfoVersion;
+    use super::parse_response;
 
     fn b<FI: FromIterator<u8>>(s: &str) -> FI {
         s.as_bytes().iter().cloned().collect()
@@ -859,4 +956,31 @@ mod test {
         assert_eq!(info.get_info(), Some(&wanted));
 
     }
+
+    #[test]
+    fn parse_info_v6_ex_packet() {
+        let packet = b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\xffiext\
+            1234\00.6.4, 16.4\0DDNet GER1 [private]\0Multeasymap\0-1258384005\0171739\0DDraceNetwork\0\
+            1\01\064\02\064\0\0\
+            nameless tee\0\0-1\0-9999\00\0\0\
+            Baum\0DDNet\0724\01337\01\0\0";
+        let mut info = match parse_response(packet) {
+            Some(Response::Info6Ex(r)) => r.parse().unwrap(),
+            _ => panic!("not an extended info response"),
+        };
+        let info = info.get_info().unwrap();
+        assert_eq!(info.info_version, ServerInfoVersion::V6Ex);
+        assert_eq!(&info.version[..], b"0.6.4, 16.4");
+        assert_eq!(&info.map[..], b"Multeasymap");
+        assert_eq!(info.map_crc, Some(0xb4fe957b));
+        assert_eq!(info.map_size, Some(171739));
+        assert!(info.has_password());
+        assert_eq!((info.num_players, info.max_players, info.num_clients, info.max_clients), (1, 64, 2, 64));
+        assert_eq!(info.clients, [
+            ClientInfo { name: b("Baum"), clan: b("DDNet"), country: 724, score: 1337, is_player: 1 },
+            ClientInfo { name: b("nameless tee"), clan: b(""), country: -1, score: -9999, is_player: 0 },
+        ]);
+        let players: Vec<_> = info.players().map(|c| &c.name[..]).collect();
+        assert_eq!(players, [b"Baum"]);
+    }
 }
@@ -0,0 +1,235 @@
+use protocol::PartialServerInfo;
+use protocol::ServerInfo;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::hash::Hash;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Default time after which incomplete server infos are discarded.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A server info whose parts have all been received.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompleteServerInfo<A> {
+    /// Address of the server that sent the parts.
+    pub addr: A,
+    /// Token of the request the parts answer.
+    pub token: i32,
+    /// Number of distinct parts that made up the info.
+    pub num_parts: u32,
+    pub info: ServerInfo,
+}
+
+struct Pending {
+    info: PartialServerInfo,
+    num_parts: u32,
+    first_part: Instant,
+}
+
+/// Reassembles multipart server info responses.
+///
+/// The parts of `Info664Response` and of `Info6ExResponse` followed by
+/// `Info6ExMoreResponse`s are collected per address and token. Once all
+/// parts have arrived, `feed` returns the merged info. Parts that don't
+/// complete within the timeout are discarded.
+pub struct InfoReassembly<A: Eq + Hash> {
+    pending: HashMap<(A, i32), Pending>,
+    timeout: Duration,
+}
+
+impl<A: Clone + Eq + Hash> InfoReassembly<A> {
+    pub fn new() -> InfoReassembly<A> {
+        InfoReassembly::with_timeout(DEFAULT_TIMEOUT)
+    }
+    pub fn with_timeout(timeout: Duration) -> InfoReassembly<A> {
+        InfoReassembly {
+            pending: HashMap::new(),
+            timeout: timeout,
+        }
+    }
+    /// Adds a part received from `addr` at the time `now`.
+    ///
+    /// Returns the complete info if this was the last missing part. If the
+    /// earlier parts with the same token are older than the timeout, they
+    /// are discarded first.
+    pub fn feed(&mut self, now: Instant, addr: A, part: PartialServerInfo)
+        -> Option<CompleteServerInfo<A>>
+    {
+        let token = part.token();
+        let key = (addr, token);
+        let expired = self.pending.get(&key)
+            .map(|p| now.duration_since(p.first_part) > self.timeout)
+            .unwrap_or(false);
+        if expired {
+            self.pending.remove(&key);
+        }
+        let complete = {
+            let pending = match self.pending.entry(key.clone()) {
+                Entry::Vacant(v) => v.insert(Pending {
+                    info: part,
+                    num_parts: 1,
+                    first_part: now,
+                }),
+                Entry::Occupied(o) => {
+                    let pending = o.into_mut();
+                    match pending.info.merge(part.clone()) {
+                        Ok(true) => pending.num_parts += 1,
+                        Ok(false) => {},
+                        Err(_) => {
+                            // The server answered differently, start over.
+                            *pending = Pending {
+                                info: part,
+                                num_parts: 1,
+                                first_part: now,
+                            };
+                        }
+                    }
+                    pending
+                }
+            };
+            pending.info.get_info().cloned().map(|i| (pending.num_parts, i))
+        };
+        complete.map(|(num_parts, info)| {
+            self.pending.remove(&key);
+            CompleteServerInfo {
+                addr: key.0,
+                token: token,
+                num_parts: num_parts,
+                info: info,
+            }
+        })
+    }
+    /// Discards the incomplete infos whose first part arrived longer than
+    /// the timeout before `now`.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.pending.retain(|_, p| now.duration_since(p.first_part) <= timeout);
+    }
+    /// Returns the number of incomplete infos.
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl<A: Clone + Eq + Hash> Default for InfoReassembly<A> {
+    fn default() -> InfoReassembly<A> {
+        InfoReassembly::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use protocol::ClientInfo;
+    use protocol::Info6ExMoreResponse;
+    use protocol::Info6ExResponse;
+    use protocol::Response;
+    use protocol::ServerInfo;
+    use protocol::ServerInfoVersion;
+    use protocol::info_6_ex_responses;
+    use protocol::parse_response;
+    use std::time::Duration;
+    use std::time::Instant;
+    use super::InfoReassembly;
+
+    const PART_0: &'static [u8] = b"86536\0version\0name\0map\06277493\0627272\0gametype\035247\03\06\04\012\0\0player3\0clan3\03\033\01\0\0player1\0clan1\01\011\00\0\0";
+    const PART_1: &'static [u8] = b"86536\01\0\0player4\0clan4\04\044\00\0\0player2\0clan2\02\022\00\0\0";
+
+    #[test]
+    fn two_parts() {
+        let start = Instant::now();
+        let mut reassembly = InfoReassembly::new();
+        let part_0 = Info6ExResponse(PART_0).parse().unwrap();
+        let part_1 = Info6ExMoreResponse(PART_1).parse().unwrap();
+
+        // The parts may arrive in any order, duplicates are ignored.
+        assert!(reassembly.feed(start, 1, part_1.clone()).is_none());
+        assert!(reassembly.feed(start, 1, part_1.clone()).is_none());
+        assert_eq!(reassembly.num_pending(), 1);
+        let complete = reassembly.feed(start, 1, part_0.clone()).unwrap();
+        assert_eq!(reassembly.num_pending(), 0);
+        assert_eq!((complete.addr, complete.token, complete.num_parts), (1, 86536, 2));
+        let names: Vec<_> = complete.info.clients.iter().map(|c| &c.name[..]).collect();
+        assert_eq!(names, [&b"player1"[..], b"player2", b"player3", b"player4"]);
+        assert_eq!(&complete.info.map[..], b"map");
+
+        // Parts from different addresses aren't mixed.
+        assert!(reassembly.feed(start, 1, part_0.clone()).is_none());
+        assert!(reassembly.feed(start, 2, part_1.clone()).is_none());
+        assert_eq!(reassembly.num_pending(), 2);
+    }
+
+    #[test]
+    fn timeout() {
+        let start = Instant::now();
+        let mut reassembly = InfoReassembly::with_timeout(Duration::from_secs(1));
+        let part_0 = Info6ExResponse(PART_0).parse().unwrap();
+        let part_1 = Info6ExMoreResponse(PART_1).parse().unwrap();
+
+        assert!(reassembly.feed(start, 1, part_0.clone()).is_none());
+        // Too late, the first part has been discarded.
+        let late = start + Duration::from_secs(2);
+        assert!(reassembly.feed(late, 1, part_1.clone()).is_none());
+        assert_eq!(reassembly.feed(late, 1, part_0.clone()).unwrap().num_parts, 2);
+
+        assert!(reassembly.feed(start, 1, part_0).is_none());
+        reassembly.expire(start + Duration::from_secs(1));
+        assert_eq!(reassembly.num_pending(), 1);
+        reassembly.expire(late);
+        assert_eq!(reassembly.num_pending(), 0);
+    }
+
+    #[test]
+    fn fragmented_round_trip() {
+        let clients: Vec<_> = (0..12).map(|i| ClientInfo {
+            name: format!("player{:02}", i).bytes().collect(),
+            clan: format!("clan{}", i).bytes().collect(),
+            country: i,
+            score: i * 100,
+            is_player: i % 2,
+        }).collect();
+        let info = ServerInfo {
+            info_version: ServerInfoVersion::V6Ex,
+            token: 1234,
+            version: "0.6.4, 16.0".bytes().collect(),
+            name: "fragmented server".bytes().collect(),
+            hostname: None,
+            map: "Kobra".bytes().collect(),
+            map_crc: Some(0xdeadbeef),
+            map_size: Some(12345),
+            game_type: "DDraceNetwork".bytes().collect(),
+            flags: 0,
+            progression: None,
+            skill_level: None,
+            num_players: 6,
+            max_players: 64,
+            num_clients: 12,
+            max_clients: 64,
+            clients: clients,
+        };
+
+        let packets = info_6_ex_responses(&info, 200).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|p| p.len() <= 200));
+
+        // Feed the fragments out of order.
+        let start = Instant::now();
+        let mut reassembly = InfoReassembly::new();
+        let mut complete = None;
+        for packet in packets.iter().rev() {
+            let part = match parse_response(packet).unwrap() {
+                Response::Info6Ex(r) => r.parse().unwrap(),
+                Response::Info6ExMore(r) => r.parse().unwrap(),
+                _ => panic!("unexpected response"),
+            };
+            assert!(complete.is_none());
+            complete = reassembly.feed(start, 1, part);
+        }
+        let complete = complete.unwrap();
+        assert_eq!(complete.num_parts, 3);
+        assert_eq!(complete.info, info);
+
+        // Too small for the general part of the info.
+        assert!(info_6_ex_responses(&info, 60).is_none());
+    }
+}
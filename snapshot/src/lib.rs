@@ -17,6 +17,7 @@ pub use receiver::ReceivedDelta;
 pub use snap::Delta;
 pub use snap::DeltaReader;
 pub use snap::Snap;
+pub use storage::ServerStorage;
 pub use storage::Storage;
 
 use common::num::Cast;
@@ -216,6 +216,11 @@ impl Snap {
         }
         Ok(p.written())
     }
+    /// Returns the checksum sent along with the deltas of this snapshot.
+    ///
+    /// Like in the reference implementation, it's the wrapping sum of the
+    /// data of all items, their keys aren't included. Receivers compare it
+    /// after applying a delta, see `Storage::add_delta`.
     pub fn crc(&self) -> i32 {
         self.buf.iter().fold(0, |s, &a| s.wrapping_add(a))
     }
@@ -284,6 +289,10 @@ impl Delta {
         assert!(self.updated_items.insert(key, start..end).is_none());
         &mut self.buf[to_usize(start..end)]
     }
+    /// Creates the delta that turns `from` into `to`.
+    ///
+    /// Items missing from `to` are deleted, unchanged items are left out.
+    /// Items present in both snapshots must have the same size.
     pub fn create(&mut self, from: &Snap, to: &Snap) {
         self.clear();
         for Item { type_id, id, .. } in from.items() {
@@ -293,10 +302,18 @@ impl Delta {
         }
         for Item { type_id, id, data } in to.items() {
             let from_data = from.item(type_id, id);
+            if from_data == Some(data) {
+                continue;
+            }
             let out_delta = self.prepare_update_item(type_id, id, data.len());
             create_delta(from_data, data, out_delta);
         }
     }
+    /// Serializes the delta in the Teeworlds format.
+    ///
+    /// The sizes of items whose type has a size according to `object_size`
+    /// are omitted. The CRC of the resulting snapshot is not part of the
+    /// delta, it's sent alongside, see `delta_chunks`.
     pub fn write<'d, 's, O>(&self, object_size: O, mut p: Packer<'d, 's>)
         -> Result<&'d [u8], CapacityError>
         where O: FnMut(u16) -> Option<u32>,
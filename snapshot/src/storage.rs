@@ -7,7 +7,6 @@ use std::collections::VecDeque;
 use warn::Warn;
 use warn::wrap;
 
-// TODO: Separate server storage from client storage.
 // TODO: Delete snapshots over time.
 
 #[derive(Clone)]
@@ -18,6 +17,10 @@ struct StoredSnap {
 
 const MAX_STORED_SNAPSHOT: usize = 100;
 
+/// Default number of snapshots kept by a `ServerStorage`, three seconds worth
+/// of ticks.
+pub const DEFAULT_SERVER_STORAGE_CAPACITY: usize = 150;
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct UnknownSnap;
 
@@ -174,3 +177,109 @@ impl Storage {
         &self.delta
     }
 }
+
+/// Limits of a `ServerStorage`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ServerStorageConfig {
+    /// Maximum number of stored snapshots, the oldest ones are evicted
+    /// first.
+    ///
+    /// Default: `DEFAULT_SERVER_STORAGE_CAPACITY`.
+    pub capacity: usize,
+    /// Snapshots more than this many ticks older than the newest one are
+    /// evicted, regardless of `capacity`.
+    ///
+    /// Default: `None`.
+    pub max_age: Option<i32>,
+}
+
+impl Default for ServerStorageConfig {
+    fn default() -> ServerStorageConfig {
+        ServerStorageConfig {
+            capacity: DEFAULT_SERVER_STORAGE_CAPACITY,
+            max_age: None,
+        }
+    }
+}
+
+/// The snapshots sent to a client, stored to create deltas against the one
+/// the client acknowledged.
+#[derive(Clone, Default)]
+pub struct ServerStorage {
+    /// Queue that stores sent snaps.
+    ///
+    /// The newest elements are in the front.
+    snaps: VecDeque<StoredSnap>,
+    free: Vec<Snap>,
+    config: ServerStorageConfig,
+}
+
+impl ServerStorage {
+    pub fn new() -> ServerStorage {
+        ServerStorage::with_config(ServerStorageConfig::default())
+    }
+    pub fn with_config(config: ServerStorageConfig) -> ServerStorage {
+        ServerStorage {
+            snaps: VecDeque::new(),
+            free: Vec::new(),
+            config: config,
+        }
+    }
+    pub fn config(&self) -> ServerStorageConfig {
+        self.config
+    }
+    pub fn reset(&mut self) {
+        let self_free = &mut self.free;
+        self.snaps.drain(..).map(|s| self_free.push(s.snap)).count();
+    }
+    pub fn len(&self) -> usize {
+        self.snaps.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.snaps.is_empty()
+    }
+    /// Returns the tick of the newest snapshot.
+    pub fn newest_tick(&self) -> Option<i32> {
+        self.snaps.front().map(|s| s.tick)
+    }
+    /// Returns the tick of the oldest snapshot.
+    pub fn oldest_tick(&self) -> Option<i32> {
+        self.snaps.back().map(|s| s.tick)
+    }
+    /// Returns a builder reusing the memory of an evicted snapshot.
+    pub fn new_builder(&mut self) -> Builder {
+        self.free.pop().unwrap_or_default().recycle()
+    }
+    /// Stores the snapshot for `tick` and evicts the snapshots exceeding the
+    /// limits.
+    ///
+    /// Panics if `tick` isn't newer than the ticks of the stored snapshots.
+    pub fn add(&mut self, tick: i32, snap: Snap) {
+        assert!(self.newest_tick().map(|t| t < tick).unwrap_or(true),
+                "snapshots must be added in tick order");
+        self.snaps.push_front(StoredSnap {
+            snap: snap,
+            tick: tick,
+        });
+        while self.snaps.len() > self.config.capacity
+            || self.config.max_age.map(|a| self.snaps.back().unwrap().tick < tick - a).unwrap_or(false)
+        {
+            self.free.push(self.snaps.pop_back().unwrap().snap);
+        }
+    }
+    /// Returns the snapshot for `tick`, if it's still stored.
+    pub fn get(&self, tick: i32) -> Option<&Snap> {
+        // The ticks are sorted in descending order.
+        self.snaps.binary_search_by(|s| tick.cmp(&s.tick)).ok().map(|i| &self.snaps[i].snap)
+    }
+    /// Evicts all snapshots older than `tick`.
+    ///
+    /// Once a client acknowledged a snapshot, older ones aren't needed as
+    /// delta references anymore.
+    pub fn purge_until(&mut self, tick: i32) {
+        if let Some(i) = self.snaps.iter().position(|s| s.tick < tick) {
+            let self_free = &mut self.free;
+            self.snaps.drain(i..).map(|s| self_free.push(s.snap)).count();
+        }
+    }
+}
@@ -8,6 +8,7 @@ use buffer::CapacityError;
 use gamenet::snap_obj::obj_size;
 use packer::Unpacker;
 use packer::with_packer;
+use snapshot::snap::Builder;
 use snapshot::snap::Delta;
 use snapshot::snap::DeltaReader;
 use snapshot::snap::Snap;
@@ -55,3 +56,59 @@ fn simple() {
     println!("{:?}", snap);
     assert_eq!(snap.crc(), SECOND_CRC);
 }
+
+fn sorted_items(snap: &Snap) -> Vec<(u16, u16, Vec<i32>)> {
+    let mut items: Vec<_> = snap.items().map(|i| (i.type_id, i.id, i.data.to_owned())).collect();
+    items.sort();
+    items
+}
+
+fn round_trip(from: &Snap, to: &Snap) -> usize {
+    let mut buf = Vec::with_capacity(4096);
+    let mut delta = Delta::new();
+    delta.create(from, to);
+    with_packer(&mut buf, |p| delta.write(obj_size, p)).unwrap();
+
+    let mut read = Delta::new();
+    DeltaReader::new().read(&mut Panic, &mut read, obj_size, &mut Unpacker::new(&buf)).unwrap();
+    let mut snap = Snap::empty();
+    snap.read_with_delta(&mut Panic, from, &read).unwrap();
+    assert_eq!(sorted_items(&snap), sorted_items(to));
+    assert_eq!(snap.crc(), to.crc());
+    buf.len()
+}
+
+fn build(items: &[(u16, u16, &[i32])]) -> Snap {
+    let mut builder = Builder::new();
+    for &(type_id, id, data) in items {
+        builder.add_item(type_id, id, data).unwrap();
+    }
+    builder.finish()
+}
+
+#[test]
+fn create_delta() {
+    let empty = Snap::empty();
+    // Type 9 has a fixed size, type 100 is unknown and gets its size
+    // written.
+    let character = [0; 22];
+    let first = build(&[
+        (9, 0, &character),
+        (100, 0, &[1, 2, 3]),
+        (100, 1, &[4]),
+    ]);
+    let mut moved = character;
+    moved[2] = 1584;
+    let second = build(&[
+        (9, 0, &moved),
+        (100, 1, &[4]),
+        (100, 2, &[-5, 6]),
+    ]);
+    round_trip(&empty, &first);
+    round_trip(&first, &second);
+    round_trip(&second, &first);
+    round_trip(&second, &empty);
+
+    // Unchanged snapshots only need the header.
+    assert_eq!(round_trip(&second, &second), 3);
+}
@@ -0,0 +1,119 @@
+extern crate snapshot;
+extern crate warn;
+
+use snapshot::Delta;
+use snapshot::ServerStorage;
+use snapshot::Snap;
+use snapshot::Storage;
+use snapshot::snap::Builder;
+use snapshot::storage::Error;
+use snapshot::storage::ServerStorageConfig;
+use std::i32;
+use warn::Panic;
+
+fn snap(value: i32) -> Snap {
+    let mut builder = Builder::new();
+    builder.add_item(1, 0, &[value]).unwrap();
+    builder.finish()
+}
+
+fn value(storage: &ServerStorage, tick: i32) -> Option<i32> {
+    storage.get(tick).map(|s| s.item(1, 0).unwrap()[0])
+}
+
+#[test]
+fn get() {
+    let mut storage = ServerStorage::new();
+    assert!(storage.get(0).is_none());
+    for tick in 0..5 {
+        storage.add(tick * 2, snap(tick));
+    }
+    assert_eq!(storage.len(), 5);
+    assert_eq!(storage.oldest_tick(), Some(0));
+    assert_eq!(storage.newest_tick(), Some(8));
+    for tick in 0..5 {
+        assert_eq!(value(&storage, tick * 2), Some(tick));
+        assert_eq!(value(&storage, tick * 2 + 1), None);
+    }
+}
+
+#[test]
+fn capacity() {
+    let mut storage = ServerStorage::with_config(ServerStorageConfig {
+        capacity: 3,
+        max_age: None,
+    });
+    for tick in 0..5 {
+        storage.add(tick, snap(tick));
+    }
+    assert_eq!(storage.len(), 3);
+    assert_eq!(value(&storage, 1), None);
+    assert_eq!(value(&storage, 2), Some(2));
+    assert_eq!(value(&storage, 4), Some(4));
+}
+
+#[test]
+fn max_age() {
+    let mut storage = ServerStorage::with_config(ServerStorageConfig {
+        capacity: 100,
+        max_age: Some(10),
+    });
+    storage.add(0, snap(0));
+    storage.add(5, snap(5));
+    storage.add(10, snap(10));
+    assert_eq!(storage.len(), 3);
+    storage.add(11, snap(11));
+    assert_eq!(storage.oldest_tick(), Some(5));
+    storage.add(30, snap(30));
+    assert_eq!(storage.len(), 1);
+    assert_eq!(value(&storage, 30), Some(30));
+}
+
+#[test]
+fn purge_until() {
+    let mut storage = ServerStorage::new();
+    for tick in 0..5 {
+        storage.add(tick, snap(tick));
+    }
+    storage.purge_until(3);
+    assert_eq!(storage.oldest_tick(), Some(3));
+    assert_eq!(value(&storage, 2), None);
+    assert_eq!(value(&storage, 3), Some(3));
+
+    // The evicted snapshots are reused.
+    let builder = storage.new_builder();
+    assert!(builder.finish().items().next().is_none());
+
+    storage.reset();
+    assert!(storage.is_empty());
+}
+
+#[test]
+#[should_panic]
+fn add_out_of_order() {
+    let mut storage = ServerStorage::new();
+    storage.add(1, snap(1));
+    storage.add(1, snap(1));
+}
+
+#[test]
+fn crc() {
+    let mut builder = Builder::new();
+    builder.add_item(1, 0, &[1, 2, 3]).unwrap();
+    builder.add_item(4, 1, &[i32::MAX, 2]).unwrap();
+    builder.add_item(0x7fff, 0xffff, &[]).unwrap();
+    let snap = builder.finish();
+    // The sum wraps around, the keys don't contribute.
+    assert_eq!(snap.crc(), -2147483641);
+
+    // Deltas resulting in a snapshot with a different checksum are rejected.
+    let mut delta = Delta::new();
+    delta.create(&Snap::empty(), &snap);
+    let mut storage = Storage::new();
+    assert_eq!(storage.add_delta(&mut Panic, Some(snap.crc() + 1), -1, 1, &delta).unwrap_err(),
+               Error::InvalidCrc);
+    assert_eq!(storage.ack_tick(), None);
+    assert_eq!(storage.add_delta(&mut Panic, Some(snap.crc()), -1, 2, &delta).unwrap().crc(),
+               snap.crc());
+    assert_eq!(storage.ack_tick(), Some(2));
+}
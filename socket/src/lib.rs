@@ -293,9 +293,11 @@ impl Callback<Addr> for Socket {
         // EHOSTUNREACH
         // ENETDOWN
         // ENTUNREACH
-        // EAGAIN EWOULDBLOCK
     }
     fn time(&mut self) -> Timestamp {
         self.time_cached
     }
+    fn would_block(&self, e: &io::Error) -> bool {
+        e.kind() == io::ErrorKind::WouldBlock
+    }
 }
@@ -4,13 +4,17 @@ extern crate datafile;
 extern crate logger;
 extern crate map;
 extern crate rmp;
+extern crate tools;
 
 use common::num::Cast;
-use map::format::Tile;
+use map::entities;
 use std::fs::File;
+use std::io::Write;
 use std::io;
 use std::path::Path;
 use std::process;
+use tools::tile_count::TileCounts;
+use tools::tile_count::TileIndex;
 
 #[derive(Debug)]
 struct Error(map::Error);
@@ -49,20 +53,13 @@ impl From<rmp::encode::ValueWriteError> for Error {
     }
 }
 
-fn count<'a, I: Iterator<Item=&'a Tile>>(tiles: I, count: &mut [u64; 256]) {
-    for tile in tiles {
-        count[tile.index.usize()] += 1;
-    }
-}
-
+// Names the tool used before `map::entities` existed are kept, so that the
+// output stays compatible.
 fn tile(index: u8) -> Option<&'static str> {
     Some(match index {
-        12 => "DFREEZE",
-        16 => "WALLJUMP",
         17 => "EHOOK_START",
         19 => "HIT_START",
         21 => "SOLO_START",
-        
         96 => "TELE_GUN",
         104 => "NPC_START",
         105 => "SUPER_START",
@@ -70,44 +67,63 @@ fn tile(index: u8) -> Option<&'static str> {
         107 => "NPH_START",
         112 => "TELE_GRENADE",
         128 => "TELE_LASER",
-
-        199 => "WEAPON_SHOTGUN",
-        200 => "WEAPON_GRENADE",
-        201 => "POWERUP_NINJA",
         202 => "WEAPON_RIFLE",
-
-        _ => return None,
+        _ => return entities::name(index),
     })
 }
 
-fn process(path: &Path, output_path: &Path) -> Result<(), Error> {
-    let mut output = File::create(output_path)?;
+fn process(path: &Path, mut output: Box<dyn Write>) -> Result<(), Error> {
     let mut map = map::Reader::open(path)?;
     let game_layers = map.game_layers()?;
 
-    let mut tiles_count = [0u64; 256];
-    count(map.layer_tiles(game_layers.game())?.iter(), &mut tiles_count);
+    let mut tiles_count = TileCounts::new();
+    for tile in map.layer_tiles_stream(game_layers.game())? {
+        tiles_count.add(&tile?);
+    }
     if let Some(f) = game_layers.front() {
-        count(map.layer_tiles(f)?.iter(), &mut tiles_count);
+        for tile in map.layer_tiles_stream(f)? {
+            tiles_count.add(&tile?);
+        }
+    }
+    if let Some(t) = game_layers.teleport() {
+        for tile in map.tele_layer_tiles(t)?.iter() {
+            tiles_count.add_index(TileIndex::new(tile.index));
+        }
+    }
+    if let Some(s) = game_layers.speedup() {
+        for tile in map.speedup_layer_tiles(s)?.iter() {
+            tiles_count.add_index(TileIndex::new(tile.index));
+        }
+    }
+    if let Some(s) = game_layers.switch() {
+        for tile in map.switch_layer_tiles(s)?.iter() {
+            tiles_count.add_index(TileIndex::new(tile.index));
+        }
+    }
+    if let Some(t) = game_layers.tune() {
+        for tile in map.tune_layer_tiles(t)?.iter() {
+            tiles_count.add_index(TileIndex::new(tile.index));
+        }
     }
 
     rmp::encode::write_uint(&mut output, game_layers.width.u64())?;
     rmp::encode::write_uint(&mut output, game_layers.height.u64())?;
 
-    let len = tiles_count.iter().enumerate().filter(|&(i, &c)| {
-        c != 0 && tile(i.assert_u8()).is_some()
+    let len = tiles_count.iter().filter(|&(i, c)| {
+        c != 0 && tile(i.get()).is_some()
     }).count();
 
     rmp::encode::write_map_len(&mut output, len.assert_u32())?;
-    for (i, &c) in tiles_count.iter().enumerate() {
+    for (i, c) in tiles_count.iter() {
         if c == 0 {
             continue;
         }
-        if let Some(desc) = tile(i.assert_u8()) {
+        if let Some(desc) = tile(i.get()) {
             rmp::encode::write_str(&mut output, desc)?;
             rmp::encode::write_bool(&mut output, true)?;
         }
     }
+    output.flush()?;
 
     Ok(())
 }
@@ -125,17 +141,31 @@ fn main() {
              .help("Sets the map file to analyse")
              .required(true))
         .arg(Arg::with_name("OUTPUT")
-             .help("Sets the msgpack file to output")
-             .required(true))
+             .help("Sets the msgpack file to output, writes to stdout if \
+                    omitted or `-`"))
         .get_matches();
 
     let path = Path::new(matches.value_of_os("MAP").unwrap());
-    let output_path = Path::new(matches.value_of_os("OUTPUT").unwrap());
-
-    match process(path, output_path) {
+    let output_path = matches.value_of_os("OUTPUT")
+        .filter(|&o| o != "-")
+        .map(Path::new);
+
+    // Only the msgpack output goes to stdout, errors are reported on stderr.
+    let output: Box<dyn Write> = match output_path {
+        Some(o) => match File::create(o) {
+            Ok(f) => Box::new(f),
+            Err(err) => {
+                eprintln!("{}: {:?}", o.display(), err);
+                process::exit(1);
+            }
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    match process(path, output) {
         Ok(()) => {},
         Err(err) => {
-            println!("{}: {:?}", path.display(), err);
+            eprintln!("{}: {:?}", path.display(), err);
             process::exit(1);
         }
     }
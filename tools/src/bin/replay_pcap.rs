@@ -0,0 +1,170 @@
+extern crate clap;
+extern crate hexdump;
+extern crate logger;
+extern crate net;
+extern crate pcap;
+extern crate tools;
+
+use hexdump::hexdump;
+use net::Timestamp;
+use net::net::ChunkOrEvent;
+use pcap::Capture;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use tools::replay::Direction;
+use tools::replay::Replay;
+
+const LINKTYPE_ETHERNET: i32 = 1;
+const LINKTYPE_RAW: i32 = 101;
+const LINKTYPE_LINUX_SLL: i32 = 113;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+const IPPROTO_UDP: u8 = 17;
+
+fn u16_be(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+/// Returns the IP packet contained in a link layer frame.
+fn link_payload(linktype: i32, frame: &[u8]) -> Option<&[u8]> {
+    let (ethertype, header_len) = match linktype {
+        LINKTYPE_ETHERNET => (12, 14),
+        LINKTYPE_LINUX_SLL => (14, 16),
+        LINKTYPE_RAW => return Some(frame),
+        _ => return None,
+    };
+    if frame.len() < header_len {
+        return None;
+    }
+    match u16_be(&frame[ethertype..]) {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Some(&frame[header_len..]),
+        _ => None,
+    }
+}
+
+/// Parses an IP packet containing a UDP datagram, returning source address,
+/// destination address and payload.
+fn udp(ip: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    if ip.is_empty() {
+        return None;
+    }
+    let (src, dst, udp) = match ip[0] >> 4 {
+        4 => {
+            let header_len = (ip[0] & 0x0f) as usize * 4;
+            if ip.len() < 20 || ip.len() < header_len || ip[9] != IPPROTO_UDP {
+                return None;
+            }
+            let src = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+            let dst = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+            (IpAddr::V4(src), IpAddr::V4(dst), &ip[header_len..])
+        }
+        6 => {
+            // Extension headers aren't supported.
+            if ip.len() < 40 || ip[6] != IPPROTO_UDP {
+                return None;
+            }
+            let mut src = [0; 16];
+            let mut dst = [0; 16];
+            src.copy_from_slice(&ip[8..24]);
+            dst.copy_from_slice(&ip[24..40]);
+            (IpAddr::V6(Ipv6Addr::from(src)), IpAddr::V6(Ipv6Addr::from(dst)), &ip[40..])
+        }
+        _ => return None,
+    };
+    if udp.len() < 8 {
+        return None;
+    }
+    let len = u16_be(&udp[4..]) as usize;
+    if len < 8 || udp.len() < len {
+        return None;
+    }
+    let src = SocketAddr::new(src, u16_be(&udp[0..]));
+    let dst = SocketAddr::new(dst, u16_be(&udp[2..]));
+    Some((src, dst, &udp[8..len]))
+}
+
+fn print_event(direction: Direction, client: SocketAddr, chunk: &ChunkOrEvent<SocketAddr>) {
+    let arrow = match direction {
+        Direction::ToServer => "->",
+        Direction::ToClient => "<-",
+    };
+    match *chunk {
+        ChunkOrEvent::Chunk(c) => {
+            println!("{} {} chunk vital={}", client, arrow, c.vital);
+            hexdump(c.data);
+        }
+        ChunkOrEvent::Connless(c) => {
+            println!("{} {} connless", client, arrow);
+            hexdump(c.data);
+        }
+        ChunkOrEvent::Connect(..) => println!("{} {} connect", client, arrow),
+        ChunkOrEvent::Ready(_) => println!("{} {} ready", client, arrow),
+        ChunkOrEvent::Disconnect(_, _, reason) => {
+            println!("{} {} disconnect {:?}", client, arrow, String::from_utf8_lossy(reason));
+        }
+    }
+}
+
+fn replay(path: &Path, server: SocketAddr) -> Result<(), pcap::Error> {
+    let mut capture = Capture::from_file(path)?;
+    let linktype = capture.get_datalink().0;
+    let mut replay = Replay::new(server);
+    loop {
+        let packet = match capture.next() {
+            Ok(p) => p,
+            Err(pcap::Error::NoMorePackets) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let ts = packet.header.ts;
+        let time = Timestamp::from_usecs_since_epoch(
+            ts.tv_sec as u64 * 1_000_000 + ts.tv_usec as u64
+        );
+        let (src, dst, data) = match link_payload(linktype, &packet).and_then(udp) {
+            Some(x) => x,
+            None => continue,
+        };
+        if dst == server {
+            replay.feed(time, Direction::ToServer, src, data, print_event);
+        } else if src == server {
+            replay.feed(time, Direction::ToClient, dst, data, print_event);
+        }
+    }
+}
+
+fn main() {
+    use clap::App;
+    use clap::Arg;
+
+    logger::init();
+
+    let matches = App::new("Capture replayer")
+        .about("Reconstructs the Teeworlds sessions of a server from a pcap capture.")
+        .arg(Arg::with_name("CAPTURE")
+            .help("Sets the pcap file to replay")
+            .required(true)
+        )
+        .arg(Arg::with_name("SERVER")
+            .help("Sets the address of the server, e.g. 127.0.0.1:8303")
+            .required(true)
+        )
+        .get_matches();
+
+    let path = Path::new(matches.value_of_os("CAPTURE").unwrap());
+    let server: SocketAddr = match matches.value_of("SERVER").unwrap().parse() {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("invalid server address: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = replay(path, server) {
+        eprintln!("{}: {:?}", path.display(), e);
+        process::exit(1);
+    }
+}
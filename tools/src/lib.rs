@@ -1,9 +1,14 @@
+extern crate arrayvec;
 extern crate datafile as df;
 extern crate logger;
 extern crate map;
+extern crate net;
+extern crate void;
 extern crate warn;
 
 pub mod client;
 pub mod map_stats;
+pub mod replay;
+pub mod tile_count;
 pub mod unhexdump;
 pub mod warn_stdout;
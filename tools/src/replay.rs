@@ -0,0 +1,160 @@
+use arrayvec::ArrayVec;
+use net::Net;
+use net::Timestamp;
+use net::net::Address;
+use net::net::Callback;
+use net::net::ChunkOrEvent;
+use net::protocol::ConnectedPacket;
+use net::protocol::ConnectedPacketType;
+use net::protocol::ControlPacket;
+use net::protocol::Packet;
+use std::collections::HashMap;
+use std::fmt;
+use void::ResultVoidExt;
+use void::Void;
+use warn;
+use warn_stdout::Stdout;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+// Swallows everything the replaying `Net`s want to send, the actual
+// responses are part of the capture.
+struct Sink {
+    time: Timestamp,
+}
+
+impl<A: Address> Callback<A> for Sink {
+    type Error = Void;
+    fn send(&mut self, _: A, _: &[u8]) -> Result<(), Void> {
+        Ok(())
+    }
+    fn time(&mut self) -> Timestamp {
+        self.time
+    }
+}
+
+fn is_connect(data: &[u8]) -> bool {
+    let mut buf: ArrayVec<[u8; 2048]> = ArrayVec::new();
+    match Packet::read(&mut warn::Ignore, data, &mut buf) {
+        Ok(Packet::Connected(ConnectedPacket {
+            type_: ConnectedPacketType::Control(ControlPacket::Connect), ..
+        })) => true,
+        _ => false,
+    }
+}
+
+/// Reconstructs the connections of a server from captured packets.
+///
+/// A server-side `Net` is fed the packets sent to the server, and a
+/// client-side `Net` per client address the packets sent back. Packets the
+/// `Net`s would send in response are discarded.
+pub struct Replay<A: Address> {
+    server_addr: A,
+    server: Net<A>,
+    clients: HashMap<A, Net<A>>,
+    sink: Sink,
+}
+
+impl<A: Address + Copy + fmt::Debug> Replay<A> {
+    pub fn new(server_addr: A) -> Replay<A> {
+        Replay {
+            server_addr: server_addr,
+            server: Net::server(),
+            clients: HashMap::new(),
+            sink: Sink { time: Timestamp::from_secs_since_epoch(0) },
+        }
+    }
+    /// Feeds a captured packet between `client` and the server.
+    ///
+    /// `time` is the capture time of the packet. `f` is called for every
+    /// event of the reconstructed session, together with the direction of
+    /// the packet and the client's address.
+    pub fn feed<F>(&mut self, time: Timestamp, direction: Direction, client: A, data: &[u8], mut f: F)
+        where F: FnMut(Direction, A, &ChunkOrEvent<A>),
+    {
+        self.sink.time = time;
+        let mut buf: ArrayVec<[u8; 2048]> = ArrayVec::new();
+        match direction {
+            Direction::ToServer => {
+                if is_connect(data) {
+                    // Mirror the connection attempt on the client side, so
+                    // that the server's answer can be interpreted.
+                    let mut net = Net::client();
+                    net.connect(&mut self.sink, self.server_addr).1.void_unwrap();
+                    self.clients.insert(client, net);
+                }
+                let mut connects = vec![];
+                {
+                    let (packet, res) = self.server.feed(&mut self.sink, &mut Stdout, client, data, &mut buf);
+                    res.void_unwrap();
+                    for chunk in packet {
+                        if let ChunkOrEvent::Connect(pid, _) = chunk {
+                            connects.push(pid);
+                        }
+                        f(direction, client, &chunk);
+                    }
+                }
+                for pid in connects {
+                    self.server.accept(&mut self.sink, pid).void_unwrap();
+                }
+            }
+            Direction::ToClient => {
+                let net = match self.clients.get_mut(&client) {
+                    Some(n) => n,
+                    // The connection attempt wasn't captured.
+                    None => return,
+                };
+                let (packet, res) = net.feed(&mut self.sink, &mut Stdout, self.server_addr, data, &mut buf);
+                res.void_unwrap();
+                for chunk in packet {
+                    f(direction, client, &chunk);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use net::Timestamp;
+    use net::net::ChunkOrEvent;
+    use super::Direction::ToClient;
+    use super::Direction::ToServer;
+    use super::Replay;
+
+    #[test]
+    fn session() {
+        let packets: &[(_, &[u8])] = &[
+            (ToServer, b"\x10\x00\x00\x01"),
+            (ToClient, b"\x10\x00\x00\x02"),
+            (ToServer, b"\x10\x00\x00\x03"),
+            (ToServer, b"\x00\x00\x01\x40\x01\x01\x42"),
+            (ToClient, b"\x10\x00\x00\x04bye\0"),
+        ];
+        let mut events = vec![];
+        let mut replay = Replay::new(0u32);
+        for &(direction, data) in packets {
+            let time = Timestamp::from_secs_since_epoch(0);
+            replay.feed(time, direction, 1, data, |d, client, chunk| {
+                assert_eq!(client, 1);
+                events.push((d, match *chunk {
+                    ChunkOrEvent::Chunk(c) => format!("chunk {:?}", c.data),
+                    ChunkOrEvent::Connless(_) => format!("connless"),
+                    ChunkOrEvent::Connect(..) => format!("connect"),
+                    ChunkOrEvent::Ready(_) => format!("ready"),
+                    ChunkOrEvent::Disconnect(_, _, reason) => format!("disconnect {:?}", reason),
+                }));
+            });
+        }
+        assert_eq!(events, [
+            (ToServer, "connect".to_owned()),
+            (ToClient, "ready".to_owned()),
+            (ToServer, "chunk [66]".to_owned()),
+            (ToClient, "disconnect [98, 121, 101]".to_owned()),
+        ]);
+    }
+}
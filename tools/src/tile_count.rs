@@ -0,0 +1,108 @@
+use map::format::Tile;
+use std::ops;
+
+/// Index of a tile, always in the range `0..256`.
+///
+/// Can be used to index into `TileCounts` without bounds checks.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TileIndex(u8);
+
+pub const NUM_TILE_INDICES: usize = 256;
+
+impl TileIndex {
+    pub fn new(index: u8) -> TileIndex {
+        TileIndex(index)
+    }
+    pub fn from_tile(tile: &Tile) -> TileIndex {
+        TileIndex(tile.index)
+    }
+    pub fn get(self) -> u8 {
+        self.0
+    }
+    pub fn usize(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Number of occurrences of each tile index.
+pub struct TileCounts([u64; NUM_TILE_INDICES]);
+
+impl TileCounts {
+    pub fn new() -> TileCounts {
+        TileCounts([0; NUM_TILE_INDICES])
+    }
+    pub fn add(&mut self, tile: &Tile) {
+        self.add_index(TileIndex::from_tile(tile));
+    }
+    pub fn add_index(&mut self, index: TileIndex) {
+        let count = &mut self.0[index.usize()];
+        *count = count.saturating_add(1);
+    }
+    pub fn add_all<'a, I: Iterator<Item=&'a Tile>>(&mut self, tiles: I) {
+        for tile in tiles {
+            self.add(tile);
+        }
+    }
+    pub fn iter(&self) -> Iter {
+        Iter {
+            counts: self,
+            next: 0,
+        }
+    }
+}
+
+impl ops::Index<TileIndex> for TileCounts {
+    type Output = u64;
+    fn index(&self, index: TileIndex) -> &u64 {
+        &self.0[index.usize()]
+    }
+}
+
+/// Iterator over all tile indices and their counts, including zero counts.
+pub struct Iter<'a> {
+    counts: &'a TileCounts,
+    next: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (TileIndex, u64);
+    fn next(&mut self) -> Option<(TileIndex, u64)> {
+        if self.next == NUM_TILE_INDICES {
+            return None;
+        }
+        let index = TileIndex(self.next as u8);
+        self.next += 1;
+        Some((index, self.counts[index]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use map::format::Tile;
+    use super::NUM_TILE_INDICES;
+    use super::TileCounts;
+    use super::TileIndex;
+
+    #[test]
+    fn all_indices() {
+        let tiles: Vec<Tile> = (0..NUM_TILE_INDICES).map(|i| Tile {
+            index: i as u8,
+            flags: 0,
+            skip: 0,
+            reserved: 0,
+        }).collect();
+        let mut counts = TileCounts::new();
+        counts.add_all(tiles.iter());
+        counts.add_all(tiles[255..].iter());
+
+        assert_eq!(counts.iter().count(), NUM_TILE_INDICES);
+        for (index, count) in counts.iter() {
+            let expected = if index == TileIndex::new(255) { 2 } else { 1 };
+            assert_eq!(count, expected);
+        }
+        assert_eq!(counts[TileIndex::new(0)], 1);
+
+        counts.add_index(TileIndex::new(0));
+        assert_eq!(counts[TileIndex::new(0)], 2);
+    }
+}
@@ -6,7 +6,12 @@
 extern crate libc;
 extern crate libz_sys as raw;
 
+use std::cmp;
+use std::error;
 use std::fmt;
+use std::mem;
+use std::ptr;
+use libc::c_int;
 use libc::c_ulong;
 
 #[derive(Clone, Copy, Eq, Hash, PartialEq)]
@@ -51,6 +56,19 @@ impl fmt::Debug for Error {
     }
 }
 
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind() {
+            Ok(ErrorKind::OutOfMemory) => f.write_str("out of memory"),
+            Ok(ErrorKind::OutputBufferTooSmall) => f.write_str("output buffer too small"),
+            Ok(ErrorKind::InvalidInput) => f.write_str("invalid input data"),
+            Err(()) => write!(f, "unknown zlib error {}", self.raw_error()),
+        }
+    }
+}
+
 /// The wrapper for zlib's `uncompress` function.
 ///
 /// Uncompresses the `src` parameter into the `dest` parameter and returning
@@ -97,3 +115,95 @@ pub fn compress_vec(source: &[u8]) -> Result<Vec<u8>, Error> {
 
     Ok(dest)
 }
+
+unsafe extern "C" fn zalloc(_opaque: raw::voidpf, items: raw::uInt, size: raw::uInt) -> raw::voidpf {
+    libc::calloc(items as libc::size_t, size as libc::size_t) as raw::voidpf
+}
+
+unsafe extern "C" fn zfree(_opaque: raw::voidpf, address: raw::voidpf) {
+    libc::free(address as *mut libc::c_void)
+}
+
+/// The wrapper for zlib's streaming `inflate` function.
+///
+/// Unlike `uncompress`, it neither needs the whole input nor the whole output
+/// in memory at once.
+pub struct Inflate {
+    // Boxed because zlib keeps a pointer to the stream in its state.
+    stream: Box<raw::z_stream>,
+    finished: bool,
+}
+
+impl Inflate {
+    pub fn new() -> Result<Inflate, Error> {
+        let mut stream = Box::new(raw::z_stream {
+            next_in: ptr::null_mut(),
+            avail_in: 0,
+            total_in: 0,
+            next_out: ptr::null_mut(),
+            avail_out: 0,
+            total_out: 0,
+            msg: ptr::null_mut(),
+            state: ptr::null_mut(),
+            zalloc: zalloc,
+            zfree: zfree,
+            opaque: ptr::null_mut(),
+            data_type: 0,
+            adler: 0,
+            reserved: 0,
+        });
+        Error::from_raw(unsafe {
+            raw::inflateInit_(&mut *stream, raw::zlibVersion(),
+                              mem::size_of::<raw::z_stream>() as c_int)
+        })?;
+        Ok(Inflate {
+            stream: stream,
+            finished: false,
+        })
+    }
+    /// Uncompresses from `src` into `dest`.
+    ///
+    /// Returns the number of bytes consumed from `src` and the number of
+    /// bytes written to `dest`. Both are zero if no progress is possible,
+    /// i.e. if `src` or `dest` is empty or the end of the compressed stream
+    /// has been reached, see `is_finished`.
+    pub fn inflate(&mut self, dest: &mut [u8], src: &[u8]) -> Result<(usize, usize), Error> {
+        if self.finished {
+            return Ok((0, 0));
+        }
+        let src_len = cmp::min(src.len(), raw::uInt::max_value() as usize);
+        let dest_len = cmp::min(dest.len(), raw::uInt::max_value() as usize);
+        self.stream.next_in = src.as_ptr() as *mut _;
+        self.stream.avail_in = src_len as raw::uInt;
+        self.stream.next_out = dest.as_mut_ptr();
+        self.stream.avail_out = dest_len as raw::uInt;
+        let result = unsafe { raw::inflate(&mut *self.stream, raw::Z_NO_FLUSH) };
+        let consumed = src_len - self.stream.avail_in as usize;
+        let written = dest_len - self.stream.avail_out as usize;
+        self.stream.next_in = ptr::null_mut();
+        self.stream.avail_in = 0;
+        self.stream.next_out = ptr::null_mut();
+        self.stream.avail_out = 0;
+        match result {
+            raw::Z_STREAM_END => self.finished = true,
+            // No progress was possible.
+            raw::Z_BUF_ERROR => {},
+            // Preset dictionaries aren't supported.
+            raw::Z_NEED_DICT => return Err(Error { inner: raw::Z_DATA_ERROR }),
+            r => Error::from_raw(r)?,
+        }
+        Ok((consumed, written))
+    }
+    /// Returns whether the end of the compressed stream has been reached.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+impl Drop for Inflate {
+    fn drop(&mut self) {
+        unsafe {
+            raw::inflateEnd(&mut *self.stream);
+        }
+    }
+}